@@ -0,0 +1,207 @@
+//! Runtime configuration of the tunables of PlayerInfo
+use anyhow::{anyhow, Result};
+
+/// The maximum amount of players the protocol can address
+pub const PROTOCOL_MAX_PLAYERS: usize = 2047;
+/// The maximum distance at which players are visible in the client
+pub const PROTOCOL_MAX_VIEW_DISTANCE: i32 = 15;
+/// The maximum amount of NPCs local to a player, which the packet writes using 8 bits
+pub const PROTOCOL_MAX_LOCAL_NPCS: usize = 255;
+/// The maximum delta of a small teleport, which is written using 5 bits per axis
+pub const PROTOCOL_MAX_REBUILD_BOUNDARY: i32 = 16;
+
+/// The tunables of PlayerInfo, set at construction and validated against each other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldInfoConfig {
+    /// The maximum amount of players that can be added. Encoding only visits the records of this many players,
+    /// so small worlds such as instanced duels should keep it down to their population
+    pub max_players: usize,
+    /// The distance in tiles at which players see each other, players further away are removed
+    pub view_distance: i32,
+    /// The amount of tiles players have to be within the view distance before they are added,
+    /// so players walking along the edge of the view are not added and removed every other tick
+    pub view_hysteresis: i32,
+    /// The maximum amount of players added to an observer each tick, the rest are added in later ticks.
+    /// Unbounded by default
+    pub max_additions_per_tick: usize,
+    /// The view distance of observers marked as idle, capped at the view distance
+    pub idle_view_distance: i32,
+    /// The maximum amount of players added to an observer marked as idle each tick
+    pub idle_max_additions_per_tick: usize,
+    /// The distance in tiles from which a movement is sent as a large teleport, and the map is rebuilt
+    pub rebuild_boundary: i32,
+    /// The capacity in bytes of the buffer masks are written to
+    pub mask_buffer_capacity: usize,
+    /// The amount of ticks a mask may stay pending without being written before it is dropped, `None` never drops masks
+    pub mask_expiry: Option<u32>,
+    /// The maximum amount of NPCs local to a player, further NPCs are added once others leave the view
+    pub max_local_npcs: usize,
+    /// The maximum amount of NPCs added to a player each tick, nearest first. The rest are added in later ticks
+    pub max_npc_additions_per_tick: usize,
+    /// Whether every encoded buffer is decoded again to verify its skip counts, failing the observer on a mismatch.
+    /// Enabled in debug builds by default
+    pub validate_skip_counts: bool,
+    /// Whether `process_all` reuses the buffer encoded for an observer for the following observers in the exact same state,
+    /// such as players standing on the same tile seeing the same players
+    pub reuse_identical_encodings: bool,
+}
+
+impl Default for WorldInfoConfig {
+    fn default() -> Self {
+        WorldInfoConfig {
+            max_players: PROTOCOL_MAX_PLAYERS,
+            view_distance: PROTOCOL_MAX_VIEW_DISTANCE,
+            view_hysteresis: 0,
+            max_additions_per_tick: usize::MAX,
+            idle_view_distance: 5,
+            idle_max_additions_per_tick: 5,
+            rebuild_boundary: PROTOCOL_MAX_REBUILD_BOUNDARY,
+            mask_buffer_capacity: 60000,
+            mask_expiry: None,
+            max_local_npcs: PROTOCOL_MAX_LOCAL_NPCS,
+            max_npc_additions_per_tick: 40,
+            validate_skip_counts: cfg!(debug_assertions),
+            reuse_identical_encodings: false,
+        }
+    }
+}
+
+impl WorldInfoConfig {
+    /// The config of a small world holding at most the given amount of players, e.g. a duel or a tournament instance
+    pub fn small_world(max_players: usize) -> WorldInfoConfig {
+        WorldInfoConfig {
+            max_players,
+            ..Default::default()
+        }
+    }
+
+    /// The view distance of observers marked as idle, which never see further than active ones
+    pub fn effective_idle_view_distance(&self) -> i32 {
+        self.idle_view_distance.min(self.view_distance)
+    }
+
+    /// Check the config against the limits of the protocol and for inconsistent combinations
+    pub fn validate(&self) -> Result<()> {
+        if self.max_players == 0 || self.max_players > PROTOCOL_MAX_PLAYERS {
+            return Err(anyhow!(
+                "Max players {} is not within 1 and {}",
+                self.max_players,
+                PROTOCOL_MAX_PLAYERS
+            ));
+        }
+        if self.view_distance < 1 || self.view_distance > PROTOCOL_MAX_VIEW_DISTANCE {
+            return Err(anyhow!(
+                "View distance {} is not within 1 and {}",
+                self.view_distance,
+                PROTOCOL_MAX_VIEW_DISTANCE
+            ));
+        }
+        if self.rebuild_boundary < 1 || self.rebuild_boundary > PROTOCOL_MAX_REBUILD_BOUNDARY {
+            return Err(anyhow!(
+                "Rebuild boundary {} is not within 1 and {}",
+                self.rebuild_boundary,
+                PROTOCOL_MAX_REBUILD_BOUNDARY
+            ));
+        }
+        // Players seen near the edge of the map would otherwise be outside of the area the client has loaded
+        if self.view_distance > self.rebuild_boundary {
+            return Err(anyhow!(
+                "View distance {} exceeds the rebuild boundary {}",
+                self.view_distance,
+                self.rebuild_boundary
+            ));
+        }
+        if self.max_additions_per_tick == 0 {
+            return Err(anyhow!("Max additions per tick cannot be 0"));
+        }
+        if self.idle_view_distance < 1 {
+            return Err(anyhow!(
+                "Idle view distance {} is below 1",
+                self.idle_view_distance
+            ));
+        }
+        // Idle observers should never get more than active ones
+        if self.idle_max_additions_per_tick == 0
+            || self.idle_max_additions_per_tick > self.max_additions_per_tick
+        {
+            return Err(anyhow!(
+                "Idle max additions per tick {} is not within 1 and the max additions per tick {}",
+                self.idle_max_additions_per_tick,
+                self.max_additions_per_tick
+            ));
+        }
+        // Idle observers add players within the same margin of their smaller view distance
+        if self.view_hysteresis < 0 || self.view_hysteresis >= self.effective_idle_view_distance() {
+            return Err(anyhow!(
+                "View hysteresis {} is not within 0 and the idle view distance {}",
+                self.view_hysteresis,
+                self.effective_idle_view_distance()
+            ));
+        }
+        if self.max_local_npcs == 0 || self.max_local_npcs > PROTOCOL_MAX_LOCAL_NPCS {
+            return Err(anyhow!(
+                "Max local NPCs {} is not within 1 and {}",
+                self.max_local_npcs,
+                PROTOCOL_MAX_LOCAL_NPCS
+            ));
+        }
+        if self.max_npc_additions_per_tick == 0 {
+            return Err(anyhow!("Max NPC additions per tick cannot be 0"));
+        }
+        if self.mask_buffer_capacity == 0 {
+            return Err(anyhow!("Mask buffer capacity cannot be 0"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_validation_test() {
+        assert!(WorldInfoConfig::default().validate().is_ok());
+
+        let config = WorldInfoConfig {
+            view_distance: 10,
+            rebuild_boundary: 8,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        // The idle view distance is capped at a smaller view distance
+        let config = WorldInfoConfig {
+            view_distance: 4,
+            rebuild_boundary: 8,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.effective_idle_view_distance(), 4);
+
+        let config = WorldInfoConfig {
+            idle_view_distance: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = WorldInfoConfig {
+            view_hysteresis: 5,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = WorldInfoConfig {
+            max_players: PROTOCOL_MAX_PLAYERS + 1,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = WorldInfoConfig {
+            max_local_npcs: PROTOCOL_MAX_LOCAL_NPCS + 1,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
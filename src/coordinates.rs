@@ -0,0 +1,141 @@
+//! Absolute coordinates of entities, and their conversions to the formats written to the client
+use std::{cmp, error::Error, fmt};
+
+/// The amount of planes the client knows of, addressed using 2 bits
+pub const WIRE_PLANES: i32 = 4;
+/// The highest x or y coordinate, addressed using 14 bits
+pub const MAX_COORDINATE: i32 = 0x3FFF;
+
+/// Coordinates that cannot be written to the client without wrapping around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateError {
+    X(i32),
+    Y(i32),
+    Plane(i32),
+}
+
+impl fmt::Display for CoordinateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoordinateError::X(x) => write!(f, "x {} is not within 0 and {}", x, MAX_COORDINATE),
+            CoordinateError::Y(y) => write!(f, "y {} is not within 0 and {}", y, MAX_COORDINATE),
+            CoordinateError::Plane(plane) => write!(f, "plane {} is negative", plane),
+        }
+    }
+}
+
+impl Error for CoordinateError {}
+
+/// Absolute coordinates on a logical plane.
+/// Logical planes may exceed the planes of the client, e.g. instances stacked at synthetic heights,
+/// and are mapped to a wire plane whenever they are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Coordinates {
+    pub x: i32,
+    pub y: i32,
+    pub plane: i32,
+}
+
+impl Coordinates {
+    pub fn new(x: i32, y: i32, plane: i32) -> Coordinates {
+        Coordinates { x, y, plane }
+    }
+
+    /// Check whether the coordinates can be written to the client.
+    /// Any non-negative logical plane is valid, as it is mapped to a wire plane
+    pub fn validate(self) -> Result<Coordinates, CoordinateError> {
+        if !(0..=MAX_COORDINATE).contains(&self.x) {
+            return Err(CoordinateError::X(self.x));
+        }
+        if !(0..=MAX_COORDINATE).contains(&self.y) {
+            return Err(CoordinateError::Y(self.y));
+        }
+        if self.plane < 0 {
+            return Err(CoordinateError::Plane(self.plane));
+        }
+
+        Ok(self)
+    }
+
+    /// Get the plane as the client sees it, logical planes are stacked in groups of `WIRE_PLANES`
+    pub fn wire_plane(self) -> i32 {
+        self.plane.rem_euclid(WIRE_PLANES)
+    }
+
+    /// Pack the coordinates into 30 bits, 2 bits of wire plane followed by 14 bits of x and y
+    pub fn packed(self) -> i32 {
+        self.wire_plane() << 28 | (self.x & 0x3FFF) << 14 | (self.y & 0x3FFF)
+    }
+
+    /// Get the 18-bit region hash, 2 bits of wire plane followed by 8 bits of region x and y
+    pub fn region_hash(self) -> i32 {
+        self.wire_plane() << 16 | (self.x >> 13 & 0xFF) << 8 | (self.y >> 13 & 0xFF)
+    }
+
+    /// Unpack an 18-bit region hash into the south-west corner of the region, the inverse of `region_hash`
+    pub fn from_region_hash(region_hash: i32) -> Coordinates {
+        Coordinates {
+            x: (region_hash >> 8 & 0xFF) << 13,
+            y: (region_hash & 0xFF) << 13,
+            plane: region_hash >> 16,
+        }
+    }
+
+    /// Get the delta to other coordinates, with the plane delta taken between wire planes
+    pub fn delta(self, other: Coordinates) -> (i32, i32, i32) {
+        (
+            other.x - self.x,
+            other.y - self.y,
+            (other.wire_plane() - self.wire_plane()).rem_euclid(WIRE_PLANES),
+        )
+    }
+
+    /// Get the Chebyshev distance in tiles to other coordinates, ignoring the plane
+    pub fn distance(self, other: Coordinates) -> i32 {
+        cmp::max((other.x - self.x).abs(), (other.y - self.y).abs())
+    }
+
+    /// Translate the coordinates by a delta in tiles on the same plane
+    pub fn translate(self, dx: i32, dy: i32) -> Coordinates {
+        Coordinates {
+            x: self.x + dx,
+            y: self.y + dy,
+            plane: self.plane,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_plane_test() {
+        let ground = Coordinates::new(3222, 3218, 0);
+        let instance = Coordinates::new(3222, 3218, 9);
+
+        assert_eq!(instance.wire_plane(), 1);
+        assert_eq!(instance.packed(), 1 << 28 | 3222 << 14 | 3218);
+        assert_eq!(instance.region_hash() >> 16, 1);
+        assert_eq!(
+            Coordinates::from_region_hash(instance.region_hash()),
+            Coordinates::new(0, 0, 1)
+        );
+        assert_eq!(ground.delta(instance), (0, 0, 1));
+        assert_eq!(instance.delta(ground), (0, 0, 3));
+
+        assert_eq!(instance.validate(), Ok(instance));
+        assert_eq!(
+            Coordinates::new(16384, 0, 0).validate(),
+            Err(CoordinateError::X(16384))
+        );
+        assert_eq!(
+            Coordinates::new(0, -1, 0).validate(),
+            Err(CoordinateError::Y(-1))
+        );
+        assert_eq!(
+            Coordinates::new(0, 0, -1).validate(),
+            Err(CoordinateError::Plane(-1))
+        );
+    }
+}
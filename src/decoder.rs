@@ -0,0 +1,421 @@
+//! Decoding of PlayerInfo packets the way the client does, used to validate what was encoded.
+//!
+//! The client skips records within a group based on the skip counts it reads, so a skip count that disagrees with
+//! the records left in its group misaligns everything read after it
+#[cfg(feature = "tinting")]
+use crate::playerinfo::TINT_MASK;
+use crate::playerinfo::{
+    PlayerKey, APPEARANCE_MASK, CHAT_MASK, DIRECTION_MASK, FACE_COORDINATE_MASK, HIT_MASK,
+    LOCK_TURNTO_MASK, MASKS, MASK_CONTINUATION_MEDIUM, MASK_CONTINUATION_SHORT,
+    MOVEMENT_CACHED_MASK, MOVEMENT_FORCED_MASK, MOVEMENT_TEMPORARY_MASK, NAME_MODIFIERS_MASK,
+    SEQUENCE_MASK, SHOUT_MASK,
+};
+use anyhow::{anyhow, Context, Result};
+use bitstream_io::{BigEndian, BitRead, BitReader};
+use std::io::Cursor;
+
+/// What the client knows about a player before a packet is decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientRecord {
+    /// Whether the player is one of the local players of the observer
+    pub local: bool,
+    /// Whether the player was skipped in the previous packet, placing it in the inactive group
+    pub inactive: bool,
+}
+
+/// An update of a single player as decoded from the bit section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedUpdate {
+    /// Only the masks of the local player are updated
+    Masks,
+    /// The local player walked a tile in the given direction
+    Walked { direction: u32, masks: bool },
+    /// The local player ran two tiles in the given direction
+    Ran { direction: u32, masks: bool },
+    /// The local player teleported by the given delta
+    Teleported {
+        dx: i32,
+        dy: i32,
+        dz: i32,
+        masks: bool,
+    },
+    /// The local player was removed
+    Removed,
+    /// The player was added at the given coordinates within its region
+    Added { x: i32, y: i32, masks: bool },
+    /// The region of the global player changed
+    Moved,
+}
+
+impl DecodedUpdate {
+    /// Whether a mask block follows for the player
+    pub fn has_masks(&self) -> bool {
+        match *self {
+            DecodedUpdate::Masks => true,
+            DecodedUpdate::Walked { masks, .. }
+            | DecodedUpdate::Ran { masks, .. }
+            | DecodedUpdate::Teleported { masks, .. }
+            | DecodedUpdate::Added { masks, .. } => masks,
+            DecodedUpdate::Removed | DecodedUpdate::Moved => false,
+        }
+    }
+}
+
+/// The mask block of a single player, split into the data of each mask
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodedMasks {
+    pub flags: u32,
+    /// The data of each mask in the order it was written
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+impl DecodedMasks {
+    /// Get the data of a mask, if it was written
+    pub fn block(&self, flag: u32) -> Option<&[u8]> {
+        self.blocks
+            .iter()
+            .find(|(mask, _)| *mask == flag)
+            .map(|(_, data)| data.as_slice())
+    }
+}
+
+/// A packet as decoded by the client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodedPacket {
+    /// The updates in the order they were decoded, along with the key of the player
+    pub updates: Vec<(PlayerKey, DecodedUpdate)>,
+    /// The mask blocks in the order they were decoded, along with the key of the player
+    pub masks: Vec<(PlayerKey, DecodedMasks)>,
+}
+
+impl DecodedPacket {
+    /// Get the update of a player, if it was not skipped
+    pub fn update(&self, player_id: PlayerKey) -> Option<DecodedUpdate> {
+        self.updates
+            .iter()
+            .find(|(key, _)| *key == player_id)
+            .map(|(_, update)| *update)
+    }
+
+    /// Get the mask block of a player, if it was written
+    pub fn masks(&self, player_id: PlayerKey) -> Option<&DecodedMasks> {
+        self.masks
+            .iter()
+            .find(|(key, _)| *key == player_id)
+            .map(|(_, masks)| masks)
+    }
+}
+
+/// The four groups in the order the client decodes them
+const GROUPS: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+
+/// Decode the bit section of a packet, failing if a skip count runs past the end of its group.
+/// Returns the size in bytes of the bit section, after which the masks start
+pub fn verify_skip_counts(packet: &[u8], records: &[ClientRecord]) -> Result<usize> {
+    decode_updates(packet, records).map(|(_, bit_size)| bit_size)
+}
+
+/// Decode a whole packet, failing if a skip count runs past the end of its group or the masks are malformed
+pub fn decode(packet: &[u8], records: &[ClientRecord]) -> Result<DecodedPacket> {
+    let (updates, bit_size) = decode_updates(packet, records)?;
+
+    let mut mask_buf = packet.get(bit_size..).context("missing mask section")?;
+    let mut masks = Vec::new();
+    for (player_id, update) in updates.iter() {
+        if update.has_masks() {
+            let decoded = read_masks(&mut mask_buf)
+                .with_context(|| format!("failed reading masks of player {}", player_id))?;
+            masks.push((*player_id, decoded));
+        }
+    }
+    if !mask_buf.is_empty() {
+        return Err(anyhow!("{} bytes left after the masks", mask_buf.len()));
+    }
+
+    Ok(DecodedPacket { updates, masks })
+}
+
+fn decode_updates(
+    packet: &[u8],
+    records: &[ClientRecord],
+) -> Result<(Vec<(PlayerKey, DecodedUpdate)>, usize)> {
+    let mut bit_buf = BitReader::endian(Cursor::new(packet), BigEndian);
+    let mut updates = Vec::new();
+
+    for (local, inactive) in GROUPS {
+        let mut skip_count = 0;
+
+        for (index, record) in records.iter().enumerate() {
+            if record.local != local || record.inactive != inactive {
+                continue;
+            }
+
+            if skip_count > 0 {
+                skip_count -= 1;
+                continue;
+            }
+
+            if bit_buf.read_bit().context("failed reading update bit")? {
+                let update = if local {
+                    read_local_update(&mut bit_buf)?
+                } else {
+                    read_global_update(&mut bit_buf)?
+                };
+                updates.push((index, update));
+            } else {
+                skip_count = read_skip_count(&mut bit_buf)
+                    .with_context(|| format!("failed reading skip count at record {}", index))?;
+            }
+        }
+
+        if skip_count != 0 {
+            return Err(anyhow!(
+                "Skip count exceeds the records left in the {} {} group by {}",
+                if inactive { "inactive" } else { "active" },
+                if local { "local" } else { "global" },
+                skip_count
+            ));
+        }
+
+        bit_buf.byte_align();
+    }
+
+    Ok((updates, bit_buf.into_reader().position() as usize))
+}
+
+fn read_skip_count(bit_buf: &mut BitReader<Cursor<&[u8]>, BigEndian>) -> Result<u32> {
+    let skip_count = match bit_buf.read::<u32>(2)? {
+        0 => 0,
+        1 => bit_buf.read::<u32>(5)?,
+        2 => bit_buf.read::<u32>(8)?,
+        _ => bit_buf.read::<u32>(11)?,
+    };
+
+    Ok(skip_count)
+}
+
+fn read_local_update(bit_buf: &mut BitReader<Cursor<&[u8]>, BigEndian>) -> Result<DecodedUpdate> {
+    let masks = bit_buf.read_bit()?;
+
+    let update = match bit_buf.read::<u32>(2)? {
+        0 if masks => DecodedUpdate::Masks,
+        // Without masks, the player is removed
+        0 => {
+            if bit_buf.read_bit()? {
+                read_coordinate_multiplier(bit_buf)?;
+            }
+            DecodedUpdate::Removed
+        }
+        1 => DecodedUpdate::Walked {
+            direction: bit_buf.read::<u32>(3)?,
+            masks,
+        },
+        2 => DecodedUpdate::Ran {
+            direction: bit_buf.read::<u32>(4)?,
+            masks,
+        },
+        _ => {
+            let bits = if bit_buf.read_bit()? { 14 } else { 5 };
+            let dz = bit_buf.read::<u32>(2)? as i32;
+            let dx = sign_extend(bit_buf.read::<u32>(bits)?, bits);
+            let dy = sign_extend(bit_buf.read::<u32>(bits)?, bits);
+            DecodedUpdate::Teleported { dx, dy, dz, masks }
+        }
+    };
+
+    Ok(update)
+}
+
+fn read_global_update(bit_buf: &mut BitReader<Cursor<&[u8]>, BigEndian>) -> Result<DecodedUpdate> {
+    match bit_buf.read::<u32>(2)? {
+        // The player is added
+        0 => {
+            if bit_buf.read_bit()? {
+                read_coordinate_multiplier(bit_buf)?;
+            }
+            Ok(DecodedUpdate::Added {
+                x: bit_buf.read::<u32>(13)? as i32,
+                y: bit_buf.read::<u32>(13)? as i32,
+                masks: bit_buf.read_bit()?,
+            })
+        }
+        // Else only the coordinate multiplier of the player changed
+        update_type => {
+            skip_coordinate_multiplier(bit_buf, update_type)?;
+            Ok(DecodedUpdate::Moved)
+        }
+    }
+}
+
+fn read_coordinate_multiplier(bit_buf: &mut BitReader<Cursor<&[u8]>, BigEndian>) -> Result<()> {
+    let update_type = bit_buf.read::<u32>(2)?;
+    skip_coordinate_multiplier(bit_buf, update_type)
+}
+
+fn skip_coordinate_multiplier(
+    bit_buf: &mut BitReader<Cursor<&[u8]>, BigEndian>,
+    update_type: u32,
+) -> Result<()> {
+    match update_type {
+        0 => {}
+        1 => bit_buf.skip(2)?,
+        2 => bit_buf.skip(2 + 3)?,
+        _ => bit_buf.skip(2 + 8 + 8)?,
+    }
+
+    Ok(())
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+fn read_masks(mask_buf: &mut &[u8]) -> Result<DecodedMasks> {
+    let mut flags = take(mask_buf, 1)?[0] as u32;
+    if flags & MASK_CONTINUATION_SHORT != 0 {
+        flags |= (take(mask_buf, 1)?[0] as u32) << 8;
+    }
+    if flags & MASK_CONTINUATION_MEDIUM != 0 {
+        flags |= (take(mask_buf, 1)?[0] as u32) << 16;
+    }
+    flags &= !(MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM);
+    // Masks registered outside of the crate have lengths only their encoder knows of
+    let unknown = MASKS.iter().fold(flags, |flags, &mask| flags & !mask);
+    if unknown != 0 {
+        return Err(anyhow!("Cannot decode custom masks {:#x}", unknown));
+    }
+
+    let mut blocks = Vec::new();
+    for &mask in MASKS {
+        if flags & mask == 0 {
+            continue;
+        }
+
+        let length = mask_length(mask, mask_buf)?;
+        blocks.push((mask, take(mask_buf, length)?.to_vec()));
+    }
+
+    Ok(DecodedMasks { flags, blocks })
+}
+
+// Get the length of the mask at the start of the buffer, from its fixed size or the lengths encoded within it
+fn mask_length(mask: u32, mask_buf: &[u8]) -> Result<usize> {
+    let length = match mask {
+        MOVEMENT_FORCED_MASK => 10,
+        SEQUENCE_MASK => 3,
+        APPEARANCE_MASK => 1 + *mask_buf.first().context("missing appearance length")? as usize,
+        SHOUT_MASK => string_length(mask_buf)?,
+        MOVEMENT_CACHED_MASK | MOVEMENT_TEMPORARY_MASK => 1,
+        CHAT_MASK => 5 + *mask_buf.get(4).context("missing chat length")? as usize,
+        NAME_MODIFIERS_MASK => {
+            let mut length = 0;
+            for _ in 0..3 {
+                length += string_length(&mask_buf[length..])?;
+            }
+            length
+        }
+        HIT_MASK => {
+            let mut length = 1;
+            let hitsplats = mask_buf
+                .first()
+                .context("missing hitsplats")?
+                .wrapping_sub(128);
+            for _ in 0..hitsplats as usize * 3 {
+                length += smart_length(mask_buf, length)?;
+            }
+            let health_bars = mask_buf
+                .get(length)
+                .context("missing health bars")?
+                .wrapping_neg();
+            length += 1;
+            for _ in 0..health_bars {
+                for _ in 0..3 {
+                    length += smart_length(mask_buf, length)?;
+                }
+                length += 1;
+            }
+            length
+        }
+        LOCK_TURNTO_MASK | DIRECTION_MASK => 2,
+        FACE_COORDINATE_MASK => 4,
+        #[cfg(feature = "tinting")]
+        TINT_MASK => 8,
+        _ => return Err(anyhow!("Cannot decode mask {:#x}", mask)),
+    };
+
+    Ok(length)
+}
+
+fn string_length(mask_buf: &[u8]) -> Result<usize> {
+    let end = mask_buf
+        .iter()
+        .position(|byte| *byte == 0)
+        .context("unterminated string")?;
+
+    Ok(end + 1)
+}
+
+fn smart_length(mask_buf: &[u8], position: usize) -> Result<usize> {
+    let first = mask_buf.get(position).context("missing smart")?;
+
+    Ok(if *first < 128 { 1 } else { 2 })
+}
+
+fn take<'a>(mask_buf: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if mask_buf.len() < length {
+        return Err(anyhow!(
+            "Expected {} bytes, but only {} are left",
+            length,
+            mask_buf.len()
+        ));
+    }
+
+    let (taken, rest) = mask_buf.split_at(length);
+    *mask_buf = rest;
+
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::Coordinates;
+    use crate::playerinfo::{DirectionMask, PlayerInfo};
+
+    #[test]
+    fn verify_skip_counts_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3230, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3260, 3218, 0))?;
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 512 })?;
+
+        let records = playerinfo.client_records(0)?;
+        let packet = playerinfo.process(0)?;
+        let decoded = decode(&packet, &records)?;
+        assert_eq!(
+            decoded.update(1),
+            Some(DecodedUpdate::Added {
+                x: 3230 & 0x1FFF,
+                y: 3218 & 0x1FFF,
+                masks: true
+            })
+        );
+        assert_eq!(
+            decoded
+                .masks(1)
+                .and_then(|masks| masks.block(DIRECTION_MASK)),
+            Some(&[0x02, 0x80][..])
+        );
+        assert_eq!(decoded.update(2), None);
+
+        // A single local player skipping two records runs past its group
+        let mut records = vec![ClientRecord::default(); 3];
+        records[0].local = true;
+        assert!(verify_skip_counts(&[0b0010_0010, 0, 0, 0], &records).is_err());
+        assert_eq!(verify_skip_counts(&[0, 0b0010_0001], &records)?, 2);
+
+        Ok(())
+    }
+}
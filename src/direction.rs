@@ -0,0 +1,187 @@
+//! Directions and their conversions to the 2048-unit angles used by the client
+use crate::playerinfo::DirectionMask;
+
+/// The amount of angle units in a full turn
+pub const ANGLE_UNITS: i32 = 2048;
+
+/// A direction an entity can face, ordered like the client's angles starting at south and turning clockwise.
+/// The 8-way directions are the even variants, the 16-way directions add the ones in between
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    South,
+    SouthSouthWest,
+    SouthWest,
+    WestSouthWest,
+    West,
+    WestNorthWest,
+    NorthWest,
+    NorthNorthWest,
+    North,
+    NorthNorthEast,
+    NorthEast,
+    EastNorthEast,
+    East,
+    EastSouthEast,
+    SouthEast,
+    SouthSouthEast,
+}
+
+const DIRECTIONS: [Direction; 16] = [
+    Direction::South,
+    Direction::SouthSouthWest,
+    Direction::SouthWest,
+    Direction::WestSouthWest,
+    Direction::West,
+    Direction::WestNorthWest,
+    Direction::NorthWest,
+    Direction::NorthNorthWest,
+    Direction::North,
+    Direction::NorthNorthEast,
+    Direction::NorthEast,
+    Direction::EastNorthEast,
+    Direction::East,
+    Direction::EastSouthEast,
+    Direction::SouthEast,
+    Direction::SouthSouthEast,
+];
+
+// The tangents of the boundaries between 16-way directions (11.25, 33.75, 56.25 and 78.75 degrees), scaled by a million.
+// Integers are used instead of atan2 so the result is identical on every platform
+const BOUNDARIES_16: [i64; 4] = [198_912, 668_179, 1_496_606, 5_027_339];
+// The tangents of the boundaries between 8-way directions (22.5 and 67.5 degrees), scaled by a million
+const BOUNDARIES_8: [i64; 2] = [414_214, 2_414_214];
+
+impl Direction {
+    /// Get the direction at the given position in 16-way order, wrapping around
+    fn from_index(index: i32) -> Direction {
+        DIRECTIONS[index.rem_euclid(DIRECTIONS.len() as i32) as usize]
+    }
+
+    /// Get the client angle of the direction, 0 being south and 1024 north
+    pub fn angle(self) -> i16 {
+        self as i16 * 128
+    }
+
+    /// Get the closest 16-way direction for a client angle
+    pub fn from_angle(angle: i32) -> Direction {
+        Self::from_index((angle.rem_euclid(ANGLE_UNITS) + 64) / 128)
+    }
+
+    /// Get the compass bearing of the direction in degrees, 0 being north and 90 east
+    pub fn degrees(self) -> i32 {
+        angle_to_degrees(self.angle() as i32)
+    }
+
+    /// Get the closest 16-way direction for a compass bearing in degrees
+    pub fn from_degrees(degrees: i32) -> Direction {
+        Self::from_angle(degrees_to_angle(degrees) as i32)
+    }
+
+    /// Get the closest 16-way direction pointing along a tile delta, `None` when there is no delta
+    pub fn from_delta(dx: i32, dy: i32) -> Option<Direction> {
+        nearest_direction(dx, dy, &BOUNDARIES_16, 1)
+    }
+
+    /// Get the closest 8-way direction pointing along a tile delta, `None` when there is no delta
+    pub fn from_delta_8(dx: i32, dy: i32) -> Option<Direction> {
+        nearest_direction(dx, dy, &BOUNDARIES_8, 2)
+    }
+
+    /// Whether the direction is one of the 8 compass and intercardinal directions
+    pub fn is_eight_way(self) -> bool {
+        self as i32 % 2 == 0
+    }
+
+    /// Get the tile delta of the direction, a single step for 8-way directions and a two tile step for the ones in between
+    pub fn delta(self) -> (i32, i32) {
+        match self {
+            Direction::South => (0, -1),
+            Direction::SouthSouthWest => (-1, -2),
+            Direction::SouthWest => (-1, -1),
+            Direction::WestSouthWest => (-2, -1),
+            Direction::West => (-1, 0),
+            Direction::WestNorthWest => (-2, 1),
+            Direction::NorthWest => (-1, 1),
+            Direction::NorthNorthWest => (-1, 2),
+            Direction::North => (0, 1),
+            Direction::NorthNorthEast => (1, 2),
+            Direction::NorthEast => (1, 1),
+            Direction::EastNorthEast => (2, 1),
+            Direction::East => (1, 0),
+            Direction::EastSouthEast => (2, -1),
+            Direction::SouthEast => (1, -1),
+            Direction::SouthSouthEast => (1, -2),
+        }
+    }
+}
+
+impl From<Direction> for DirectionMask {
+    fn from(direction: Direction) -> Self {
+        DirectionMask {
+            direction: direction.angle(),
+        }
+    }
+}
+
+fn nearest_direction(dx: i32, dy: i32, boundaries: &[i64], step: i32) -> Option<Direction> {
+    if dx == 0 && dy == 0 {
+        return None;
+    }
+
+    let horizontal = i64::from(dx).abs();
+    let vertical = i64::from(dy).abs();
+
+    // The amount of 16th turns the delta is away from the horizontal axis, 0 to 4
+    let sector = boundaries
+        .iter()
+        .filter(|&&boundary| vertical * 1_000_000 > horizontal * boundary)
+        .count() as i32
+        * step;
+
+    // The bearing in 16th turns, clockwise from north
+    let bearing = match (dx >= 0, dy >= 0) {
+        (true, true) => 4 - sector,
+        (true, false) => 4 + sector,
+        (false, false) => 12 - sector,
+        (false, true) => 12 + sector,
+    };
+
+    // The client starts at south, half a turn after north
+    Some(Direction::from_index(bearing + 8))
+}
+
+/// Convert a compass bearing in degrees, 0 being north and 90 east, into client angle units
+pub fn degrees_to_angle(degrees: i32) -> i16 {
+    // The client measures from south, half a turn away from north
+    let degrees = (degrees + 180).rem_euclid(360);
+    ((degrees * ANGLE_UNITS + 180) / 360 % ANGLE_UNITS) as i16
+}
+
+/// Convert client angle units into a compass bearing in degrees, 0 being north and 90 east
+pub fn angle_to_degrees(angle: i32) -> i32 {
+    let degrees = (angle.rem_euclid(ANGLE_UNITS) * 360 + ANGLE_UNITS / 2) / ANGLE_UNITS;
+    (degrees + 180) % 360
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direction_conversion_test() {
+        for direction in DIRECTIONS {
+            let (dx, dy) = direction.delta();
+            assert_eq!(Direction::from_delta(dx, dy), Some(direction));
+            assert_eq!(Direction::from_angle(direction.angle() as i32), direction);
+            assert_eq!(Direction::from_degrees(direction.degrees()), direction);
+        }
+
+        assert_eq!(DirectionMask::from(Direction::East).direction, 1536);
+        assert_eq!(degrees_to_angle(90), 1536);
+        assert_eq!(degrees_to_angle(-90), 512);
+        assert_eq!(angle_to_degrees(1024), 0);
+        assert_eq!(Direction::from_delta(0, 0), None);
+        assert_eq!(Direction::from_delta_8(5, 2), Some(Direction::East));
+        assert_eq!(Direction::from_delta(5, 2), Some(Direction::EastNorthEast));
+    }
+}
@@ -1,4 +1,22 @@
-//! Rust library containing an implementation for PlayerInfo and NpcInfo, used to update players in the world.
+//! Rust library containing an implementation for PlayerInfo and NpcInfo, used to update players in the world,
+//! ZoneInfo for the events of the zones around them, WorldEntityInfo for movable world entities such as ships,
+//! and the WorldInfo combining them.
 
+pub mod config;
+pub mod coordinates;
+pub mod decoder;
+pub mod determinism;
+pub mod direction;
+#[cfg(feature = "alloc-metrics")]
+pub mod metrics;
 pub mod npcinfo;
 pub mod playerinfo;
+pub mod rebuild;
+pub mod revision;
+#[cfg(test)]
+mod scenario;
+pub mod snapshot;
+#[cfg(feature = "world-entities")]
+pub mod worldentityinfo;
+pub mod worldinfo;
+pub mod zoneinfo;
@@ -0,0 +1,102 @@
+//! Allocation and buffer size metrics, enabled using the `alloc-metrics` feature.
+//!
+//! Allocations are only counted once `CountingAllocator` is installed as the global allocator
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps the system allocator, counting every allocation. Install it using `#[global_allocator]`
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Get the amount of allocations and allocated bytes counted so far, across all threads
+pub fn allocation_counts() -> (u64, u64) {
+    (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    )
+}
+
+/// The metrics of processing the players during a single tick
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TickMetrics {
+    /// The amount of players processed
+    pub packets: usize,
+    /// The amount of allocations made while processing, including those of other threads in the meantime
+    pub allocations: u64,
+    /// The amount of bytes allocated while processing
+    pub allocated_bytes: u64,
+    /// The size in bytes of the largest encoded buffer
+    pub peak_packet_size: usize,
+    /// The size in bytes of the largest amount of masks written for a single player
+    pub peak_mask_buffer_size: usize,
+    /// The amount of buffers reused from an observer in the same state, see `WorldInfoConfig::reuse_identical_encodings`
+    pub reused_packets: usize,
+}
+
+impl TickMetrics {
+    pub(crate) fn record(
+        &mut self,
+        allocations: (u64, u64),
+        packet_size: usize,
+        mask_buffer_size: usize,
+    ) {
+        let (count, bytes) = allocation_counts();
+
+        self.packets += 1;
+        self.allocations += count.wrapping_sub(allocations.0);
+        self.allocated_bytes += bytes.wrapping_sub(allocations.1);
+        self.peak_packet_size = self.peak_packet_size.max(packet_size);
+        self.peak_mask_buffer_size = self.peak_mask_buffer_size.max(mask_buffer_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playerinfo::{DirectionMask, PlayerInfo};
+    use anyhow::Result;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn tick_metrics_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 0 })?;
+        playerinfo.process_all();
+        assert_eq!(playerinfo.metrics(), &TickMetrics::default());
+
+        playerinfo.tick();
+        let metrics = playerinfo.metrics();
+        assert_eq!(metrics.packets, 2);
+        assert!(metrics.allocations > 0);
+        assert_eq!(metrics.peak_mask_buffer_size, 3);
+
+        Ok(())
+    }
+}
@@ -1 +1,2041 @@
 //! NpcInfo stuff
+use crate::config::WorldInfoConfig;
+use crate::coordinates::Coordinates;
+use crate::playerinfo::{
+    self, FaceCoordinateMask, FaceEntity, FaceEntityMask, ForcedChatMask, ForcedMovementMask,
+    HitMask, Mask, MaskError, MoveSpeed, PlayerKey, MASK_CONTINUATION_MEDIUM,
+    MASK_CONTINUATION_SHORT, MAX_FORCED_CHAT_LENGTH, MAX_HITSPLATS,
+};
+use anyhow::{anyhow, Context, Result};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+use osrs_buffer::WriteExt;
+use slab::Slab;
+use std::io::{Cursor, Write};
+
+/// The key of an NPC, the index the client knows it by
+pub type NpcKey = usize;
+
+// The bits of the index of an added NPC in the small variant, where the highest index terminates the additions
+const SMALL_INDEX_BITS: u32 = 13;
+// The bits of the delta between an added NPC and the observer along each axis in the small variant
+const SMALL_DELTA_BITS: u32 = 5;
+const SMALL_ID_BITS: u32 = 14;
+const LARGE_INDEX_BITS: u32 = 16;
+const LARGE_DELTA_BITS: u32 = 8;
+const LARGE_ID_BITS: u32 = 16;
+// The furthest an NPC can be from the observer in the large variant, as reached by its deltas
+const LARGE_VIEW_DISTANCE: i32 = (1 << (LARGE_DELTA_BITS - 1)) - 1;
+const MAX_NPCS: usize = (1 << LARGE_INDEX_BITS) - 1;
+const MAX_MOVEMENT_STEPS: usize = 2;
+const MAX_NPC_SIZE: u8 = 5;
+const MAX_NPC_NAME_LENGTH: usize = 80;
+// The amount of models, recolours and retextures is written using 8 bits each
+const MAX_CUSTOMISATION_PARTS: usize = 255;
+
+const NPC_MOVEMENT_NONE: u32 = 0;
+const NPC_MOVEMENT_WALK: u32 = 1;
+const NPC_MOVEMENT_RUN: u32 = 2;
+const NPC_MOVEMENT_REMOVE: u32 = 3;
+
+/// The layout of the NPC info packet written to a player
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NpcInfoVariant {
+    /// Additions written using 13-bit indices and 5-bit deltas, seeing NPCs within the configured view distance.
+    /// Used by most worlds
+    #[default]
+    Small,
+    /// Additions written using 16-bit indices, 8-bit deltas and 16-bit ids, seeing NPCs as far as its deltas reach.
+    /// Used for players in crowded areas, or where NPCs are seen from afar
+    Large,
+}
+
+impl NpcInfoVariant {
+    /// The bits of the index of an added NPC
+    pub fn index_bits(self) -> u32 {
+        match self {
+            NpcInfoVariant::Small => SMALL_INDEX_BITS,
+            NpcInfoVariant::Large => LARGE_INDEX_BITS,
+        }
+    }
+
+    /// The bits of the delta between an added NPC and the player along each axis
+    pub fn delta_bits(self) -> u32 {
+        match self {
+            NpcInfoVariant::Small => SMALL_DELTA_BITS,
+            NpcInfoVariant::Large => LARGE_DELTA_BITS,
+        }
+    }
+
+    /// The bits of the id of an added NPC
+    pub fn id_bits(self) -> u32 {
+        match self {
+            NpcInfoVariant::Small => SMALL_ID_BITS,
+            NpcInfoVariant::Large => LARGE_ID_BITS,
+        }
+    }
+
+    /// The index terminating the additions, which no NPC can have
+    fn index_terminator(self) -> u32 {
+        (1 << self.index_bits()) - 1
+    }
+
+    /// Whether the index and the id of the NPC can be written in an addition
+    fn can_add(self, npc_id: NpcKey, id: u16) -> bool {
+        (npc_id as u32) < self.index_terminator() && (id as u32) < 1 << self.id_bits()
+    }
+
+    /// Whether the delta to the south-west tile of the NPC can be written in an addition,
+    /// which large NPCs seen by their nearest tile may exceed
+    fn can_reach(self, observer: Coordinates, npc: &NpcUpdate) -> bool {
+        let (dx, dy, _) = observer.delta(npc.coordinates);
+        let reach = -(1 << (self.delta_bits() - 1))..1 << (self.delta_bits() - 1);
+        reach.contains(&dx) && reach.contains(&dy)
+    }
+
+    /// The distance in tiles at which NPCs are seen
+    pub fn view_distance(self, config: &WorldInfoConfig) -> i32 {
+        match self {
+            NpcInfoVariant::Small => config.view_distance,
+            NpcInfoVariant::Large => LARGE_VIEW_DISTANCE,
+        }
+    }
+}
+
+// The masks and their associated bit values
+pub const NPC_SEQUENCE_MASK: u32 = 0x1;
+pub const NPC_HIT_MASK: u32 = 0x2;
+pub const NPC_TRANSFORM_MASK: u32 = 0x4;
+pub const NPC_FACE_ENTITY_MASK: u32 = 0x8;
+pub const NPC_FACE_COORDINATE_MASK: u32 = 0x10;
+pub const NPC_SPOT_ANIMATION_MASK: u32 = 0x20;
+pub const NPC_FORCED_CHAT_MASK: u32 = 0x80;
+pub const NPC_EXACT_MOVE_MASK: u32 = 0x100;
+pub const NPC_BASE_ANIMATION_SET_MASK: u32 = 0x200;
+pub const NPC_COMBAT_LEVEL_MASK: u32 = 0x400;
+pub const NPC_NAME_MASK: u32 = 0x800;
+pub const NPC_CUSTOMISATION_MASK: u32 = 0x1000;
+
+/// The masks in which order they should be written out
+pub const NPC_MASKS: &[u32] = &[
+    NPC_SEQUENCE_MASK,
+    NPC_HIT_MASK,
+    NPC_TRANSFORM_MASK,
+    NPC_FACE_ENTITY_MASK,
+    NPC_FACE_COORDINATE_MASK,
+    NPC_SPOT_ANIMATION_MASK,
+    NPC_FORCED_CHAT_MASK,
+    NPC_EXACT_MOVE_MASK,
+    NPC_BASE_ANIMATION_SET_MASK,
+    NPC_COMBAT_LEVEL_MASK,
+    NPC_NAME_MASK,
+    NPC_CUSTOMISATION_MASK,
+];
+
+/// The masks set on an NPC this tick
+#[derive(Default)]
+pub struct NpcMasks {
+    sequence_mask: Option<NpcSequenceMask>,
+    hit_mask: Option<HitMask>,
+    transform_mask: Option<NpcTransformMask>,
+    // Kept after being written while locked to an entity, so it is replayed to observers adding the NPC later
+    face_entity_mask: Option<FaceEntityMask>,
+    face_coordinate_mask: Option<FaceCoordinateMask>,
+    spot_animation_mask: Option<SpotAnimationMask>,
+    forced_chat_mask: Option<ForcedChatMask>,
+    exact_move_mask: Option<ForcedMovementMask>,
+    base_animation_set_mask: Option<NpcBaseAnimationSetMask>,
+    // Kept after being written while overriding the level, like the lock to an entity
+    combat_level_mask: Option<NpcCombatLevelMask>,
+    name_mask: Option<NpcNameMask>,
+    customisation_mask: Option<NpcCustomisationMask>,
+}
+
+impl NpcMasks {
+    /// Get the flags of the masks describing the lasting state of the NPC, replayed to observers adding it later.
+    /// Overrides of the definition last until a mask resets them, as does a lock to an entity until released
+    fn persistent_mask_flags(&self) -> u32 {
+        let mut flags = 0;
+        // Released locks and levels of the definition are the default of the client
+        if self
+            .face_entity_mask
+            .as_ref()
+            .is_some_and(|mask| mask.entity != FaceEntity::None)
+        {
+            flags |= NPC_FACE_ENTITY_MASK;
+        }
+        if self
+            .combat_level_mask
+            .as_ref()
+            .is_some_and(|mask| mask.level.is_some())
+        {
+            flags |= NPC_COMBAT_LEVEL_MASK;
+        }
+        if self
+            .name_mask
+            .as_ref()
+            .is_some_and(|mask| mask.name.is_some())
+        {
+            flags |= NPC_NAME_MASK;
+        }
+        if self
+            .customisation_mask
+            .as_ref()
+            .is_some_and(|mask| mask.is_customised())
+        {
+            flags |= NPC_CUSTOMISATION_MASK;
+        }
+
+        flags
+    }
+
+    /// Drop every mask except those describing the lasting state of the NPC
+    fn retain_persistent(&mut self) {
+        let flags = self.persistent_mask_flags();
+        let masks = std::mem::take(self);
+        *self = NpcMasks {
+            face_entity_mask: masks
+                .face_entity_mask
+                .filter(|_| flags & NPC_FACE_ENTITY_MASK != 0),
+            combat_level_mask: masks
+                .combat_level_mask
+                .filter(|_| flags & NPC_COMBAT_LEVEL_MASK != 0),
+            name_mask: masks.name_mask.filter(|_| flags & NPC_NAME_MASK != 0),
+            customisation_mask: masks
+                .customisation_mask
+                .filter(|_| flags & NPC_CUSTOMISATION_MASK != 0),
+            ..Default::default()
+        };
+    }
+}
+
+/// The sequence (animation) mask of the NPC
+pub struct NpcSequenceMask {
+    /// The id of the sequence, -1 stops the current sequence
+    pub id: i16,
+    /// The delay in client cycles before the sequence starts
+    pub delay: u8,
+}
+
+/// A mask that can be set on an NPC using `NpcInfo::set_npc_mask`
+pub trait NpcMask: Sized {
+    /// The bit of the mask in the mask flags
+    const FLAG: u32;
+
+    /// Write the mask to the mask buffer
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()>;
+
+    /// Get the slot holding the mask on an NPC
+    fn slot(masks: &NpcMasks) -> &Option<Self>;
+
+    /// Get the slot holding the mask on an NPC mutably
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self>;
+}
+
+impl NpcMask for NpcSequenceMask {
+    const FLAG: u32 = NPC_SEQUENCE_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_i16_add(self.id)?;
+        mask_buf.write_i8_neg(self.delay as i8)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.sequence_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.sequence_mask
+    }
+}
+
+// The hitsplats of NPCs look the same to every observer, so only their default types are written
+impl NpcMask for HitMask {
+    const FLAG: u32 = NPC_HIT_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        playerinfo::write_hit_mask(self, None, mask_buf)
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.hit_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.hit_mask
+    }
+}
+
+/// The transform mask of the NPC, changing the definition the client renders it with, e.g. shapeshifting bosses
+pub struct NpcTransformMask {
+    pub id: u16,
+}
+
+impl NpcMask for NpcTransformMask {
+    const FLAG: u32 = NPC_TRANSFORM_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_u16_le(self.id)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.transform_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.transform_mask
+    }
+}
+
+// The same index as for players, where NPCs are offset by 32768
+impl NpcMask for FaceEntityMask {
+    const FLAG: u32 = NPC_FACE_ENTITY_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_i16_add(self.entity.index() as i16)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.face_entity_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.face_entity_mask
+    }
+}
+
+impl NpcMask for FaceCoordinateMask {
+    const FLAG: u32 = NPC_FACE_COORDINATE_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        // Fine coordinates in half tiles like for players, pointing at the centre of the tile
+        mask_buf.write_u16_le((self.x * 2 + 1) as u16)?;
+        mask_buf.write_u16_le((self.y * 2 + 1) as u16)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.face_coordinate_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.face_coordinate_mask
+    }
+}
+
+/// The spot animation mask of the NPC, playing a graphic on it, e.g. a spell hitting it
+pub struct SpotAnimationMask {
+    /// The id of the spot animation, 65535 stops the current one
+    pub id: u16,
+    /// The height above the ground the spot animation is played at
+    pub height: u16,
+    /// The delay in client cycles before the spot animation starts
+    pub delay: u16,
+}
+
+impl NpcMask for SpotAnimationMask {
+    const FLAG: u32 = NPC_SPOT_ANIMATION_MASK;
+
+    // The height and delay are packed into a single int
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_u16_le(self.id)?;
+        mask_buf.write_i32_me((self.height as i32) << 16 | self.delay as i32)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.spot_animation_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.spot_animation_mask
+    }
+}
+
+impl NpcMask for ForcedChatMask {
+    const FLAG: u32 = NPC_FORCED_CHAT_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_string_cp1252(&self.text)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.forced_chat_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.forced_chat_mask
+    }
+}
+
+// Encoded like the forced movement of players, relative to the position of the NPC
+impl NpcMask for ForcedMovementMask {
+    const FLAG: u32 = NPC_EXACT_MOVE_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        playerinfo::write_forced_movement_mask(self, mask_buf)
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.exact_move_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.exact_move_mask
+    }
+}
+
+/// The base animation set mask of the NPC, overriding the sequences it stands, turns and moves with.
+/// Sequences left as `None` keep those of the definition. Only known to newer revisions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NpcBaseAnimationSetMask {
+    pub stand: Option<u16>,
+    pub turn_left: Option<u16>,
+    pub turn_right: Option<u16>,
+    pub walk: Option<u16>,
+    pub walk_back: Option<u16>,
+    pub walk_left: Option<u16>,
+    pub walk_right: Option<u16>,
+    pub run: Option<u16>,
+}
+
+impl NpcBaseAnimationSetMask {
+    // The sequences in the order the client reads them
+    fn sequences(&self) -> [Option<u16>; 8] {
+        [
+            self.stand,
+            self.turn_left,
+            self.turn_right,
+            self.walk,
+            self.walk_back,
+            self.walk_left,
+            self.walk_right,
+            self.run,
+        ]
+    }
+}
+
+impl NpcMask for NpcBaseAnimationSetMask {
+    const FLAG: u32 = NPC_BASE_ANIMATION_SET_MASK;
+
+    // A bit for each overridden sequence, followed by the overridden sequences
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        let sequences = self.sequences();
+        let overridden = sequences
+            .iter()
+            .enumerate()
+            .filter(|(_, sequence)| sequence.is_some())
+            .fold(0u8, |flags, (bit, _)| flags | 1 << bit);
+
+        mask_buf.write_u8(overridden)?;
+        for sequence in sequences.into_iter().flatten() {
+            mask_buf.write_u16(sequence)?;
+        }
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.base_animation_set_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.base_animation_set_mask
+    }
+}
+
+/// The combat level mask of the NPC, overriding the level shown by the client, e.g. for scaled bosses
+pub struct NpcCombatLevelMask {
+    /// The level to show, `None` shows the level of the definition again
+    pub level: Option<u16>,
+}
+
+impl NpcMask for NpcCombatLevelMask {
+    const FLAG: u32 = NPC_COMBAT_LEVEL_MASK;
+
+    // The client reads -1 as the level of the definition
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_i32_le(self.level.map_or(-1, i32::from))?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.combat_level_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.combat_level_mask
+    }
+}
+
+/// The name mask of the NPC, overriding the name shown by the client, e.g. for clones of players
+pub struct NpcNameMask {
+    /// The name to show, `None` shows the name of the definition again
+    pub name: Option<String>,
+}
+
+impl NpcMask for NpcNameMask {
+    const FLAG: u32 = NPC_NAME_MASK;
+
+    // The client reads an empty name as the name of the definition
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_string_cp1252(self.name.as_deref().unwrap_or_default())?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.name_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.name_mask
+    }
+}
+
+/// The customisation mask of the NPC, replacing the models and colours of its definition, e.g. for player-owned pets.
+/// A mask customising nothing resets the NPC to its definition
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NpcCustomisationMask {
+    /// The models to render instead of those of the definition
+    pub models: Option<Vec<u16>>,
+    /// The colours to replace, as the colour of the model and the colour to show instead
+    pub recolours: Vec<(u16, u16)>,
+    /// The textures to replace, as the texture of the model and the texture to show instead
+    pub retextures: Vec<(u16, u16)>,
+}
+
+impl NpcCustomisationMask {
+    /// Whether the mask changes anything about the definition
+    pub fn is_customised(&self) -> bool {
+        self.models.is_some() || !self.recolours.is_empty() || !self.retextures.is_empty()
+    }
+}
+
+impl NpcMask for NpcCustomisationMask {
+    const FLAG: u32 = NPC_CUSTOMISATION_MASK;
+
+    // A bit for each customised part followed by the parts, where no bits reset the NPC to its definition
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        let mut flags = 0;
+        if self.models.is_some() {
+            flags |= 0x1;
+        }
+        if !self.recolours.is_empty() {
+            flags |= 0x2;
+        }
+        if !self.retextures.is_empty() {
+            flags |= 0x4;
+        }
+        mask_buf.write_u8(flags)?;
+
+        if let Some(models) = &self.models {
+            mask_buf.write_u8(models.len() as u8)?;
+            for &model in models {
+                mask_buf.write_u16(model)?;
+            }
+        }
+        for replacements in [&self.recolours, &self.retextures] {
+            if replacements.is_empty() {
+                continue;
+            }
+            mask_buf.write_u8(replacements.len() as u8)?;
+            for &(from, to) in replacements {
+                mask_buf.write_u16(from)?;
+                mask_buf.write_u16(to)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn slot(masks: &NpcMasks) -> &Option<Self> {
+        &masks.customisation_mask
+    }
+
+    fn slot_mut(masks: &mut NpcMasks) -> &mut Option<Self> {
+        &mut masks.customisation_mask
+    }
+}
+
+struct NpcUpdate {
+    // The definition the client renders the NPC with
+    id: u16,
+    coordinates: Coordinates,
+    // The length in tiles of the square the NPC occupies north-east of its coordinates
+    size: u8,
+    movement_steps: Vec<(i32, i32)>,
+    // The speed single steps are written at, where two steps are always a run
+    move_speed: MoveSpeed,
+    // Whether the NPC was placed this tick, which observers see as the NPC being removed and added anew
+    teleported: bool,
+    masks: NpcMasks,
+    mask_flags: u32,
+}
+
+impl NpcUpdate {
+    /// Get the masks written to observers adding the NPC, which includes the lasting state set in an earlier tick.
+    /// The addition already carries the definition the NPC is transformed into
+    fn addition_mask_flags(&self) -> u32 {
+        (self.mask_flags | self.masks.persistent_mask_flags()) & !NPC_TRANSFORM_MASK
+    }
+}
+
+// An NPC known to the client of an observer
+struct LocalNpc {
+    npc_id: NpcKey,
+    // Whether the NPC was removed from the NpcInfo, so its key may already belong to another NPC
+    removed: bool,
+}
+
+struct NpcObserver {
+    coordinates: Coordinates,
+    variant: NpcInfoVariant,
+    // The local NPCs in the order the client knows them
+    local_npcs: Vec<LocalNpc>,
+}
+
+/// The NpcInfo containing the NPCs of the world and the NPCs seen by each player
+pub struct NpcInfo {
+    npcs: Slab<NpcUpdate>,
+    // Keyed by the same keys as the players of the PlayerInfo
+    observers: Slab<NpcObserver>,
+    config: WorldInfoConfig,
+}
+
+impl Default for NpcInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NpcInfo {
+    /// Create a NpcInfo using the default config
+    pub fn new() -> NpcInfo {
+        NpcInfo::with_config(WorldInfoConfig::default()).expect("default config is valid")
+    }
+
+    /// Create a NpcInfo using the view distance, NPC limits and mask buffer capacity of the config.
+    /// Fails if the config is invalid
+    pub fn with_config(config: WorldInfoConfig) -> Result<NpcInfo> {
+        config.validate()?;
+
+        Ok(NpcInfo {
+            npcs: Slab::new(),
+            observers: Slab::new(),
+            config,
+        })
+    }
+
+    /// Get the config of the NpcInfo
+    pub fn config(&self) -> &WorldInfoConfig {
+        &self.config
+    }
+
+    /// Add a player seeing the NPCs around the coordinates, keyed like the players of the PlayerInfo
+    pub fn add_player(&mut self, coordinates: Coordinates) -> Result<PlayerKey> {
+        coordinates.validate()?;
+
+        let player_id = self.observers.vacant_key();
+        if player_id >= self.config.max_players {
+            return Err(anyhow!(
+                "Maximum amount of players processable by NpcInfo reached"
+            ));
+        }
+
+        self.observers.insert(NpcObserver {
+            coordinates,
+            variant: NpcInfoVariant::default(),
+            local_npcs: Vec::new(),
+        });
+
+        Ok(player_id)
+    }
+
+    /// Remove a player from the NpcInfo
+    pub fn remove_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        self.observers
+            .try_remove(player_id)
+            .context("failed getting player")?;
+
+        Ok(())
+    }
+
+    /// Move the player the NPCs are seen from, which should be kept in line with its coordinates in the PlayerInfo
+    pub fn set_player_coordinates(
+        &mut self,
+        player_id: PlayerKey,
+        coordinates: Coordinates,
+    ) -> Result<()> {
+        coordinates.validate()?;
+
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Resend the NPCs around the player, e.g. after its client missed updates while suspended.
+    /// The next process removes every local NPC and adds the NPCs in view anew at their current coordinates
+    pub fn resync_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        for local in observer.local_npcs.iter_mut() {
+            local.removed = true;
+        }
+
+        Ok(())
+    }
+
+    /// Select the layout of the NPC info packets of the player, e.g. the large variant once it enters a crowded area.
+    /// Local NPCs are kept across variants, and removed once out of the view distance of the new variant
+    pub fn set_npc_info_variant(
+        &mut self,
+        player_id: PlayerKey,
+        variant: NpcInfoVariant,
+    ) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .variant = variant;
+
+        Ok(())
+    }
+
+    /// Get the layout of the NPC info packets of the player
+    pub fn npc_info_variant(&self, player_id: PlayerKey) -> Option<NpcInfoVariant> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.variant)
+    }
+
+    /// Get the local NPCs of the player, in the order its client knows them
+    pub fn local_npcs(&self, player_id: PlayerKey) -> Vec<NpcKey> {
+        self.observers
+            .get(player_id)
+            .map(|observer| {
+                observer
+                    .local_npcs
+                    .iter()
+                    .filter(|local| !local.removed)
+                    .map(|local| local.npc_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Add an NPC of the given definition at the coordinates, returning the index the client knows it by.
+    /// Players using the small variant do not see NPCs with an index or id beyond what the variant can write
+    pub fn add_npc(&mut self, id: u16, coordinates: Coordinates) -> Result<NpcKey> {
+        coordinates.validate()?;
+        let npc_id = self.npcs.vacant_key();
+        if npc_id >= MAX_NPCS {
+            return Err(anyhow!(
+                "Maximum amount of NPCs processable by NpcInfo reached"
+            ));
+        }
+
+        self.npcs.insert(NpcUpdate {
+            id,
+            coordinates,
+            size: 1,
+            movement_steps: Vec::with_capacity(MAX_MOVEMENT_STEPS),
+            move_speed: MoveSpeed::Walk,
+            teleported: false,
+            masks: NpcMasks::default(),
+            mask_flags: 0,
+        });
+
+        Ok(npc_id)
+    }
+
+    /// Remove an NPC, which observers that can see it remove in their next update
+    pub fn remove_npc(&mut self, npc_id: NpcKey) -> Result<()> {
+        self.npcs.try_remove(npc_id).context("failed getting NPC")?;
+
+        // The key is free to be reused by another NPC, which the client has to add anew
+        for (_, observer) in self.observers.iter_mut() {
+            for local in observer.local_npcs.iter_mut() {
+                if local.npc_id == npc_id {
+                    local.removed = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the coordinates of the NPC
+    pub fn npc_coordinates(&self, npc_id: NpcKey) -> Option<Coordinates> {
+        self.npcs.get(npc_id).map(|npc| npc.coordinates)
+    }
+
+    /// Place the NPC at the given coordinates this tick, e.g. when teleporting or respawning.
+    /// Observers that can see the NPC remove it and add it anew at the coordinates, dropping its path.
+    /// Fails with a `CoordinateError` if the coordinates cannot be written to the client
+    pub fn set_npc_coordinates(&mut self, npc_id: NpcKey, coordinates: Coordinates) -> Result<()> {
+        coordinates.validate()?;
+
+        let npc = self.npcs.get_mut(npc_id).context("failed getting NPC")?;
+        npc.movement_steps.clear();
+        npc.coordinates = coordinates;
+        npc.teleported = true;
+
+        Ok(())
+    }
+
+    /// Set the length in tiles of the square the NPC occupies, e.g. after transforming into a larger NPC.
+    /// Players see the NPC when any of its tiles is within their view distance
+    pub fn set_npc_size(&mut self, npc_id: NpcKey, size: u8) -> Result<()> {
+        if !(1..=MAX_NPC_SIZE).contains(&size) {
+            return Err(anyhow!(
+                "NPC size {} is not between 1 and {}",
+                size,
+                MAX_NPC_SIZE
+            ));
+        }
+
+        self.npcs
+            .get_mut(npc_id)
+            .context("failed getting NPC")?
+            .size = size;
+
+        Ok(())
+    }
+
+    /// Set the speed single steps of the NPC are written at, kept until it is changed.
+    /// Crawling NPCs move half a tile per tick, two steps in the same tick are written as a run at any speed
+    pub fn set_npc_move_speed(&mut self, npc_id: NpcKey, move_speed: MoveSpeed) -> Result<()> {
+        self.npcs
+            .get_mut(npc_id)
+            .context("failed getting NPC")?
+            .move_speed = move_speed;
+
+        Ok(())
+    }
+
+    /// Queue a single tile step of the NPC this tick, a second step makes the NPC run.
+    /// Steps after the NPC was placed this tick move it from its new coordinates.
+    /// Fails with a `CoordinateError` if the step leaves the coordinates that can be written to the client
+    pub fn queue_npc_step(&mut self, npc_id: NpcKey, dx: i32, dy: i32) -> Result<()> {
+        if walk_direction(dx, dy).is_none() {
+            return Err(anyhow!("Step {}, {} is not a single tile step", dx, dy));
+        }
+
+        let npc = self.npcs.get_mut(npc_id).context("failed getting NPC")?;
+        if npc.teleported {
+            npc.coordinates = npc.coordinates.translate(dx, dy).validate()?;
+            return Ok(());
+        }
+        if npc.movement_steps.len() >= MAX_MOVEMENT_STEPS {
+            return Err(anyhow!(
+                "NPC cannot take more than {} steps a tick",
+                MAX_MOVEMENT_STEPS
+            ));
+        }
+        let coordinates = npc.coordinates.translate(dx, dy).validate()?;
+
+        npc.movement_steps.push((dx, dy));
+        npc.coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Set a mask on the NPC, replacing the pending one
+    pub fn set_npc_mask<M: NpcMask>(&mut self, npc_id: NpcKey, mask: M) -> Result<()> {
+        let npc = self.npcs.get_mut(npc_id).context("failed getting NPC")?;
+        *M::slot_mut(&mut npc.masks) = Some(mask);
+        npc.mask_flags |= M::FLAG;
+
+        Ok(())
+    }
+
+    /// Set the sequence mask of the NPC, replacing the pending one
+    pub fn add_npc_sequence_mask(
+        &mut self,
+        npc_id: NpcKey,
+        sequence_mask: NpcSequenceMask,
+    ) -> Result<()> {
+        self.set_npc_mask(npc_id, sequence_mask)
+    }
+
+    /// Add hitsplats and health bars to the NPC, returning whether any were applied.
+    /// Like for players, setting it again within the tick adds to the pending ones up to the limit of a tick
+    pub fn add_npc_hit_mask(&mut self, npc_id: NpcKey, hit_mask: HitMask) -> Result<bool> {
+        playerinfo::validate_hit_mask(&hit_mask)?;
+
+        let npc = self.npcs.get_mut(npc_id).context("failed getting NPC")?;
+        let applied = match npc.masks.hit_mask.as_mut() {
+            Some(pending) => pending.append(hit_mask, MAX_HITSPLATS),
+            None => {
+                npc.masks.hit_mask = Some(hit_mask);
+                true
+            }
+        };
+        npc.mask_flags |= NPC_HIT_MASK;
+
+        Ok(applied)
+    }
+
+    /// Set the transform mask of the NPC, changing the definition the client renders it with.
+    /// The NPC keeps the new definition, so observers adding it later are sent the new definition in the addition
+    pub fn add_npc_transform_mask(
+        &mut self,
+        npc_id: NpcKey,
+        transform_mask: NpcTransformMask,
+    ) -> Result<()> {
+        let npc = self.npcs.get_mut(npc_id).context("failed getting NPC")?;
+        npc.id = transform_mask.id;
+
+        self.set_npc_mask(npc_id, transform_mask)
+    }
+
+    /// Set the face entity mask of the NPC, turning it towards a player or another NPC until released.
+    /// Fails if the index of the entity cannot be written to the client
+    pub fn add_npc_face_entity_mask(
+        &mut self,
+        npc_id: NpcKey,
+        face_entity_mask: FaceEntityMask,
+    ) -> Result<()> {
+        playerinfo::validate_face_entity_mask(&face_entity_mask)?;
+
+        self.set_npc_mask(npc_id, face_entity_mask)
+    }
+
+    /// Set the face coordinate mask of the NPC, turning it towards the centre of a tile once.
+    /// Set in the tick the NPC is added, the NPC is spawned facing the tile.
+    /// Fails if the coordinates cannot be written to the client
+    pub fn add_npc_face_coordinate_mask(
+        &mut self,
+        npc_id: NpcKey,
+        face_coordinate_mask: FaceCoordinateMask,
+    ) -> Result<()> {
+        playerinfo::validate_face_coordinate_mask(&face_coordinate_mask)?;
+
+        self.set_npc_mask(npc_id, face_coordinate_mask)
+    }
+
+    /// Set the spot animation mask of the NPC, replacing the pending one
+    pub fn add_npc_spot_animation_mask(
+        &mut self,
+        npc_id: NpcKey,
+        spot_animation_mask: SpotAnimationMask,
+    ) -> Result<()> {
+        self.set_npc_mask(npc_id, spot_animation_mask)
+    }
+
+    /// Set the overhead text of the NPC, failing if the text cannot be written as a CP1252 string
+    pub fn add_npc_forced_chat_mask(
+        &mut self,
+        npc_id: NpcKey,
+        forced_chat_mask: ForcedChatMask,
+    ) -> Result<()> {
+        playerinfo::validate_cp1252(&forced_chat_mask.text, MAX_FORCED_CHAT_LENGTH)?;
+
+        self.set_npc_mask(npc_id, forced_chat_mask)
+    }
+
+    /// Set the exact move mask of the NPC, sliding it between two tiles at an exact pace, e.g. the lunge of a boss.
+    /// Fails if the client cannot interpolate the movement
+    pub fn add_npc_exact_move_mask(
+        &mut self,
+        npc_id: NpcKey,
+        exact_move_mask: ForcedMovementMask,
+    ) -> Result<()> {
+        playerinfo::validate_forced_movement_mask(&exact_move_mask)?;
+
+        self.set_npc_mask(npc_id, exact_move_mask)
+    }
+
+    /// Set the base animation set mask of the NPC, overriding the sequences it stands and moves with.
+    /// Fails if no sequence is overridden
+    pub fn add_npc_base_animation_set_mask(
+        &mut self,
+        npc_id: NpcKey,
+        base_animation_set_mask: NpcBaseAnimationSetMask,
+    ) -> Result<()> {
+        if base_animation_set_mask
+            .sequences()
+            .iter()
+            .all(Option::is_none)
+        {
+            return Err(anyhow!("Base animation set mask overrides no sequence"));
+        }
+
+        self.set_npc_mask(npc_id, base_animation_set_mask)
+    }
+
+    /// Set the combat level mask of the NPC, overriding the level shown until reset
+    pub fn add_npc_combat_level_mask(
+        &mut self,
+        npc_id: NpcKey,
+        combat_level_mask: NpcCombatLevelMask,
+    ) -> Result<()> {
+        self.set_npc_mask(npc_id, combat_level_mask)
+    }
+
+    /// Set the name mask of the NPC, overriding the name shown until reset.
+    /// Fails if the name is empty or cannot be written as a CP1252 string
+    pub fn add_npc_name_mask(&mut self, npc_id: NpcKey, name_mask: NpcNameMask) -> Result<()> {
+        if let Some(name) = &name_mask.name {
+            if name.is_empty() {
+                return Err(anyhow!("Name cannot be empty, as it resets the name"));
+            }
+            playerinfo::validate_cp1252(name, MAX_NPC_NAME_LENGTH)?;
+        }
+
+        self.set_npc_mask(npc_id, name_mask)
+    }
+
+    /// Set the customisation mask of the NPC, replacing the models and colours of its definition until reset.
+    /// Fails if the client cannot read the amount of models, recolours or retextures
+    pub fn add_npc_customisation_mask(
+        &mut self,
+        npc_id: NpcKey,
+        customisation_mask: NpcCustomisationMask,
+    ) -> Result<()> {
+        let models = customisation_mask.models.as_ref().map_or(0, Vec::len);
+        if models > MAX_CUSTOMISATION_PARTS
+            || customisation_mask.recolours.len() > MAX_CUSTOMISATION_PARTS
+            || customisation_mask.retextures.len() > MAX_CUSTOMISATION_PARTS
+        {
+            return Err(anyhow!(
+                "Customisation exceeds {} models, recolours or retextures",
+                MAX_CUSTOMISATION_PARTS
+            ));
+        }
+
+        self.set_npc_mask(npc_id, customisation_mask)
+    }
+
+    /// Get the definition the client renders the NPC with
+    pub fn npc_id(&self, npc_id: NpcKey) -> Option<u16> {
+        self.npcs.get(npc_id).map(|npc| npc.id)
+    }
+
+    /// Advance to the next tick, clearing the movement and masks of every NPC.
+    /// Should be called once at the end of every tick, after all players have been processed
+    pub fn tick(&mut self) {
+        for (_, npc) in self.npcs.iter_mut() {
+            npc.movement_steps.clear();
+            npc.teleported = false;
+            npc.masks.retain_persistent();
+            npc.mask_flags = 0;
+        }
+    }
+
+    /// Process a player contained in the NpcInfo, returning a buffer with the updates of the NPCs around the player.
+    /// Local NPCs are updated in the order the client knows them, removing those out of view,
+    /// after which the NPCs that came into view are added
+    pub fn process(&mut self, player_id: PlayerKey) -> Result<Vec<u8>> {
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+        let mut mask_buf = Cursor::new(Vec::with_capacity(self.config.mask_buffer_capacity));
+        let variant = observer.variant;
+        let view_distance = variant.view_distance(&self.config);
+
+        bit_buf.write(8, observer.local_npcs.len() as u32)?;
+        let mut local_npcs = Vec::with_capacity(observer.local_npcs.len());
+        for local in observer.local_npcs.drain(..) {
+            let npc = match self.npcs.get(local.npc_id) {
+                Some(npc)
+                    if !local.removed
+                        && !npc.teleported
+                        && in_view(observer.coordinates, npc, view_distance) =>
+                {
+                    npc
+                }
+                _ => {
+                    bit_buf.write_bit(true)?;
+                    bit_buf.write(2, NPC_MOVEMENT_REMOVE)?;
+                    continue;
+                }
+            };
+
+            let mask_update = npc.mask_flags != 0;
+            write_npc_movement(&mut bit_buf, npc, mask_update)?;
+            if mask_update {
+                write_npc_mask_update(&mut mask_buf, npc, npc.mask_flags)?;
+            }
+            local_npcs.push(local);
+        }
+
+        // Like the client, the nearest NPCs are added first. NPCs past the limits are added in later ticks
+        let mut candidates: Vec<(i32, NpcKey)> = self
+            .npcs
+            .iter()
+            .filter(|&(npc_id, npc)| {
+                variant.can_add(npc_id, npc.id)
+                    && in_view(observer.coordinates, npc, view_distance)
+                    && variant.can_reach(observer.coordinates, npc)
+                    && !local_npcs.iter().any(|local| local.npc_id == npc_id)
+            })
+            .map(|(npc_id, npc)| (npc_distance(observer.coordinates, npc), npc_id))
+            .collect();
+        candidates.sort_unstable();
+        let additions = self
+            .config
+            .max_local_npcs
+            .saturating_sub(local_npcs.len())
+            .min(self.config.max_npc_additions_per_tick);
+
+        for &(_, npc_id) in candidates.iter().take(additions) {
+            let npc = &self.npcs[npc_id];
+            let mask_flags = npc.addition_mask_flags();
+            let mask_update = mask_flags != 0;
+            write_npc_addition(
+                &mut bit_buf,
+                variant,
+                npc_id,
+                npc,
+                observer.coordinates,
+                mask_update,
+            )?;
+            if mask_update {
+                write_npc_mask_update(&mut mask_buf, npc, mask_flags)?;
+            }
+            local_npcs.push(LocalNpc {
+                npc_id,
+                removed: false,
+            });
+        }
+        bit_buf.write(variant.index_bits(), variant.index_terminator())?;
+        bit_buf.byte_align()?;
+        observer.local_npcs = local_npcs;
+
+        let mut vec = bit_buf.into_writer();
+        vec.write_all(mask_buf.get_ref())?;
+
+        Ok(vec)
+    }
+}
+
+/// Whether the NPC is within view of an observer at the coordinates
+fn in_view(coordinates: Coordinates, npc: &NpcUpdate, view_distance: i32) -> bool {
+    coordinates.plane == npc.coordinates.plane && npc_distance(coordinates, npc) <= view_distance
+}
+
+// The distance to the nearest tile the NPC occupies
+fn npc_distance(coordinates: Coordinates, npc: &NpcUpdate) -> i32 {
+    let far = i32::from(npc.size) - 1;
+    let nearest = Coordinates {
+        x: coordinates
+            .x
+            .clamp(npc.coordinates.x, npc.coordinates.x + far),
+        y: coordinates
+            .y
+            .clamp(npc.coordinates.y, npc.coordinates.y + far),
+        ..npc.coordinates
+    };
+    coordinates.distance(nearest)
+}
+
+fn write_npc_movement(
+    bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+    npc: &NpcUpdate,
+    mask_update: bool,
+) -> Result<()> {
+    match npc.movement_steps[..] {
+        [] if !mask_update => bit_buf.write_bit(false)?,
+        [] => {
+            bit_buf.write_bit(true)?;
+            bit_buf.write(2, NPC_MOVEMENT_NONE)?;
+        }
+        // Crawling shares its type with running, told apart by the bit following it
+        [(dx, dy)] if npc.move_speed == MoveSpeed::Crawl => {
+            bit_buf.write_bit(true)?;
+            bit_buf.write(2, NPC_MOVEMENT_RUN)?;
+            bit_buf.write_bit(false)?;
+            bit_buf.write(3, walk_direction(dx, dy).context("crawl direction")?)?;
+            bit_buf.write_bit(mask_update)?;
+        }
+        [(dx, dy)] => {
+            bit_buf.write_bit(true)?;
+            bit_buf.write(2, NPC_MOVEMENT_WALK)?;
+            bit_buf.write(3, walk_direction(dx, dy).context("walk direction")?)?;
+            bit_buf.write_bit(mask_update)?;
+        }
+        [(walk_dx, walk_dy), (run_dx, run_dy), ..] => {
+            bit_buf.write_bit(true)?;
+            bit_buf.write(2, NPC_MOVEMENT_RUN)?;
+            bit_buf.write_bit(true)?;
+            bit_buf.write(
+                3,
+                walk_direction(walk_dx, walk_dy).context("walk direction")?,
+            )?;
+            bit_buf.write(3, walk_direction(run_dx, run_dy).context("run direction")?)?;
+            bit_buf.write_bit(mask_update)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_npc_addition(
+    bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+    variant: NpcInfoVariant,
+    npc_id: NpcKey,
+    npc: &NpcUpdate,
+    observer: Coordinates,
+    mask_update: bool,
+) -> Result<()> {
+    let (dx, dy, _) = observer.delta(npc.coordinates);
+    let delta_bits = variant.delta_bits();
+    let delta_mask = (1 << delta_bits) - 1;
+
+    bit_buf.write(variant.index_bits(), npc_id as u32)?;
+    bit_buf.write(delta_bits, dx & delta_mask)?;
+    bit_buf.write(delta_bits, dy & delta_mask)?;
+    bit_buf.write_bit(mask_update)?;
+    // The client drops the path of NPCs that jumped, which added NPCs never have
+    bit_buf.write_bit(true)?;
+    bit_buf.write(variant.id_bits(), npc.id as u32)?;
+
+    Ok(())
+}
+
+// Write the masks of the NPC, in the same header layout as the masks of players
+fn write_npc_mask_update(
+    mask_buf: &mut Cursor<Vec<u8>>,
+    npc: &NpcUpdate,
+    mask_flags: u32,
+) -> Result<()> {
+    if mask_flags > 0xFFFF {
+        let header = mask_flags | MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM;
+        mask_buf.write_i8(header as i8)?;
+        mask_buf.write_i8((header >> 8) as i8)?;
+        mask_buf.write_i8((header >> 16) as i8)?;
+    } else if mask_flags > 0xFF {
+        let header = mask_flags | MASK_CONTINUATION_SHORT;
+        mask_buf.write_i8(header as i8)?;
+        mask_buf.write_i8((header >> 8) as i8)?;
+    } else {
+        mask_buf.write_i8(mask_flags as i8)?;
+    }
+
+    for &mask in NPC_MASKS {
+        if mask_flags & mask != 0 {
+            write_npc_mask(&npc.masks, mask, mask_buf)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_npc_mask(masks: &NpcMasks, mask: u32, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    match mask {
+        NPC_SEQUENCE_MASK => write_mask::<NpcSequenceMask>(masks, mask_buf),
+        NPC_HIT_MASK => write_mask::<HitMask>(masks, mask_buf),
+        NPC_TRANSFORM_MASK => write_mask::<NpcTransformMask>(masks, mask_buf),
+        NPC_FACE_ENTITY_MASK => write_mask::<FaceEntityMask>(masks, mask_buf),
+        NPC_FACE_COORDINATE_MASK => write_mask::<FaceCoordinateMask>(masks, mask_buf),
+        NPC_SPOT_ANIMATION_MASK => write_mask::<SpotAnimationMask>(masks, mask_buf),
+        NPC_FORCED_CHAT_MASK => write_mask::<ForcedChatMask>(masks, mask_buf),
+        NPC_EXACT_MOVE_MASK => write_mask::<ForcedMovementMask>(masks, mask_buf),
+        NPC_BASE_ANIMATION_SET_MASK => write_mask::<NpcBaseAnimationSetMask>(masks, mask_buf),
+        NPC_COMBAT_LEVEL_MASK => write_mask::<NpcCombatLevelMask>(masks, mask_buf),
+        NPC_NAME_MASK => write_mask::<NpcNameMask>(masks, mask_buf),
+        NPC_CUSTOMISATION_MASK => write_mask::<NpcCustomisationMask>(masks, mask_buf),
+        _ => Err(MaskError::Unwritable(mask).into()),
+    }
+}
+
+fn write_mask<M: NpcMask>(masks: &NpcMasks, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    M::slot(masks)
+        .as_ref()
+        .ok_or(MaskError::Missing(M::FLAG))?
+        .encode(mask_buf)
+}
+
+fn walk_direction(dx: i32, dy: i32) -> Option<u32> {
+    match (dx, dy) {
+        (-1, -1) => Some(0),
+        (0, -1) => Some(1),
+        (1, -1) => Some(2),
+        (-1, 0) => Some(3),
+        (1, 0) => Some(4),
+        (-1, 1) => Some(5),
+        (0, 1) => Some(6),
+        (1, 1) => Some(7),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playerinfo::{HealthBar, Hitsplat};
+    use bitstream_io::{BitRead, BitReader};
+
+    // An NPC update as decoded by the client
+    #[derive(Debug, PartialEq, Eq)]
+    enum Decoded {
+        Idle,
+        Masks,
+        Walked(u32),
+        Crawled(u32),
+        Ran(u32, u32),
+        Removed,
+        Added {
+            index: u32,
+            dx: i32,
+            dy: i32,
+            id: u32,
+        },
+    }
+
+    // The local count, a single addition of a small NPC without masks and the terminator
+    const BARE_ADDITION_SIZE: usize =
+        (8 + 2 * SMALL_INDEX_BITS + 2 * SMALL_DELTA_BITS + 2 + SMALL_ID_BITS).div_ceil(8) as usize;
+
+    // A player with an NPC next to them, both added in a previous tick
+    fn npc_in_view() -> Result<(NpcInfo, PlayerKey, NpcKey)> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3201, 3200, 0))?;
+        npcinfo.process(player_id)?;
+        npcinfo.tick();
+
+        Ok((npcinfo, player_id, npc_id))
+    }
+
+    // Decode the bit section of a packet like the client would
+    fn decode(packet: &[u8], variant: NpcInfoVariant) -> Result<Vec<Decoded>> {
+        let mut reader = BitReader::endian(Cursor::new(packet), BigEndian);
+        let mut updates = Vec::new();
+
+        let local_count = reader.read::<u32>(8)?;
+        for _ in 0..local_count {
+            if !reader.read_bit()? {
+                updates.push(Decoded::Idle);
+                continue;
+            }
+            updates.push(match reader.read::<u32>(2)? {
+                NPC_MOVEMENT_NONE => Decoded::Masks,
+                NPC_MOVEMENT_WALK => {
+                    let direction = reader.read(3)?;
+                    reader.read_bit()?;
+                    Decoded::Walked(direction)
+                }
+                NPC_MOVEMENT_RUN if !reader.read_bit()? => {
+                    let direction = reader.read(3)?;
+                    reader.read_bit()?;
+                    Decoded::Crawled(direction)
+                }
+                NPC_MOVEMENT_RUN => {
+                    let walk = reader.read(3)?;
+                    let run = reader.read(3)?;
+                    reader.read_bit()?;
+                    Decoded::Ran(walk, run)
+                }
+                _ => Decoded::Removed,
+            });
+        }
+
+        loop {
+            let index = reader.read::<u32>(variant.index_bits())?;
+            if index == variant.index_terminator() {
+                break;
+            }
+            let dx = reader.read_signed::<i32>(variant.delta_bits())?;
+            let dy = reader.read_signed::<i32>(variant.delta_bits())?;
+            reader.read_bit()?;
+            reader.read_bit()?;
+            let id = reader.read(variant.id_bits())?;
+            updates.push(Decoded::Added { index, dx, dy, id });
+        }
+
+        Ok(updates)
+    }
+
+    #[test]
+    fn small_variant_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        npcinfo.add_npc(100, Coordinates::new(3205, 3198, 0))?;
+        npcinfo.add_npc(101, Coordinates::new(3200, 3216, 0))?;
+
+        // 8 bits of local NPCs, then the 13-bit index, 5-bit deltas, flags and id, terminated by a 13-bit index
+        assert_eq!(
+            npcinfo.process(player_id)?,
+            [0x00, 0x00, 0x01, 0x7C, 0x80, 0xC9, 0xFF, 0xF0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_variant_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let far = npcinfo.add_npc(100, Coordinates::new(3240, 3160, 0))?;
+        let high = npcinfo.add_npc(20000, Coordinates::new(3201, 3200, 0))?;
+
+        assert_eq!(
+            npcinfo.npc_info_variant(player_id),
+            Some(NpcInfoVariant::Small)
+        );
+        assert!(decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?.is_empty());
+        npcinfo.tick();
+
+        npcinfo.set_npc_info_variant(player_id, NpcInfoVariant::Large)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Large)?,
+            [
+                Decoded::Added {
+                    index: high as u32,
+                    dx: 1,
+                    dy: 0,
+                    id: 20000
+                },
+                Decoded::Added {
+                    index: far as u32,
+                    dx: 40,
+                    dy: -40,
+                    id: 100
+                }
+            ]
+        );
+        npcinfo.tick();
+
+        // Switching back removes the NPC out of the smaller view, keeping the one that cannot be added anew
+        npcinfo.set_npc_info_variant(player_id, NpcInfoVariant::Small)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Idle, Decoded::Removed]
+        );
+        assert_eq!(npcinfo.local_npcs(player_id), [high]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_sequence_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.add_npc_sequence_mask(npc_id, NpcSequenceMask { id: 422, delay: 2 })?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(decode(&vec, NpcInfoVariant::Small)?, [Decoded::Masks]);
+        assert_eq!(vec[vec.len() - 4..], [0x01, 0x01, 0x26, 0xFE]);
+        npcinfo.tick();
+
+        // Masks are written once
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Idle]
+        );
+        assert!(npcinfo
+            .add_npc_sequence_mask(npc_id + 1, NpcSequenceMask { id: 422, delay: 0 })
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_hit_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        let hitsplat = Hitsplat {
+            hitsplat_type: 1,
+            damage: 12,
+            ..Default::default()
+        };
+        let health_bar = HealthBar {
+            health_percentage: 50,
+            ..Default::default()
+        };
+        assert!(npcinfo.add_npc_hit_mask(npc_id, HitMask::single(hitsplat, health_bar))?);
+        assert!(npcinfo.add_npc_hit_mask(
+            npc_id,
+            HitMask {
+                hitsplats: vec![hitsplat; MAX_HITSPLATS],
+                health_bars: Vec::new()
+            }
+        )?);
+        assert!(!npcinfo.add_npc_hit_mask(
+            npc_id,
+            HitMask {
+                hitsplats: vec![hitsplat],
+                health_bars: Vec::new()
+            }
+        )?);
+
+        // Splats past the limit of a tick are dropped, the health bar is written as a ratio of its width
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(decode(&vec, NpcInfoVariant::Small)?, [Decoded::Masks]);
+        let block = &vec[vec.len() - 19..];
+        assert_eq!(block[..2], [NPC_HIT_MASK as u8, 0x80 + MAX_HITSPLATS as u8]);
+        assert_eq!(block[2..5], [1, 12, 0]);
+        assert_eq!(block[14..], [0xFF, 0, 0, 0, 128 - 15]);
+
+        assert!(npcinfo
+            .add_npc_hit_mask(npc_id, HitMask::default())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_transform_mask_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let near = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let far = npcinfo.add_player(Coordinates::new(3240, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3201, 3200, 0))?;
+        npcinfo.process(near)?;
+        npcinfo.process(far)?;
+        npcinfo.tick();
+
+        npcinfo.add_npc_transform_mask(npc_id, NpcTransformMask { id: 8615 })?;
+        assert_eq!(npcinfo.npc_id(npc_id), Some(8615));
+        let vec = npcinfo.process(near)?;
+        assert_eq!(decode(&vec, NpcInfoVariant::Small)?, [Decoded::Masks]);
+        assert_eq!(vec[vec.len() - 3..], [NPC_TRANSFORM_MASK as u8, 0xA7, 0x21]);
+
+        // Observers adding the NPC see the new definition in the addition, without the mask
+        npcinfo.set_player_coordinates(far, Coordinates::new(3200, 3200, 0))?;
+        let vec = npcinfo.process(far)?;
+        assert_eq!(vec.len(), BARE_ADDITION_SIZE);
+        assert_eq!(
+            decode(&vec, NpcInfoVariant::Small)?,
+            [Decoded::Added {
+                index: npc_id as u32,
+                dx: 1,
+                dy: 0,
+                id: 8615
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_face_entity_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+        let later = npcinfo.add_player(Coordinates::new(3240, 3200, 0))?;
+
+        npcinfo.add_npc_face_entity_mask(
+            npc_id,
+            FaceEntityMask {
+                entity: FaceEntity::Npc(5),
+            },
+        )?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(
+            vec[vec.len() - 3..],
+            [NPC_FACE_ENTITY_MASK as u8, 0x80, 0x85]
+        );
+        npcinfo.tick();
+
+        // The lock is replayed to observers adding the NPC later, until released
+        npcinfo.set_player_coordinates(later, Coordinates::new(3200, 3200, 0))?;
+        let vec = npcinfo.process(later)?;
+        assert_eq!(
+            vec[vec.len() - 3..],
+            [NPC_FACE_ENTITY_MASK as u8, 0x80, 0x85]
+        );
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Idle]
+        );
+        npcinfo.tick();
+
+        npcinfo.add_npc_face_entity_mask(
+            npc_id,
+            FaceEntityMask {
+                entity: FaceEntity::None,
+            },
+        )?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(
+            vec[vec.len() - 3..],
+            [NPC_FACE_ENTITY_MASK as u8, 0xFF, 0x7F]
+        );
+        npcinfo.tick();
+        npcinfo.remove_player(later)?;
+        let later = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        assert_eq!(npcinfo.process(later)?.len(), BARE_ADDITION_SIZE);
+
+        assert!(npcinfo
+            .add_npc_face_entity_mask(
+                npc_id,
+                FaceEntityMask {
+                    entity: FaceEntity::Npc(32768),
+                },
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_face_coordinate_mask_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3201, 3200, 0))?;
+        npcinfo.add_npc_face_coordinate_mask(npc_id, FaceCoordinateMask { x: 3222, y: 3218 })?;
+
+        // Spawned facing the tile
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(
+            vec[vec.len() - 5..],
+            [NPC_FACE_COORDINATE_MASK as u8, 0x2D, 0x19, 0x25, 0x19]
+        );
+        npcinfo.tick();
+
+        // The tile is only faced once
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Idle]
+        );
+        assert!(npcinfo
+            .add_npc_face_coordinate_mask(npc_id, FaceCoordinateMask { x: -1, y: 3218 })
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_spot_animation_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.add_npc_sequence_mask(npc_id, NpcSequenceMask { id: 711, delay: 0 })?;
+        npcinfo.add_npc_spot_animation_mask(
+            npc_id,
+            SpotAnimationMask {
+                id: 85,
+                height: 100,
+                delay: 2,
+            },
+        )?;
+
+        // Written after the sequence, with the height and delay packed into an int
+        let vec = npcinfo.process(player_id)?;
+        let flags = (NPC_SEQUENCE_MASK | NPC_SPOT_ANIMATION_MASK) as u8;
+        assert_eq!(
+            vec[vec.len() - 10..],
+            [flags, 0x02, 0x47, 0x00, 0x55, 0x00, 0x64, 0x00, 0x02, 0x00]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_forced_chat_mask_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3201, 3200, 0))?;
+        npcinfo.add_npc_forced_chat_mask(
+            npc_id,
+            ForcedChatMask {
+                text: "Graaah!".to_string(),
+            },
+        )?;
+
+        // Written as a nul terminated string
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(vec[vec.len() - 9], NPC_FORCED_CHAT_MASK as u8);
+        assert_eq!(vec[vec.len() - 8..], *b"Graaah!\0");
+
+        for text in ["x".repeat(MAX_FORCED_CHAT_LENGTH + 1), "Grääh".to_string()] {
+            assert!(npcinfo
+                .add_npc_forced_chat_mask(npc_id, ForcedChatMask { text })
+                .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_exact_move_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        let lunge = ForcedMovementMask {
+            start_dx: 0,
+            start_dy: 0,
+            end_dx: 3,
+            end_dy: 0,
+            start_delay: 10,
+            end_delay: 20,
+            direction: 1536,
+        };
+        npcinfo.add_npc_exact_move_mask(npc_id, lunge)?;
+
+        // The flag exceeds the first byte of the header, so the continuation marker is set
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(
+            vec[vec.len() - 12..],
+            [0x40, 0x01, 0x80, 0x80, 0xFD, 0x00, 0x0A, 0x00, 0x00, 0x1E, 0x06, 0x80]
+        );
+
+        let instant = ForcedMovementMask {
+            start_dx: 0,
+            start_dy: 0,
+            end_dx: 3,
+            end_dy: 0,
+            start_delay: 10,
+            end_delay: 0,
+            direction: 1536,
+        };
+        assert!(npcinfo.add_npc_exact_move_mask(npc_id, instant).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_base_animation_set_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.add_npc_base_animation_set_mask(
+            npc_id,
+            NpcBaseAnimationSetMask {
+                stand: Some(808),
+                walk: Some(819),
+                ..Default::default()
+            },
+        )?;
+
+        // Only the overridden sequences are written
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(
+            vec[vec.len() - 7..],
+            [0x40, 0x02, 0b1001, 0x03, 0x28, 0x03, 0x33]
+        );
+        assert!(npcinfo
+            .add_npc_base_animation_set_mask(npc_id, NpcBaseAnimationSetMask::default())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_combat_level_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.add_npc_combat_level_mask(npc_id, NpcCombatLevelMask { level: Some(1000) })?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(vec[vec.len() - 6..], [0x40, 0x04, 0xE8, 0x03, 0x00, 0x00]);
+        npcinfo.tick();
+
+        // The override is shown to observers adding the NPC later, until it is reset
+        let later = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let vec = npcinfo.process(later)?;
+        assert_eq!(vec[vec.len() - 6..], [0x40, 0x04, 0xE8, 0x03, 0x00, 0x00]);
+        npcinfo.tick();
+
+        npcinfo.add_npc_combat_level_mask(npc_id, NpcCombatLevelMask { level: None })?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(vec[vec.len() - 6..], [0x40, 0x04, 0xFF, 0xFF, 0xFF, 0xFF]);
+        npcinfo.tick();
+        let latest = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        assert_eq!(npcinfo.process(latest)?.len(), BARE_ADDITION_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_name_mask_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3201, 3200, 0))?;
+        npcinfo.add_npc_name_mask(
+            npc_id,
+            NpcNameMask {
+                name: Some("Zezima".to_string()),
+            },
+        )?;
+        npcinfo.process(player_id)?;
+        npcinfo.tick();
+
+        // The name is shown to observers adding the NPC later, until it is reset
+        let later = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let vec = npcinfo.process(later)?;
+        assert_eq!(vec[vec.len() - 9..], *b"\x40\x08Zezima\0");
+        npcinfo.tick();
+
+        npcinfo.add_npc_name_mask(npc_id, NpcNameMask { name: None })?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(vec[vec.len() - 3..], [0x40, 0x08, 0x00]);
+
+        for name in ["", "Zézima"] {
+            let name = Some(name.to_string());
+            assert!(npcinfo
+                .add_npc_name_mask(npc_id, NpcNameMask { name })
+                .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_customisation_mask_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.add_npc_customisation_mask(
+            npc_id,
+            NpcCustomisationMask {
+                models: Some(vec![0x1234]),
+                recolours: vec![(1, 2)],
+                ..Default::default()
+            },
+        )?;
+        let vec = npcinfo.process(player_id)?;
+        let customisation = [0x03, 0x01, 0x12, 0x34, 0x01, 0x00, 0x01, 0x00, 0x02];
+        assert_eq!(vec[vec.len() - 11..vec.len() - 9], [0x40, 0x10]);
+        assert_eq!(vec[vec.len() - 9..], customisation);
+        npcinfo.tick();
+
+        // The customisation is shown to observers adding the NPC later, until it is reset
+        let later = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let vec = npcinfo.process(later)?;
+        assert_eq!(vec[vec.len() - 9..], customisation);
+        npcinfo.tick();
+
+        npcinfo.add_npc_customisation_mask(npc_id, NpcCustomisationMask::default())?;
+        let vec = npcinfo.process(player_id)?;
+        assert_eq!(vec[vec.len() - 3..], [0x40, 0x10, 0x00]);
+        npcinfo.tick();
+        let latest = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        assert_eq!(npcinfo.process(latest)?.len(), BARE_ADDITION_SIZE);
+
+        let oversized = NpcCustomisationMask {
+            recolours: vec![(1, 2); MAX_CUSTOMISATION_PARTS + 1],
+            ..Default::default()
+        };
+        assert!(npcinfo
+            .add_npc_customisation_mask(npc_id, oversized)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_limits_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::with_config(WorldInfoConfig {
+            max_local_npcs: 3,
+            max_npc_additions_per_tick: 2,
+            ..Default::default()
+        })?;
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npcs = [4, 1, 3, 2]
+            .into_iter()
+            .map(|dx| npcinfo.add_npc(100, Coordinates::new(3200 + dx, 3200, 0)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // The nearest NPCs are added first, the rest carried over to later ticks
+        npcinfo.process(player_id)?;
+        assert_eq!(npcinfo.local_npcs(player_id), [npcs[1], npcs[3]]);
+        npcinfo.tick();
+        npcinfo.process(player_id)?;
+        assert_eq!(npcinfo.local_npcs(player_id), [npcs[1], npcs[3], npcs[2]]);
+        npcinfo.tick();
+
+        // The furthest NPC is only added once another leaves the view
+        npcinfo.process(player_id)?;
+        assert_eq!(npcinfo.local_npcs(player_id).len(), 3);
+        npcinfo.tick();
+        npcinfo.remove_npc(npcs[1])?;
+        npcinfo.process(player_id)?;
+        assert_eq!(npcinfo.local_npcs(player_id), [npcs[3], npcs[2], npcs[0]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_movement_test() -> Result<()> {
+        let (mut npcinfo, player_id, npc_id) = npc_in_view()?;
+
+        npcinfo.set_npc_move_speed(npc_id, MoveSpeed::Crawl)?;
+        npcinfo.queue_npc_step(npc_id, 0, 1)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Crawled(6)]
+        );
+        npcinfo.tick();
+
+        // Two steps are a run at any speed
+        npcinfo.queue_npc_step(npc_id, 0, 1)?;
+        npcinfo.queue_npc_step(npc_id, 0, 1)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Ran(6, 6)]
+        );
+        npcinfo.tick();
+
+        // Teleports within view remove the NPC and add it anew, including steps taken after it
+        npcinfo.queue_npc_step(npc_id, 1, 0)?;
+        npcinfo.set_npc_coordinates(npc_id, Coordinates::new(3195, 3195, 0))?;
+        npcinfo.queue_npc_step(npc_id, -1, 0)?;
+        assert_eq!(
+            npcinfo.npc_coordinates(npc_id),
+            Some(Coordinates::new(3194, 3195, 0))
+        );
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [
+                Decoded::Removed,
+                Decoded::Added {
+                    index: npc_id as u32,
+                    dx: -6,
+                    dy: -5,
+                    id: 100
+                }
+            ]
+        );
+        npcinfo.tick();
+
+        // Respawning out of view only removes the NPC
+        npcinfo.set_npc_coordinates(npc_id, Coordinates::new(3300, 3300, 0))?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Removed]
+        );
+        assert!(npcinfo
+            .set_npc_coordinates(npc_id, Coordinates::new(-1, 3300, 0))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_size_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = npcinfo.add_npc(100, Coordinates::new(3184, 3184, 0))?;
+        assert!(decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?.is_empty());
+        npcinfo.tick();
+
+        // The north-east tile of a size 3 NPC is within view
+        npcinfo.set_npc_size(npc_id, 3)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Added {
+                index: npc_id as u32,
+                dx: -16,
+                dy: -16,
+                id: 100
+            }]
+        );
+        npcinfo.tick();
+
+        npcinfo.set_npc_size(npc_id, 1)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Removed]
+        );
+        assert!(npcinfo.set_npc_size(npc_id, 0).is_err());
+        assert!(npcinfo.set_npc_size(npc_id, 6).is_err());
+        npcinfo.tick();
+
+        // The east tiles of a size 5 NPC are within view, but its south-west tile is past the reach of the delta
+        npcinfo.set_npc_size(npc_id, 5)?;
+        npcinfo.set_npc_coordinates(npc_id, Coordinates::new(3181, 3200, 0))?;
+        assert!(decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?.is_empty());
+        assert!(npcinfo.local_npcs(player_id).is_empty());
+        npcinfo.tick();
+
+        npcinfo.set_npc_coordinates(npc_id, Coordinates::new(3184, 3200, 0))?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Added {
+                index: npc_id as u32,
+                dx: -16,
+                dy: 0,
+                id: 100
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_view_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let player_id = npcinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let near = npcinfo.add_npc(100, Coordinates::new(3205, 3198, 0))?;
+        npcinfo.add_npc(101, Coordinates::new(3230, 3200, 0))?;
+        npcinfo.add_npc(102, Coordinates::new(3200, 3200, 1))?;
+
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Added {
+                index: near as u32,
+                dx: 5,
+                dy: -2,
+                id: 100
+            }]
+        );
+        assert_eq!(npcinfo.local_npcs(player_id), [near]);
+        npcinfo.tick();
+
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Idle]
+        );
+        npcinfo.tick();
+
+        npcinfo.queue_npc_step(near, 1, 0)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Walked(4)]
+        );
+        npcinfo.tick();
+
+        npcinfo.queue_npc_step(near, 0, 1)?;
+        npcinfo.queue_npc_step(near, 1, 1)?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Ran(6, 7)]
+        );
+        npcinfo.tick();
+
+        // Walking away removes the NPC, as does removing it from the NpcInfo
+        npcinfo.set_player_coordinates(player_id, Coordinates::new(3180, 3200, 0))?;
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [Decoded::Removed]
+        );
+        assert!(npcinfo.local_npcs(player_id).is_empty());
+        npcinfo.tick();
+
+        npcinfo.set_player_coordinates(player_id, Coordinates::new(3200, 3200, 0))?;
+        npcinfo.process(player_id)?;
+        npcinfo.tick();
+        npcinfo.remove_npc(near)?;
+        let replacement = npcinfo.add_npc(103, Coordinates::new(3201, 3201, 0))?;
+        assert_eq!(replacement, near);
+        assert_eq!(
+            decode(&npcinfo.process(player_id)?, NpcInfoVariant::Small)?,
+            [
+                Decoded::Removed,
+                Decoded::Added {
+                    index: near as u32,
+                    dx: 1,
+                    dy: 1,
+                    id: 103
+                }
+            ]
+        );
+
+        assert!(npcinfo.queue_npc_step(near, 2, 0).is_err());
+
+        Ok(())
+    }
+}
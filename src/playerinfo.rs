@@ -1,20 +1,52 @@
 //! PlayerInfo stuff
+use crate::config::{WorldInfoConfig, PROTOCOL_MAX_PLAYERS};
+use crate::coordinates::{Coordinates, WIRE_PLANES};
+use crate::decoder::{self, ClientRecord};
+#[cfg(feature = "alloc-metrics")]
+use crate::metrics::TickMetrics;
+use crate::revision::{self, DefaultRevision, Revision};
+use crate::snapshot::{ObserverSnapshot, PlayerInfoSnapshot, RecordSnapshot, SubjectSnapshot};
 use anyhow::{anyhow, Context, Result};
 use bitstream_io::{BigEndian, BitWrite, BitWriter};
 use osrs_buffer::WriteExt;
 use slab::Slab;
 use std::{
+    any::{Any, TypeId},
+    cell::OnceCell,
     cmp,
+    error::Error,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
     io::{Cursor, Write},
+    marker::PhantomData,
+    sync::Arc,
 };
 
-const MAX_PLAYERS: usize = 2047;
-const MAX_PLAYER_MASKS: usize = 15;
+/// The key of a player, the index of its records and updates
+pub type PlayerKey = usize;
+
+const MAX_PLAYERS: usize = PROTOCOL_MAX_PLAYERS;
 const MAX_MOVEMENT_STEPS: usize = 2;
+// The maximum amount of hitsplats, and of health bars, queued on a player in a single tick
+pub(crate) const MAX_HITSPLATS: usize = 4;
+const HEALTH_BAR_WIDTH: u16 = 30;
+const MAX_NAME_MODIFIER_LENGTH: usize = 80;
+pub(crate) const MAX_FORCED_CHAT_LENGTH: usize = 80;
+const FACE_ENTITY_NPC_OFFSET: u16 = 32768;
+// The offsets the client tells the items worn in an appearance slot apart from the identity kits by
+const APPEARANCE_ITEM_OFFSET: i16 = 0x200;
+const APPEARANCE_IDK_OFFSET: i16 = 0x100;
+const APPEARANCE_KIT_LIMIT: i16 = APPEARANCE_ITEM_OFFSET - APPEARANCE_IDK_OFFSET;
+// The markers in the head slot of a morphed player, after which the client stops reading the slots
+const APPEARANCE_NPC_MARKER: i16 = -1;
+const APPEARANCE_LOC_MARKER: i16 = -2;
+// The upper bound in bytes of the bits of an addition along with the skip following it
+const MAX_ADDITION_SIZE: usize = 9;
+/// The smallest packet size a player can join with, holding the skips closing both groups and a single addition
+pub const MIN_JOIN_PACKET_SIZE: usize = 3 * MAX_ADDITION_SIZE;
 
 const UPDATE_GROUP_ACTIVE: i32 = 0;
 const UPDATE_GROUP_INACTIVE: i32 = 1;
-const REBUILD_BOUNDARY: i32 = 16;
 
 const LOCAL_MOVEMENT_NONE: i32 = 0;
 const LOCAL_MOVEMENT_WALK: i32 = 1;
@@ -29,29 +61,118 @@ struct MovementUpdate {
 
 pub struct PlayerMasks {
     appearance_mask: Option<AppearanceMask>,
+    // The appearance mask as written to every observer, encoded once until the mask changes
+    appearance_encoding: OnceCell<Vec<u8>>,
     direction_mask: Option<DirectionMask>,
+    sequence_mask: Option<SequenceMask>,
+    chat_mask: Option<ChatMask>,
+    hit_mask: Option<HitMask>,
+    forced_movement_mask: Option<ForcedMovementMask>,
+    temporary_move_speed_mask: Option<TemporaryMoveSpeedMask>,
+    name_modifiers_mask: Option<NameModifiersMask>,
+    forced_chat_mask: Option<ForcedChatMask>,
+    face_entity_mask: Option<FaceEntityMask>,
+    face_coordinate_mask: Option<FaceCoordinateMask>,
+    #[cfg(feature = "tinting")]
+    tint_mask: Option<TintMask>,
+    // The values of the registered masks set on the player, keyed by their flag
+    custom_masks: Vec<(u32, Box<dyn Any + Send>)>,
+}
+
+/// Masks to set on a player at once using `PlayerInfo::apply_updates`
+#[derive(Default)]
+pub struct PlayerUpdates {
+    pub appearance: Option<AppearanceMask>,
+    pub direction: Option<DirectionMask>,
+    pub sequence: Option<SequenceMask>,
+    pub chat: Option<ChatMask>,
+    pub hit: Option<HitMask>,
+    pub forced_movement: Option<ForcedMovementMask>,
+    pub temporary_move_speed: Option<TemporaryMoveSpeedMask>,
+    pub name_modifiers: Option<NameModifiersMask>,
+    pub forced_chat: Option<ForcedChatMask>,
+    pub face_entity: Option<FaceEntityMask>,
+    pub face_coordinate: Option<FaceCoordinateMask>,
+    #[cfg(feature = "tinting")]
+    pub tint: Option<TintMask>,
+}
+
+impl PlayerUpdates {
+    fn validate(&self) -> Result<()> {
+        if let Some(appearance_mask) = &self.appearance {
+            validate_appearance_mask(appearance_mask)?;
+        }
+        if let Some(hit_mask) = &self.hit {
+            validate_hit_mask(hit_mask)?;
+        }
+        if let Some(forced_movement_mask) = &self.forced_movement {
+            validate_forced_movement_mask(forced_movement_mask)?;
+        }
+        if let Some(face_entity_mask) = &self.face_entity {
+            validate_face_entity_mask(face_entity_mask)?;
+        }
+        if let Some(face_coordinate_mask) = &self.face_coordinate {
+            validate_face_coordinate_mask(face_coordinate_mask)?;
+        }
+        #[cfg(feature = "tinting")]
+        if let Some(tint_mask) = &self.tint {
+            validate_tint_mask(tint_mask)?;
+        }
+        if let Some(forced_chat_mask) = &self.forced_chat {
+            validate_cp1252(&forced_chat_mask.text, MAX_FORCED_CHAT_LENGTH)?;
+        }
+        if let Some(name_modifiers_mask) = &self.name_modifiers {
+            validate_name_modifiers_mask(name_modifiers_mask)?;
+        }
+        if let Some(chat_mask) = &self.chat {
+            validate_chat_mask(chat_mask)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What a player is rendered as by the appearance mask
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AppearanceMorph {
+    /// Its equipment and body parts
+    #[default]
+    Player,
+    /// An NPC, e.g. when transformed by a ring
+    Npc(u16),
+    /// A loc, e.g. when hiding as a bush in a minigame
+    Loc(u16),
 }
 
 /// The appearance mask of the player
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AppearanceMask {
     pub gender: i8,
     pub skull: bool,
     pub overhead_prayer: i8,
-    //pub npc: i32,
+    /// What the player is rendered as, where its equipment and body parts are only written for `AppearanceMorph::Player`
+    pub morph: AppearanceMorph,
     //pub looks: PlayerLooks,
+    /// The items worn in the equipment slots, or 0 for none
     pub head: i16,
     pub cape: i16,
     pub neck: i16,
     pub weapon: i16,
+    /// The item worn on the body, or 0 to show the torso identity kit
     pub body: i16,
     pub shield: i16,
+    /// The identity kits of the body parts, or -1 for none
+    pub torso: i16,
     pub arms: i16,
+    /// Whether the body item hides the arms
     pub is_full_body: bool,
     pub legs: i16,
     pub hair: i16,
+    /// Whether the head item hides the hair
     pub covers_hair: bool,
     pub hands: i16,
     pub feet: i16,
+    /// Whether the head item hides the beard
     pub covers_face: bool,
     pub beard: i16,
     pub colors_hair: i8,
@@ -72,873 +193,6652 @@ pub struct AppearanceMask {
     pub hidden: i8,
 }
 
-/// The direction mask of the player
-pub struct DirectionMask {
-    pub direction: i16,
+/// The equipment slots of the appearance mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EquipmentSlot {
+    Head,
+    Cape,
+    Neck,
+    Weapon,
+    Body,
+    Shield,
 }
 
-pub struct PlayerUpdate {
-    masks: PlayerMasks,
-    mask_flags: u32,
-    movement_steps: Vec<(i32, i32)>,
-    displaced: bool,
-    movement_update: MovementUpdate,
+/// The body parts of the appearance mask, each shown as an identity kit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyPart {
+    Torso,
+    Arms,
+    Legs,
+    Hair,
+    Hands,
+    Feet,
+    Beard,
 }
 
-/// Contains the data of the PlayerInfo entry
-pub struct PlayerInfoData {
-    // START RSMOD IMPL
-    flags: i32,
-    local: bool,
-    coordinates: i32,
-    reset: bool,
-    // END RSMOD IMPL
-
-    // The rest below here are custom, and might need to be revised in terms of correct structure
-    local_to_global: bool,
-    global_to_local: bool,
+// The identity kits new characters start with, in the order of `BodyPart`
+const DEFAULT_MALE_KITS: [i16; 7] = [18, 26, 36, 0, 33, 42, 10];
+const DEFAULT_FEMALE_KITS: [i16; 7] = [56, 61, 70, 45, 67, 79, -1];
+// The stances of an unarmed player: stand, turn, walk, turn 180, turn 90 clockwise, turn 90 counter-clockwise and run
+const DEFAULT_STANCES: [i16; 7] = [808, 823, 819, 820, 821, 822, 824];
+
+impl AppearanceMask {
+    /// Build an appearance mask starting from an unarmed male character with the default kits and colours,
+    /// no skull and no overhead prayer
+    pub fn builder() -> AppearanceMaskBuilder {
+        AppearanceMaskBuilder {
+            mask: AppearanceMask {
+                gender: 0,
+                skull: false,
+                overhead_prayer: -1,
+                morph: AppearanceMorph::Player,
+                head: 0,
+                cape: 0,
+                neck: 0,
+                weapon: 0,
+                body: 0,
+                shield: 0,
+                torso: 0,
+                arms: 0,
+                is_full_body: false,
+                legs: 0,
+                hair: 0,
+                covers_hair: false,
+                hands: 0,
+                feet: 0,
+                covers_face: false,
+                beard: 0,
+                colors_hair: 0,
+                colors_torso: 0,
+                colors_legs: 0,
+                colors_feet: 0,
+                colors_skin: 0,
+                weapon_stance_stand: 0,
+                weapon_stance_turn: 0,
+                weapon_stance_walk: 0,
+                weapon_stance_turn180: 0,
+                weapon_stance_turn90cw: 0,
+                weapon_stance_turn90ccw: 0,
+                weapon_stance_run: 0,
+                username: String::new(),
+                combat_level: 3,
+                skill_id_level: 0,
+                hidden: 0,
+            },
+        }
+        .with_kits(DEFAULT_MALE_KITS)
+        .with_stances(DEFAULT_STANCES)
+    }
 }
 
-/// The PlayerInfo containing information about all players and their associated masks
-pub struct PlayerInfo {
-    // A many-to-many mapping from a player to all other players.
-    // This means a player with id 0 will store data of player
-    // 0, 1, 2, 3, ... 2047
-    playerinfos: Slab<Slab<PlayerInfoData>>,
-    // TODO: Use this field here for playermasks (or potentially just PlayerUpdates) as it will not have issues with the borrow checker
-    playerupdates: Slab<PlayerUpdate>,
+/// Builds an `AppearanceMask`, see `AppearanceMask::builder`
+pub struct AppearanceMaskBuilder {
+    mask: AppearanceMask,
 }
 
-fn get_local_skip_count(
-    playerinfos: &Slab<Slab<PlayerInfoData>>,
-    update_group: i32,
-    player_id: usize,
-    offset: usize,
-) -> Result<i32> {
-    let mut count = 0;
-
-    for i in offset..MAX_PLAYERS {
-        // Grab the playerinfo
-        let playerinfoentryother = playerinfos
-            .get(player_id)
-            .context("failed 1")?
-            .get(i)
-            .context("failed 2")?;
-
-        // Return if the playerinfo is not in this group
-        if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
-            continue;
-        }
-
-        // Break if a player needs to be updated
-        let is_update_required = true;
-        if is_update_required {
-            break;
+impl AppearanceMaskBuilder {
+    /// Set the gender, which also switches to the default kits of the gender
+    pub fn with_gender(mut self, gender: i8) -> AppearanceMaskBuilder {
+        self.mask.gender = gender;
+        if gender == 0 {
+            self.with_kits(DEFAULT_MALE_KITS)
+        } else {
+            self.with_kits(DEFAULT_FEMALE_KITS)
         }
+    }
 
-        // Increment the skip count by 1
-        count += 1;
+    pub fn with_username(mut self, username: impl Into<String>) -> AppearanceMaskBuilder {
+        self.mask.username = username.into();
+        self
     }
 
-    Ok(count)
-}
+    pub fn with_combat_level(mut self, combat_level: i8) -> AppearanceMaskBuilder {
+        self.mask.combat_level = combat_level;
+        self
+    }
 
-impl Default for PlayerInfo {
-    fn default() -> Self {
-        Self::new()
+    pub fn with_skull(mut self, skull: bool) -> AppearanceMaskBuilder {
+        self.mask.skull = skull;
+        self
     }
-}
 
-impl PlayerInfo {
-    /// Create a new PlayerInfo
-    pub fn new() -> PlayerInfo {
-        PlayerInfo {
-            playerinfos: Slab::new(),
-            playerupdates: Slab::new(),
-        }
+    /// Set the overhead prayer icon, -1 for none
+    pub fn with_overhead_prayer(mut self, overhead_prayer: i8) -> AppearanceMaskBuilder {
+        self.mask.overhead_prayer = overhead_prayer;
+        self
     }
 
-    // TODO: Return the coordinates of all global players in this function, as to aid with the InterestInit packet
-    /// Add a new player to the PlayerInfo
-    pub fn add_player(&mut self, coordinates: i32) -> Result<()> {
-        // Get the playerinfo id using a vacant key, check for exceeding limit
-        let playerinfo_id = self.playerinfos.vacant_key();
-        if playerinfo_id > MAX_PLAYERS {
-            return Err(anyhow!(
-                "Maximum amount of players processable by PlayerInfo reached"
-            ));
-        }
+    pub fn with_morph(mut self, morph: AppearanceMorph) -> AppearanceMaskBuilder {
+        self.mask.morph = morph;
+        self
+    }
 
-        // Create a new playerinfo entry
-        let mut playerinfoentry = Slab::new();
+    /// Wear the item in the equipment slot, 0 for none
+    pub fn with_item(mut self, slot: EquipmentSlot, item: i16) -> AppearanceMaskBuilder {
+        let field = match slot {
+            EquipmentSlot::Head => &mut self.mask.head,
+            EquipmentSlot::Cape => &mut self.mask.cape,
+            EquipmentSlot::Neck => &mut self.mask.neck,
+            EquipmentSlot::Weapon => &mut self.mask.weapon,
+            EquipmentSlot::Body => &mut self.mask.body,
+            EquipmentSlot::Shield => &mut self.mask.shield,
+        };
+        *field = item;
+        self
+    }
 
-        // Generate the playerinfo data for the given player
-        for playerinfo in 0..MAX_PLAYERS {
-            if playerinfo_id == playerinfo {
-                add_playerinfodata(&mut playerinfoentry, true, coordinates)
-                    .expect("failed adding update record for local player");
-            }
-            add_playerinfodata(&mut playerinfoentry, false, 0)
-                .expect("failed adding update record for external player");
-        }
+    /// Show the identity kit on the body part
+    pub fn with_kit(mut self, part: BodyPart, kit: i16) -> AppearanceMaskBuilder {
+        let field = match part {
+            BodyPart::Torso => &mut self.mask.torso,
+            BodyPart::Arms => &mut self.mask.arms,
+            BodyPart::Legs => &mut self.mask.legs,
+            BodyPart::Hair => &mut self.mask.hair,
+            BodyPart::Hands => &mut self.mask.hands,
+            BodyPart::Feet => &mut self.mask.feet,
+            BodyPart::Beard => &mut self.mask.beard,
+        };
+        *field = kit;
+        self
+    }
 
-        // Insert the PlayerInfoEntry
-        self.playerinfos.insert(playerinfoentry);
-        self.playerupdates.insert(PlayerUpdate {
-            movement_steps: Vec::with_capacity(MAX_MOVEMENT_STEPS),
-            displaced: false,
-            movement_update: MovementUpdate { x: 0, y: 0, z: 0 },
-            mask_flags: 0,
-            masks: PlayerMasks {
-                appearance_mask: None,
-                direction_mask: None,
-            },
-        });
+    /// Show the identity kits on every body part, in the order of `BodyPart`
+    pub fn with_kits(self, kits: [i16; 7]) -> AppearanceMaskBuilder {
+        let parts = [
+            BodyPart::Torso,
+            BodyPart::Arms,
+            BodyPart::Legs,
+            BodyPart::Hair,
+            BodyPart::Hands,
+            BodyPart::Feet,
+            BodyPart::Beard,
+        ];
+        parts
+            .into_iter()
+            .zip(kits)
+            .fold(self, |builder, (part, kit)| builder.with_kit(part, kit))
+    }
 
-        Ok(())
+    /// Set the colours of the hair, torso, legs, feet and skin
+    pub fn with_colors(mut self, colors: [i8; 5]) -> AppearanceMaskBuilder {
+        [
+            self.mask.colors_hair,
+            self.mask.colors_torso,
+            self.mask.colors_legs,
+            self.mask.colors_feet,
+            self.mask.colors_skin,
+        ] = colors;
+        self
     }
 
-    /// Get the masks on the player. Useful for checking if a mask is already set
-    pub fn get_player_masks(&mut self, key: usize) -> Result<&PlayerMasks> {
-        let player_update = self
-            .playerupdates
-            .get_mut(key)
-            .context("failed getting playermask vec")?;
+    /// Set the stances of the weapon: stand, turn, walk, turn 180, turn 90 clockwise, turn 90 counter-clockwise and run
+    pub fn with_stances(mut self, stances: [i16; 7]) -> AppearanceMaskBuilder {
+        [
+            self.mask.weapon_stance_stand,
+            self.mask.weapon_stance_turn,
+            self.mask.weapon_stance_walk,
+            self.mask.weapon_stance_turn180,
+            self.mask.weapon_stance_turn90cw,
+            self.mask.weapon_stance_turn90ccw,
+            self.mask.weapon_stance_run,
+        ] = stances;
+        self
+    }
 
-        Ok(&player_update.masks)
+    pub fn build(self) -> AppearanceMask {
+        self.mask
     }
+}
 
-    pub fn add_player_appearance_mask(
-        &mut self,
-        player_id: usize,
-        appearance_mask: AppearanceMask,
-    ) -> Result<()> {
-        let player_update = self
-            .playerupdates
-            .get_mut(player_id)
-            .context("failed getting player")?;
+/// The direction mask of the player
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectionMask {
+    pub direction: i16,
+}
 
-        player_update.masks.appearance_mask = Some(appearance_mask);
-        player_update.mask_flags |= APPEARANCE_MASK;
+/// The sequence (animation) mask of the player
+pub struct SequenceMask {
+    /// The id of the sequence, -1 stops the current sequence
+    pub id: i16,
+    /// The delay in client cycles before the sequence starts
+    pub delay: u8,
+    /// The priority from the sequence type, a pending sequence is only replaced by one of at least the same priority
+    pub priority: u8,
+}
 
-        Ok(())
+/// The public chat mask of the player
+pub struct ChatMask {
+    /// The message, compressed using the huffman table from the cache as the client expects it
+    pub text: Vec<u8>,
+    pub colour: u8,
+    pub effect: u8,
+    /// The icon shown in front of the name, e.g. for moderators
+    pub rights_icon: u8,
+    /// Whether the message was sent by the autotyper
+    pub autotyper: bool,
+}
+
+/// The hit mask of the player, the hitsplats and health bars shown on the player during the tick.
+/// Setting it again within the same tick adds to the pending hitsplats and health bars
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HitMask {
+    pub hitsplats: Vec<Hitsplat>,
+    pub health_bars: Vec<HealthBar>,
+}
+
+impl HitMask {
+    /// A single hitsplat along with the health bar of the player
+    pub fn single(hitsplat: Hitsplat, health_bar: HealthBar) -> HitMask {
+        HitMask {
+            hitsplats: vec![hitsplat],
+            health_bars: vec![health_bar],
+        }
     }
+}
 
-    pub fn add_player_direction_mask(
-        &mut self,
-        player_id: usize,
-        direction_mask: DirectionMask,
-    ) -> Result<()> {
-        let player_update = self
-            .playerupdates
-            .get_mut(player_id)
-            .context("failed getting player")?;
+/// A hitsplat shown on the player
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hitsplat {
+    /// The type of the hitsplat, e.g. a regular hit or a block
+    pub hitsplat_type: u16,
+    pub damage: u16,
+    /// The delay in client cycles before the hitsplat is shown
+    pub delay: u16,
+    /// The player that dealt the hit
+    pub source: Option<PlayerKey>,
+    /// The type shown to the source instead, e.g. to tell its own hits apart from those of others
+    pub source_type: Option<u16>,
+    /// The type shown to the player taking the hit instead
+    pub victim_type: Option<u16>,
+}
 
-        player_update.masks.direction_mask = Some(direction_mask);
-        player_update.mask_flags |= DIRECTION_MASK;
+impl Hitsplat {
+    /// Get the type of the hitsplat as shown to the observer, when shown on the subject
+    fn type_for(&self, observer: PlayerKey, subject: PlayerKey) -> u16 {
+        let variant = if self.source == Some(observer) {
+            self.source_type
+        } else if observer == subject {
+            self.victim_type
+        } else {
+            None
+        };
 
-        Ok(())
+        variant.unwrap_or(self.hitsplat_type)
     }
 
-    /// TODO: Consider remove
-    pub fn get_player(&mut self, key: usize) -> Option<&Slab<PlayerInfoData>> {
-        self.playerinfos.get(key)
+    /// Whether the hitsplat is shown differently to some observers
+    fn has_variants(&self) -> bool {
+        self.source_type.is_some() || self.victim_type.is_some()
     }
+}
 
-    /// TODO: Consider remove
-    pub fn get_player_mut(&mut self, key: usize) -> Option<&mut Slab<PlayerInfoData>> {
-        self.playerinfos.get_mut(key)
-    }
+/// A health bar shown above the player, a later health bar of the same type within the tick replaces it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthBar {
+    /// The type of the health bar
+    pub health_bar: u16,
+    /// The remaining health of the player, from 0 to 100
+    pub health_percentage: u8,
+    /// The delay in client cycles before the health bar is updated
+    pub delay: u16,
+}
 
-    /// Remove a player from the PlayerInfo
-    pub fn remove_player(&mut self, key: usize) -> Result<()> {
-        self.playerinfos.remove(key);
-        self.playerupdates.remove(key);
+/// The forced movement mask of the player, moving it between two tiles at an exact pace, e.g. across agility obstacles.
+/// Tiles are relative to the position of the player
+pub struct ForcedMovementMask {
+    pub start_dx: i8,
+    pub start_dy: i8,
+    pub end_dx: i8,
+    pub end_dy: i8,
+    /// The client cycles after which the player arrives at the start tile
+    pub start_delay: u16,
+    /// The client cycles after which the player arrives at the end tile, after the start delay
+    pub end_delay: u16,
+    /// The angle the player faces while moving
+    pub direction: u16,
+}
 
-        Ok(())
-    }
+/// An entity a player can be locked to face
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaceEntity {
+    Player(PlayerKey),
+    Npc(u16),
+    /// Releases the lock
+    None,
+}
 
-    /// Process a player contained in the PlayerInfo, returning a buffer with data about all the updates for the specified player,
-    /// to be sent
-    pub fn process(&mut self, player_id: usize) -> Result<Vec<u8>> {
-        // TODO: Remove this, do proper checking instead in the local_player_info and global_player_info places, simply return if the player id does not exist
-        if self.playerinfos.get(player_id).is_none() {
-            return Ok(Vec::new());
+impl FaceEntity {
+    /// Get the index the client expects, npcs are offset by 32768 and no entity is 65535
+    pub(crate) fn index(self) -> u16 {
+        match self {
+            FaceEntity::Player(player_id) => player_id as u16,
+            FaceEntity::Npc(npc_id) => npc_id + FACE_ENTITY_NPC_OFFSET,
+            FaceEntity::None => u16::MAX,
         }
+    }
+}
 
-        let mut main_buf = BitWriter::endian(Vec::new(), BigEndian);
-        // Supply the mask buffer instead, as to prevent this big ass allocation
-        let mut mask_buf = Cursor::new(vec![0; 60000]);
+/// The face entity (lock turn-to) mask of the player, turning it towards an entity until released
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaceEntityMask {
+    pub entity: FaceEntity,
+}
 
-        // Write local player data (players around the player)
-        self.local_player_info(player_id, &mut main_buf, &mut mask_buf, UPDATE_GROUP_ACTIVE)?;
-        main_buf.byte_align()?;
+/// The face coordinate mask of the player, turning it towards the centre of a tile.
+/// It supersedes the direction mask, so only the facing set last is replayed to observers the player is added to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaceCoordinateMask {
+    pub x: i32,
+    pub y: i32,
+}
 
-        self.local_player_info(
-            player_id,
-            &mut main_buf,
-            &mut mask_buf,
-            UPDATE_GROUP_INACTIVE,
-        )?;
-        main_buf.byte_align()?;
+/// The tint mask of the player, recolouring its model between the start and end delay in client cycles,
+/// e.g. while charging the totems of the Nightmare. Only known to newer revisions
+#[cfg(feature = "tinting")]
+pub struct TintMask {
+    pub start_delay: u16,
+    pub end_delay: u16,
+    pub hue: u8,
+    pub saturation: u8,
+    pub lightness: u8,
+    pub opacity: u8,
+}
 
-        // Write global player data (players that the player cannot see)
-        self.global_player_info(
-            player_id,
-            &mut main_buf,
-            &mut mask_buf,
-            UPDATE_GROUP_INACTIVE,
-        )?;
-        main_buf.byte_align()?;
+/// The forced chat (shout) mask of the player, text shown above its head without appearing in the chatbox.
+/// The text is written as a CP1252 string and thus has to be ASCII, without nul characters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForcedChatMask {
+    pub text: String,
+}
 
-        self.global_player_info(player_id, &mut main_buf, &mut mask_buf, UPDATE_GROUP_ACTIVE)?;
-        main_buf.byte_align()?;
+/// The name modifiers mask of the player, text shown around its name when right-clicked, e.g. titles and clan decorations.
+/// Modifiers are written as CP1252 strings and thus have to be ASCII, without nul characters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameModifiersMask {
+    /// Shown before the name
+    pub prefix: String,
+    /// Shown between the name and the combat level
+    pub infix: String,
+    /// Shown after the combat level
+    pub suffix: String,
+}
 
-        // Convert the main_buf into a writer
-        let mut vec = main_buf.into_writer();
+/// The temporary movement speed mask of the player, overriding the speed it moves at for this tick only,
+/// e.g. to run a single tick while walking
+pub struct TemporaryMoveSpeedMask {
+    pub move_speed: MoveSpeed,
+}
 
-        // Write the mask_buf's data
-        vec.write_all(&mask_buf.get_ref()[..mask_buf.position() as usize])?;
+/// The speed at which the client moves a player, kept by the client until it is changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MoveSpeed {
+    /// Half a tile per tick, e.g. when the player is heavily burdened
+    Crawl = 0,
+    /// A tile per tick, which the client assumes for players it has not been told about
+    #[default]
+    Walk = 1,
+    /// Two tiles per tick
+    Run = 2,
+}
 
-        // Group the records
-        for i in 0..MAX_PLAYERS {
-            self.group(player_id, i).ok();
+/// How a mask that is set multiple times within the same tick is combined with the pending one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskCoalescing {
+    /// The most recently set value wins, e.g. sequences and directions
+    LastWins,
+    /// The pending block is replaced as a whole, as it describes the full state of the player, e.g. appearance
+    Replace,
+    /// Values are queued up to the given limit, anything past the limit is dropped, e.g. hitsplats
+    Append(usize),
+}
+
+impl MaskCoalescing {
+    /// Combine a newly set mask with the one pending on the player, returning whether it was stored
+    fn coalesce<M: Mask>(self, pending: &mut Option<M>, mask: M) -> bool {
+        match self {
+            MaskCoalescing::LastWins | MaskCoalescing::Replace => {
+                *pending = Some(mask);
+                true
+            }
+            MaskCoalescing::Append(limit) => match pending {
+                Some(pending) => pending.append(mask, limit),
+                None if limit > 0 => {
+                    *pending = Some(mask);
+                    true
+                }
+                None => false,
+            },
         }
+    }
+}
 
-        // Return the bit buffer including the mask buffer
-        Ok(vec)
+/// Get the coalescing rule the protocol expects for the given mask
+pub const fn mask_coalescing(mask: u32) -> MaskCoalescing {
+    match mask {
+        APPEARANCE_MASK | NAME_MODIFIERS_MASK => MaskCoalescing::Replace,
+        HIT_MASK => MaskCoalescing::Append(MAX_HITSPLATS),
+        _ => MaskCoalescing::LastWins,
     }
+}
 
-    fn local_player_info(
-        &mut self,
-        player_id: usize,
-        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-        mask_buf: &mut Cursor<Vec<u8>>,
-        update_group: i32,
-    ) -> Result<()> {
-        let mut skip_count = 0;
+mod sealed {
+    /// Implemented by the masks of the crate only, whose slots live on the private fields of `PlayerMasks`
+    pub trait Sealed {}
+}
 
-        for current_player_id in 0..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(current_player_id)
-                .context("failed 2")?;
+/// A mask that can be set on a player using `PlayerInfo::set_mask`.
+/// The trait is sealed, masks defined outside of the crate are registered using `PlayerInfo::register_mask` instead
+pub trait Mask: sealed::Sealed + Sized {
+    /// The bit of the mask in the mask flags
+    const FLAG: u32;
+    /// How the mask is combined with a pending one
+    const COALESCING: MaskCoalescing = mask_coalescing(Self::FLAG);
+    /// Whether the mask is kept after being written, so it can be replayed to observers that did not see it
+    const REPLAY: bool = is_persistent_mask(Self::FLAG);
+
+    /// Whether the mask may replace the pending one when it is not forced
+    fn replaces(&self, _pending: &Self) -> bool {
+        true
+    }
 
-            // Test whether the playerinfo is local, and whether it is in the correct update group (active, inactive)
-            if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
-                continue;
-            }
+    /// Queue the values of the mask after the pending one up to the limit, returning whether any were stored.
+    /// Masks holding a single value cannot queue anything
+    fn append(&mut self, _mask: Self, _limit: usize) -> bool {
+        false
+    }
 
-            // Check whether entries should be skipped
-            if skip_count > 0 {
-                skip_count -= 1;
-                playerinfoentryother.flags |= 0x2;
-                continue;
-            }
+    /// Write the mask to the mask buffer
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()>;
 
-            // Get the player updates
-            let player_updates = self
-                .playerupdates
-                .get_mut(current_player_id)
-                .context("testy boi")?;
+    /// Get the slot holding the mask on a player
+    fn slot(masks: &PlayerMasks) -> &Option<Self>;
 
-            // Get whether there is mask or movement updates
-            let mask_update = player_updates.mask_flags > 0;
-            let movement_update =
-                !player_updates.movement_steps.is_empty() || player_updates.displaced;
+    /// Get the slot holding the mask on a player mutably
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self>;
+}
 
-            // Check whether a player update is needed
-            // If the player is to be removed, or it has a mask update, or it has a movement update, the first bit is set to true
-            // (player update in this context)
-            let player_update =
-                playerinfoentryother.local_to_global || mask_update || movement_update;
+/// A mask defined outside of the crate, registered on a flag bit using `PlayerInfo::register_mask`.
+/// Registered masks are written after the masks of the crate, in the order they were registered
+pub trait MaskEncoder: Send + 'static {
+    /// The value set on a player using `PlayerInfo::set_custom_mask`
+    type Value: Send + 'static;
 
-            // Write the player update bool to signify whether a player needs to be updated or not
-            bit_buf.write_bit(player_update)?;
+    /// Write the value of the mask to the mask buffer
+    fn encode(&self, value: &Self::Value, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()>;
+}
 
-            // Check if a player update is needed, else write the skip count
-            if player_update {
-                // Check whether the local player should be removed and turned into a global player
-                if playerinfoentryother.local_to_global {
-                    playerinfoentryother.reset = true;
-                    remove_local_player(bit_buf, playerinfoentryother, mask_update)?;
-                // Else write a movement update
-                } else if movement_update {
-                    write_local_movement(bit_buf, player_updates, mask_update)
-                        .expect("failed writing local movement");
-                // Else write to the bitbuffer that it should read masks
-                } else {
-                    write_mask_update_signal(bit_buf).expect("failed writing mask update signal");
-                }
-            } else {
-                playerinfoentryother.flags |= 0x2;
-                skip_count = get_local_skip_count(
-                    &self.playerinfos,
-                    update_group,
-                    player_id,
-                    current_player_id + 1,
-                )?;
-                write_skip_count(bit_buf, skip_count, player_update).ok();
-            }
+/// A mask encoder writing values using a closure, registered using `PlayerInfo::register_mask_writer`
+pub struct MaskWriter<T, F> {
+    writer: F,
+    value: PhantomData<fn(&T)>,
+}
 
-            // TODO: Move writing of masks to its own step.
-            // This is only here because the borrow checker errors on "get_local_skip_count" as the PlayerInfo struct is borrowed when that function is called
-            // Ideally this step should be after this whole block, so after write_skip_count.
-            if mask_update {
-                write_mask_update(mask_buf, player_updates)?;
-            }
-        }
+impl<T, F> MaskEncoder for MaskWriter<T, F>
+where
+    T: Send + 'static,
+    F: Fn(&T, &mut Cursor<Vec<u8>>) -> Result<()> + Send + 'static,
+{
+    type Value = T;
 
-        Ok(())
+    fn encode(&self, value: &T, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        (self.writer)(value, mask_buf)
     }
+}
 
-    fn write_local_bit_data(&mut self) {}
+// A registered mask encoder with its value type erased, so encoders of different values can be stored together
+trait CustomMaskEncoder: Send {
+    fn value_type(&self) -> TypeId;
 
-    fn get_global_skip_count(
-        &mut self,
-        update_group: i32,
-        player_id: usize,
-        offset: usize,
-    ) -> Result<i32> {
-        let mut count = 0;
+    fn encode_any(&self, value: &dyn Any, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()>;
+}
 
-        for i in offset..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(i)
-                .context("failed 2")?;
+impl<E: MaskEncoder> CustomMaskEncoder for E {
+    fn value_type(&self) -> TypeId {
+        TypeId::of::<E::Value>()
+    }
 
-            // Return if the playerinfo is not in this group
-            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
-                continue;
-            }
+    fn encode_any(&self, value: &dyn Any, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        let value = value
+            .downcast_ref::<E::Value>()
+            .context("custom mask value has the wrong type")?;
+        self.encode(value, mask_buf)
+    }
+}
 
-            // Check here if a player needs to be added, aka they are within view distance. Simply pass over a mask
+struct CustomMask {
+    flag: u32,
+    encoder: Box<dyn CustomMaskEncoder>,
+}
 
-            // Increment the skip count by 1
-            count += 1;
-        }
+/// How an item is rendered when worn, as defined by the item definitions of the cache.
+/// The client reads the worn model of the item from its own definitions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WornItem {
+    /// Whether a body item hides the arms
+    pub is_full_body: bool,
+    /// Whether a head item hides the hair
+    pub covers_hair: bool,
+    /// Whether a head item hides the beard
+    pub covers_face: bool,
+}
 
-        Ok(count)
+/// Looks up how the items of the appearance mask are worn, e.g. backed by the item definitions of the cache of a server.
+/// Items it has no worn model for are encoded as empty slots
+pub trait EquipmentProvider: Send {
+    fn worn_item(&self, item: i16) -> Option<WornItem>;
+}
+
+impl<F: Fn(i16) -> Option<WornItem> + Send> EquipmentProvider for F {
+    fn worn_item(&self, item: i16) -> Option<WornItem> {
+        self(item)
     }
+}
 
-    fn group(&mut self, player_id: usize, index: usize) -> Result<()> {
-        // Get the playerinfo
-        let playerinfoentryother = self
-            .playerinfos
-            .get_mut(player_id)
-            .context("failed getting playerinfoentry")?
-            .get_mut(index)
-            .context("failed playerinfoother")?;
+// The encoders consulted while writing the masks of a player, besides the encoders of the crate
+#[derive(Clone, Copy)]
+struct MaskEncoders<'a> {
+    custom_masks: &'a [CustomMask],
+    equipment: Option<&'a dyn EquipmentProvider>,
+}
 
-        // Shift its flags
-        playerinfoentryother.flags >>= 1;
+impl sealed::Sealed for AppearanceMask {}
 
-        // Check whether the playerinfoentry should be reset
-        if playerinfoentryother.reset {
-            playerinfoentryother.flags = 0;
-            playerinfoentryother.coordinates = 0;
-            playerinfoentryother.local = false;
-            playerinfoentryother.reset = false;
-            playerinfoentryother.local_to_global = false;
-            playerinfoentryother.global_to_local = false;
-        }
+impl Mask for AppearanceMask {
+    const FLAG: u32 = APPEARANCE_MASK;
 
-        Ok(())
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_appearance_mask(self, None, mask_buf)
     }
 
-    fn global_player_info(
-        &mut self,
-        player_id: usize,
-        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-        mask_buf: &mut Cursor<Vec<u8>>,
-        update_group: i32,
-    ) -> Result<i32> {
-        let mut skip_count = 0;
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.appearance_mask
+    }
 
-        for other_player_id in 0..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(other_player_id)
-                .context("failed 2")?;
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        // Any change of the mask is encoded anew
+        masks.appearance_encoding.take();
+        &mut masks.appearance_mask
+    }
+}
 
-            // Test whether the playerinfo is global, and whether it is in the correct update group (active, inactive)
-            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
-                continue;
-            }
+impl sealed::Sealed for DirectionMask {}
 
-            // Check whether entries should be skipped
-            if skip_count > 0 {
-                skip_count -= 1;
-                playerinfoentryother.flags |= 0x2;
-                continue;
-            }
+impl Mask for DirectionMask {
+    const FLAG: u32 = DIRECTION_MASK;
 
-            let player_update = false;
-            bit_buf.write_bit(player_update)?;
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_direction_mask(self, mask_buf)
+    }
 
-            // Check whether a global player should be made local
-            if playerinfoentryother.global_to_local {}
-
-            // TODO: Make some Option type here for that a player should be added
-            /*if world.players.get(i).is_some() {
-                let capacity_reached = added + previously_added >= max_player_additions_per_cycle
-                    || local_count >= max_local_players;
-
-                if player_can_view_other_player(world, player_id, i) && !capacity_reached {
-                    write_player_addition(bit_buf, world, player_id, i).unwrap();
-                    write_new_player_masks(mask_buf, world, i);
-                    *get_update_record_flags(world, player_id, i) |= 0x2;
-
-                    // Set local to true
-                    *world
-                        .players
-                        .get_mut(player_id)
-                        .unwrap()
-                        .update_record_local
-                        .get_mut(i)
-                        .unwrap() = true;
-
-                    // Set the coordinate to the player's coordinate
-                    *world
-                        .players
-                        .get_mut(player_id)
-                        .unwrap()
-                        .update_record_coordinates
-                        .get_mut(i)
-                        .unwrap() = world
-                        .players
-                        .get(i)
-                        .unwrap()
-                        .coordinates
-                        .get_packed_18_bits();
-
-                    // need it as packed 18 bits here instead of just .coords. consider making function: get_coords_as_18_bit(coords);
-                    added += 1;
-                }
-                continue;
-            }*/
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.direction_mask
+    }
 
-            playerinfoentryother.flags |= 0x2;
-            skip_count =
-                self.get_global_skip_count(update_group, player_id, other_player_id + 1)?;
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.direction_mask
+    }
+}
 
-            write_skip_count(bit_buf, skip_count, false).ok();
-        }
+impl sealed::Sealed for ChatMask {}
 
-        Ok(0)
+impl Mask for ChatMask {
+    const FLAG: u32 = CHAT_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_chat_mask(self, mask_buf)
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.chat_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.chat_mask
     }
 }
 
-fn write_skip_count(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    skip_count: i32,
-    player_update: bool,
-) -> Result<()> {
-    if skip_count == 0 {
-        bit_buf.write(2, skip_count as u32)?;
-    } else if skip_count < 32 {
-        bit_buf.write(2, 1)?;
-        bit_buf.write(5, skip_count as u32)?;
-    } else if skip_count < 256 {
-        bit_buf.write(2, 2)?;
-        bit_buf.write(8, skip_count as u32)?;
-    } else {
-        if skip_count > MAX_PLAYERS as i32 {
-            return Err(anyhow!("Skip count out of range error"));
+impl sealed::Sealed for HitMask {}
+
+impl Mask for HitMask {
+    const FLAG: u32 = HIT_MASK;
+
+    fn append(&mut self, mask: Self, limit: usize) -> bool {
+        let mut applied = false;
+        for hitsplat in mask.hitsplats {
+            if self.hitsplats.len() < limit {
+                self.hitsplats.push(hitsplat);
+                applied = true;
+            }
         }
-        bit_buf.write(2, 3)?;
-        bit_buf.write(11, cmp::min(MAX_PLAYERS, skip_count as usize) as u32)?;
+        for health_bar in mask.health_bars {
+            match self
+                .health_bars
+                .iter()
+                .position(|pending| pending.health_bar == health_bar.health_bar)
+            {
+                Some(index) => self.health_bars[index] = health_bar,
+                None if self.health_bars.len() < limit => self.health_bars.push(health_bar),
+                None => continue,
+            }
+            applied = true;
+        }
+
+        applied
     }
 
-    Ok(())
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_hit_mask(self, None, mask_buf)
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.hit_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.hit_mask
+    }
 }
 
-fn add_playerinfodata(
-    playerinfo: &mut Slab<PlayerInfoData>,
-    local: bool,
-    coordinates: i32,
-) -> Result<()> {
-    playerinfo.insert(PlayerInfoData {
-        flags: 0,
-        local,
-        coordinates,
-        reset: false,
-        local_to_global: false,
-        global_to_local: false,
-    });
+impl sealed::Sealed for ForcedMovementMask {}
 
-    Ok(())
+impl Mask for ForcedMovementMask {
+    const FLAG: u32 = MOVEMENT_FORCED_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_forced_movement_mask(self, mask_buf)
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.forced_movement_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.forced_movement_mask
+    }
 }
 
-// The masks and their associated bit values
-const MOVEMENT_FORCED_MASK: u32 = 0x200;
-const SPOT_ANIMATION_MASK: u32 = 0x800;
-const SEQUENCE_MASK: u32 = 0x80;
-const APPEARANCE_MASK: u32 = 0x2;
-const SHOUT_MASK: u32 = 0x20;
-const LOCK_TURNTO_MASK: u32 = 0x4;
-const MOVEMENT_CACHED_MASK: u32 = 0x1000;
-const CHAT_MASK: u32 = 0x1;
-const NAME_MODIFIERS_MASK: u32 = 0x100;
-const HIT_MASK: u32 = 0x10;
-const MOVEMENT_TEMPORARY_MASK: u32 = 0x400;
-const DIRECTION_MASK: u32 = 0x8;
-
-// The masks in which order they should be written out
-const MASKS: [u32; 12] = [
-    MOVEMENT_FORCED_MASK,
-    SPOT_ANIMATION_MASK,
-    SEQUENCE_MASK,
-    APPEARANCE_MASK,
-    SHOUT_MASK,
-    LOCK_TURNTO_MASK,
-    MOVEMENT_CACHED_MASK,
-    CHAT_MASK,
-    NAME_MODIFIERS_MASK,
-    HIT_MASK,
-    MOVEMENT_TEMPORARY_MASK,
-    DIRECTION_MASK,
-];
+impl sealed::Sealed for FaceEntityMask {}
 
-fn write_mask_update(mask_buf: &mut Cursor<Vec<u8>>, playerinfo: &mut PlayerUpdate) -> Result<()> {
-    if playerinfo.mask_flags >= 0xFF {
-        mask_buf.write_i8((playerinfo.mask_flags | 0x40) as i8)?;
-        mask_buf.write_i8((playerinfo.mask_flags >> 8) as i8)?;
-    } else {
-        mask_buf.write_i8(playerinfo.mask_flags as i8)?;
+impl Mask for FaceEntityMask {
+    const FLAG: u32 = LOCK_TURNTO_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_u16(self.entity.index())?;
+
+        Ok(())
     }
 
-    for mask in MASKS {
-        let mask_id = playerinfo.mask_flags & mask;
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.face_entity_mask
+    }
 
-        match mask_id {
-            APPEARANCE_MASK => write_appearance_mask(
-                &playerinfo
-                    .masks
-                    .appearance_mask
-                    .take()
-                    .expect("missing appearance mask"),
-                mask_buf,
-            ),
-            DIRECTION_MASK => write_direction_mask(
-                &playerinfo
-                    .masks
-                    .direction_mask
-                    .take()
-                    .expect("missing direction mask"),
-                mask_buf,
-            ),
-            _ => Ok(()),
-        }?;
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.face_entity_mask
     }
+}
 
-    playerinfo.mask_flags = 0;
+impl sealed::Sealed for FaceCoordinateMask {}
 
-    Ok(())
+impl Mask for FaceCoordinateMask {
+    const FLAG: u32 = FACE_COORDINATE_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        // The client expects fine coordinates in half tiles, pointing at the centre of the tile
+        mask_buf.write_u16((self.x * 2 + 1) as u16)?;
+        mask_buf.write_u16((self.y * 2 + 1) as u16)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.face_coordinate_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.face_coordinate_mask
+    }
 }
 
-fn remove_local_player(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    playerinfo: &PlayerInfoData,
-    local_player_mask_update_required: bool,
-) -> Result<()> {
-    let new_coordinates = 123;
-    let record_coordinates = 12311;
+#[cfg(feature = "tinting")]
+impl sealed::Sealed for TintMask {}
 
-    let coordinate_change = new_coordinates != record_coordinates;
+#[cfg(feature = "tinting")]
+impl Mask for TintMask {
+    const FLAG: u32 = TINT_MASK;
 
-    bit_buf.write_bit(local_player_mask_update_required)?;
-    bit_buf.write(2, 0)?;
-    bit_buf.write_bit(coordinate_change)?;
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_u16(self.start_delay)?;
+        mask_buf.write_u16(self.end_delay)?;
+        mask_buf.write_u8(self.hue)?;
+        mask_buf.write_u8(self.saturation)?;
+        mask_buf.write_u8(self.lightness)?;
+        mask_buf.write_u8(self.opacity)?;
 
-    if coordinate_change {
-        write_coordinate_multiplier(bit_buf, record_coordinates, new_coordinates)?;
+        Ok(())
     }
 
-    Ok(())
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.tint_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.tint_mask
+    }
 }
 
-fn write_coordinate_multiplier(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    old_multiplier: i32,
-    new_multiplier: i32,
-) -> Result<()> {
-    let current_multiplier_y = new_multiplier & 0xFF;
-    let current_multiplier_x = (new_multiplier >> 8) & 0xFF;
-    let current_level = (new_multiplier >> 8) & 0x3;
+impl sealed::Sealed for ForcedChatMask {}
 
-    let last_multiplier_y = old_multiplier & 0xFF;
-    let last_multiplier_x = (old_multiplier >> 8) & 0xFF;
-    let last_level = (old_multiplier >> 8) & 0x3;
+impl Mask for ForcedChatMask {
+    const FLAG: u32 = SHOUT_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_string_cp1252(&self.text)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.forced_chat_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.forced_chat_mask
+    }
+}
+
+impl sealed::Sealed for NameModifiersMask {}
+
+impl Mask for NameModifiersMask {
+    const FLAG: u32 = NAME_MODIFIERS_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_string_cp1252(&self.prefix)?;
+        mask_buf.write_string_cp1252(&self.infix)?;
+        mask_buf.write_string_cp1252(&self.suffix)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.name_modifiers_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.name_modifiers_mask
+    }
+}
+
+impl sealed::Sealed for TemporaryMoveSpeedMask {}
+
+impl Mask for TemporaryMoveSpeedMask {
+    const FLAG: u32 = MOVEMENT_TEMPORARY_MASK;
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        mask_buf.write_i8_sub(self.move_speed as i8)?;
+
+        Ok(())
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.temporary_move_speed_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.temporary_move_speed_mask
+    }
+}
+
+impl sealed::Sealed for SequenceMask {}
+
+impl Mask for SequenceMask {
+    const FLAG: u32 = SEQUENCE_MASK;
+
+    // Like the client, a pending sequence is only replaced by one of at least the same priority.
+    // Stopping the sequence (id -1) always applies
+    fn replaces(&self, pending: &Self) -> bool {
+        self.id == -1 || pending.id == -1 || self.priority >= pending.priority
+    }
+
+    fn encode(&self, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+        write_sequence_mask(self, mask_buf)
+    }
+
+    fn slot(masks: &PlayerMasks) -> &Option<Self> {
+        &masks.sequence_mask
+    }
+
+    fn slot_mut(masks: &mut PlayerMasks) -> &mut Option<Self> {
+        &mut masks.sequence_mask
+    }
+}
+
+pub struct PlayerUpdate {
+    masks: PlayerMasks,
+    mask_flags: u32,
+    // The tick each pending mask was set in, indexed by the bit position of the mask
+    mask_cycles: [u32; 32],
+    // The pending masks that have been written to at least one observer this tick
+    consumed_mask_flags: u32,
+    movement_steps: Vec<(i32, i32)>,
+    displaced: bool,
+    movement_update: MovementUpdate,
+    // The absolute position of the player, known once set using add_player_at
+    coordinates: Option<Coordinates>,
+    // The speed the player moves at, replayed to observers the player is added to
+    move_speed: MoveSpeed,
+    // Whether the masks of the player are withheld from observers, except for its appearance when added
+    suppressed: bool,
+    // Whether the movement of the player this tick has been reported to the movement hook
+    movement_reported: bool,
+}
+
+impl PlayerUpdate {
+    fn new() -> PlayerUpdate {
+        PlayerUpdate {
+            movement_steps: Vec::with_capacity(MAX_MOVEMENT_STEPS),
+            displaced: false,
+            movement_update: MovementUpdate { x: 0, y: 0, z: 0 },
+            coordinates: None,
+            move_speed: MoveSpeed::Walk,
+            suppressed: false,
+            movement_reported: false,
+            mask_flags: 0,
+            mask_cycles: [0; 32],
+            consumed_mask_flags: 0,
+            masks: PlayerMasks {
+                appearance_mask: None,
+                appearance_encoding: OnceCell::new(),
+                direction_mask: None,
+                sequence_mask: None,
+                chat_mask: None,
+                hit_mask: None,
+                forced_movement_mask: None,
+                temporary_move_speed_mask: None,
+                name_modifiers_mask: None,
+                forced_chat_mask: None,
+                face_entity_mask: None,
+                face_coordinate_mask: None,
+                #[cfg(feature = "tinting")]
+                tint_mask: None,
+                custom_masks: Vec::new(),
+            },
+        }
+    }
+
+    /// Get the flags of the persistent masks the player has data for, replayed to observers the player is added to
+    fn persistent_mask_flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.masks.appearance_mask.is_some() {
+            flags |= APPEARANCE_MASK;
+        }
+        if self.masks.direction_mask.is_some() {
+            flags |= DIRECTION_MASK;
+        }
+        if self.masks.face_coordinate_mask.is_some() {
+            flags |= FACE_COORDINATE_MASK;
+        }
+        if self.masks.name_modifiers_mask.is_some() {
+            flags |= NAME_MODIFIERS_MASK;
+        }
+        // Released locks are the default of the client
+        if self
+            .masks
+            .face_entity_mask
+            .as_ref()
+            .is_some_and(|mask| mask.entity != FaceEntity::None)
+        {
+            flags |= LOCK_TURNTO_MASK;
+        }
+        if self.move_speed != MoveSpeed::default() {
+            flags |= MOVEMENT_CACHED_MASK;
+        }
+
+        flags
+    }
+
+    /// Get the pending masks that may be written to observers, none while extended info is suppressed
+    fn broadcast_mask_flags(&self) -> u32 {
+        if self.suppressed {
+            0
+        } else {
+            self.mask_flags
+        }
+    }
+
+    /// Get the pending masks written to the given observer, where the player itself always gets all of its masks
+    fn observed_mask_flags(&self, own: bool) -> u32 {
+        if own {
+            self.mask_flags
+        } else {
+            self.broadcast_mask_flags()
+        }
+    }
+
+    /// Flag a mask as pending, remembering the tick it was set in
+    fn mark_mask(&mut self, mask: u32, cycle: u32) {
+        self.mask_flags |= mask;
+        self.mask_cycles[mask.trailing_zeros() as usize] = cycle;
+        self.drop_superseded_facing(mask, cycle);
+    }
+
+    /// Drop the facing superseded by a newly set one, so a stale direction is never replayed after a face coordinate.
+    /// Both are kept when set in the same tick, as observers in view get both
+    fn drop_superseded_facing(&mut self, mask: u32, cycle: u32) {
+        let superseded = superseded_facing(mask);
+        if superseded == 0
+            || self.mask_flags & superseded != 0
+                && self.mask_cycles[superseded.trailing_zeros() as usize] == cycle
+        {
+            return;
+        }
+
+        self.mask_flags &= !superseded;
+        self.consumed_mask_flags &= !superseded;
+        match superseded {
+            DIRECTION_MASK => self.masks.direction_mask = None,
+            _ => self.masks.face_coordinate_mask = None,
+        }
+    }
+
+    /// Set a mask following its coalescing rule, returning whether it was stored
+    fn set_mask<M: Mask>(&mut self, mask: M, force: bool, cycle: u32) -> bool {
+        let slot = M::slot_mut(&mut self.masks);
+        if !force {
+            if let Some(pending) = slot.as_ref() {
+                if !mask.replaces(pending) {
+                    return false;
+                }
+            }
+        }
+
+        let applied = M::COALESCING.coalesce(slot, mask);
+        if applied {
+            self.mark_mask(M::FLAG, cycle);
+        }
+
+        applied
+    }
+
+    /// Set a mask in place of the pending one, regardless of its coalescing rule
+    fn replace_mask<M: Mask>(&mut self, mask: M, cycle: u32) {
+        *M::slot_mut(&mut self.masks) = Some(mask);
+        self.mark_mask(M::FLAG, cycle);
+    }
+
+    /// Get the movement of the player this tick as a delta of x, y and plane
+    fn tick_delta(&self) -> (i32, i32, i32) {
+        if self.displaced {
+            return (
+                self.movement_update.x,
+                self.movement_update.y,
+                self.movement_update.z,
+            );
+        }
+
+        self.movement_steps
+            .iter()
+            .fold((0, 0, 0), |(x, y, z), (dx, dy)| (x + dx, y + dy, z))
+    }
+
+    /// Drop a pending mask. Persistent masks keep their data, as it still describes the player when replayed later
+    fn clear_mask(&mut self, mask: u32) {
+        self.mask_flags &= !mask;
+        self.consumed_mask_flags &= !mask;
+
+        if is_persistent_mask(mask) {
+            return;
+        }
+
+        match mask {
+            APPEARANCE_MASK => *AppearanceMask::slot_mut(&mut self.masks) = None,
+            DIRECTION_MASK => self.masks.direction_mask = None,
+            SEQUENCE_MASK => self.masks.sequence_mask = None,
+            CHAT_MASK => self.masks.chat_mask = None,
+            HIT_MASK => self.masks.hit_mask = None,
+            MOVEMENT_FORCED_MASK => self.masks.forced_movement_mask = None,
+            MOVEMENT_TEMPORARY_MASK => self.masks.temporary_move_speed_mask = None,
+            NAME_MODIFIERS_MASK => self.masks.name_modifiers_mask = None,
+            SHOUT_MASK => self.masks.forced_chat_mask = None,
+            LOCK_TURNTO_MASK => self.masks.face_entity_mask = None,
+            FACE_COORDINATE_MASK => self.masks.face_coordinate_mask = None,
+            #[cfg(feature = "tinting")]
+            TINT_MASK => self.masks.tint_mask = None,
+            _ => self.masks.custom_masks.retain(|(flag, _)| *flag != mask),
+        }
+    }
+}
+
+/// Whether a mask describes the full state of a player rather than a one-off event, and thus never expires
+fn is_state_mask(mask: u32) -> bool {
+    mask_coalescing(mask) == MaskCoalescing::Replace
+}
+
+/// Get the facing mask superseded by the given one, as the client only keeps the facing set last
+const fn superseded_facing(mask: u32) -> u32 {
+    match mask {
+        DIRECTION_MASK => FACE_COORDINATE_MASK,
+        FACE_COORDINATE_MASK => DIRECTION_MASK,
+        _ => 0,
+    }
+}
+
+/// Whether the data of a mask lasts after being written, and is replayed to observers that did not see it
+const fn is_persistent_mask(mask: u32) -> bool {
+    mask & PERSISTENT_MASKS != 0
+}
+
+/// Contains the data of the PlayerInfo entry
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PlayerInfoData {
+    // START RSMOD IMPL
+    flags: i32,
+    local: bool,
+    coordinates: i32,
+    reset: bool,
+    // END RSMOD IMPL
+
+    // The rest below here are custom, and might need to be revised in terms of correct structure
+    local_to_global: bool,
+    global_to_local: bool,
+    // Movement of the player missed by the suspended observer, sent as a single teleport once resumed
+    pending_movement: Option<(i32, i32, i32)>,
+    // State masks of the player missed by the suspended observer, replayed once resumed
+    pending_mask_flags: u32,
+}
+
+#[cfg(test)]
+impl PlayerInfoData {
+    // Whether the record is in the steady state it returns to after a tick without any updates of the subject:
+    // in the inactive group, with nothing pending
+    fn is_steady(&self) -> bool {
+        self.flags == UPDATE_GROUP_INACTIVE
+            && !self.reset
+            && !self.local_to_global
+            && !self.global_to_local
+            && self.pending_movement.is_none()
+            && self.pending_mask_flags == 0
+    }
+}
+
+/// A player entering or leaving the local players of an observer, reported by `PlayerInfo::transitions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// The subject was added to the local players of the observer
+    GlobalToLocal {
+        observer: PlayerKey,
+        subject: PlayerKey,
+    },
+    /// The subject was removed from the local players of the observer
+    LocalToGlobal {
+        observer: PlayerKey,
+        subject: PlayerKey,
+    },
+}
+
+/// Contains the state of a player as an observer of the other players
+struct ObserverState {
+    // Whether the observer cannot accept updates right now, e.g. while on a loading screen
+    suspended: bool,
+    // The size in bytes above which an encoded buffer is reported to the oversized packet hook
+    size_threshold: Option<usize>,
+    // Whether encoding failed for the observer, leaving its records out of sync with the client until resynced
+    quarantined: bool,
+    // Whether the observer is idle, and sees less players until its player becomes active again
+    idle: bool,
+    // The observer group the observer sees players through, instead of through its own player
+    group: Option<ObserverGroupKey>,
+    // While joining, the size in bytes its buffers are bounded by, adding as many players as fit each tick
+    join_packet_size: Option<usize>,
+}
+
+// The players an observer may still add while encoding a single buffer
+struct Additions {
+    remaining: usize,
+    // While joining, the bytes left for the bits and masks of additions
+    bytes: Option<usize>,
+    // Whether an addition was taken, as the first one is always made so that joining cannot stall
+    taken: bool,
+}
+
+impl Additions {
+    // Take an addition with masks of the given size, failing once the bytes run out, which ends the additions.
+    // The first addition is taken even if it exceeds the bytes, e.g. a player with a large appearance
+    fn take(&mut self, mask_size: usize) -> bool {
+        if let Some(bytes) = self.bytes.as_mut() {
+            let size = MAX_ADDITION_SIZE + mask_size;
+            if size > *bytes && self.taken {
+                self.remaining = 0;
+                return false;
+            }
+            *bytes = bytes.saturating_sub(size);
+        }
+        self.remaining -= 1;
+        self.taken = true;
+
+        true
+    }
+}
+
+/// The key of an observer group
+pub type ObserverGroupKey = usize;
+
+/// Observers sharing their interest, e.g. the spectators of a tournament instance, who see the players around the
+/// centre of the group rather than those around their own player
+struct ObserverGroup {
+    center: Coordinates,
+    // The distance of every player to the centre, computed once per group while processing all players.
+    // `None` for players on another plane or without coordinates
+    distances: Option<Arc<[Option<i32>]>>,
+}
+
+/// Where an observer sees players from
+enum Interest {
+    /// The coordinates the observer sees players around, `None` when unknown
+    Center(Option<Coordinates>),
+    /// The distances of every player to the centre of the group of the observer
+    Distances(Arc<[Option<i32>]>),
+}
+
+/// The amount and size of the masks of a single kind written to an encoded buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaskSize {
+    pub mask: u32,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// A size breakdown of an encoded buffer that exceeded the size threshold of its observer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OversizedPacket {
+    pub player_id: usize,
+    pub threshold: usize,
+    /// The total size of the buffer in bytes
+    pub size: usize,
+    /// The size of the bit section holding movement, additions and skip counts
+    pub bit_size: usize,
+    /// The size of the mask flag headers of all updated players
+    pub header_size: usize,
+    /// The masks written to the buffer, in the order they are written
+    pub masks: Vec<MaskSize>,
+}
+
+type OversizedPacketHook = Box<dyn FnMut(&OversizedPacket) + Send>;
+
+/// The kind of movement written for a player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovementKind {
+    Walk,
+    Run,
+    Teleport,
+}
+
+/// The movement of a player during a tick as written to the observers it is local to, reported to the movement hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedMovement {
+    pub player_id: PlayerKey,
+    pub kind: MovementKind,
+    pub dx: i32,
+    pub dy: i32,
+    pub dz: i32,
+}
+
+type MovementHook = Box<dyn FnMut(&EncodedMovement) + Send>;
+
+/// A protocol anomaly detected while encoding, reported to the anomaly sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// A mask flagged as pending on the subject without its data, failing the encoding of the observer
+    MissingMask {
+        observer: PlayerKey,
+        subject: PlayerKey,
+        mask: u32,
+    },
+    /// The skip counts of an encoded buffer did not decode like they were written, failing the encoding of the observer.
+    /// Only detected while `WorldInfoConfig::validate_skip_counts` is enabled
+    SkipMismatch { observer: PlayerKey, reason: String },
+    /// The steps of the subject moved it by a delta no walk or run can express, e.g. stepping back and forth,
+    /// which is written as a teleport instead
+    UnwritableMovement {
+        observer: PlayerKey,
+        subject: PlayerKey,
+        dx: i32,
+        dy: i32,
+    },
+}
+
+/// Receives the protocol anomalies detected while encoding, e.g. to ship them to the logging pipeline of a server
+pub trait AnomalySink: Send {
+    fn report(&mut self, anomaly: &Anomaly);
+}
+
+impl<F: FnMut(&Anomaly) + Send> AnomalySink for F {
+    fn report(&mut self, anomaly: &Anomaly) {
+        self(anomaly)
+    }
+}
+
+fn report_anomaly(sink: &mut Option<Box<dyn AnomalySink>>, anomaly: Anomaly) {
+    if let Some(sink) = sink.as_mut() {
+        sink.report(&anomaly);
+    }
+}
+
+fn report_missing_mask(
+    sink: &mut Option<Box<dyn AnomalySink>>,
+    error: &anyhow::Error,
+    observer: PlayerKey,
+    subject: PlayerKey,
+) {
+    if let Some(MaskError::Missing(mask)) = error.downcast_ref::<MaskError>() {
+        report_anomaly(
+            sink,
+            Anomaly::MissingMask {
+                observer,
+                subject,
+                mask: *mask,
+            },
+        );
+    }
+}
+
+/// Tracks the amount and size of the masks written while encoding a buffer, indexed by the bit position of the mask
+#[derive(Clone, Default)]
+struct MaskSizes {
+    counts: [usize; 32],
+    bytes: [usize; 32],
+    header_size: usize,
+}
+
+impl MaskSizes {
+    fn record(&mut self, mask: u32, bytes: usize) {
+        let index = mask.trailing_zeros() as usize;
+        self.counts[index] += 1;
+        self.bytes[index] += bytes;
+    }
+}
+
+/// The buffers encoded during `process_all`, along with the state shared by every observer
+struct EncodingCache {
+    // The players that are the source or the victim of a pending hitsplat shown differently to them
+    hitsplat_variants: Vec<bool>,
+    encodings: Vec<CachedEncoding>,
+}
+
+/// Everything the encoding of the observer depends on besides its records and the players it sees, which are the
+/// same for every observer within a single `process_all`
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct EncodingState {
+    group: Option<ObserverGroupKey>,
+    // Only set for observers outside of a group, who see players from their own player
+    coordinates: Option<Coordinates>,
+    view_distance: i32,
+    enter_distance: i32,
+    max_additions: usize,
+    // Set to the observer when it sees the players differently from every other observer
+    variant: Option<usize>,
+}
+
+/// A buffer encoded during `process_all`, reused for observers in the same state with the same records
+struct CachedEncoding {
+    state_hash: u64,
+    state: EncodingState,
+    // The records of the observer before encoding
+    previous_records: Slab<PlayerInfoData>,
+    packet: Vec<u8>,
+    bit_size: usize,
+    mask_sizes: MaskSizes,
+    // The records of the observer after encoding, and the subjects that transitioned while encoding
+    records: Slab<PlayerInfoData>,
+    transitions: Vec<Transition>,
+}
+
+/// The PlayerInfo containing information about all players and their associated masks,
+/// encoded for the revision selected at compile time
+pub struct PlayerInfo<R = DefaultRevision> {
+    // A many-to-many mapping from a player to all other players.
+    // This means a player with id 0 will store data of player
+    // 0, 1, 2, 3, ... 2047
+    playerinfos: Slab<Slab<PlayerInfoData>>,
+    // TODO: Use this field here for playermasks (or potentially just PlayerUpdates) as it will not have issues with the borrow checker
+    playerupdates: Slab<PlayerUpdate>,
+    observers: Slab<ObserverState>,
+    // The current tick, advanced by every call to tick
+    cycle: u32,
+    config: WorldInfoConfig,
+    // Called with a size breakdown whenever an encoded buffer exceeds the threshold of its observer
+    oversized_packet_hook: Option<OversizedPacketHook>,
+    movement_hook: Option<MovementHook>,
+    anomaly_sink: Option<Box<dyn AnomalySink>>,
+    equipment_provider: Option<Box<dyn EquipmentProvider>>,
+    groups: Slab<ObserverGroup>,
+    // The masks registered outside of the crate, in the order they are written
+    custom_masks: Vec<CustomMask>,
+    revision: R,
+    // The locality transitions written during the tick in progress, and those of the last completed tick
+    tick_transitions: Vec<Transition>,
+    transitions: Vec<Transition>,
+    // The metrics of the tick in progress, and those of the last completed tick
+    #[cfg(feature = "alloc-metrics")]
+    tick_metrics: TickMetrics,
+    #[cfg(feature = "alloc-metrics")]
+    metrics: TickMetrics,
+}
+
+fn get_local_skip_count(
+    playerinfos: &Slab<Slab<PlayerInfoData>>,
+    update_group: i32,
+    player_id: usize,
+    offset: usize,
+    limit: usize,
+) -> Result<i32> {
+    let mut count = 0;
+
+    for i in offset..limit {
+        // Grab the playerinfo
+        let playerinfoentryother = playerinfos
+            .get(player_id)
+            .context("failed 1")?
+            .get(i)
+            .context("failed 2")?;
+
+        // Return if the playerinfo is not in this group
+        if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
+            continue;
+        }
+
+        // Break if a player needs to be updated
+        let is_update_required = true;
+        if is_update_required {
+            break;
+        }
+
+        // Increment the skip count by 1
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+impl Default for PlayerInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlayerInfo {
+    /// Create a new PlayerInfo using the default config
+    pub fn new() -> PlayerInfo {
+        Self::with_config(WorldInfoConfig::default()).expect("default config is valid")
+    }
+
+    /// Create a new PlayerInfo using the given config, failing if the config is invalid
+    pub fn with_config(config: WorldInfoConfig) -> Result<PlayerInfo> {
+        Self::with_revision(config, DefaultRevision)
+    }
+
+    /// Restore a PlayerInfo from a snapshot, keeping the player ids and the records of every observer,
+    /// so connected clients can continue without a resync.
+    /// Masks are not part of a snapshot, and state masks such as the appearance have to be set again,
+    /// as do the registrations of custom masks
+    pub fn restore(snapshot: &PlayerInfoSnapshot) -> Result<PlayerInfo> {
+        Self::restore_with_config(snapshot, WorldInfoConfig::default())
+    }
+
+    /// Restore a PlayerInfo from a snapshot like `restore`, using the given config
+    pub fn restore_with_config(
+        snapshot: &PlayerInfoSnapshot,
+        config: WorldInfoConfig,
+    ) -> Result<PlayerInfo> {
+        Self::restore_with_revision(snapshot, config, DefaultRevision)
+    }
+}
+
+impl<R: Revision> PlayerInfo<R> {
+    /// Create a new PlayerInfo encoding masks for the given revision, failing if the config or the revision is invalid
+    pub fn with_revision(config: WorldInfoConfig, revision: R) -> Result<PlayerInfo<R>> {
+        config.validate()?;
+        revision::validate(&revision)?;
+
+        Ok(PlayerInfo {
+            playerinfos: Slab::new(),
+            playerupdates: Slab::new(),
+            observers: Slab::new(),
+            cycle: 0,
+            config,
+            oversized_packet_hook: None,
+            movement_hook: None,
+            anomaly_sink: None,
+            equipment_provider: None,
+            groups: Slab::new(),
+            custom_masks: Vec::new(),
+            revision,
+            tick_transitions: Vec::new(),
+            transitions: Vec::new(),
+            #[cfg(feature = "alloc-metrics")]
+            tick_metrics: TickMetrics::default(),
+            #[cfg(feature = "alloc-metrics")]
+            metrics: TickMetrics::default(),
+        })
+    }
+
+    /// Get the metrics of the last completed tick
+    #[cfg(feature = "alloc-metrics")]
+    pub fn metrics(&self) -> &TickMetrics {
+        &self.metrics
+    }
+
+    /// Get the players that were added to or removed from the local players of each observer during the last completed tick,
+    /// in the order they were written
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    /// Request the subject to be removed from the local players of the observer in its next update.
+    /// The interest of the observer still applies, so a subject within view is added again in a later update,
+    /// which replays its full state
+    pub fn request_local_to_global(&mut self, observer_id: usize, subject_id: usize) -> Result<()> {
+        let record = self
+            .playerinfos
+            .get_mut(observer_id)
+            .context("failed getting observer")?
+            .get_mut(subject_id)
+            .context("failed getting record")?;
+        if observer_id == subject_id {
+            return Err(anyhow!("Player {} is always local to itself", observer_id));
+        }
+        if !record.local {
+            return Err(anyhow!(
+                "Player {} is not local to {}",
+                subject_id,
+                observer_id
+            ));
+        }
+
+        record.local_to_global = true;
+
+        Ok(())
+    }
+
+    /// Request the subject to be added to the local players of the observer in its next update, regardless of its view.
+    /// The interest of the observer still applies, so a subject outside of view is removed again in a later update
+    pub fn request_global_to_local(&mut self, observer_id: usize, subject_id: usize) -> Result<()> {
+        if observer_id == subject_id {
+            return Err(anyhow!("Player {} is always local to itself", observer_id));
+        }
+        // The addition is written using the absolute coordinates of the subject
+        self.playerupdates
+            .get(subject_id)
+            .and_then(|subject| subject.coordinates)
+            .context("Player does not have coordinates")?;
+
+        let record = self
+            .playerinfos
+            .get_mut(observer_id)
+            .context("failed getting observer")?
+            .get_mut(subject_id)
+            .context("failed getting record")?;
+        if record.local {
+            return Err(anyhow!(
+                "Player {} is already local to {}",
+                subject_id,
+                observer_id
+            ));
+        }
+
+        record.global_to_local = true;
+
+        Ok(())
+    }
+
+    /// Add an observer group seeing the players around the centre, e.g. a tournament arena watched by its spectators
+    pub fn add_observer_group(&mut self, center: Coordinates) -> Result<ObserverGroupKey> {
+        center.validate()?;
+
+        Ok(self.groups.insert(ObserverGroup {
+            center,
+            distances: None,
+        }))
+    }
+
+    /// Move the centre of an observer group, its observers see the players around the new centre from the next update
+    pub fn set_observer_group_center(
+        &mut self,
+        group_id: ObserverGroupKey,
+        center: Coordinates,
+    ) -> Result<()> {
+        center.validate()?;
+
+        self.groups
+            .get_mut(group_id)
+            .context("failed getting observer group")?
+            .center = center;
+
+        Ok(())
+    }
+
+    /// Remove an observer group, its observers see the players around their own player again
+    pub fn remove_observer_group(&mut self, group_id: ObserverGroupKey) -> Result<()> {
+        self.groups
+            .try_remove(group_id)
+            .context("failed getting observer group")?;
+        for (_, observer) in self.observers.iter_mut() {
+            if observer.group == Some(group_id) {
+                observer.group = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Let the player see players through an observer group, or through its own player again using `None`.
+    /// The player itself stays local to its observer regardless of the group
+    pub fn set_observer_group(
+        &mut self,
+        player_id: usize,
+        group_id: Option<ObserverGroupKey>,
+    ) -> Result<()> {
+        if let Some(group_id) = group_id {
+            self.groups
+                .get(group_id)
+                .context("failed getting observer group")?;
+        }
+
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting observer")?
+            .group = group_id;
+
+        Ok(())
+    }
+
+    /// Get the observer group the player sees players through
+    pub fn observer_group(&self, player_id: usize) -> Option<ObserverGroupKey> {
+        self.observers
+            .get(player_id)
+            .and_then(|observer| observer.group)
+    }
+
+    /// Get the config the PlayerInfo was created with
+    pub fn config(&self) -> &WorldInfoConfig {
+        &self.config
+    }
+
+    // TODO: Return the coordinates of all global players in this function, as to aid with the InterestInit packet
+    /// Add a new player to the PlayerInfo, at the region packed by `Coordinates::region_hash`.
+    /// Fails with a `CoordinateError` if the unpacked coordinates cannot be written to the client
+    pub fn add_player(&mut self, coordinates: i32) -> Result<()> {
+        let unpacked = Coordinates::from_region_hash(coordinates).validate()?;
+        if unpacked.region_hash() != coordinates {
+            return Err(anyhow!("Region hash {:#x} exceeds 18 bits", coordinates));
+        }
+
+        // Get the playerinfo id using a vacant key, check for exceeding limit
+        let playerinfo_id = self.playerinfos.vacant_key();
+        if playerinfo_id >= self.config.max_players {
+            return Err(anyhow!(
+                "Maximum amount of players processable by PlayerInfo reached"
+            ));
+        }
+
+        // Insert the PlayerInfoEntry
+        self.playerinfos
+            .insert(new_playerinfoentry(playerinfo_id, coordinates));
+        self.playerupdates.insert(PlayerUpdate::new());
+        self.observers.insert(ObserverState {
+            suspended: false,
+            size_threshold: None,
+            quarantined: false,
+            idle: false,
+            group: None,
+            join_packet_size: None,
+        });
+
+        Ok(())
+    }
+
+    /// Add a new player at absolute coordinates, which allows moving it using `teleport_player` and `queue_player_step`
+    /// Fails with a `CoordinateError` if the coordinates cannot be written to the client
+    pub fn add_player_at(&mut self, coordinates: Coordinates) -> Result<()> {
+        coordinates.validate()?;
+
+        let player_id = self.playerinfos.vacant_key();
+        self.add_player(coordinates.region_hash())?;
+        self.playerupdates[player_id].coordinates = Some(coordinates);
+
+        Ok(())
+    }
+
+    // The key the next added player is given
+    pub(crate) fn next_player_key(&self) -> PlayerKey {
+        self.playerinfos.vacant_key()
+    }
+
+    /// Get the absolute coordinates of the player, if known
+    pub fn player_coordinates(&self, player_id: usize) -> Option<Coordinates> {
+        self.playerupdates
+            .get(player_id)
+            .and_then(|player_update| player_update.coordinates)
+    }
+
+    /// Move the player to the given coordinates this tick, sent as a teleport.
+    /// The plane may be any logical plane, only the wire plane is written to the client.
+    /// Fails with a `CoordinateError` if the coordinates cannot be written to the client
+    pub fn teleport_player(&mut self, player_id: usize, to: Coordinates) -> Result<()> {
+        to.validate()?;
+
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        let from = player_update
+            .coordinates
+            .context("Coordinates of the player are unknown")?;
+
+        // Steps and teleports earlier this tick are merged, as observers last saw the player before any of them
+        let (x, y, z) = player_update.tick_delta();
+        let (dx, dy, dz) = from.delta(to);
+        player_update.movement_steps.clear();
+        player_update.movement_update = MovementUpdate {
+            x: x + dx,
+            y: y + dy,
+            z: (z + dz).rem_euclid(WIRE_PLANES),
+        };
+        player_update.displaced = true;
+        player_update.coordinates = Some(to);
+
+        Ok(())
+    }
+
+    /// Queue a single tile step of the player this tick, a second step makes the player run.
+    /// Fails with a `CoordinateError` if the step leaves the coordinates that can be written to the client
+    pub fn queue_player_step(&mut self, player_id: usize, dx: i32, dy: i32) -> Result<()> {
+        if dx.abs() > 1 || dy.abs() > 1 || (dx == 0 && dy == 0) {
+            return Err(anyhow!("Step {}, {} is not a single tile step", dx, dy));
+        }
+
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        if let Some(coordinates) = player_update.coordinates {
+            coordinates.translate(dx, dy).validate()?;
+        }
+
+        if player_update.displaced {
+            player_update.movement_update.x += dx;
+            player_update.movement_update.y += dy;
+        } else if player_update.movement_steps.len() < MAX_MOVEMENT_STEPS {
+            player_update.movement_steps.push((dx, dy));
+        } else {
+            return Err(anyhow!(
+                "Player cannot take more than {} steps a tick",
+                MAX_MOVEMENT_STEPS
+            ));
+        }
+
+        player_update.coordinates = player_update
+            .coordinates
+            .map(|coordinates| coordinates.translate(dx, dy));
+
+        Ok(())
+    }
+
+    /// Set the speed the player moves at. A changed speed is written to the current observers,
+    /// and observers the player is added to later are told about speeds other than walking
+    pub fn set_player_move_speed(&mut self, player_id: usize, move_speed: MoveSpeed) -> Result<()> {
+        let cycle = self.cycle;
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+
+        if player_update.move_speed != move_speed {
+            player_update.move_speed = move_speed;
+            player_update.mark_mask(MOVEMENT_CACHED_MASK, cycle);
+        }
+
+        Ok(())
+    }
+
+    /// Withhold all masks of the player from other observers, e.g. for cutscene actors or hidden staff.
+    /// Masks set while suppressed are dropped at the end of the tick, only the appearance is still written when the
+    /// player is added to an observer. The player itself still sees its own masks
+    pub fn suppress_extended_info(&mut self, player_id: usize, suppress: bool) -> Result<()> {
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .suppressed = suppress;
+
+        Ok(())
+    }
+
+    /// Whether the masks of the player are withheld from observers
+    pub fn is_extended_info_suppressed(&self, player_id: usize) -> bool {
+        self.playerupdates
+            .get(player_id)
+            .is_some_and(|player_update| player_update.suppressed)
+    }
+
+    /// Get the speed the player moves at
+    pub fn player_move_speed(&self, player_id: usize) -> Option<MoveSpeed> {
+        self.playerupdates
+            .get(player_id)
+            .map(|player_update| player_update.move_speed)
+    }
+
+    /// Get the masks on the player. Useful for checking if a mask is already set
+    pub fn get_player_masks(&mut self, key: usize) -> Result<&PlayerMasks> {
+        let player_update = self
+            .playerupdates
+            .get_mut(key)
+            .context("failed getting playermask vec")?;
+
+        Ok(&player_update.masks)
+    }
+
+    pub fn add_player_appearance_mask(
+        &mut self,
+        player_id: usize,
+        appearance_mask: AppearanceMask,
+    ) -> Result<()> {
+        validate_appearance_mask(&appearance_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(appearance_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Set the public chat mask of the player, failing if the compressed text does not fit the mask
+    pub fn add_player_chat_mask(&mut self, player_id: usize, chat_mask: ChatMask) -> Result<()> {
+        validate_chat_mask(&chat_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(chat_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Add hitsplats and health bars to the player, returning whether any were applied,
+    /// as hitsplats and health bars past the limit of a tick are dropped
+    pub fn add_player_hit_mask(&mut self, player_id: usize, hit_mask: HitMask) -> Result<bool> {
+        validate_hit_mask(&hit_mask)?;
+
+        let cycle = self.cycle;
+        Ok(self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(hit_mask, false, cycle))
+    }
+
+    /// Set the forced movement mask of the player, failing if the client cannot interpolate the movement
+    pub fn add_player_forced_movement_mask(
+        &mut self,
+        player_id: usize,
+        forced_movement_mask: ForcedMovementMask,
+    ) -> Result<()> {
+        validate_forced_movement_mask(&forced_movement_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(forced_movement_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Lock the player to face an entity until released using `FaceEntity::None`, failing if the index of the entity
+    /// cannot be written. The lock is replayed to observers the player is added to
+    pub fn add_player_face_entity_mask(
+        &mut self,
+        player_id: usize,
+        face_entity_mask: FaceEntityMask,
+    ) -> Result<()> {
+        validate_face_entity_mask(&face_entity_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(face_entity_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Turn the player towards a tile, failing if the coordinates cannot be written to the client
+    pub fn add_player_face_coordinate_mask(
+        &mut self,
+        player_id: usize,
+        face_coordinate_mask: FaceCoordinateMask,
+    ) -> Result<()> {
+        validate_face_coordinate_mask(&face_coordinate_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(face_coordinate_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Tint the model of the player, failing if the tint ends before it starts
+    #[cfg(feature = "tinting")]
+    pub fn add_player_tint_mask(&mut self, player_id: usize, tint_mask: TintMask) -> Result<()> {
+        validate_tint_mask(&tint_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(tint_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Set the forced chat of the player, failing if the text cannot be written as a CP1252 string
+    pub fn add_player_forced_chat_mask(
+        &mut self,
+        player_id: usize,
+        forced_chat_mask: ForcedChatMask,
+    ) -> Result<()> {
+        validate_cp1252(&forced_chat_mask.text, MAX_FORCED_CHAT_LENGTH)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(forced_chat_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Set the name modifiers of the player, failing if a modifier cannot be written as a CP1252 string.
+    /// Modifiers are replayed to observers the player is added to, set empty modifiers to remove them
+    pub fn add_player_name_modifiers_mask(
+        &mut self,
+        player_id: usize,
+        name_modifiers_mask: NameModifiersMask,
+    ) -> Result<()> {
+        validate_name_modifiers_mask(&name_modifiers_mask)?;
+
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(name_modifiers_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Set the speed the player moves at for this tick only, the speed set using `set_player_move_speed` applies again
+    /// from the next tick
+    pub fn add_player_temporary_move_speed_mask(
+        &mut self,
+        player_id: usize,
+        temporary_move_speed_mask: TemporaryMoveSpeedMask,
+    ) -> Result<()> {
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(temporary_move_speed_mask, false, cycle);
+
+        Ok(())
+    }
+
+    pub fn add_player_direction_mask(
+        &mut self,
+        player_id: usize,
+        direction_mask: DirectionMask,
+    ) -> Result<()> {
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(direction_mask, false, cycle);
+
+        Ok(())
+    }
+
+    /// Set the sequence mask of the player, returning whether it was applied.
+    /// Like the client, a pending sequence is only replaced by one of at least the same priority,
+    /// unless `force` is set. Stopping the sequence (id -1) always applies
+    pub fn add_player_sequence_mask(
+        &mut self,
+        player_id: usize,
+        sequence_mask: SequenceMask,
+        force: bool,
+    ) -> Result<bool> {
+        let cycle = self.cycle;
+        Ok(self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(sequence_mask, force, cycle))
+    }
+
+    /// Set any mask on the player following its coalescing rule, returning whether it was applied
+    pub fn set_mask<M: Mask>(&mut self, player_id: usize, mask: M) -> Result<bool> {
+        let cycle = self.cycle;
+        Ok(self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .set_mask(mask, false, cycle))
+    }
+
+    /// Register a mask defined outside of the crate on the given flag bit, written after the masks of the crate.
+    /// Fails if the flag is not a single bit of the mask header, or is taken by another mask
+    pub fn register_mask<E: MaskEncoder>(&mut self, flag: u32, encoder: E) -> Result<()> {
+        if flag.count_ones() != 1 || flag > 0xFFFFFF {
+            return Err(anyhow!(
+                "Mask flag {:#x} is not a single bit of the header",
+                flag
+            ));
+        }
+        if flag & (MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM) != 0 {
+            return Err(anyhow!("Mask flag {:#x} is a continuation marker", flag));
+        }
+        let builtin_flags = MASKS.iter().fold(0, |flags, &mask| flags | mask);
+        if MASKS.contains(&flag)
+            || self.revision.wire_flags(builtin_flags) & flag != 0
+            || self.custom_masks.iter().any(|custom| custom.flag == flag)
+        {
+            return Err(anyhow!("Mask flag {:#x} is already taken", flag));
+        }
+
+        self.custom_masks.push(CustomMask {
+            flag,
+            encoder: Box::new(encoder),
+        });
+
+        Ok(())
+    }
+
+    /// Register a mask defined outside of the crate on the given flag bit, written using the closure
+    pub fn register_mask_writer<T, F>(&mut self, flag: u32, writer: F) -> Result<()>
+    where
+        T: Send + 'static,
+        F: Fn(&T, &mut Cursor<Vec<u8>>) -> Result<()> + Send + 'static,
+    {
+        self.register_mask(
+            flag,
+            MaskWriter {
+                writer,
+                value: PhantomData,
+            },
+        )
+    }
+
+    /// Set the value of a registered mask on the player, replacing the pending one.
+    /// Fails if no mask is registered on the flag, or its encoder writes another type of value
+    pub fn set_custom_mask<T: Send + 'static>(
+        &mut self,
+        player_id: usize,
+        flag: u32,
+        value: T,
+    ) -> Result<()> {
+        let custom = self
+            .custom_masks
+            .iter()
+            .find(|custom| custom.flag == flag)
+            .with_context(|| format!("no mask registered on flag {:#x}", flag))?;
+        if custom.encoder.value_type() != TypeId::of::<T>() {
+            return Err(anyhow!("Mask {:#x} writes another type of value", flag));
+        }
+
+        let cycle = self.cycle;
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        let custom_masks = &mut player_update.masks.custom_masks;
+        custom_masks.retain(|(other, _)| *other != flag);
+        custom_masks.push((flag, Box::new(value)));
+        player_update.mark_mask(flag, cycle);
+
+        Ok(())
+    }
+
+    /// Set any mask on the player in place of the pending one, regardless of its coalescing rule.
+    /// E.g. replaces a queued sequence by one of a lower priority, or the queued hitsplats instead of adding to them
+    pub fn replace_mask<M: Mask>(&mut self, player_id: usize, mask: M) -> Result<()> {
+        let cycle = self.cycle;
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .replace_mask(mask, cycle);
+
+        Ok(())
+    }
+
+    /// Cancel a pending mask of the player before it is written, returning whether it was pending.
+    /// Persistent masks such as the appearance keep describing the player, and are still replayed to the observers
+    /// it is added to. Observers processed earlier in the tick have already been sent the mask
+    pub fn clear_player_mask(&mut self, player_id: usize, mask: u32) -> Result<bool> {
+        if !MASKS.contains(&mask) && !self.custom_masks.iter().any(|custom| custom.flag == mask) {
+            return Err(MaskError::Unknown(mask).into());
+        }
+
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        let pending = player_update.mask_flags & mask != 0;
+        player_update.clear_mask(mask);
+
+        Ok(pending)
+    }
+
+    /// Set several masks of the player at once, returning the flags of the masks that were applied.
+    /// Each mask follows the same rules as its own setter, the sequence mask is not forced
+    pub fn apply_updates(&mut self, player_id: usize, updates: PlayerUpdates) -> Result<u32> {
+        let cycle = self.cycle;
+        let player_update = self
+            .playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        // Nothing is applied unless every mask is valid, so a failed batch leaves no masks pending
+        updates.validate()?;
+
+        let mut applied = 0;
+        if let Some(appearance_mask) = updates.appearance {
+            if player_update.set_mask(appearance_mask, false, cycle) {
+                applied |= APPEARANCE_MASK;
+            }
+        }
+        if let Some(direction_mask) = updates.direction {
+            if player_update.set_mask(direction_mask, false, cycle) {
+                applied |= DIRECTION_MASK;
+            }
+        }
+        if let Some(sequence_mask) = updates.sequence {
+            if player_update.set_mask(sequence_mask, false, cycle) {
+                applied |= SEQUENCE_MASK;
+            }
+        }
+        if let Some(hit_mask) = updates.hit {
+            if player_update.set_mask(hit_mask, false, cycle) {
+                applied |= HIT_MASK;
+            }
+        }
+        if let Some(forced_movement_mask) = updates.forced_movement {
+            if player_update.set_mask(forced_movement_mask, false, cycle) {
+                applied |= MOVEMENT_FORCED_MASK;
+            }
+        }
+        if let Some(temporary_move_speed_mask) = updates.temporary_move_speed {
+            if player_update.set_mask(temporary_move_speed_mask, false, cycle) {
+                applied |= MOVEMENT_TEMPORARY_MASK;
+            }
+        }
+        if let Some(face_entity_mask) = updates.face_entity {
+            if player_update.set_mask(face_entity_mask, false, cycle) {
+                applied |= LOCK_TURNTO_MASK;
+            }
+        }
+        if let Some(face_coordinate_mask) = updates.face_coordinate {
+            if player_update.set_mask(face_coordinate_mask, false, cycle) {
+                applied |= FACE_COORDINATE_MASK;
+            }
+        }
+        #[cfg(feature = "tinting")]
+        if let Some(tint_mask) = updates.tint {
+            if player_update.set_mask(tint_mask, false, cycle) {
+                applied |= TINT_MASK;
+            }
+        }
+        if let Some(forced_chat_mask) = updates.forced_chat {
+            if player_update.set_mask(forced_chat_mask, false, cycle) {
+                applied |= SHOUT_MASK;
+            }
+        }
+        if let Some(name_modifiers_mask) = updates.name_modifiers {
+            if player_update.set_mask(name_modifiers_mask, false, cycle) {
+                applied |= NAME_MODIFIERS_MASK;
+            }
+        }
+        if let Some(chat_mask) = updates.chat {
+            if player_update.set_mask(chat_mask, false, cycle) {
+                applied |= CHAT_MASK;
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Set the amount of ticks a mask may stay pending without being written to any observer before it is dropped.
+    /// Masks that describe the full state of the player, such as appearance, never expire.
+    /// `None` keeps masks pending until they are consumed, which is the default
+    pub fn set_mask_expiry(&mut self, expiry: Option<u32>) {
+        self.config.mask_expiry = expiry;
+    }
+
+    /// Advance to the next tick. Movement and masks that were written to observers are cleared, masks that were not
+    /// consumed are dropped once they exceed the configured expiry, and suspended observers accumulate what they missed.
+    /// Should be called once at the end of every tick, after all players have been processed.
+    /// Once a tick passes without any updates, every record is back in the inactive group with nothing pending
+    pub fn tick(&mut self) {
+        self.accumulate_suspended();
+        self.transitions = std::mem::take(&mut self.tick_transitions);
+
+        #[cfg(feature = "alloc-metrics")]
+        {
+            self.metrics = std::mem::take(&mut self.tick_metrics);
+        }
+
+        for (_, player_update) in self.playerupdates.iter_mut() {
+            let custom_masks = self.custom_masks.iter().map(|custom| custom.flag);
+            for mask in MASKS.iter().copied().chain(custom_masks) {
+                if player_update.mask_flags & mask == 0 {
+                    continue;
+                }
+
+                // Masks of suppressed players are dropped, so they do not leak once suppression ends
+                let consumed =
+                    player_update.suppressed || player_update.consumed_mask_flags & mask != 0;
+                let expired = match self.config.mask_expiry {
+                    Some(expiry) if !is_state_mask(mask) => {
+                        // The amount of ticks the mask has been pending for, including the current one
+                        let age = self.cycle.wrapping_sub(
+                            player_update.mask_cycles[mask.trailing_zeros() as usize],
+                        ) + 1;
+                        age >= expiry
+                    }
+                    _ => false,
+                };
+
+                if consumed || expired {
+                    player_update.clear_mask(mask);
+                }
+            }
+
+            player_update.movement_steps.clear();
+            player_update.movement_reported = false;
+            player_update.displaced = false;
+            player_update.movement_update = MovementUpdate { x: 0, y: 0, z: 0 };
+        }
+
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+
+    /// Suspend updates for a player whose client cannot accept them right now, e.g. while on a loading screen.
+    /// Processing a suspended player returns an empty buffer that should not be sent, while the movement and
+    /// state masks of the players around them accumulate until resumed
+    pub fn suspend_player(&mut self, player_id: usize) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting observer")?
+            .suspended = true;
+
+        Ok(())
+    }
+
+    /// Resume updates for a suspended player. The next time the player is processed, everything missed
+    /// while suspended is caught up on in a single encoding
+    pub fn resume_player(&mut self, player_id: usize) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting observer")?
+            .suspended = false;
+
+        Ok(())
+    }
+
+    /// Whether updates for the player are suspended
+    pub fn is_suspended(&self, player_id: usize) -> bool {
+        self.observers
+            .get(player_id)
+            .is_some_and(|observer| observer.suspended)
+    }
+
+    /// Whether processing the player would encode anything beyond skipping every player,
+    /// i.e. a player enters or leaves its view, a local player moves, has masks, or has updates missed while suspended.
+    /// Always false for suspended or unknown players, as nothing is encoded for them
+    pub fn has_pending_update(&self, player_id: usize) -> bool {
+        if self.is_suspended(player_id) {
+            return false;
+        }
+
+        let records = match self.playerinfos.get(player_id) {
+            Some(records) => records,
+            None => return false,
+        };
+
+        let view_distance = self.view_distance(player_id);
+        let enter_distance = self.enter_distance(player_id);
+        let interest = self.interest(player_id);
+        records
+            .iter()
+            .take(self.config.max_players)
+            .any(|(subject_id, record)| {
+                if record.global_to_local {
+                    return true;
+                }
+                if !record.local {
+                    return in_view(&self.playerupdates, &interest, enter_distance, subject_id)
+                        == Some(true);
+                }
+
+                subject_id != player_id
+                    && in_view(&self.playerupdates, &interest, view_distance, subject_id)
+                        == Some(false)
+                    || self.has_subject_update(player_id, subject_id, record)
+            })
+    }
+
+    /// Whether the player itself moves or has masks pending this tick, which the observers seeing it are sent.
+    /// `has_pending_update` is about what the player is sent as an observer instead
+    pub fn has_pending_updates(&self, player_id: usize) -> bool {
+        self.playerupdates
+            .get(player_id)
+            .is_some_and(|player_update| {
+                player_update.mask_flags != 0
+                    || player_update.displaced
+                    || !player_update.movement_steps.is_empty()
+            })
+    }
+
+    /// Get the flags of the masks pending on the player, including those already written to some observers this tick
+    pub fn pending_mask_flags(&self, player_id: usize) -> u32 {
+        self.playerupdates
+            .get(player_id)
+            .map_or(0, |player_update| player_update.mask_flags)
+    }
+
+    // Whether a local player has to be written to an observer with the given record of it
+    fn has_subject_update(
+        &self,
+        observer_id: usize,
+        subject_id: usize,
+        record: &PlayerInfoData,
+    ) -> bool {
+        record.local_to_global
+            || record.pending_movement.is_some()
+            || record.pending_mask_flags != 0
+            || self
+                .playerupdates
+                .get(subject_id)
+                .is_some_and(|player_update| {
+                    player_update.observed_mask_flags(observer_id == subject_id) != 0
+                        || player_update.displaced
+                        || !player_update.movement_steps.is_empty()
+                })
+    }
+
+    /// Mark the player as idle, e.g. when it has been away from the keyboard for a while.
+    /// Idle players see others within a smaller distance and get less players added each tick,
+    /// until their own player moves or gets a mask, which restores the full settings
+    pub fn set_player_idle(&mut self, player_id: usize, idle: bool) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("Player does not exist")?
+            .idle = idle;
+
+        Ok(())
+    }
+
+    /// Whether the player is marked as idle
+    pub fn is_idle(&self, player_id: usize) -> bool {
+        self.observers
+            .get(player_id)
+            .is_some_and(|observer| observer.idle)
+    }
+
+    // The distance at which the observer sees other players, beyond which they are removed
+    fn view_distance(&self, player_id: usize) -> i32 {
+        if self.is_idle(player_id) {
+            self.config.effective_idle_view_distance()
+        } else {
+            self.config.view_distance
+        }
+    }
+
+    // Get the state the encoding of the observer depends on. Observers on the same tile share it
+    fn encoding_state(&self, player_id: usize, hitsplat_variants: &[bool]) -> EncodingState {
+        let group = self
+            .observers
+            .get(player_id)
+            .and_then(|observer| observer.group);
+        let player_update = self.playerupdates.get(player_id);
+        // A suppressed observer is the only one seeing its own masks, and hitsplats may differ for their source and victim
+        let suppressed = player_update.is_some_and(|player_update| player_update.suppressed);
+        let variant = suppressed || hitsplat_variants.get(player_id).copied().unwrap_or(false);
+
+        EncodingState {
+            group,
+            coordinates: player_update
+                .filter(|_| group.is_none())
+                .and_then(|player_update| player_update.coordinates),
+            view_distance: self.view_distance(player_id),
+            enter_distance: self.enter_distance(player_id),
+            max_additions: self.max_additions(player_id),
+            variant: variant.then_some(player_id),
+        }
+    }
+
+    // Hash the state of the observer along with its records. Observers in the same state with the same records hash the same
+    fn observer_state_hash(&self, player_id: usize, state: &EncodingState) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        state.hash(&mut hasher);
+        if let Some(records) = self.playerinfos.get(player_id) {
+            for (index, record) in records.iter().take(self.config.max_players) {
+                index.hash(&mut hasher);
+                record.hash(&mut hasher);
+            }
+        }
+
+        hasher.finish()
+    }
+
+    // Whether the observer has the same records as those the cached buffer was encoded from
+    fn has_records(&self, player_id: usize, cached: &CachedEncoding) -> bool {
+        self.playerinfos.get(player_id).is_some_and(|records| {
+            records
+                .iter()
+                .take(self.config.max_players)
+                .eq(cached.previous_records.iter().take(self.config.max_players))
+        })
+    }
+
+    // Find the players that are the source or the victim of a pending hitsplat that is shown differently to them
+    fn hitsplat_variants(&self) -> Vec<bool> {
+        let mut players = vec![false; self.playerupdates.capacity()];
+        for (subject_id, player_update) in self.playerupdates.iter() {
+            let Some(hit_mask) = &player_update.masks.hit_mask else {
+                continue;
+            };
+            for hitsplat in hit_mask
+                .hitsplats
+                .iter()
+                .filter(|hitsplat| hitsplat.has_variants())
+            {
+                players[subject_id] = true;
+                if let Some(source) = hitsplat.source.and_then(|source| players.get_mut(source)) {
+                    *source = true;
+                }
+            }
+        }
+
+        players
+    }
+
+    // Get where the observer sees players from, the centre of its observer group or its own player
+    fn interest(&self, player_id: usize) -> Interest {
+        let group = self
+            .observers
+            .get(player_id)
+            .and_then(|observer| observer.group)
+            .and_then(|group| self.groups.get(group));
+
+        match group {
+            Some(ObserverGroup {
+                distances: Some(distances),
+                ..
+            }) => Interest::Distances(distances.clone()),
+            Some(group) => Interest::Center(Some(group.center)),
+            None => Interest::Center(
+                self.playerupdates
+                    .get(player_id)
+                    .and_then(|player_update| player_update.coordinates),
+            ),
+        }
+    }
+
+    // The distance within which other players are added to the observer
+    fn enter_distance(&self, player_id: usize) -> i32 {
+        self.view_distance(player_id) - self.config.view_hysteresis
+    }
+
+    // The maximum amount of players added to the observer each tick
+    fn max_additions(&self, player_id: usize) -> usize {
+        if self.is_idle(player_id) {
+            self.config.idle_max_additions_per_tick
+        } else {
+            self.config.max_additions_per_tick
+        }
+    }
+
+    /// Get what the client of the player knows about each other player, as needed to decode its next packet
+    pub fn client_records(&self, player_id: usize) -> Result<Vec<ClientRecord>> {
+        let records = self
+            .playerinfos
+            .get(player_id)
+            .context("failed getting observer")?;
+
+        Ok((0..MAX_PLAYERS)
+            .map(|index| {
+                records
+                    .get(index)
+                    .map(|record| ClientRecord {
+                        local: record.local,
+                        inactive: record.flags & 0x1 != 0,
+                    })
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    /// Whether encoding failed for the player, and its updates are withheld until it is resynced
+    pub fn is_quarantined(&self, player_id: usize) -> bool {
+        self.observers
+            .get(player_id)
+            .is_some_and(|observer| observer.quarantined)
+    }
+
+    /// Reset the records of the player to those of a player that has just logged in, lifting a quarantine.
+    /// The client has to be resynced as well, by sending it a fresh GPI initialisation
+    pub fn resync_player(&mut self, player_id: usize, coordinates: i32) -> Result<()> {
+        let records = self
+            .playerinfos
+            .get_mut(player_id)
+            .context("Player does not exist")?;
+        *records = new_playerinfoentry(player_id, coordinates);
+        self.observers[player_id].quarantined = false;
+
+        Ok(())
+    }
+
+    /// Start the bulk join of a player that has just been added, e.g. a spectator joining a busy area, returning its
+    /// GPI initialisation. Until every player in view has been added, the additions of the player are bounded by the
+    /// packet size instead of the additions per tick, continuing with what did not fit in the following ticks.
+    /// Players are added in index order, the order the client caches their appearances in.
+    /// Fails if the packet size is below `MIN_JOIN_PACKET_SIZE`, or if the player already sees other players,
+    /// which the initialisation would desync
+    pub fn encode_join(&mut self, player_id: usize, packet_size: usize) -> Result<Vec<u8>> {
+        if packet_size < MIN_JOIN_PACKET_SIZE {
+            return Err(anyhow!(
+                "Join packet size {} is below {}",
+                packet_size,
+                MIN_JOIN_PACKET_SIZE
+            ));
+        }
+        let coordinates = self
+            .player_coordinates(player_id)
+            .context("missing coordinates of joining player")?;
+        let records = self
+            .playerinfos
+            .get(player_id)
+            .context("Player does not exist")?;
+        if records
+            .iter()
+            .any(|(index, record)| index != player_id && record.local)
+        {
+            return Err(anyhow!("Player {} already sees other players", player_id));
+        }
+
+        // The absolute coordinates of the player, followed by the regions the client knows of every other player
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+        bit_buf.write(30, coordinates.packed())?;
+        for (index, record) in records.iter() {
+            if index != player_id {
+                bit_buf.write(18, record.coordinates)?;
+            }
+        }
+        bit_buf.byte_align()?;
+
+        self.observers[player_id].join_packet_size = Some(packet_size);
+
+        Ok(bit_buf.into_writer())
+    }
+
+    /// Whether the player is still adding the players in view in bulk, after `encode_join`
+    pub fn is_joining(&self, player_id: usize) -> bool {
+        self.observers
+            .get(player_id)
+            .is_some_and(|observer| observer.join_packet_size.is_some())
+    }
+
+    /// Set the size in bytes above which an encoded buffer for the player is reported to the oversized packet hook,
+    /// `None` disables reporting for the player
+    pub fn set_packet_size_threshold(
+        &mut self,
+        player_id: usize,
+        threshold: Option<usize>,
+    ) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting observer")?
+            .size_threshold = threshold;
+
+        Ok(())
+    }
+
+    /// Set the hook that is called with a size breakdown whenever an encoded buffer exceeds the size threshold
+    /// of its player, useful for finding out which content produces packets close to the client's limits
+    pub fn set_oversized_packet_hook(
+        &mut self,
+        hook: impl FnMut(&OversizedPacket) + Send + 'static,
+    ) {
+        self.oversized_packet_hook = Some(Box::new(hook));
+    }
+
+    /// Set the hook that is called with the movement of each player as it is first written during a tick,
+    /// e.g. for anti-cheat to check what clients were told against the limits of the player.
+    /// Catching up observers on movement they missed while suspended is not reported
+    pub fn set_movement_hook(&mut self, hook: impl FnMut(&EncodedMovement) + Send + 'static) {
+        self.movement_hook = Some(Box::new(hook));
+    }
+
+    /// Set the sink receiving the protocol anomalies detected while encoding
+    pub fn set_anomaly_sink(&mut self, sink: impl AnomalySink + 'static) {
+        self.anomaly_sink = Some(Box::new(sink));
+    }
+
+    /// Set the provider the appearance masks look up their worn items from.
+    /// Without one every item is encoded as worn
+    pub fn set_equipment_provider(&mut self, provider: impl EquipmentProvider + 'static) {
+        self.equipment_provider = Some(Box::new(provider));
+
+        // Appearances encoded using the previous provider may show other items
+        for (_, player_update) in self.playerupdates.iter_mut() {
+            player_update.masks.appearance_encoding.take();
+        }
+    }
+
+    /// Report the encoded buffer of a player to the oversized packet hook if it exceeds the threshold of the player
+    fn report_oversized(
+        &mut self,
+        player_id: usize,
+        size: usize,
+        bit_size: usize,
+        mask_sizes: &MaskSizes,
+    ) {
+        let threshold = match self
+            .observers
+            .get(player_id)
+            .and_then(|observer| observer.size_threshold)
+        {
+            Some(threshold) if size > threshold => threshold,
+            _ => return,
+        };
+
+        if let Some(hook) = self.oversized_packet_hook.as_mut() {
+            let custom_masks = self.custom_masks.iter().map(|custom| custom.flag);
+            let masks = MASKS
+                .iter()
+                .copied()
+                .chain(custom_masks)
+                .filter_map(|mask| {
+                    let index = mask.trailing_zeros() as usize;
+                    (mask_sizes.counts[index] > 0).then(|| MaskSize {
+                        mask,
+                        count: mask_sizes.counts[index],
+                        bytes: mask_sizes.bytes[index],
+                    })
+                })
+                .collect();
+
+            hook(&OversizedPacket {
+                player_id,
+                threshold,
+                size,
+                bit_size,
+                header_size: mask_sizes.header_size,
+                masks,
+            });
+        }
+    }
+
+    /// Accumulate the movement and state masks of this tick on the local players of every suspended observer
+    fn accumulate_suspended(&mut self) {
+        let suspended: Vec<PlayerKey> = self
+            .observers
+            .iter()
+            .filter(|(_, observer)| observer.suspended)
+            .map(|(observer_id, _)| observer_id)
+            .collect();
+
+        for observer_id in suspended {
+            self.accumulate_missed(observer_id);
+        }
+    }
+
+    /// Accumulate the movement and state masks of this tick on the local players of an observer that is not sent them
+    fn accumulate_missed(&mut self, observer_id: usize) {
+        let records = match self.playerinfos.get_mut(observer_id) {
+            Some(records) => records,
+            None => return,
+        };
+
+        for (subject_id, record) in records.iter_mut() {
+            if !record.local {
+                continue;
+            }
+
+            let subject = match self.playerupdates.get(subject_id) {
+                Some(subject) => subject,
+                None => continue,
+            };
+
+            let (dx, dy, dz) = subject.tick_delta();
+            if (dx, dy, dz) != (0, 0, 0) {
+                let (x, y, z) = record.pending_movement.unwrap_or((0, 0, 0));
+                record.pending_movement = Some((x + dx, y + dy, z + dz));
+            }
+
+            let mask_flags = subject.observed_mask_flags(subject_id == observer_id);
+            for &mask in MASKS {
+                if mask_flags & mask != 0 && is_persistent_mask(mask) {
+                    let superseded = superseded_facing(mask) & !mask_flags;
+                    record.pending_mask_flags = record.pending_mask_flags & !superseded | mask;
+                }
+            }
+        }
+    }
+
+    /// Take a snapshot of the current state, e.g. to compare against a later one when debugging desyncs
+    pub fn snapshot(&self) -> PlayerInfoSnapshot {
+        let observers = self
+            .playerinfos
+            .iter()
+            .map(|(player_id, records)| ObserverSnapshot {
+                player_id,
+                suspended: self.observers[player_id].suspended,
+                records: records
+                    .iter()
+                    .map(|(_, record)| RecordSnapshot {
+                        flags: record.flags,
+                        local: record.local,
+                        coordinates: record.coordinates,
+                        reset: record.reset,
+                        local_to_global: record.local_to_global,
+                        global_to_local: record.global_to_local,
+                        pending_movement: record.pending_movement,
+                        pending_mask_flags: record.pending_mask_flags,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let subjects = self
+            .playerupdates
+            .iter()
+            .map(|(player_id, player_update)| SubjectSnapshot {
+                player_id,
+                mask_flags: player_update.mask_flags,
+                coordinates: player_update.coordinates,
+                move_speed: player_update.move_speed,
+                appearance: player_update.masks.appearance_mask.clone(),
+                direction: player_update.masks.direction_mask.clone(),
+                face_coordinate: player_update.masks.face_coordinate_mask.clone(),
+                face_entity: player_update.masks.face_entity_mask.clone(),
+                name_modifiers: player_update.masks.name_modifiers_mask.clone(),
+            })
+            .collect();
+
+        PlayerInfoSnapshot {
+            cycle: self.cycle,
+            observers,
+            subjects,
+        }
+    }
+
+    /// Restore a PlayerInfo from a snapshot like `restore`, encoding masks for the given revision
+    pub fn restore_with_revision(
+        snapshot: &PlayerInfoSnapshot,
+        config: WorldInfoConfig,
+        revision: R,
+    ) -> Result<PlayerInfo<R>> {
+        config.validate()?;
+        revision::validate(&revision)?;
+
+        let mut playerinfos = Vec::with_capacity(snapshot.observers.len());
+        let mut observers = Vec::with_capacity(snapshot.observers.len());
+        for observer in &snapshot.observers {
+            if observer.player_id >= config.max_players || observer.records.len() != MAX_PLAYERS {
+                return Err(anyhow!(
+                    "Invalid snapshot of observer {} with {} records",
+                    observer.player_id,
+                    observer.records.len()
+                ));
+            }
+            // The records past the max players are skipped as a whole, so they have to be alike
+            let tail = &observer.records[config.max_players..];
+            if tail
+                .iter()
+                .any(|record| *record != tail[0] || record.local || record.global_to_local)
+            {
+                return Err(anyhow!(
+                    "Snapshot of observer {} has records past the max players {}",
+                    observer.player_id,
+                    config.max_players
+                ));
+            }
+
+            let records = observer
+                .records
+                .iter()
+                .map(|record| PlayerInfoData {
+                    flags: record.flags,
+                    local: record.local,
+                    coordinates: record.coordinates,
+                    reset: record.reset,
+                    local_to_global: record.local_to_global,
+                    global_to_local: record.global_to_local,
+                    pending_movement: record.pending_movement,
+                    pending_mask_flags: record.pending_mask_flags,
+                })
+                .enumerate()
+                .collect::<Slab<_>>();
+
+            playerinfos.push((observer.player_id, records));
+            observers.push((
+                observer.player_id,
+                ObserverState {
+                    suspended: observer.suspended,
+                    size_threshold: None,
+                    quarantined: false,
+                    idle: false,
+                    group: None,
+                    join_packet_size: None,
+                },
+            ));
+        }
+
+        // Players keep where they are and their lasting state, so observers adding them later still see them as before
+        let playerupdates: Slab<PlayerUpdate> = snapshot
+            .observers
+            .iter()
+            .map(|observer| {
+                let mut player_update = PlayerUpdate::new();
+                if let Some(subject) = snapshot.subject(observer.player_id) {
+                    player_update.coordinates = subject.coordinates;
+                    player_update.move_speed = subject.move_speed;
+                    player_update.masks.appearance_mask = subject.appearance.clone();
+                    player_update.masks.direction_mask = subject.direction.clone();
+                    player_update.masks.face_coordinate_mask = subject.face_coordinate.clone();
+                    player_update.masks.face_entity_mask = subject.face_entity.clone();
+                    player_update.masks.name_modifiers_mask = subject.name_modifiers.clone();
+                }
+                (observer.player_id, player_update)
+            })
+            .collect();
+        if playerupdates.len() != snapshot.observers.len() {
+            return Err(anyhow!("Snapshot contains duplicate observers"));
+        }
+
+        Ok(PlayerInfo {
+            playerinfos: playerinfos.into_iter().collect(),
+            playerupdates,
+            observers: observers.into_iter().collect(),
+            cycle: snapshot.cycle,
+            config,
+            oversized_packet_hook: None,
+            movement_hook: None,
+            anomaly_sink: None,
+            equipment_provider: None,
+            groups: Slab::new(),
+            custom_masks: Vec::new(),
+            revision,
+            tick_transitions: Vec::new(),
+            transitions: Vec::new(),
+            #[cfg(feature = "alloc-metrics")]
+            tick_metrics: TickMetrics::default(),
+            #[cfg(feature = "alloc-metrics")]
+            metrics: TickMetrics::default(),
+        })
+    }
+
+    /// TODO: Consider remove
+    pub fn get_player(&mut self, key: usize) -> Option<&Slab<PlayerInfoData>> {
+        self.playerinfos.get(key)
+    }
+
+    /// TODO: Consider remove
+    pub fn get_player_mut(&mut self, key: usize) -> Option<&mut Slab<PlayerInfoData>> {
+        self.playerinfos.get_mut(key)
+    }
+
+    /// Remove a player from the PlayerInfo
+    pub fn remove_player(&mut self, key: usize) -> Result<()> {
+        // Observers that can see the player remove it in their next update
+        for (_, records) in self.playerinfos.iter_mut() {
+            if let Some(record) = records.get_mut(key) {
+                if record.local {
+                    record.local_to_global = true;
+                }
+                record.global_to_local = false;
+            }
+        }
+
+        self.playerinfos.remove(key);
+        self.playerupdates.remove(key);
+        self.observers.remove(key);
+
+        Ok(())
+    }
+
+    /// Process every player, returning the encoded buffer or error of each player.
+    /// A player that fails to encode is quarantined instead of aborting the other players,
+    /// and keeps failing until it is resynced using `resync_player`
+    pub fn process_all(&mut self) -> Vec<(PlayerKey, Result<Vec<u8>>)> {
+        let player_ids: Vec<PlayerKey> = self.playerinfos.iter().map(|(key, _)| key).collect();
+        let mut cache = self
+            .config
+            .reuse_identical_encodings
+            .then(|| EncodingCache {
+                hitsplat_variants: self.hitsplat_variants(),
+                encodings: Vec::new(),
+            });
+
+        // The players around each observer group are found once for all of its observers
+        let members: Vec<ObserverGroupKey> = self
+            .observers
+            .iter()
+            .filter_map(|(_, observer)| observer.group)
+            .collect();
+        for (group_id, group) in self.groups.iter_mut() {
+            if !members.contains(&group_id) {
+                continue;
+            }
+
+            let mut distances = vec![None; self.playerupdates.capacity()];
+            for (subject_id, subject) in self.playerupdates.iter() {
+                distances[subject_id] = subject
+                    .coordinates
+                    .filter(|coordinates| coordinates.plane == group.center.plane)
+                    .map(|coordinates| group.center.distance(coordinates));
+            }
+            group.distances = Some(distances.into());
+        }
+
+        let results = player_ids
+            .into_iter()
+            .map(|player_id| {
+                let result = self.process_cached(player_id, cache.as_mut());
+                if result.is_err() {
+                    self.observers[player_id].quarantined = true;
+                }
+                (player_id, result)
+            })
+            .collect();
+
+        for (_, group) in self.groups.iter_mut() {
+            group.distances = None;
+        }
+
+        results
+    }
+
+    /// Process a player contained in the PlayerInfo, returning a buffer with data about all the updates for the specified player,
+    /// to be sent.
+    ///
+    /// The movement and masks of the player itself are never deferred, as its client rubber-bands without them.
+    /// The player is always local to itself, so its own update is written with the local players ahead of any additions,
+    /// regardless of the additions cap, the view of an idle observer or its extended info being suppressed
+    pub fn process(&mut self, player_id: usize) -> Result<Vec<u8>> {
+        self.process_cached(player_id, None)
+    }
+
+    /// Encode the smallest valid buffer for a player, skipping every player, for ticks where the player is not processed
+    /// but its client still expects an update. No masks are written nor consumed, the movement and state masks of its
+    /// local players are caught up on the next time the player is processed instead
+    pub fn encode_keepalive(&mut self, player_id: usize) -> Result<Vec<u8>> {
+        if self.playerinfos.get(player_id).is_none() {
+            return Ok(Vec::new());
+        }
+        if self.is_quarantined(player_id) {
+            return Err(anyhow!(
+                "Player {} is quarantined until it is resynced",
+                player_id
+            ));
+        }
+        if self.is_suspended(player_id) {
+            return Ok(Vec::new());
+        }
+
+        let limit = self.config.max_players;
+        let tail = self.tail_flags(player_id);
+        let mut main_buf = BitWriter::endian(Vec::new(), BigEndian);
+        for (local, update_group) in [
+            (true, UPDATE_GROUP_ACTIVE),
+            (true, UPDATE_GROUP_INACTIVE),
+            (false, UPDATE_GROUP_INACTIVE),
+            (false, UPDATE_GROUP_ACTIVE),
+        ] {
+            let mut count = self.playerinfos[player_id]
+                .iter()
+                .take(limit)
+                .filter(|(_, record)| record.local == local && record.flags == update_group)
+                .count();
+            if !local && tail == Some(update_group) {
+                count += MAX_PLAYERS - limit;
+            }
+
+            // A single skip count covers the whole group
+            if count > 0 {
+                main_buf.write_bit(false)?;
+                write_skip_count(&mut main_buf, count as i32 - 1, false)?;
+            }
+            main_buf.byte_align()?;
+        }
+
+        // The client moves the skipped players between groups as it would for any update
+        self.accumulate_missed(player_id);
+        for (_, record) in self.playerinfos[player_id].iter_mut().take(limit) {
+            record.flags |= 0x2;
+        }
+        for i in 0..limit {
+            self.group(player_id, i)?;
+        }
+        self.group_tail(player_id);
+
+        Ok(main_buf.into_writer())
+    }
+
+    // Encode the buffer of a player, reusing a buffer from the cache if an observer in the same state was encoded
+    fn process_cached(
+        &mut self,
+        player_id: usize,
+        cache: Option<&mut EncodingCache>,
+    ) -> Result<Vec<u8>> {
+        // TODO: Remove this, do proper checking instead in the local_player_info and global_player_info places, simply return if the player id does not exist
+        if self.playerinfos.get(player_id).is_none() {
+            return Ok(Vec::new());
+        }
+
+        if self.is_quarantined(player_id) {
+            return Err(anyhow!(
+                "Player {} is quarantined until it is resynced",
+                player_id
+            ));
+        }
+
+        // Suspended observers are left untouched until they are resumed
+        if self.is_suspended(player_id) {
+            return Ok(Vec::new());
+        }
+
+        // Idle observers are back to the full settings as soon as their player is active
+        if self.has_pending_updates(player_id) {
+            self.observers[player_id].idle = false;
+        }
+
+        #[cfg(feature = "alloc-metrics")]
+        let allocations = crate::metrics::allocation_counts();
+
+        // Joining observers add players until their buffer is full, which no other observer shares
+        let join_packet_size = self.observers[player_id].join_packet_size;
+        let state = cache
+            .as_ref()
+            .filter(|_| join_packet_size.is_none())
+            .map(|cache| self.encoding_state(player_id, &cache.hitsplat_variants));
+        let state_hash = state
+            .as_ref()
+            .map(|state| self.observer_state_hash(player_id, state));
+        if let (Some(cache), Some(state), Some(state_hash)) = (cache.as_deref(), state, state_hash)
+        {
+            // The hash only narrows down the candidates, the state they were encoded from has to match exactly
+            if let Some(cached) = cache.encodings.iter().find(|cached| {
+                cached.state_hash == state_hash
+                    && cached.state == state
+                    && self.has_records(player_id, cached)
+            }) {
+                self.playerinfos[player_id] = cached.records.clone();
+                self.tick_transitions
+                    .extend(
+                        cached
+                            .transitions
+                            .iter()
+                            .map(|transition| match *transition {
+                                Transition::GlobalToLocal { subject, .. } => {
+                                    Transition::GlobalToLocal {
+                                        observer: player_id,
+                                        subject,
+                                    }
+                                }
+                                Transition::LocalToGlobal { subject, .. } => {
+                                    Transition::LocalToGlobal {
+                                        observer: player_id,
+                                        subject,
+                                    }
+                                }
+                            }),
+                    );
+                self.report_oversized(
+                    player_id,
+                    cached.packet.len(),
+                    cached.bit_size,
+                    &cached.mask_sizes,
+                );
+
+                #[cfg(feature = "alloc-metrics")]
+                {
+                    self.tick_metrics.record(
+                        allocations,
+                        cached.packet.len(),
+                        cached.packet.len() - cached.bit_size,
+                    );
+                    self.tick_metrics.reused_packets += 1;
+                }
+
+                return Ok(cached.packet.clone());
+            }
+        }
+        let previous_records = state.map(|_| self.playerinfos[player_id].clone());
+        let transitions_start = self.tick_transitions.len();
+
+        let mut main_buf = BitWriter::endian(Vec::new(), BigEndian);
+        // Supply the mask buffer instead, as to prevent this big ass allocation
+        let mut mask_buf = Cursor::new(vec![0; self.config.mask_buffer_capacity]);
+        let mut mask_sizes = MaskSizes::default();
+        let mut additions = Additions {
+            remaining: match join_packet_size {
+                Some(_) => usize::MAX,
+                None => self.max_additions(player_id),
+            },
+            bytes: None,
+            taken: false,
+        };
+        let records = if self.config.validate_skip_counts {
+            Some(self.client_records(player_id)?)
+        } else {
+            None
+        };
+
+        // Write local player data (players around the player)
+        self.local_player_info(
+            player_id,
+            &mut main_buf,
+            &mut mask_buf,
+            &mut mask_sizes,
+            UPDATE_GROUP_ACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        self.local_player_info(
+            player_id,
+            &mut main_buf,
+            &mut mask_buf,
+            &mut mask_sizes,
+            UPDATE_GROUP_INACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        // The additions get what is left of the packet after the local players and the skips closing both groups
+        if let Some(join_packet_size) = join_packet_size {
+            let size = main_buf.writer().map_or(0, |writer| writer.len())
+                + mask_buf.position() as usize
+                + 2 * MAX_ADDITION_SIZE;
+            additions.bytes = Some(join_packet_size.saturating_sub(size));
+        }
+
+        // Write global player data (players that the player cannot see)
+        self.global_player_info(
+            player_id,
+            &mut main_buf,
+            &mut mask_buf,
+            &mut mask_sizes,
+            &mut additions,
+            UPDATE_GROUP_INACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        self.global_player_info(
+            player_id,
+            &mut main_buf,
+            &mut mask_buf,
+            &mut mask_sizes,
+            &mut additions,
+            UPDATE_GROUP_ACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        // Convert the main_buf into a writer
+        let mut vec = main_buf.into_writer();
+        let bit_size = vec.len();
+
+        // Decode the bit section like the client would, before the masks make it ambiguous
+        if let Some(records) = records {
+            let verified = decoder::verify_skip_counts(&vec, &records).and_then(|decoded_size| {
+                if decoded_size != bit_size {
+                    return Err(anyhow!(
+                        "Decoded {} bytes of the {} byte bit section",
+                        decoded_size,
+                        bit_size
+                    ));
+                }
+                Ok(())
+            });
+            if let Err(error) = verified {
+                report_anomaly(
+                    &mut self.anomaly_sink,
+                    Anomaly::SkipMismatch {
+                        observer: player_id,
+                        reason: error.to_string(),
+                    },
+                );
+                return Err(error);
+            }
+        }
+
+        // Write the mask_buf's data
+        vec.write_all(&mask_buf.get_ref()[..mask_buf.position() as usize])?;
+
+        self.report_oversized(player_id, vec.len(), bit_size, &mask_sizes);
+
+        #[cfg(feature = "alloc-metrics")]
+        self.tick_metrics
+            .record(allocations, vec.len(), mask_buf.position() as usize);
+
+        // Group the records
+        for i in 0..self.config.max_players {
+            self.group(player_id, i)?;
+        }
+        self.group_tail(player_id);
+
+        // Joining ends once every player in view fit in a single buffer
+        if additions.remaining > 0 {
+            self.observers[player_id].join_packet_size = None;
+        }
+
+        if let (Some(cache), Some(state), Some(state_hash), Some(previous_records)) =
+            (cache, state, state_hash, previous_records)
+        {
+            cache.encodings.push(CachedEncoding {
+                state_hash,
+                state,
+                previous_records,
+                packet: vec.clone(),
+                bit_size,
+                mask_sizes,
+                records: self.playerinfos[player_id].clone(),
+                transitions: self.tick_transitions[transitions_start..].to_vec(),
+            });
+        }
+
+        // Return the bit buffer including the mask buffer
+        Ok(vec)
+    }
+
+    fn local_player_info(
+        &mut self,
+        player_id: usize,
+        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+        mask_buf: &mut Cursor<Vec<u8>>,
+        mask_sizes: &mut MaskSizes,
+        update_group: i32,
+    ) -> Result<()> {
+        let mut skip_count = 0;
+        let rebuild_boundary = self.config.rebuild_boundary;
+        let view_distance = self.view_distance(player_id);
+        let interest = self.interest(player_id);
+
+        // Players past the max players never exist, so they are never local
+        for current_player_id in 0..self.config.max_players {
+            // Grab the playerinfo
+            let playerinfoentryother = self
+                .playerinfos
+                .get_mut(player_id)
+                .context("failed 1")?
+                .get_mut(current_player_id)
+                .context("failed 2")?;
+
+            // Test whether the playerinfo is local, and whether it is in the correct update group (active, inactive)
+            if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
+                continue;
+            }
+
+            // Check whether entries should be skipped
+            if skip_count > 0 {
+                skip_count -= 1;
+                playerinfoentryother.flags |= 0x2;
+                continue;
+            }
+
+            // Players that left the view of the observer are removed from its local players
+            if current_player_id != player_id
+                && in_view(
+                    &self.playerupdates,
+                    &interest,
+                    view_distance,
+                    current_player_id,
+                ) == Some(false)
+            {
+                playerinfoentryother.local_to_global = true;
+            }
+
+            // Check whether the local player should be removed and turned into a global player
+            if playerinfoentryother.local_to_global {
+                let (last_seen, coordinates) = match self
+                    .playerupdates
+                    .get(current_player_id)
+                    .and_then(|subject| Some((subject, subject.coordinates?)))
+                {
+                    Some((subject, coordinates)) => {
+                        // The client still sees the player where it was before any movement it did not receive
+                        let (dx, dy, dz) = subject.tick_delta();
+                        let (x, y, z) = playerinfoentryother.pending_movement.unwrap_or((0, 0, 0));
+                        let last_seen = Coordinates::new(
+                            coordinates.x - dx - x,
+                            coordinates.y - dy - y,
+                            coordinates.plane - dz - z,
+                        );
+                        (last_seen.region_hash(), coordinates.region_hash())
+                    }
+                    None => (
+                        playerinfoentryother.coordinates,
+                        playerinfoentryother.coordinates,
+                    ),
+                };
+
+                bit_buf.write_bit(true)?;
+                remove_local_player(bit_buf, last_seen, coordinates)?;
+                playerinfoentryother.coordinates = coordinates;
+                playerinfoentryother.reset = true;
+                self.tick_transitions.push(Transition::LocalToGlobal {
+                    observer: player_id,
+                    subject: current_player_id,
+                });
+                continue;
+            }
+
+            // Get the player updates
+            let player_updates = self
+                .playerupdates
+                .get_mut(current_player_id)
+                .context("testy boi")?;
+
+            // Take whatever the observer missed while suspended
+            let pending_movement = playerinfoentryother.pending_movement.take();
+            let own = current_player_id == player_id;
+            let mask_flags =
+                player_updates.observed_mask_flags(own) | playerinfoentryother.pending_mask_flags;
+            playerinfoentryother.pending_mask_flags = 0;
+
+            // Get whether there is mask or movement updates
+            let mask_update = mask_flags > 0;
+            let movement_update = pending_movement.is_some()
+                || !player_updates.movement_steps.is_empty()
+                || player_updates.displaced;
+
+            // Check whether a player update is needed
+            // If the player has a mask update, or it has a movement update, the first bit is set to true
+            // (player update in this context)
+            let player_update = mask_update || movement_update;
+
+            // Write the player update bool to signify whether a player needs to be updated or not
+            bit_buf.write_bit(player_update)?;
+
+            // Check if a player update is needed, else write the skip count
+            if player_update {
+                // Catch up on the movement missed while suspended, along with this tick's, in a single teleport
+                if let Some((x, y, z)) = pending_movement {
+                    let (dx, dy, dz) = player_updates.tick_delta();
+                    write_local_teleport(
+                        bit_buf,
+                        x + dx,
+                        y + dy,
+                        z + dz,
+                        mask_update,
+                        rebuild_boundary,
+                    )?;
+                // Else write a movement update
+                } else if movement_update {
+                    let movement = write_local_movement(
+                        bit_buf,
+                        player_updates,
+                        mask_update,
+                        rebuild_boundary,
+                    )?;
+                    let (kind, dx, dy, dz) = match movement {
+                        Some(movement) => movement,
+                        None => {
+                            let (dx, dy, dz) = player_updates.tick_delta();
+                            report_anomaly(
+                                &mut self.anomaly_sink,
+                                Anomaly::UnwritableMovement {
+                                    observer: player_id,
+                                    subject: current_player_id,
+                                    dx,
+                                    dy,
+                                },
+                            );
+                            write_local_teleport(
+                                bit_buf,
+                                dx,
+                                dy,
+                                dz,
+                                mask_update,
+                                rebuild_boundary,
+                            )?;
+                            (MovementKind::Teleport, dx, dy, dz)
+                        }
+                    };
+                    if !player_updates.movement_reported {
+                        player_updates.movement_reported = true;
+                        if let Some(hook) = self.movement_hook.as_mut() {
+                            hook(&EncodedMovement {
+                                player_id: current_player_id,
+                                kind,
+                                dx,
+                                dy,
+                                dz,
+                            });
+                        }
+                    }
+                // Else write to the bitbuffer that it should read masks
+                } else {
+                    write_mask_update_signal(bit_buf)?;
+                }
+            } else {
+                playerinfoentryother.flags |= 0x2;
+                skip_count = get_local_skip_count(
+                    &self.playerinfos,
+                    update_group,
+                    player_id,
+                    current_player_id + 1,
+                    self.config.max_players,
+                )?;
+                write_skip_count(bit_buf, skip_count, player_update)?;
+            }
+
+            // TODO: Move writing of masks to its own step.
+            // This is only here because the borrow checker errors on "get_local_skip_count" as the PlayerInfo struct is borrowed when that function is called
+            // Ideally this step should be after this whole block, so after write_skip_count.
+            if mask_update {
+                write_mask_update(
+                    mask_buf,
+                    player_updates,
+                    mask_flags,
+                    mask_sizes,
+                    (player_id, current_player_id),
+                    MaskEncoders {
+                        custom_masks: &self.custom_masks,
+                        equipment: self.equipment_provider.as_deref(),
+                    },
+                    &self.revision,
+                )
+                .inspect_err(|error| {
+                    report_missing_mask(&mut self.anomaly_sink, error, player_id, current_player_id)
+                })?;
+                player_updates.consumed_mask_flags |= player_updates.observed_mask_flags(own);
+            }
+        }
+
+        check_skip_count_exhausted(skip_count, update_group)?;
+
+        Ok(())
+    }
+
+    fn get_global_skip_count(
+        &mut self,
+        update_group: i32,
+        player_id: usize,
+        offset: usize,
+        can_add: bool,
+    ) -> Result<i32> {
+        let mut count = 0;
+        let enter_distance = self.enter_distance(player_id);
+        let interest = self.interest(player_id);
+
+        for i in offset..self.config.max_players {
+            // Grab the playerinfo
+            let playerinfoentryother = self
+                .playerinfos
+                .get_mut(player_id)
+                .context("failed 1")?
+                .get_mut(i)
+                .context("failed 2")?;
+
+            // Return if the playerinfo is not in this group
+            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
+                continue;
+            }
+
+            // Stop before the player to be added, which the records past the max players come after
+            if playerinfoentryother.global_to_local
+                || can_add
+                    && in_view(&self.playerupdates, &interest, enter_distance, i) == Some(true)
+            {
+                return Ok(count);
+            }
+
+            // Increment the skip count by 1
+            count += 1;
+        }
+
+        // Followed by the records past the max players when they are in this group
+        if self.tail_flags(player_id) == Some(update_group & 0x1) {
+            count += (MAX_PLAYERS - self.config.max_players) as i32;
+        }
+
+        Ok(count)
+    }
+
+    // Get the flags shared by the records past the max players, which are never local nor added, `None` without any.
+    // They are skipped as a whole instead of being visited, keeping the cost of small worlds down to their population
+    fn tail_flags(&self, player_id: usize) -> Option<i32> {
+        self.playerinfos
+            .get(player_id)?
+            .get(self.config.max_players)
+            .map(|record| record.flags)
+    }
+
+    // Move the records past the max players to the inactive group once skipped in the active group,
+    // which they then stay in for good
+    fn group_tail(&mut self, player_id: usize) {
+        if self.tail_flags(player_id) != Some(0) {
+            return;
+        }
+
+        let limit = self.config.max_players;
+        for (_, record) in self.playerinfos[player_id].iter_mut().skip(limit) {
+            record.flags = 1;
+        }
+    }
+
+    fn group(&mut self, player_id: usize, index: usize) -> Result<()> {
+        // Get the playerinfo
+        let playerinfoentryother = self
+            .playerinfos
+            .get_mut(player_id)
+            .context("failed getting playerinfoentry")?
+            .get_mut(index)
+            .context("failed playerinfoother")?;
+
+        // Shift its flags
+        playerinfoentryother.flags >>= 1;
+
+        // Check whether the playerinfoentry should be reset
+        // The coordinates are kept, as the client remembers the region of the player after removing it
+        if playerinfoentryother.reset {
+            playerinfoentryother.flags = 0;
+            playerinfoentryother.local = false;
+            playerinfoentryother.pending_movement = None;
+            playerinfoentryother.pending_mask_flags = 0;
+            playerinfoentryother.reset = false;
+            playerinfoentryother.local_to_global = false;
+            playerinfoentryother.global_to_local = false;
+        }
+
+        Ok(())
+    }
+
+    fn global_player_info(
+        &mut self,
+        player_id: usize,
+        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+        mask_buf: &mut Cursor<Vec<u8>>,
+        mask_sizes: &mut MaskSizes,
+        additions: &mut Additions,
+        update_group: i32,
+    ) -> Result<i32> {
+        let mut skip_count = 0;
+        let enter_distance = self.enter_distance(player_id);
+        let interest = self.interest(player_id);
+
+        for other_player_id in 0..self.config.max_players {
+            // Grab the playerinfo
+            let playerinfoentryother = self
+                .playerinfos
+                .get_mut(player_id)
+                .context("failed 1")?
+                .get_mut(other_player_id)
+                .context("failed 2")?;
+
+            // Test whether the playerinfo is global, and whether it is in the correct update group (active, inactive)
+            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
+                continue;
+            }
+
+            // Check whether entries should be skipped
+            if skip_count > 0 {
+                skip_count -= 1;
+                playerinfoentryother.flags |= 0x2;
+                continue;
+            }
+
+            // Add the player to the local players of the observer once it is within view,
+            // players past the additions of this tick are added in a later tick. Requested additions are always made
+            let requested = playerinfoentryother.global_to_local;
+            if requested
+                || additions.remaining > 0
+                    && in_view(
+                        &self.playerupdates,
+                        &interest,
+                        enter_distance,
+                        other_player_id,
+                    ) == Some(true)
+            {
+                let subject = self
+                    .playerupdates
+                    .get_mut(other_player_id)
+                    .context("failed getting added player")?;
+                let coordinates = subject
+                    .coordinates
+                    .context("missing coordinates of added player")?;
+
+                // The observer has never seen the player, so its lasting state is replayed along with any pending masks.
+                // Suppressed players still need their appearance to be rendered at all
+                let mask_flags = if subject.suppressed {
+                    subject.persistent_mask_flags() & APPEARANCE_MASK
+                } else {
+                    subject.mask_flags | subject.persistent_mask_flags()
+                };
+
+                // The masks are written first, so an addition that does not fit in the packet of a joining observer
+                // can be taken back and made in a later tick instead
+                let mask_start = mask_buf.position();
+                let previous_sizes = additions.bytes.map(|_| mask_sizes.clone());
+                if mask_flags != 0 {
+                    write_mask_update(
+                        mask_buf,
+                        subject,
+                        mask_flags,
+                        mask_sizes,
+                        (player_id, other_player_id),
+                        MaskEncoders {
+                            custom_masks: &self.custom_masks,
+                            equipment: self.equipment_provider.as_deref(),
+                        },
+                        &self.revision,
+                    )
+                    .inspect_err(|error| {
+                        report_missing_mask(
+                            &mut self.anomaly_sink,
+                            error,
+                            player_id,
+                            other_player_id,
+                        )
+                    })?;
+                }
+                let mask_size = (mask_buf.position() - mask_start) as usize;
+
+                if requested || additions.take(mask_size) {
+                    if mask_flags != 0 {
+                        subject.consumed_mask_flags |= subject.broadcast_mask_flags();
+                    }
+
+                    bit_buf.write_bit(true)?;
+                    write_player_addition(
+                        bit_buf,
+                        playerinfoentryother.coordinates,
+                        coordinates,
+                        mask_flags != 0,
+                    )?;
+
+                    playerinfoentryother.local = true;
+                    playerinfoentryother.global_to_local = false;
+                    playerinfoentryother.coordinates = coordinates.region_hash();
+                    playerinfoentryother.flags |= 0x2;
+                    self.tick_transitions.push(Transition::GlobalToLocal {
+                        observer: player_id,
+                        subject: other_player_id,
+                    });
+                    continue;
+                }
+
+                mask_buf.set_position(mask_start);
+                if let Some(previous_sizes) = previous_sizes {
+                    *mask_sizes = previous_sizes;
+                }
+            }
+
+            bit_buf.write_bit(false)?;
+
+            playerinfoentryother.flags |= 0x2;
+            skip_count = self.get_global_skip_count(
+                update_group,
+                player_id,
+                other_player_id + 1,
+                additions.remaining > 0,
+            )?;
+
+            write_skip_count(bit_buf, skip_count, false)?;
+        }
+
+        // The records past the max players are covered by the last skip count, unless an addition came right before
+        if self.tail_flags(player_id) == Some(update_group & 0x1) {
+            let tail = (MAX_PLAYERS - self.config.max_players) as i32;
+            if skip_count > 0 {
+                skip_count -= tail;
+            } else {
+                bit_buf.write_bit(false)?;
+                write_skip_count(bit_buf, tail - 1, false)?;
+            }
+        }
+
+        check_skip_count_exhausted(skip_count, update_group)?;
+
+        Ok(0)
+    }
+}
+
+/// Whether the subject is within view of an observer with the given interest, `None` when the coordinates of either
+/// are unknown
+fn in_view(
+    playerupdates: &Slab<PlayerUpdate>,
+    interest: &Interest,
+    view_distance: i32,
+    subject: usize,
+) -> Option<bool> {
+    match interest {
+        Interest::Center(center) => {
+            let center = (*center)?;
+            let subject = playerupdates.get(subject)?.coordinates?;
+
+            Some(center.plane == subject.plane && center.distance(subject) <= view_distance)
+        }
+        Interest::Distances(distances) => {
+            playerupdates.get(subject)?.coordinates?;
+
+            Some(
+                distances
+                    .get(subject)
+                    .copied()
+                    .flatten()
+                    .is_some_and(|distance| distance <= view_distance),
+            )
+        }
+    }
+}
+
+// A skip count left over at the end of a group was written for records that do not exist,
+// which misaligns the client for the rest of the packet
+fn check_skip_count_exhausted(skip_count: i32, update_group: i32) -> Result<()> {
+    if skip_count != 0 {
+        return Err(anyhow!(
+            "Skip count exceeds the records left in update group {} by {}",
+            update_group,
+            skip_count
+        ));
+    }
+
+    Ok(())
+}
+
+/// Write a run of records left without an update, as the amount of records following the first one
+pub(crate) fn write_skip_count(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    skip_count: i32,
+    _player_update: bool,
+) -> Result<()> {
+    if skip_count == 0 {
+        bit_buf.write(2, skip_count as u32)?;
+    } else if skip_count < 32 {
+        bit_buf.write(2, 1)?;
+        bit_buf.write(5, skip_count as u32)?;
+    } else if skip_count < 256 {
+        bit_buf.write(2, 2)?;
+        bit_buf.write(8, skip_count as u32)?;
+    } else {
+        if skip_count > MAX_PLAYERS as i32 {
+            return Err(anyhow!("Skip count out of range error"));
+        }
+        bit_buf.write(2, 3)?;
+        bit_buf.write(11, cmp::min(MAX_PLAYERS, skip_count as usize) as u32)?;
+    }
+
+    Ok(())
+}
+
+/// Generate the records of a player that has just logged in, where only the player itself is local
+fn new_playerinfoentry(player_id: usize, coordinates: i32) -> Slab<PlayerInfoData> {
+    let mut playerinfoentry = Slab::new();
+
+    for playerinfo in 0..MAX_PLAYERS {
+        if player_id == playerinfo {
+            add_playerinfodata(&mut playerinfoentry, true, coordinates)
+                .expect("failed adding update record for local player");
+        } else {
+            add_playerinfodata(&mut playerinfoentry, false, 0)
+                .expect("failed adding update record for external player");
+        }
+    }
+
+    playerinfoentry
+}
+
+fn add_playerinfodata(
+    playerinfo: &mut Slab<PlayerInfoData>,
+    local: bool,
+    coordinates: i32,
+) -> Result<()> {
+    playerinfo.insert(PlayerInfoData {
+        flags: 0,
+        local,
+        coordinates,
+        reset: false,
+        local_to_global: false,
+        global_to_local: false,
+        pending_movement: None,
+        pending_mask_flags: 0,
+    });
+
+    Ok(())
+}
+
+// The masks and their associated bit values
+pub const MOVEMENT_FORCED_MASK: u32 = 0x200;
+pub const SPOT_ANIMATION_MASK: u32 = 0x800;
+pub const SEQUENCE_MASK: u32 = 0x80;
+pub const APPEARANCE_MASK: u32 = 0x2;
+pub const SHOUT_MASK: u32 = 0x20;
+pub const LOCK_TURNTO_MASK: u32 = 0x4;
+pub const MOVEMENT_CACHED_MASK: u32 = 0x1000;
+pub const CHAT_MASK: u32 = 0x1;
+pub const NAME_MODIFIERS_MASK: u32 = 0x100;
+pub const HIT_MASK: u32 = 0x10;
+pub const MOVEMENT_TEMPORARY_MASK: u32 = 0x400;
+pub const DIRECTION_MASK: u32 = 0x8;
+pub const FACE_COORDINATE_MASK: u32 = 0x2000;
+// Past the second byte of the header, as its 0x40 bit is taken by the continuation marker
+#[cfg(feature = "tinting")]
+pub const TINT_MASK: u32 = 0x10000;
+
+// The bits marking that another byte of mask flags follows, in the first and second byte of the header
+pub(crate) const MASK_CONTINUATION_SHORT: u32 = 0x40;
+pub(crate) const MASK_CONTINUATION_MEDIUM: u32 = 0x4000;
+
+// Masks describing the lasting state of a player, e.g. what the player looks like and where it is facing
+const PERSISTENT_MASKS: u32 = APPEARANCE_MASK
+    | DIRECTION_MASK
+    | FACE_COORDINATE_MASK
+    | MOVEMENT_CACHED_MASK
+    | NAME_MODIFIERS_MASK
+    | LOCK_TURNTO_MASK;
+
+/// A mask that cannot be written to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskError {
+    /// Flags without a known mask
+    Unknown(u32),
+    /// A known mask that cannot be written yet, e.g. spot animations
+    Unwritable(u32),
+    /// A mask flagged as pending without its data
+    Missing(u32),
+}
+
+impl fmt::Display for MaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaskError::Unknown(flags) => write!(f, "unknown mask flags {:#x}", flags),
+            MaskError::Unwritable(mask) => write!(f, "mask {:#x} cannot be written", mask),
+            MaskError::Missing(mask) => write!(f, "mask {:#x} is flagged without its data", mask),
+        }
+    }
+}
+
+impl Error for MaskError {}
+
+/// The masks in which order they should be written out, as read by the default revision
+pub const MASKS: &[u32] = &[
+    MOVEMENT_FORCED_MASK,
+    SPOT_ANIMATION_MASK,
+    SEQUENCE_MASK,
+    APPEARANCE_MASK,
+    SHOUT_MASK,
+    LOCK_TURNTO_MASK,
+    MOVEMENT_CACHED_MASK,
+    CHAT_MASK,
+    NAME_MODIFIERS_MASK,
+    HIT_MASK,
+    MOVEMENT_TEMPORARY_MASK,
+    DIRECTION_MASK,
+    FACE_COORDINATE_MASK,
+    #[cfg(feature = "tinting")]
+    TINT_MASK,
+];
+
+// The continuation markers are read as part of the header, so no mask may use them
+const _: () = {
+    let mut i = 0;
+    while i < MASKS.len() {
+        assert!(MASKS[i] & (MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM) == 0);
+        assert!(MASKS[i] <= 0xFFFFFF);
+        i += 1;
+    }
+};
+
+// Write the masks of the subject as seen by the observer, given as the observer and the subject
+fn write_mask_update<R: Revision>(
+    mask_buf: &mut Cursor<Vec<u8>>,
+    playerinfo: &PlayerUpdate,
+    mask_flags: u32,
+    mask_sizes: &mut MaskSizes,
+    viewer: (PlayerKey, PlayerKey),
+    encoders: MaskEncoders,
+    revision: &R,
+) -> Result<()> {
+    let custom_masks = encoders.custom_masks;
+    // Flags the client does not know of would misalign everything it reads after them
+    let custom_flags = custom_masks
+        .iter()
+        .fold(0, |flags, custom| flags | custom.flag);
+    let unknown = MASKS
+        .iter()
+        .fold(mask_flags & !custom_flags, |flags, &mask| flags & !mask);
+    if unknown != 0 {
+        return Err(MaskError::Unknown(unknown).into());
+    }
+
+    // Registered masks are read from their own flags in every revision
+    let wire_flags = revision.wire_flags(mask_flags & !custom_flags) | mask_flags & custom_flags;
+    let header_start = mask_buf.position();
+    if wire_flags > 0xFFFF {
+        let header = wire_flags | MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM;
+        mask_buf.write_i8(header as i8)?;
+        mask_buf.write_i8((header >> 8) as i8)?;
+        mask_buf.write_i8((header >> 16) as i8)?;
+    } else if wire_flags > 0xFF {
+        let header = wire_flags | MASK_CONTINUATION_SHORT;
+        mask_buf.write_i8(header as i8)?;
+        mask_buf.write_i8((header >> 8) as i8)?;
+    } else {
+        mask_buf.write_i8(wire_flags as i8)?;
+    }
+    mask_sizes.header_size += (mask_buf.position() - header_start) as usize;
+
+    // Every flagged mask is written in the order the client reads them
+    for &mask in revision.masks() {
+        if mask_flags & mask == 0 {
+            continue;
+        }
+        let mask_start = mask_buf.position();
+
+        match mask {
+            APPEARANCE_MASK => {
+                write_cached_appearance_mask(&playerinfo.masks, encoders.equipment, mask_buf)
+            }
+            DIRECTION_MASK => write_mask::<DirectionMask>(&playerinfo.masks, mask_buf),
+            SEQUENCE_MASK => write_mask::<SequenceMask>(&playerinfo.masks, mask_buf),
+            CHAT_MASK => write_mask::<ChatMask>(&playerinfo.masks, mask_buf),
+            HIT_MASK => write_hit_mask(
+                playerinfo
+                    .masks
+                    .hit_mask
+                    .as_ref()
+                    .ok_or(MaskError::Missing(HIT_MASK))?,
+                Some(viewer),
+                mask_buf,
+            ),
+            MOVEMENT_FORCED_MASK => write_mask::<ForcedMovementMask>(&playerinfo.masks, mask_buf),
+            NAME_MODIFIERS_MASK => write_mask::<NameModifiersMask>(&playerinfo.masks, mask_buf),
+            SHOUT_MASK => write_mask::<ForcedChatMask>(&playerinfo.masks, mask_buf),
+            LOCK_TURNTO_MASK => write_mask::<FaceEntityMask>(&playerinfo.masks, mask_buf),
+            FACE_COORDINATE_MASK => write_mask::<FaceCoordinateMask>(&playerinfo.masks, mask_buf),
+            #[cfg(feature = "tinting")]
+            TINT_MASK => write_mask::<TintMask>(&playerinfo.masks, mask_buf),
+            MOVEMENT_TEMPORARY_MASK => {
+                write_mask::<TemporaryMoveSpeedMask>(&playerinfo.masks, mask_buf)
+            }
+            MOVEMENT_CACHED_MASK => write_move_speed(playerinfo.move_speed, mask_buf),
+            _ => Err(MaskError::Unwritable(mask).into()),
+        }?;
+
+        mask_sizes.record(mask, (mask_buf.position() - mask_start) as usize);
+    }
+
+    for custom in custom_masks {
+        if mask_flags & custom.flag == 0 {
+            continue;
+        }
+        let mask_start = mask_buf.position();
+
+        let (_, value) = playerinfo
+            .masks
+            .custom_masks
+            .iter()
+            .find(|(flag, _)| *flag == custom.flag)
+            .ok_or(MaskError::Missing(custom.flag))?;
+        custom.encoder.encode_any(value.as_ref(), mask_buf)?;
+
+        mask_sizes.record(custom.flag, (mask_buf.position() - mask_start) as usize);
+    }
+
+    Ok(())
+}
+
+fn write_mask<M: Mask>(masks: &PlayerMasks, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    M::slot(masks)
+        .as_ref()
+        .ok_or(MaskError::Missing(M::FLAG))?
+        .encode(mask_buf)
+}
+
+fn remove_local_player(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    record_coordinates: i32,
+    new_coordinates: i32,
+) -> Result<()> {
+    let coordinate_change = new_coordinates != record_coordinates;
+
+    // Removed players have no masks
+    bit_buf.write_bit(false)?;
+    bit_buf.write(2, 0)?;
+    bit_buf.write_bit(coordinate_change)?;
+
+    if coordinate_change {
+        write_coordinate_multiplier(bit_buf, record_coordinates, new_coordinates)?;
+    }
+
+    Ok(())
+}
+
+fn write_player_addition(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    record_coordinates: i32,
+    coordinates: Coordinates,
+    mask_update: bool,
+) -> Result<()> {
+    let new_coordinates = coordinates.region_hash();
+    let coordinate_change = new_coordinates != record_coordinates;
+
+    bit_buf.write(2, 0)?;
+    bit_buf.write_bit(coordinate_change)?;
+
+    if coordinate_change {
+        write_coordinate_multiplier(bit_buf, record_coordinates, new_coordinates)?;
+    }
+
+    bit_buf.write(13, coordinates.x & 0x1FFF)?;
+    bit_buf.write(13, coordinates.y & 0x1FFF)?;
+    bit_buf.write_bit(mask_update)?;
+
+    Ok(())
+}
+
+fn write_coordinate_multiplier(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    old_multiplier: i32,
+    new_multiplier: i32,
+) -> Result<()> {
+    let current_multiplier_y = new_multiplier & 0xFF;
+    let current_multiplier_x = (new_multiplier >> 8) & 0xFF;
+    let current_level = (new_multiplier >> 16) & 0x3;
+
+    let last_multiplier_y = old_multiplier & 0xFF;
+    let last_multiplier_x = (old_multiplier >> 8) & 0xFF;
+    let last_level = (old_multiplier >> 16) & 0x3;
+
+    let diff_x = current_multiplier_x - last_multiplier_x;
+    let diff_y = current_multiplier_y - last_multiplier_y;
+    // The client adds the level delta and wraps it to 2 bits
+    let diff_level = (current_level - last_level) & 0x3;
+
+    let level_change = diff_x == 0 && diff_y == 0;
+    let small_change = diff_x.abs() <= 1 && diff_y.abs() <= 1;
+
+    if level_change {
+        bit_buf.write(2, 1)?;
+        bit_buf.write(2, diff_level as u32)?;
+    } else if small_change {
+        let direction;
+
+        if diff_x == -1 && diff_y == -1 {
+            direction = 0;
+        } else if diff_x == 1 && diff_y == -1 {
+            direction = 2;
+        } else if diff_x == -1 && diff_y == 1 {
+            direction = 5;
+        } else if diff_x == 1 && diff_y == 1 {
+            direction = 7;
+        } else if diff_y == -1 {
+            direction = 1;
+        } else if diff_x == -1 {
+            direction = 3;
+        } else if diff_x == 1 {
+            direction = 4;
+        } else {
+            direction = 6;
+        }
+
+        bit_buf.write(2, 2)?;
+        bit_buf.write(2, diff_level as u32)?;
+        bit_buf.write(3, direction)?;
+    } else {
+        bit_buf.write(2, 3)?;
+        bit_buf.write(2, diff_level as u32)?;
+        bit_buf.write(8, diff_x as u32 & 0xFF)?;
+        bit_buf.write(8, diff_y as u32 & 0xFF)?;
+    }
+
+    Ok(())
+}
+
+// Write the movement of a local player, returning the kind of movement and the delta that were written
+fn write_local_movement(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    playerinfoentry: &PlayerUpdate,
+    mask_update: bool,
+    rebuild_boundary: i32,
+) -> Result<Option<(MovementKind, i32, i32, i32)>> {
+    let direction_diff_x = [-1, 0, 1, -1, 1, -1, 0, 1];
+    let direction_diff_y = [-1, -1, -1, 0, 0, 1, 1, 1];
+
+    let movement_update = &playerinfoentry.movement_update;
+
+    let large_change =
+        movement_update.x.abs() >= rebuild_boundary || movement_update.y.abs() >= rebuild_boundary;
+    let teleport = playerinfoentry.displaced || large_change;
+
+    if teleport {
+        write_local_teleport(
+            bit_buf,
+            movement_update.x,
+            movement_update.y,
+            movement_update.z,
+            mask_update,
+            rebuild_boundary,
+        )?;
+        return Ok(Some((
+            MovementKind::Teleport,
+            movement_update.x,
+            movement_update.y,
+            movement_update.z,
+        )));
+    }
+
+    let movement_steps = &playerinfoentry.movement_steps;
+    let walk_step = movement_steps.first().context("failed getting walk step")?;
+    let walk_rotation = get_direction_rotation(walk_step)?;
+
+    let mut dx = *direction_diff_x.get(walk_rotation as usize).context("dx")?;
+    let mut dy = *direction_diff_y.get(walk_rotation as usize).context("dy")?;
+
+    let mut running = false;
+    let mut direction = 0;
+
+    if let Some(run_step) = movement_steps.get(1) {
+        let run_rotation = get_direction_rotation(run_step)?;
+
+        dx += *direction_diff_x
+            .get(run_rotation as usize)
+            .context("dx 2")?;
+        dy += *direction_diff_y
+            .get(run_rotation as usize)
+            .context("dy 2")?;
+
+        if let Some(run_dir) = run_dir(dx, dy) {
+            direction = run_dir;
+            running = true;
+        }
+    }
+
+    if !running {
+        match walk_dir(dx, dy) {
+            Some(walk_dir) => direction = walk_dir,
+            // Nothing is written for steps cancelling each other out, which no direction expresses
+            None => return Ok(None),
+        }
+    }
+
+    bit_buf.write_bit(mask_update)?;
+    if running {
+        bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
+        bit_buf.write(4, direction)?;
+
+        Ok(Some((MovementKind::Run, dx, dy, 0)))
+    } else {
+        bit_buf.write(2, LOCAL_MOVEMENT_WALK)?;
+        bit_buf.write(3, direction)?;
+
+        // The delta of the direction written, which the client moves the player by
+        Ok(Some((
+            MovementKind::Walk,
+            direction_diff_x[direction as usize],
+            direction_diff_y[direction as usize],
+            0,
+        )))
+    }
+}
+
+fn write_local_teleport(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    dx: i32,
+    dy: i32,
+    dz: i32,
+    mask_update: bool,
+    rebuild_boundary: i32,
+) -> Result<()> {
+    let large_change = dx.abs() >= rebuild_boundary || dy.abs() >= rebuild_boundary;
+
+    bit_buf.write_bit(mask_update)?;
+    bit_buf.write(2, LOCAL_MOVEMENT_TELEPORT)?;
+    bit_buf.write_bit(large_change)?;
+    bit_buf.write(2, dz & 0x3)?;
+
+    if large_change {
+        bit_buf.write(14, dx & 0x3FFF)?;
+        bit_buf.write(14, dy & 0x3FFF)?;
+    } else {
+        bit_buf.write(5, dx & 0x1F)?;
+        bit_buf.write(5, dy & 0x1F)?;
+    }
+
+    Ok(())
+}
+
+fn write_mask_update_signal(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+) -> Result<()> {
+    bit_buf.write_bit(true)?;
+    bit_buf.write(2, LOCAL_MOVEMENT_NONE)?;
+
+    Ok(())
+}
+
+fn write_direction_mask(
+    direction_mask: &DirectionMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i16_add(direction_mask.direction)?;
+
+    Ok(())
+}
+
+fn write_sequence_mask(sequence_mask: &SequenceMask, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    mask_buf.write_i16_le(sequence_mask.id)?;
+    mask_buf.write_i8_neg(sequence_mask.delay as i8)?;
+
+    Ok(())
+}
+
+// Every worn item and identity kit must fit the slot it is written to, past the offset of its kind
+fn validate_appearance_mask(appearance_mask: &AppearanceMask) -> Result<()> {
+    let items = [
+        appearance_mask.head,
+        appearance_mask.cape,
+        appearance_mask.neck,
+        appearance_mask.weapon,
+        appearance_mask.body,
+        appearance_mask.shield,
+    ];
+    for item in items {
+        appearance_item_slot(item)?;
+    }
+    let kits = [
+        appearance_mask.torso,
+        appearance_mask.arms,
+        appearance_mask.legs,
+        appearance_mask.hair,
+        appearance_mask.hands,
+        appearance_mask.feet,
+        appearance_mask.beard,
+    ];
+    for kit in kits {
+        appearance_kit_slot(kit)?;
+    }
+
+    Ok(())
+}
+
+fn validate_chat_mask(chat_mask: &ChatMask) -> Result<()> {
+    if chat_mask.text.len() > u8::MAX as usize {
+        return Err(anyhow!(
+            "Chat text of {} bytes exceeds {} bytes",
+            chat_mask.text.len(),
+            u8::MAX
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_name_modifiers_mask(name_modifiers_mask: &NameModifiersMask) -> Result<()> {
+    for modifier in [
+        &name_modifiers_mask.prefix,
+        &name_modifiers_mask.infix,
+        &name_modifiers_mask.suffix,
+    ] {
+        validate_cp1252(modifier, MAX_NAME_MODIFIER_LENGTH)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_face_entity_mask(face_entity_mask: &FaceEntityMask) -> Result<()> {
+    match face_entity_mask.entity {
+        FaceEntity::Player(player_id) if player_id >= MAX_PLAYERS => {
+            Err(anyhow!("Player index {} is out of range", player_id))
+        }
+        FaceEntity::Npc(npc_id) if npc_id >= FACE_ENTITY_NPC_OFFSET => {
+            Err(anyhow!("Npc index {} is out of range", npc_id))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn validate_face_coordinate_mask(
+    face_coordinate_mask: &FaceCoordinateMask,
+) -> Result<()> {
+    Coordinates::new(face_coordinate_mask.x, face_coordinate_mask.y, 0).validate()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "tinting")]
+fn validate_tint_mask(tint_mask: &TintMask) -> Result<()> {
+    if tint_mask.end_delay < tint_mask.start_delay {
+        return Err(anyhow!(
+            "Tint ends at {} before it starts at {}",
+            tint_mask.end_delay,
+            tint_mask.start_delay
+        ));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_cp1252(text: &str, max_length: usize) -> Result<()> {
+    if text.len() > max_length {
+        return Err(anyhow!(
+            "Text of {} bytes exceeds {} bytes",
+            text.len(),
+            max_length
+        ));
+    }
+    // A nul character terminates the string early in the client, and non-ASCII characters differ in CP1252
+    if text.bytes().any(|byte| byte == 0 || !byte.is_ascii()) {
+        return Err(anyhow!(
+            "Text {:?} cannot be written as a CP1252 string",
+            text
+        ));
+    }
+
+    Ok(())
+}
+
+fn write_chat_mask(chat_mask: &ChatMask, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    mask_buf.write_u16_le((chat_mask.colour as u16) << 8 | chat_mask.effect as u16)?;
+    mask_buf.write_u8(chat_mask.rights_icon)?;
+    mask_buf.write_u8(chat_mask.autotyper as u8)?;
+    mask_buf.write_u8(chat_mask.text.len() as u8)?;
+    mask_buf.write_all(&chat_mask.text)?;
+
+    Ok(())
+}
+
+pub(crate) fn validate_hit_mask(hit_mask: &HitMask) -> Result<()> {
+    if hit_mask.hitsplats.is_empty() && hit_mask.health_bars.is_empty() {
+        return Err(anyhow!("Hit mask has neither hitsplats nor health bars"));
+    }
+    if hit_mask.hitsplats.len() > MAX_HITSPLATS || hit_mask.health_bars.len() > MAX_HITSPLATS {
+        return Err(anyhow!(
+            "Hit mask exceeds {} hitsplats or health bars",
+            MAX_HITSPLATS
+        ));
+    }
+    // The client reserves the highest smart values for hitsplats with two parts and empty hitsplats
+    for hitsplat in &hit_mask.hitsplats {
+        let types = [hitsplat.source_type, hitsplat.victim_type];
+        if hitsplat.hitsplat_type >= 32766
+            || types.iter().flatten().any(|&variant| variant >= 32766)
+            || hitsplat.damage > 32767
+            || hitsplat.delay > 32767
+        {
+            return Err(anyhow!("Hitsplat values exceed what the client can read"));
+        }
+    }
+    for health_bar in &hit_mask.health_bars {
+        if health_bar.health_bar >= 32767 || health_bar.delay > 32767 {
+            return Err(anyhow!("Health bar values exceed what the client can read"));
+        }
+        if health_bar.health_percentage > 100 {
+            return Err(anyhow!(
+                "Health percentage {} exceeds 100",
+                health_bar.health_percentage
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Write the hit mask as seen by the given observer of the subject, or as seen by bystanders without one
+pub(crate) fn write_hit_mask(
+    hit_mask: &HitMask,
+    viewer: Option<(PlayerKey, PlayerKey)>,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i8_add(hit_mask.hitsplats.len() as i8)?;
+    for hitsplat in &hit_mask.hitsplats {
+        let hitsplat_type = match viewer {
+            Some((observer, subject)) => hitsplat.type_for(observer, subject),
+            None => hitsplat.hitsplat_type,
+        };
+        mask_buf.write_u16_smart(hitsplat_type)?;
+        mask_buf.write_u16_smart(hitsplat.damage)?;
+        mask_buf.write_u16_smart(hitsplat.delay)?;
+    }
+
+    // Health bars are filled relative to the width of the default health bar, without transitioning
+    mask_buf.write_i8_neg(hit_mask.health_bars.len() as i8)?;
+    for health_bar in &hit_mask.health_bars {
+        mask_buf.write_u16_smart(health_bar.health_bar)?;
+        mask_buf.write_u16_smart(health_bar.delay)?;
+        mask_buf.write_u16_smart(0)?;
+        mask_buf
+            .write_i8_sub((health_bar.health_percentage as u16 * HEALTH_BAR_WIDTH / 100) as i8)?;
+    }
+
+    Ok(())
+}
+
+fn write_move_speed(move_speed: MoveSpeed, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    mask_buf.write_i8_add(move_speed as i8)?;
+
+    Ok(())
+}
+
+pub(crate) fn validate_forced_movement_mask(
+    forced_movement_mask: &ForcedMovementMask,
+) -> Result<()> {
+    // The client divides by the time spent moving to the end tile
+    if forced_movement_mask.end_delay == 0 {
+        return Err(anyhow!("Forced movement must take at least a client cycle"));
+    }
+    if forced_movement_mask
+        .start_delay
+        .checked_add(forced_movement_mask.end_delay)
+        .is_none()
+    {
+        return Err(anyhow!("Forced movement takes too many client cycles"));
+    }
+    if forced_movement_mask.direction >= 2048 {
+        return Err(anyhow!(
+            "Forced movement direction {} exceeds 2047",
+            forced_movement_mask.direction
+        ));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn write_forced_movement_mask(
+    forced_movement_mask: &ForcedMovementMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i8_sub(forced_movement_mask.start_dx)?;
+    mask_buf.write_i8_add(forced_movement_mask.start_dy)?;
+    mask_buf.write_i8_neg(forced_movement_mask.end_dx)?;
+    mask_buf.write_i8(forced_movement_mask.end_dy)?;
+    mask_buf.write_u16_le(forced_movement_mask.start_delay)?;
+    mask_buf.write_u16(forced_movement_mask.start_delay + forced_movement_mask.end_delay)?;
+    mask_buf.write_i16_add(forced_movement_mask.direction as i16)?;
+
+    Ok(())
+}
+
+// Write the appearance mask, encoded by the first observer and shared by every observer and addition after it
+fn write_cached_appearance_mask(
+    masks: &PlayerMasks,
+    equipment: Option<&dyn EquipmentProvider>,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    let encoding = match masks.appearance_encoding.get() {
+        Some(encoding) => encoding,
+        None => {
+            let appearance_mask = masks
+                .appearance_mask
+                .as_ref()
+                .ok_or(MaskError::Missing(APPEARANCE_MASK))?;
+            let mut encoding = Cursor::new(Vec::new());
+            write_appearance_mask(appearance_mask, equipment, &mut encoding)?;
+            masks
+                .appearance_encoding
+                .get_or_init(|| encoding.into_inner())
+        }
+    };
+    mask_buf.write_all(encoding)?;
+
+    Ok(())
+}
+
+fn write_appearance_mask(
+    appearance_mask: &AppearanceMask,
+    equipment: Option<&dyn EquipmentProvider>,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    let mut temp_buf = Cursor::new(Vec::new());
+
+    temp_buf.write_i8(appearance_mask.gender)?;
+    if appearance_mask.skull {
+        temp_buf.write_i8(1)?;
+    } else {
+        temp_buf.write_i8(-1)?;
+    }
+
+    temp_buf.write_i8(appearance_mask.overhead_prayer)?;
+
+    match appearance_mask.morph {
+        AppearanceMorph::Player => {
+            write_appearance_slots(&mut temp_buf, appearance_mask, equipment)?
+        }
+        AppearanceMorph::Npc(npc) => {
+            temp_buf.write_i16(APPEARANCE_NPC_MARKER)?;
+            temp_buf.write_u16(npc)?;
+        }
+        AppearanceMorph::Loc(loc) => {
+            temp_buf.write_i16(APPEARANCE_LOC_MARKER)?;
+            temp_buf.write_u16(loc)?;
+        }
+    }
+
+    temp_buf.write_i8(appearance_mask.colors_hair)?;
+    temp_buf.write_i8(appearance_mask.colors_torso)?;
+    temp_buf.write_i8(appearance_mask.colors_legs)?;
+    temp_buf.write_i8(appearance_mask.colors_feet)?;
+    temp_buf.write_i8(appearance_mask.colors_skin)?;
+
+    temp_buf.write_i16(appearance_mask.weapon_stance_stand)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_walk)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn180)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn90cw)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn90ccw)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_run)?;
+
+    temp_buf.write_string_cp1252(&appearance_mask.username)?;
+    temp_buf.write_i8(appearance_mask.combat_level)?;
+    temp_buf.write_i16(appearance_mask.skill_id_level)?;
+    temp_buf.write_i8(appearance_mask.hidden)?;
+
+    mask_buf.write_i8(temp_buf.position() as i8)?;
+
+    mask_buf.write_bytes_reversed_add(temp_buf.get_ref())?;
+
+    Ok(())
+}
+
+// Write the twelve slots of the equipment and body parts, each an empty byte, a worn item or an identity kit
+fn write_appearance_slots(
+    buf: &mut Cursor<Vec<u8>>,
+    appearance_mask: &AppearanceMask,
+    equipment: Option<&dyn EquipmentProvider>,
+) -> Result<()> {
+    // Items without a worn model are left out, showing the identity kit of their slot if it has one
+    let worn = |item: i16| match equipment {
+        Some(equipment) if item != 0 => equipment.worn_item(item).map_or(0, |_| item),
+        _ => item,
+    };
+    // The body parts hidden by the worn items, as flagged on the mask or by the definitions of the items
+    let worn_flags = |item: i16| {
+        equipment
+            .filter(|_| item != 0)
+            .and_then(|equipment| equipment.worn_item(item))
+            .unwrap_or_default()
+    };
+    let (body, head) = (
+        worn_flags(appearance_mask.body),
+        worn_flags(appearance_mask.head),
+    );
+    let hide_arms = appearance_mask.is_full_body || body.is_full_body;
+    let hide_hair = appearance_mask.covers_hair || head.covers_hair;
+    let hide_beard = appearance_mask.covers_face || head.covers_face || appearance_mask.gender != 0;
+
+    write_appearance_item(buf, worn(appearance_mask.head))?;
+    write_appearance_item(buf, worn(appearance_mask.cape))?;
+    write_appearance_item(buf, worn(appearance_mask.neck))?;
+    write_appearance_item(buf, worn(appearance_mask.weapon))?;
+    match worn(appearance_mask.body) {
+        0 => write_appearance_kit(buf, appearance_mask.torso, false)?,
+        body => write_appearance_item(buf, body)?,
+    }
+    write_appearance_item(buf, worn(appearance_mask.shield))?;
+    write_appearance_kit(buf, appearance_mask.arms, hide_arms)?;
+    write_appearance_kit(buf, appearance_mask.legs, false)?;
+    write_appearance_kit(buf, appearance_mask.hair, hide_hair)?;
+    write_appearance_kit(buf, appearance_mask.hands, false)?;
+    write_appearance_kit(buf, appearance_mask.feet, false)?;
+    write_appearance_kit(buf, appearance_mask.beard, hide_beard)?;
+
+    Ok(())
+}
+
+// Write the item worn in an equipment slot, where an empty slot is a single zero byte
+fn write_appearance_item(buf: &mut Cursor<Vec<u8>>, item: i16) -> Result<()> {
+    match appearance_item_slot(item)? {
+        Some(slot) => buf.write_u16(slot)?,
+        None => buf.write_i8(0)?,
+    }
+
+    Ok(())
+}
+
+// Write the identity kit of a body part, where a hidden part or a part without a kit is an empty slot
+fn write_appearance_kit(buf: &mut Cursor<Vec<u8>>, kit: i16, hidden: bool) -> Result<()> {
+    match appearance_kit_slot(kit)? {
+        Some(slot) if !hidden => buf.write_u16(slot)?,
+        _ => buf.write_i8(0)?,
+    }
+
+    Ok(())
+}
+
+// Get the value of the slot of a worn item, none for 0. Items must stay below the markers of morphed players
+fn appearance_item_slot(item: i16) -> Result<Option<u16>> {
+    if item == 0 {
+        return Ok(None);
+    }
+
+    u16::try_from(item)
+        .ok()
+        .and_then(|item| item.checked_add(APPEARANCE_ITEM_OFFSET as u16))
+        .filter(|&slot| slot <= i16::MAX as u16)
+        .map(Some)
+        .ok_or_else(|| anyhow!("Worn item {} is out of range", item))
+}
+
+// Get the value of the slot of an identity kit, none for -1. Kits must stay below the offset of the worn items
+fn appearance_kit_slot(kit: i16) -> Result<Option<u16>> {
+    match kit {
+        -1 => Ok(None),
+        0..APPEARANCE_KIT_LIMIT => Ok(Some((APPEARANCE_IDK_OFFSET + kit) as u16)),
+        _ => Err(anyhow!("Identity kit {} is out of range", kit)),
+    }
+}
+
+fn get_direction_rotation(some_movement: &(i32, i32)) -> Result<i32> {
+    match some_movement {
+        (-1, -1) => Ok(0),
+        (0, -1) => Ok(1),
+        (1, -1) => Ok(2),
+        (-1, 0) => Ok(3),
+        (1, 0) => Ok(4),
+        (-1, 1) => Ok(5),
+        (0, 1) => Ok(6),
+        (1, 1) => Ok(7),
+        _ => Err(anyhow!("Failed getting direction rotation")),
+    }
+}
+
+fn run_dir(dx: i32, dy: i32) -> Option<i32> {
+    match (dx, dy) {
+        (-2, -2) => Some(0),
+        (-1, -2) => Some(1),
+        (0, -2) => Some(2),
+        (1, -2) => Some(3),
+        (2, -2) => Some(4),
+        (-2, -1) => Some(5),
+        (2, -1) => Some(6),
+        (-2, 0) => Some(7),
+        (2, 0) => Some(8),
+        (-2, 1) => Some(9),
+        (2, 1) => Some(10),
+        (-2, 2) => Some(11),
+        (-1, 2) => Some(12),
+        (0, 2) => Some(13),
+        (1, 2) => Some(14),
+        (2, 2) => Some(15),
+        _ => None,
+    }
+}
+
+fn walk_dir(dx: i32, dy: i32) -> Option<i32> {
+    match (dx, dy) {
+        (-1, -1) => Some(0),
+        (0, -1) => Some(1),
+        (1, -1) => Some(2),
+        (-1, 0) => Some(3),
+        (1, 0) => Some(4),
+        (-1, 1) => Some(5),
+        (0, 1) => Some(6),
+        (1, 1) => Some(7),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::CoordinateError;
+    use crate::decoder::DecodedUpdate;
+    use crate::determinism::SplitMix64;
+    use crate::scenario::{scenario_with_config, EAST};
+
+    #[test]
+    fn add_player_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        assert_eq!(playerinfo.playerinfos.len(), 1);
+
+        // Regions past the highest coordinate and planes past the 2 bits of the hash are rejected
+        for region_hash in [123, 0x200, 0x40000, -1] {
+            assert!(playerinfo.add_player(region_hash).is_err());
+        }
+        assert_eq!(playerinfo.playerinfos.len(), 1);
+
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            max_players: 1,
+            ..Default::default()
+        })?;
+        playerinfo.add_player(0x101)?;
+        assert!(playerinfo.add_player(0x100).is_err());
+        assert_eq!(playerinfo.config().max_players, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_player_records_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+
+        // Every observer has a single record of each player, of which only its own is local
+        for (observer, records) in playerinfo.playerinfos.iter() {
+            assert_eq!(records.len(), MAX_PLAYERS);
+            for (subject, record) in records.iter() {
+                assert_eq!(record.local, subject == observer);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn mask_coalescing_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 1536 })?;
+
+        let masks = playerinfo.get_player_masks(0)?;
+        assert_eq!(
+            masks.direction_mask.as_ref().map(|mask| mask.direction),
+            Some(1536)
+        );
+
+        let hitsplat = |damage| HitMask {
+            hitsplats: vec![Hitsplat {
+                damage,
+                ..Default::default()
+            }],
+            health_bars: Vec::new(),
+        };
+        let mut pending = Some(hitsplat(1));
+        assert!(!MaskCoalescing::Append(1).coalesce(&mut pending, hitsplat(2)));
+        assert_eq!(pending, Some(hitsplat(1)));
+
+        const { assert!(AppearanceMask::REPLAY && DirectionMask::REPLAY && !SequenceMask::REPLAY) };
+        assert!(playerinfo.set_mask(0, DirectionMask { direction: 0 })?);
+        assert!(playerinfo.set_mask(
+            0,
+            SequenceMask {
+                id: 808,
+                delay: 0,
+                priority: 5
+            }
+        )?);
+        assert!(!playerinfo.set_mask(
+            0,
+            SequenceMask {
+                id: 809,
+                delay: 0,
+                priority: 4
+            }
+        )?);
+        assert_eq!(
+            playerinfo.playerupdates[0].mask_flags,
+            DirectionMask::FLAG | SequenceMask::FLAG
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mask_expiry_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.set_mask_expiry(Some(2));
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.add_player_sequence_mask(
+            0,
+            SequenceMask {
+                id: 808,
+                delay: 0,
+                priority: 0,
+            },
+            false,
+        )?;
+        playerinfo.tick();
+        assert!(playerinfo.get_player_masks(0)?.sequence_mask.is_some());
+
+        // The direction stays known as the facing of the player, only its pending update expires
+        playerinfo.tick();
+        let masks = playerinfo.get_player_masks(0)?;
+        assert!(masks.sequence_mask.is_none());
+        assert!(masks.direction_mask.is_some());
+        assert_eq!(playerinfo.playerupdates[0].mask_flags, 0);
+
+        Ok(())
+    }
+
+    // Drive the PlayerInfo with random players, movement, masks and requests, followed by quiet ticks
+    fn run_random_ticks(seed: u64, config: WorldInfoConfig) -> Result<PlayerInfo> {
+        let mut rng = SplitMix64(seed);
+        let mut playerinfo = PlayerInfo::with_config(config)?;
+        let random_coordinates = |rng: &mut SplitMix64| {
+            Coordinates::new(3200 + rng.below(40) as i32, 3200 + rng.below(40) as i32, 0)
+        };
+
+        for _ in 0..64 {
+            let players: Vec<PlayerKey> =
+                playerinfo.playerinfos.iter().map(|(key, _)| key).collect();
+            if players.len() < 24 && rng.below(3) == 0 {
+                playerinfo.add_player_at(random_coordinates(&mut rng))?;
+            }
+            if !players.is_empty() && rng.below(16) == 0 {
+                playerinfo.remove_player(players[rng.below(players.len() as u64) as usize])?;
+            }
+
+            let players: Vec<PlayerKey> =
+                playerinfo.playerinfos.iter().map(|(key, _)| key).collect();
+            for &player_id in &players {
+                match rng.below(12) {
+                    0..=3 => {
+                        let (dx, dy) = [
+                            (0, 1),
+                            (1, 1),
+                            (1, 0),
+                            (1, -1),
+                            (0, -1),
+                            (-1, -1),
+                            (-1, 0),
+                            (-1, 1),
+                        ][rng.below(8) as usize];
+                        playerinfo.queue_player_step(player_id, dx, dy).ok();
+                    }
+                    4 => playerinfo.teleport_player(player_id, random_coordinates(&mut rng))?,
+                    5 => playerinfo.suspend_player(player_id)?,
+                    6 => playerinfo.set_player_idle(player_id, rng.below(2) == 0)?,
+                    7 => {
+                        let subject_id = players[rng.below(players.len() as u64) as usize];
+                        playerinfo
+                            .request_local_to_global(player_id, subject_id)
+                            .ok();
+                        playerinfo
+                            .request_global_to_local(player_id, subject_id)
+                            .ok();
+                    }
+                    _ => playerinfo.resume_player(player_id)?,
+                }
+                if rng.below(2) == 0 {
+                    playerinfo.add_player_direction_mask(
+                        player_id,
+                        DirectionMask {
+                            direction: rng.below(2048) as i16,
+                        },
+                    )?;
+                }
+            }
+
+            for (player_id, result) in playerinfo.process_all() {
+                result.with_context(|| {
+                    format!("seed {} failed encoding player {}", seed, player_id)
+                })?;
+            }
+            playerinfo.tick();
+        }
+
+        let players: Vec<PlayerKey> = playerinfo.playerinfos.iter().map(|(key, _)| key).collect();
+        for &player_id in &players {
+            playerinfo.resume_player(player_id)?;
+            playerinfo.set_player_idle(player_id, false)?;
+        }
+
+        // Quiet ticks, until every player in view has been added and every player out of view removed
+        for _ in 0..16 {
+            for (_, result) in playerinfo.process_all() {
+                result?;
+            }
+            playerinfo.tick();
+            if playerinfo.transitions().is_empty() {
+                break;
+            }
+        }
+
+        Ok(playerinfo)
+    }
+
+    #[test]
+    fn steady_state_property_test() -> Result<()> {
+        for seed in 0..16 {
+            let config = if seed % 8 == 0 {
+                WorldInfoConfig::default()
+            } else {
+                WorldInfoConfig::small_world(32)
+            };
+            let mut playerinfo = run_random_ticks(seed, config)?;
+
+            // A tick without any updates returns every record to its steady state
+            for (_, result) in playerinfo.process_all() {
+                result?;
+            }
+            playerinfo.tick();
+            assert!(playerinfo.transitions().is_empty(), "seed {}", seed);
+
+            for (observer_id, records) in playerinfo.playerinfos.iter() {
+                for (subject_id, record) in records.iter() {
+                    assert!(
+                        record.is_steady(),
+                        "seed {} record of {} seen by {}: flags {}, reset {}, local to global {}, global to local {}",
+                        seed,
+                        subject_id,
+                        observer_id,
+                        record.flags,
+                        record.reset,
+                        record.local_to_global,
+                        record.global_to_local
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_and_replace_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let sequence = |id, priority| SequenceMask {
+            id,
+            delay: 0,
+            priority,
+        };
+        playerinfo.add_player_sequence_mask(0, sequence(422, 6), false)?;
+        playerinfo.add_player_forced_chat_mask(
+            0,
+            ForcedChatMask {
+                text: "hi".to_string(),
+            },
+        )?;
+        assert!(playerinfo.clear_player_mask(0, SEQUENCE_MASK)?);
+        assert!(!playerinfo.clear_player_mask(0, SEQUENCE_MASK)?);
+        assert!(playerinfo.clear_player_mask(0, 0x40).is_err());
+        assert!(playerinfo.get_player_masks(0)?.sequence_mask.is_none());
+        assert_eq!(playerinfo.playerupdates[0].mask_flags, SHOUT_MASK);
+
+        // The replacement applies regardless of the priority of the pending sequence
+        playerinfo.add_player_sequence_mask(0, sequence(422, 6), false)?;
+        playerinfo.replace_mask(0, sequence(829, 5))?;
+        assert_eq!(
+            playerinfo
+                .get_player_masks(0)?
+                .sequence_mask
+                .as_ref()
+                .map(|mask| mask.id),
+            Some(829)
+        );
+
+        // Hitsplats are replaced rather than queued
+        let hit = |damage| {
+            HitMask::single(
+                Hitsplat {
+                    damage,
+                    ..Default::default()
+                },
+                HealthBar {
+                    health_bar: 0,
+                    health_percentage: 50,
+                    delay: 0,
+                },
+            )
+        };
+        playerinfo.add_player_hit_mask(0, hit(1))?;
+        playerinfo.replace_mask(0, hit(2))?;
+        let hitsplats = &playerinfo
+            .get_player_masks(0)?
+            .hit_mask
+            .as_ref()
+            .unwrap()
+            .hitsplats;
+        assert_eq!(hitsplats.len(), 1);
+        assert_eq!(hitsplats[0].damage, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_priority_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let attack = SequenceMask {
+            id: 422,
+            delay: 0,
+            priority: 6,
+        };
+        let eat = SequenceMask {
+            id: 829,
+            delay: 0,
+            priority: 5,
+        };
+
+        assert!(playerinfo.add_player_sequence_mask(0, attack, false)?);
+        assert!(!playerinfo.add_player_sequence_mask(0, eat, false)?);
+        assert_eq!(
+            playerinfo
+                .get_player_masks(0)?
+                .sequence_mask
+                .as_ref()
+                .map(|mask| mask.id),
+            Some(422)
+        );
+
+        let eat = SequenceMask {
+            id: 829,
+            delay: 0,
+            priority: 5,
+        };
+        assert!(playerinfo.add_player_sequence_mask(0, eat, true)?);
+        assert_eq!(
+            playerinfo
+                .get_player_masks(0)?
+                .sequence_mask
+                .as_ref()
+                .map(|mask| mask.id),
+            Some(829)
+        );
+
+        Ok(())
+    }
+
+    fn test_appearance() -> AppearanceMask {
+        AppearanceMask::builder()
+            .with_username("Sage")
+            .with_combat_level(126)
+            .build()
+    }
+
+    // Decode the block of an encoded appearance mask, reversed and offset as the client reads it
+    fn appearance_block(
+        appearance_mask: &AppearanceMask,
+        equipment: Option<&dyn EquipmentProvider>,
+    ) -> Result<Vec<u8>> {
+        let mut mask_buf = Cursor::new(Vec::new());
+        write_appearance_mask(appearance_mask, equipment, &mut mask_buf)?;
+        let encoded = mask_buf.into_inner();
+        assert_eq!(encoded[0] as usize, encoded.len() - 1);
+
+        Ok(encoded[1..]
+            .iter()
+            .rev()
+            .map(|b| b.wrapping_sub(128))
+            .collect())
+    }
+
+    #[test]
+    fn appearance_equipment_test() -> Result<()> {
+        let appearance_mask = AppearanceMask {
+            head: 1163,
+            weapon: 4151,
+            body: 1127,
+            ..test_appearance()
+        };
+        let block = appearance_block(&appearance_mask, None)?;
+
+        // Worn items are offset by 0x200 and identity kits by 0x100, empty slots are a single zero byte
+        assert_eq!(
+            block[3..17],
+            [0x06, 0x8B, 0, 0, 0x12, 0x37, 0x06, 0x67, 0, 0x01, 0x1A, 0x01, 0x24, 0x01]
+        );
+
+        // Without a body item the torso kit is shown
+        let block = appearance_block(&test_appearance(), None)?;
+        assert_eq!(block[3..11], [0, 0, 0, 0, 0x01, 0x12, 0, 0x01]);
+
+        // Items the provider has no worn model for are left out
+        let equipment = |item: i16| (item != 4151).then_some(WornItem::default());
+        let block = appearance_block(&appearance_mask, Some(&equipment))?;
+        assert_eq!(block[3..9], [0x06, 0x8B, 0, 0, 0, 0x06]);
+
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.set_equipment_provider(equipment);
+        playerinfo.add_player_appearance_mask(0, appearance_mask)?;
+        let mut with_weapon = PlayerInfo::new();
+        with_weapon.add_player(0x101)?;
+        with_weapon.add_player_appearance_mask(
+            0,
+            AppearanceMask {
+                head: 1163,
+                weapon: 4151,
+                body: 1127,
+                ..test_appearance()
+            },
+        )?;
+        assert_eq!(
+            playerinfo.process(0)?.len() + 1,
+            with_weapon.process(0)?.len()
+        );
+
+        // Items and kits past what their slot holds are rejected rather than wrapping into another kind
+        for appearance_mask in [
+            AppearanceMask {
+                weapon: 32256,
+                ..test_appearance()
+            },
+            AppearanceMask {
+                head: -5,
+                ..test_appearance()
+            },
+            AppearanceMask {
+                hair: 256,
+                ..test_appearance()
+            },
+            AppearanceMask {
+                legs: -2,
+                ..test_appearance()
+            },
+        ] {
+            assert!(appearance_block(&appearance_mask, None).is_err());
+            assert!(playerinfo
+                .add_player_appearance_mask(0, appearance_mask)
+                .is_err());
+        }
+        let block = appearance_block(
+            &AppearanceMask {
+                weapon: 32255,
+                hair: -1,
+                ..test_appearance()
+            },
+            None,
+        )?;
+        assert_eq!(block[3..8], [0, 0, 0, 0x7F, 0xFF]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn appearance_cover_test() -> Result<()> {
+        // Hidden body parts are empty slots
+        let covered = AppearanceMask {
+            is_full_body: true,
+            covers_hair: true,
+            covers_face: true,
+            ..test_appearance()
+        };
+        let block = appearance_block(&covered, None)?;
+        assert_eq!(
+            block[3..20],
+            [0, 0, 0, 0, 0x01, 0x12, 0, 0, 0x01, 0x24, 0, 0x01, 0x21, 0x01, 0x2A, 0, 0]
+        );
+
+        // The flags of the worn items hide them just the same
+        let equipment = |item: i16| {
+            Some(WornItem {
+                is_full_body: item == 1127,
+                covers_hair: item == 1163,
+                covers_face: false,
+            })
+        };
+        let armoured = AppearanceMask {
+            head: 1163,
+            body: 1127,
+            ..test_appearance()
+        };
+        let block = appearance_block(&armoured, Some(&equipment))?;
+        assert_eq!(
+            block[3..21],
+            [
+                0x06, 0x8B, 0, 0, 0, 0x06, 0x67, 0, 0, 0x01, 0x24, 0, 0x01, 0x21, 0x01, 0x2A, 0x01,
+                0x0A
+            ]
+        );
+
+        // Only male players show their beard
+        let female = AppearanceMask {
+            gender: 1,
+            ..test_appearance()
+        };
+        let masked = AppearanceMask {
+            covers_face: true,
+            ..test_appearance()
+        };
+        assert_eq!(
+            appearance_block(&female, None)?[1..],
+            appearance_block(&masked, None)?[1..]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn appearance_morph_test() -> Result<()> {
+        // The marker and the NPC replace the slots, followed by the colours
+        let easter_egg = AppearanceMask {
+            morph: AppearanceMorph::Npc(2958),
+            head: 1163,
+            colors_hair: 3,
+            ..test_appearance()
+        };
+        let block = appearance_block(&easter_egg, None)?;
+        assert_eq!(block[3..8], [0xFF, 0xFF, 0x0B, 0x8E, 3]);
+        // Four empty item slots, the torso, the empty shield slot and six identity kits
+        let slots_len = 4 + 2 + 1 + 6 * 2;
+        assert_eq!(
+            block.len(),
+            appearance_block(&test_appearance(), None)?.len() - slots_len + 4
+        );
+
+        // Locs are marked apart from NPCs
+        let bush = AppearanceMask {
+            morph: AppearanceMorph::Loc(1124),
+            ..test_appearance()
+        };
+        let block = appearance_block(&bush, None)?;
+        assert_eq!(block[3..7], [0xFF, 0xFE, 0x04, 0x64]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn appearance_cache_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_appearance_mask(0, test_appearance())?;
+        let cached = |playerinfo: &PlayerInfo| {
+            playerinfo.playerupdates[0]
+                .masks
+                .appearance_encoding
+                .get()
+                .cloned()
+        };
+        assert_eq!(cached(&playerinfo), None);
+
+        // The first observer encodes the appearance, which the other observers and later additions share
+        playerinfo.process(0)?;
+        let encoding = cached(&playerinfo).context("appearance encoded")?;
+        let mut expected = Cursor::new(Vec::new());
+        write_appearance_mask(&test_appearance(), None, &mut expected)?;
+        assert_eq!(encoding, expected.into_inner());
+        let vec = playerinfo.process(1)?;
+        assert!(vec.windows(encoding.len()).any(|window| window == encoding));
+        playerinfo.tick();
+        assert_eq!(cached(&playerinfo), Some(encoding));
+
+        // Changing the mask or the equipment provider encodes it anew
+        playerinfo.add_player_appearance_mask(
+            0,
+            AppearanceMask {
+                head: 1163,
+                ..test_appearance()
+            },
+        )?;
+        assert_eq!(cached(&playerinfo), None);
+        playerinfo.process(1)?;
+        assert!(cached(&playerinfo).is_some());
+        playerinfo.set_equipment_provider(|_| None);
+        assert_eq!(cached(&playerinfo), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn appearance_builder_test() -> Result<()> {
+        let appearance_mask = AppearanceMask::builder()
+            .with_gender(1)
+            .with_username("Zezima")
+            .with_overhead_prayer(2)
+            .with_item(EquipmentSlot::Weapon, 4151)
+            .with_kit(BodyPart::Hair, 48)
+            .with_colors([1, 2, 3, 4, 5])
+            .build();
+        assert_eq!(appearance_mask.weapon, 4151);
+        assert_eq!(
+            (
+                appearance_mask.torso,
+                appearance_mask.hair,
+                appearance_mask.beard
+            ),
+            (56, 48, -1)
+        );
+        assert_eq!(
+            (appearance_mask.colors_hair, appearance_mask.colors_skin),
+            (1, 5)
+        );
+        assert_eq!(appearance_mask.weapon_stance_run, 824);
+        assert!(!appearance_mask.skull);
+
+        // Female players write an empty beard slot, whatever their beard kit
+        let block = appearance_block(&appearance_mask, None)?;
+        assert_eq!(block[2..8], [2, 0, 0, 0, 0x12, 0x37]);
+        assert_eq!(block[21], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn suspended_catch_up_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        assert!(!playerinfo.has_pending_update(0));
+        playerinfo.suspend_player(0)?;
+
+        playerinfo.add_player_appearance_mask(0, test_appearance())?;
+        playerinfo.playerupdates[0].movement_steps.push((1, 0));
+        assert!(!playerinfo.has_pending_update(0));
+        assert!(playerinfo.process(0)?.is_empty());
+        playerinfo.tick();
+
+        playerinfo.playerupdates[0].movement_steps.push((1, 0));
+        assert!(playerinfo.process(0)?.is_empty());
+        playerinfo.tick();
+
+        playerinfo.resume_player(0)?;
+        assert!(playerinfo.has_pending_update(0));
+        let vec = playerinfo.process(0)?;
+
+        // Update, mask update, teleport, small change on the same plane, followed by the delta of two tiles east
+        assert_eq!(vec[0], 0b1111_0000);
+        assert_eq!(vec[1], 0b0010_0000);
+        assert!(playerinfo.playerinfos[0][0].pending_movement.is_none());
+        assert_eq!(playerinfo.playerinfos[0][0].pending_mask_flags, 0);
+
+        playerinfo.tick();
+        assert!(!playerinfo.has_pending_update(0));
+        assert!(!playerinfo.has_pending_updates(0));
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 0 })?;
+        assert!(playerinfo.has_pending_update(0));
+        assert!(playerinfo.has_pending_updates(0));
+        assert_eq!(playerinfo.pending_mask_flags(0), DIRECTION_MASK);
+        assert_eq!(playerinfo.pending_mask_flags(1), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_updates_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player_sequence_mask(
+            0,
+            SequenceMask {
+                id: 808,
+                delay: 0,
+                priority: 5,
+            },
+            false,
+        )?;
+
+        let applied = playerinfo.apply_updates(
+            0,
+            PlayerUpdates {
+                direction: Some(DirectionMask { direction: 512 }),
+                sequence: Some(SequenceMask {
+                    id: 809,
+                    delay: 0,
+                    priority: 1,
+                }),
+                ..Default::default()
+            },
+        )?;
+
+        // The lower priority sequence does not replace the pending one
+        assert_eq!(applied, DIRECTION_MASK);
+        assert_eq!(
+            playerinfo.playerupdates[0].mask_flags,
+            DIRECTION_MASK | SEQUENCE_MASK
+        );
+        assert!(playerinfo
+            .apply_updates(1, PlayerUpdates::default())
+            .is_err());
+        playerinfo.process(0)?;
+        playerinfo.tick();
+
+        // An invalid mask fails the whole batch, leaving the valid masks before it unset
+        assert!(playerinfo
+            .apply_updates(
+                0,
+                PlayerUpdates {
+                    direction: Some(DirectionMask { direction: 1024 }),
+                    hit: Some(HitMask {
+                        hitsplats: Vec::new(),
+                        health_bars: Vec::new(),
+                    }),
+                    ..Default::default()
+                },
+            )
+            .is_err());
+        assert_eq!(playerinfo.playerupdates[0].mask_flags, 0);
+        assert_eq!(
+            playerinfo.playerupdates[0]
+                .masks
+                .direction_mask
+                .as_ref()
+                .map(|mask| mask.direction),
+            Some(512)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn process_all_quarantine_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+
+        // A local record of a player that does not exist cannot be encoded
+        playerinfo.playerinfos[0][5].local = true;
+
+        let results = playerinfo.process_all();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        assert!(results[1].1.is_ok());
+        assert!(playerinfo.is_quarantined(0));
+        assert!(!playerinfo.is_quarantined(1));
+        assert!(playerinfo.process(0).is_err());
+
+        playerinfo.resync_player(0, 123)?;
+        assert!(!playerinfo.is_quarantined(0));
+        assert!(playerinfo
+            .process_all()
+            .iter()
+            .all(|(_, result)| result.is_ok()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn suppress_extended_info_test() -> Result<()> {
+        let mut expected = PlayerInfo::new();
+        expected.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        expected.add_player_at(Coordinates::new(3230, 3218, 0))?;
+        expected.add_player_appearance_mask(1, test_appearance())?;
+
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3230, 3218, 0))?;
+        playerinfo.add_player_appearance_mask(1, test_appearance())?;
+        playerinfo.suppress_extended_info(1, true)?;
+        assert!(playerinfo.is_extended_info_suppressed(1));
+
+        // Only the appearance is written on addition
+        let sequence = || SequenceMask {
+            id: 808,
+            delay: 0,
+            priority: 1,
+        };
+        playerinfo.add_player_sequence_mask(1, sequence(), false)?;
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 512 })?;
+        assert_eq!(playerinfo.process(0)?, expected.process(0)?);
+        playerinfo.process(1)?;
+        playerinfo.tick();
+        assert!(playerinfo.playerupdates[1].masks.sequence_mask.is_none());
+
+        // Existing observers see none of the masks
+        playerinfo.add_player_sequence_mask(1, sequence(), false)?;
+        assert!(!playerinfo.has_pending_update(0));
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        // Masks set while suppressed do not leak afterwards
+        playerinfo.suppress_extended_info(1, false)?;
+        assert!(!playerinfo.has_pending_update(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn hit_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let hit_mask = |damage, health_percentage| {
+            HitMask::single(
+                Hitsplat {
+                    hitsplat_type: 1,
+                    damage,
+                    ..Default::default()
+                },
+                HealthBar {
+                    health_bar: 0,
+                    health_percentage,
+                    delay: 0,
+                },
+            )
+        };
+        assert!(playerinfo.add_player_hit_mask(0, hit_mask(200, 80))?);
+        assert!(playerinfo.add_player_hit_mask(0, hit_mask(3, 50))?);
+        assert!(playerinfo.add_player_hit_mask(0, hit_mask(3, 101)).is_err());
+        assert!(playerinfo
+            .add_player_hit_mask(0, HitMask::default())
+            .is_err());
+
+        let vec = playerinfo.process(0)?;
+        // The header, hitsplats of 200 and 3 damage, and a single health bar filled halfway
+        assert_eq!(
+            vec[vec.len() - 14..],
+            [
+                HIT_MASK as u8,
+                130,
+                1,
+                0x80,
+                200,
+                0,
+                1,
+                3,
+                0,
+                255,
+                0,
+                0,
+                0,
+                128 - 15
+            ]
+        );
+        playerinfo.tick();
+
+        // Hitsplats past the limit of a tick are dropped
+        for damage in 0..MAX_HITSPLATS as u16 {
+            assert!(playerinfo.add_player_hit_mask(0, hit_mask(damage, 50))?);
+        }
+        assert!(!playerinfo.add_player_hit_mask(
+            0,
+            HitMask {
+                hitsplats: hit_mask(9, 50).hitsplats,
+                health_bars: Vec::new()
+            }
+        )?);
+        let masks = playerinfo.get_player_masks(0)?;
+        assert_eq!(
+            masks.hit_mask.as_ref().map(|mask| mask.hitsplats.len()),
+            Some(MAX_HITSPLATS)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_mask_update_test() -> Result<()> {
+        let mut player_update = PlayerUpdate::new();
+        player_update.set_mask(DirectionMask { direction: 512 }, false, 0);
+        player_update.set_mask(FaceCoordinateMask { x: 3222, y: 3218 }, false, 0);
+        let write = |player_update: &PlayerUpdate, mask_flags| {
+            let mut mask_buf = Cursor::new(Vec::new());
+            write_mask_update(
+                &mut mask_buf,
+                player_update,
+                mask_flags,
+                &mut MaskSizes::default(),
+                (0, 0),
+                MaskEncoders {
+                    custom_masks: &[],
+                    equipment: None,
+                },
+                &DefaultRevision,
+            )
+            .map(|_| mask_buf.into_inner())
+        };
+
+        // Every flagged mask is written in order
+        assert_eq!(
+            write(&player_update, player_update.mask_flags)?,
+            [0x48, 0x20, 0x02, 0x80, 0x19, 0x2D, 0x19, 0x25]
+        );
+
+        let error = |result: Result<Vec<u8>>| {
+            result
+                .err()
+                .and_then(|error| error.downcast_ref::<MaskError>().copied())
+        };
+        assert_eq!(
+            error(write(&player_update, DIRECTION_MASK | 0x40)),
+            Some(MaskError::Unknown(0x40))
+        );
+        assert_eq!(
+            error(write(&player_update, SPOT_ANIMATION_MASK)),
+            Some(MaskError::Unwritable(SPOT_ANIMATION_MASK))
+        );
+        assert_eq!(
+            error(write(&player_update, SEQUENCE_MASK)),
+            Some(MaskError::Missing(SEQUENCE_MASK))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hitsplat_variants_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            reuse_identical_encodings: true,
+            ..Default::default()
+        })?;
+        for _ in 0..3 {
+            playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        }
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        // Player 0 hits player 1, while player 2 watches from the same tile
+        playerinfo.add_player_hit_mask(
+            1,
+            HitMask {
+                hitsplats: vec![Hitsplat {
+                    hitsplat_type: 1,
+                    damage: 10,
+                    source: Some(0),
+                    source_type: Some(2),
+                    victim_type: Some(3),
+                    ..Default::default()
+                }],
+                health_bars: Vec::new(),
+            },
+        )?;
+        let records: Vec<_> = (0..3)
+            .map(|player_id| playerinfo.client_records(player_id))
+            .collect::<Result<_>>()?;
+        for ((player_id, packet), records) in playerinfo.process_all().into_iter().zip(records) {
+            let decoded = decoder::decode(&packet?, &records)?;
+            let hit = decoded
+                .masks(1)
+                .and_then(|masks| masks.block(HIT_MASK))
+                .context("missing hit mask")?;
+            assert_eq!(hit[1], [2, 3, 1][player_id]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn forced_movement_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let forced_movement_mask = ForcedMovementMask {
+            start_dx: 0,
+            start_dy: 0,
+            end_dx: 2,
+            end_dy: 0,
+            start_delay: 0,
+            end_delay: 60,
+            direction: 1536,
+        };
+        assert!(playerinfo
+            .add_player_forced_movement_mask(
+                0,
+                ForcedMovementMask {
+                    end_delay: 0,
+                    ..forced_movement_mask
+                }
+            )
+            .is_err());
+        playerinfo.add_player_forced_movement_mask(0, forced_movement_mask)?;
+
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 12..],
+            [0x40, 0x2, 128, 128, 254, 0, 0, 0, 0, 60, 0x06, 0x80]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_speed_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        // Changing the speed is written to observers that already see the player
+        playerinfo.set_player_move_speed(0, MoveSpeed::Run)?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 3..],
+            [
+                0x40,
+                (MOVEMENT_CACHED_MASK >> 8) as u8,
+                128 + MoveSpeed::Run as u8
+            ]
+        );
+        playerinfo.tick();
+
+        // Setting the same speed again is not
+        playerinfo.set_player_move_speed(0, MoveSpeed::Run)?;
+        assert!(!playerinfo.has_pending_update(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn temporary_move_speed_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        // Written after the cached speed, which is only replayed on addition
+        playerinfo.queue_player_step(0, 1, 0)?;
+        playerinfo.add_player_temporary_move_speed_mask(
+            0,
+            TemporaryMoveSpeedMask {
+                move_speed: MoveSpeed::Run,
+            },
+        )?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 3..],
+            [
+                0x40,
+                (MOVEMENT_TEMPORARY_MASK >> 8) as u8,
+                128 - MoveSpeed::Run as u8
+            ]
+        );
+        playerinfo.tick();
+
+        // The override only lasts for the tick it was set in
+        assert!(playerinfo
+            .get_player_masks(0)?
+            .temporary_move_speed_mask
+            .is_none());
+        assert_eq!(playerinfo.player_move_speed(0), Some(MoveSpeed::Walk));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let chat_mask = |text: Vec<u8>| ChatMask {
+            text,
+            colour: 2,
+            effect: 1,
+            rights_icon: 1,
+            autotyper: false,
+        };
+        assert!(playerinfo
+            .add_player_chat_mask(0, chat_mask(vec![0; 256]))
+            .is_err());
+        playerinfo.add_player_chat_mask(0, chat_mask(vec![5, 0x9a, 0x40]))?;
+        let vec = playerinfo.process(0)?;
+
+        // The mask header followed by the effects, rights icon, autotyper flag and the compressed text
+        assert_eq!(
+            vec[vec.len() - 9..],
+            [CHAT_MASK as u8, 1, 2, 1, 0, 3, 5, 0x9a, 0x40]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn face_entity_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        assert!(playerinfo
+            .add_player_face_entity_mask(
+                0,
+                FaceEntityMask {
+                    entity: FaceEntity::Npc(32768)
+                }
+            )
+            .is_err());
+        playerinfo.add_player_face_entity_mask(
+            0,
+            FaceEntityMask {
+                entity: FaceEntity::Npc(5),
+            },
+        )?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 3..], [LOCK_TURNTO_MASK as u8, 0x80, 5]);
+        playerinfo.tick();
+        assert_eq!(
+            playerinfo.playerupdates[0].persistent_mask_flags(),
+            LOCK_TURNTO_MASK
+        );
+
+        // Releasing the lock is not replayed
+        playerinfo.add_player_face_entity_mask(
+            0,
+            FaceEntityMask {
+                entity: FaceEntity::None,
+            },
+        )?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 3..], [LOCK_TURNTO_MASK as u8, 0xFF, 0xFF]);
+        assert_eq!(playerinfo.playerupdates[0].persistent_mask_flags(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn face_coordinate_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        assert!(playerinfo
+            .add_player_face_coordinate_mask(0, FaceCoordinateMask { x: -1, y: 0 })
+            .is_err());
+        playerinfo.add_player_face_coordinate_mask(0, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 6..],
+            [
+                0x40,
+                (FACE_COORDINATE_MASK >> 8) as u8,
+                0x19,
+                0x2D,
+                0x19,
+                0x25
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn face_coordinate_replay_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3260, 3218, 0))?;
+
+        // Facing a tile after a direction replays only the tile
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 1536 })?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        playerinfo.add_player_face_coordinate_mask(1, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        playerinfo.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 6..],
+            [
+                0x40,
+                (FACE_COORDINATE_MASK >> 8) as u8,
+                0x19,
+                0x2D,
+                0x19,
+                0x25
+            ]
+        );
+        playerinfo.process(1)?;
+        playerinfo.tick();
+
+        // Turning to a direction again supersedes the tile
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 1536 })?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        playerinfo.teleport_player(1, Coordinates::new(3260, 3218, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        playerinfo.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 3..], [DIRECTION_MASK as u8, 0x06, 0x80]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_join_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            max_additions_per_tick: 1,
+            idle_max_additions_per_tick: 1,
+            ..Default::default()
+        })?;
+        for x in 0..6 {
+            playerinfo.add_player_at(Coordinates::new(3200 + x, 3200, 0))?;
+        }
+
+        let init = playerinfo.encode_join(0, 40)?;
+        assert_eq!((30 + 18 * (MAX_PLAYERS - 1)).div_ceil(8), init.len());
+        assert_eq!(
+            u32::from_be_bytes(init[..4].try_into()?) >> 2,
+            Coordinates::new(3200, 3200, 0).packed() as u32
+        );
+        assert!(playerinfo.is_joining(0));
+
+        // As many players as fit in the packet are added each tick, regardless of the additions per tick
+        let mut added = Vec::new();
+        for _ in 0..3 {
+            let vec = playerinfo.process(0)?;
+            assert!(vec.len() <= 40);
+            playerinfo.tick();
+            added.push(playerinfo.transitions().len());
+        }
+        assert_eq!(added, [2, 2, 1]);
+        assert!(!playerinfo.is_joining(0));
+
+        // The initialisation would desync a client that already sees other players
+        assert!(playerinfo.encode_join(0, 40).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn small_join_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        for x in 0..4 {
+            playerinfo.add_player_at(Coordinates::new(3200 + x, 3200, 0))?;
+            playerinfo.add_player_appearance_mask(x as usize, test_appearance())?;
+        }
+        playerinfo.process_all();
+        playerinfo.tick();
+        let joining = playerinfo.next_player_key();
+        playerinfo.add_player_at(Coordinates::new(3200, 3201, 0))?;
+
+        assert!(playerinfo.encode_join(joining, 8).is_err());
+        playerinfo.encode_join(joining, MIN_JOIN_PACKET_SIZE)?;
+
+        // Every appearance exceeds the packet, yet a player is still added each tick
+        for _ in 0..4 {
+            assert!(playerinfo.is_joining(joining));
+            playerinfo.process(joining)?;
+            playerinfo.tick();
+            assert_eq!(playerinfo.transitions().len(), 1);
+        }
+        playerinfo.process(joining)?;
+        assert!(!playerinfo.is_joining(joining));
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_mask_test() -> Result<()> {
+        struct TeamMask;
+
+        impl MaskEncoder for TeamMask {
+            type Value = u8;
+
+            fn encode(&self, value: &u8, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+                mask_buf.write_u8(*value)?;
+                Ok(())
+            }
+        }
+
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        playerinfo.register_mask(0x20000, TeamMask)?;
+        playerinfo.register_mask_writer(0x8000, |value: &u16, mask_buf| {
+            mask_buf.write_u16(*value)?;
+            Ok(())
+        })?;
+        assert!(playerinfo.register_mask(DIRECTION_MASK, TeamMask).is_err());
+        assert!(playerinfo.register_mask(0x8000, TeamMask).is_err());
+        assert!(playerinfo
+            .register_mask(MASK_CONTINUATION_MEDIUM, TeamMask)
+            .is_err());
+        assert!(playerinfo.register_mask(0x3000000, TeamMask).is_err());
+        assert!(playerinfo.set_custom_mask(0, 0x80000, 1u8).is_err());
+        assert!(playerinfo.set_custom_mask(0, 0x8000, 1u8).is_err());
+
+        playerinfo.set_custom_mask(0, 0x8000, 0x1234u16)?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 4..], [0x40, 0x80, 0x12, 0x34]);
+        playerinfo.tick();
+        assert_eq!(playerinfo.playerupdates[0].mask_flags, 0);
+
+        // Registered masks are written after the masks of the crate, in the order they were registered
+        playerinfo.set_custom_mask(0, 0x8000, 0x1234u16)?;
+        playerinfo.set_custom_mask(0, 0x20000, 7u8)?;
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 8..],
+            [0x48, 0xC0, 0x02, 0x02, 0x80, 0x07, 0x12, 0x34]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mask_header_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.register_mask_writer(0x20000, |value: &u8, mask_buf| {
+            mask_buf.write_u8(*value)?;
+            Ok(())
+        })?;
+
+        // A flag past the second byte sets both continuation markers, so the header spans all three bytes
+        playerinfo.set_custom_mask(0, 0x20000, 7u8)?;
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.add_player_face_coordinate_mask(0, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        let records = playerinfo.client_records(0)?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 10..vec.len() - 7],
+            [
+                (DIRECTION_MASK | MASK_CONTINUATION_SHORT) as u8,
+                ((FACE_COORDINATE_MASK | MASK_CONTINUATION_MEDIUM) >> 8) as u8,
+                0x02
+            ]
+        );
+        assert_eq!(vec[vec.len() - 1], 7);
+
+        // The decoder reads the third byte, finding the custom flag it cannot decode
+        let error = decoder::decode(&vec, &records).unwrap_err();
+        assert!(format!("{:#}", error).contains("0x20000"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tinting")]
+    #[test]
+    fn tint_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        let tint_mask = |start_delay, end_delay| TintMask {
+            start_delay,
+            end_delay,
+            hue: 12,
+            saturation: 34,
+            lightness: 56,
+            opacity: 78,
+        };
+        assert!(playerinfo
+            .add_player_tint_mask(0, tint_mask(30, 0))
+            .is_err());
+        playerinfo.add_player_tint_mask(0, tint_mask(0, 300))?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 11..],
+            [
+                0x40,
+                0x40,
+                (TINT_MASK >> 16) as u8,
+                0,
+                0,
+                0x01,
+                0x2C,
+                12,
+                34,
+                56,
+                78
+            ]
+        );
+
+        playerinfo.tick();
+
+        // Along with the face coordinate mask, the header spans all three bytes
+        playerinfo.add_player_tint_mask(0, tint_mask(0, 300))?;
+        playerinfo.add_player_face_coordinate_mask(0, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        let records = playerinfo.client_records(0)?;
+        let decoded = decoder::decode(&playerinfo.process(0)?, &records)?;
+        assert_eq!(
+            decoded.masks(0).map(|masks| masks.flags),
+            Some(FACE_COORDINATE_MASK | TINT_MASK)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn forced_chat_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        assert!(playerinfo
+            .add_player_forced_chat_mask(
+                0,
+                ForcedChatMask {
+                    text: "Caf\u{e9}".to_string()
+                }
+            )
+            .is_err());
+        playerinfo.add_player_forced_chat_mask(
+            0,
+            ForcedChatMask {
+                text: "Hi!".to_string(),
+            },
+        )?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 5..],
+            [SHOUT_MASK as u8, b'H', b'i', b'!', 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn name_modifiers_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+
+        assert!(playerinfo
+            .add_player_name_modifiers_mask(
+                0,
+                NameModifiersMask {
+                    prefix: "Sir\0".to_string(),
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        assert!(playerinfo
+            .add_player_name_modifiers_mask(
+                0,
+                NameModifiersMask {
+                    suffix: "x".repeat(MAX_NAME_MODIFIER_LENGTH + 1),
+                    ..Default::default()
+                }
+            )
+            .is_err());
+
+        playerinfo.add_player_name_modifiers_mask(
+            0,
+            NameModifiersMask {
+                prefix: "Sir".to_string(),
+                infix: String::new(),
+                suffix: "the Great".to_string(),
+            },
+        )?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 17..],
+            [
+                0x40,
+                (NAME_MODIFIERS_MASK >> 8) as u8,
+                b'S',
+                b'i',
+                b'r',
+                0,
+                0,
+                b't',
+                b'h',
+                b'e',
+                b' ',
+                b'G',
+                b'r',
+                b'e',
+                b'a',
+                b't',
+                0,
+            ]
+        );
+        playerinfo.tick();
+
+        // The modifiers are kept to be replayed
+        assert!(playerinfo
+            .get_player_masks(0)?
+            .name_modifiers_mask
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn interest_replay_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3240, 3218, 0))?;
+
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 1536 })?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        assert!(!playerinfo.playerinfos[0][1].local);
+        assert!(!playerinfo.has_pending_update(0));
+
+        // Walking into view adds the player, replaying where it is facing
+        playerinfo.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        assert!(playerinfo.has_pending_update(0));
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 3..], [DIRECTION_MASK as u8, 0x06, 0x80]);
+        assert!(playerinfo.playerinfos[0][1].local);
+        assert_eq!(playerinfo.player_move_speed(1), Some(MoveSpeed::Walk));
+        assert_eq!(
+            playerinfo.playerinfos[0][1].coordinates,
+            Coordinates::new(3230, 3218, 0).region_hash()
+        );
+        playerinfo.process(1)?;
+        playerinfo.tick();
+
+        // Leaving the view removes the player again
+        playerinfo.teleport_player(1, Coordinates::new(3260, 3218, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        assert!(!playerinfo.playerinfos[0][1].local);
+
+        // Coming back replays the speed the player moves at, as the client assumes walking
+        playerinfo.set_player_move_speed(1, MoveSpeed::Run)?;
+        playerinfo.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 5..],
+            [
+                (DIRECTION_MASK | 0x40) as u8,
+                (MOVEMENT_CACHED_MASK >> 8) as u8,
+                128 + MoveSpeed::Run as u8,
+                0x06,
+                0x80
+            ]
+        );
+        playerinfo.process(1)?;
+        playerinfo.tick();
+        assert!(playerinfo.playerinfos[0][1].local);
+
+        // As does logging out
+        playerinfo.remove_player(1)?;
+        assert!(playerinfo.has_pending_update(0));
+        playerinfo.process(0)?;
+        assert!(!playerinfo.playerinfos[0][1].local);
+
+        Ok(())
+    }
+
+    #[test]
+    fn view_hysteresis_test() {
+        let walked = |direction| DecodedUpdate::Walked {
+            direction,
+            masks: false,
+        };
+
+        scenario_with_config(WorldInfoConfig {
+            view_hysteresis: 2,
+            ..Default::default()
+        })
+        .player("a")
+        .at(3200, 3200)
+        .player("b")
+        .at(3214, 3200)
+        .tick()
+        .expect_nothing("a", "b")
+        // Players are added once within the view distance minus the hysteresis
+        .walks_west(1)
+        .tick()
+        .expect(
+            "a",
+            "b",
+            DecodedUpdate::Added {
+                x: 3213,
+                y: 3200,
+                masks: false,
+            },
+        )
+        // And only removed once past the view distance
+        .walks_east(3)
+        .ticks(2)
+        .expect("a", "b", walked(EAST))
+        .tick()
+        .expect("a", "b", DecodedUpdate::Removed);
+    }
 
-    let diff_x = current_multiplier_x - last_multiplier_x;
-    let diff_y = current_multiplier_y - last_multiplier_y;
-    let diff_level = current_level - last_level;
+    #[test]
+    fn idle_observer_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            idle_max_additions_per_tick: 1,
+            ..Default::default()
+        })?;
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3232, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3224, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3225, 3218, 0))?;
+
+        // Idle observers only add nearby players, one per tick
+        playerinfo.set_player_idle(0, true)?;
+        assert!(playerinfo.is_idle(0));
+        playerinfo.process(0)?;
+        assert!(!playerinfo.playerinfos[0][1].local);
+        assert!(playerinfo.playerinfos[0][2].local);
+        assert!(!playerinfo.playerinfos[0][3].local);
+        playerinfo.tick();
+
+        playerinfo.process(0)?;
+        assert!(playerinfo.playerinfos[0][3].local);
+        playerinfo.tick();
+
+        // Moving restores the full view distance
+        playerinfo.queue_player_step(0, 1, 0)?;
+        playerinfo.process(0)?;
+        assert!(!playerinfo.is_idle(0));
+        assert!(playerinfo.playerinfos[0][1].local);
 
-    let level_change = diff_level != 0;
-    let small_change = diff_x.abs() <= 1 && diff_y.abs() <= 1;
+        Ok(())
+    }
 
-    if level_change {
-        bit_buf.write(2, 1)?;
-        bit_buf.write(2, diff_level as u32)?;
-    } else if small_change {
-        let direction;
+    #[test]
+    fn transition_report_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3230, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3260, 3218, 0))?;
 
-        if diff_x == -1 && diff_y == -1 {
-            direction = 0;
-        } else if diff_x == 1 && diff_y == -1 {
-            direction = 2;
-        } else if diff_x == -1 && diff_y == 1 {
-            direction = 5;
-        } else if diff_x == 1 && diff_y == 1 {
-            direction = 7;
-        } else if diff_y == -1 {
-            direction = 1;
-        } else if diff_x == -1 {
-            direction = 3;
-        } else if diff_x == 1 {
-            direction = 4;
-        } else {
-            direction = 6;
-        }
+        playerinfo.process_all();
+        assert!(playerinfo.transitions().is_empty());
+        playerinfo.tick();
+        assert_eq!(
+            playerinfo.transitions(),
+            [
+                Transition::GlobalToLocal {
+                    observer: 0,
+                    subject: 1
+                },
+                Transition::GlobalToLocal {
+                    observer: 1,
+                    subject: 0
+                },
+            ]
+        );
 
-        bit_buf.write(2, 2)?;
-        bit_buf.write(2, diff_level as u32)?;
-        bit_buf.write(3, direction)?;
-    } else {
-        bit_buf.write(2, 3)?;
-        bit_buf.write(2, diff_level as u32)?;
-        bit_buf.write(8, diff_x as u32 & 0xFF)?;
-        bit_buf.write(8, diff_y as u32 & 0xFF)?;
-    }
+        // Requested transitions are made regardless of the view
+        assert!(playerinfo.request_local_to_global(0, 2).is_err());
+        assert!(playerinfo.request_global_to_local(0, 1).is_err());
+        playerinfo.request_local_to_global(0, 1)?;
+        playerinfo.request_global_to_local(0, 2)?;
+        assert!(playerinfo.has_pending_update(0));
+        playerinfo.process(0)?;
+        playerinfo.tick();
+        assert_eq!(
+            playerinfo.transitions(),
+            [
+                Transition::LocalToGlobal {
+                    observer: 0,
+                    subject: 1
+                },
+                Transition::GlobalToLocal {
+                    observer: 0,
+                    subject: 2
+                },
+            ]
+        );
 
-    Ok(())
-}
+        // After which the view applies again
+        playerinfo.process(0)?;
+        playerinfo.tick();
+        assert_eq!(
+            playerinfo.transitions(),
+            [
+                Transition::LocalToGlobal {
+                    observer: 0,
+                    subject: 2
+                },
+                Transition::GlobalToLocal {
+                    observer: 0,
+                    subject: 1
+                },
+            ]
+        );
 
-fn write_local_movement(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    playerinfoentry: &mut PlayerUpdate,
-    mask_update: bool,
-) -> Result<()> {
-    let direction_diff_x = [-1, 0, 1, -1, 1, -1, 0, 1];
-    let direction_diff_y = [-1, -1, -1, 0, 0, 1, 1, 1];
+        Ok(())
+    }
 
-    let movement_update = &playerinfoentry.movement_update;
+    #[test]
+    fn own_updates_priority_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            max_additions_per_tick: 1,
+            idle_max_additions_per_tick: 1,
+            ..Default::default()
+        })?;
+        for x in 0..6 {
+            playerinfo.add_player_at(Coordinates::new(3222 + x, 3218, 0))?;
+        }
+        assert!(playerinfo.request_local_to_global(0, 0).is_err());
+
+        // The own update is written even with the additions capped and extended info suppressed
+        playerinfo.suppress_extended_info(0, true)?;
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.queue_player_step(0, 1, 0)?;
+        let records = playerinfo.client_records(0)?;
+        let packet = decoder::decode(&playerinfo.process(0)?, &records)?;
+        assert_eq!(
+            packet.update(0),
+            Some(DecodedUpdate::Walked {
+                direction: EAST,
+                masks: true
+            })
+        );
+        assert!(packet
+            .masks(0)
+            .is_some_and(|masks| masks.block(DIRECTION_MASK).is_some()));
+        let additions = (1..6)
+            .filter(|subject| matches!(packet.update(*subject), Some(DecodedUpdate::Added { .. })))
+            .count();
+        assert_eq!(additions, 1);
+
+        // While other observers see none of its masks
+        let records = playerinfo.client_records(1)?;
+        let packet = decoder::decode(&playerinfo.process(1)?, &records)?;
+        assert!(packet.masks(0).is_none());
 
-    let large_change =
-        movement_update.x.abs() >= REBUILD_BOUNDARY || movement_update.y.abs() >= REBUILD_BOUNDARY;
-    let teleport = large_change || false;
+        Ok(())
+    }
 
-    bit_buf.write_bit(mask_update)?;
-    if teleport {
-        // SKIP TELEPORT FOR NOW
-        bit_buf.write(2, LOCAL_MOVEMENT_TELEPORT)?;
-        bit_buf.write_bit(large_change)?;
-        bit_buf.write(2, movement_update.z & 0x3)?;
-
-        if large_change {
-            bit_buf.write(14, movement_update.x & 0x3FFF)?;
-            bit_buf.write(14, movement_update.y & 0x3FFF)?;
-        } else {
-            bit_buf.write(5, movement_update.x & 0x1F)?;
-            bit_buf.write(5, movement_update.y & 0x1F)?;
+    #[test]
+    fn observer_group_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_config(WorldInfoConfig {
+            reuse_identical_encodings: true,
+            ..Default::default()
+        })?;
+        // Two spectators next to each other, and a fighter in the arena they watch
+        playerinfo.add_player_at(Coordinates::new(3200, 3200, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3201, 3200, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3302, 3300, 0))?;
+        let group = playerinfo.add_observer_group(Coordinates::new(3300, 3300, 0))?;
+        assert!(playerinfo.set_observer_group(0, Some(group + 1)).is_err());
+        playerinfo.set_observer_group(0, Some(group))?;
+        playerinfo.set_observer_group(1, Some(group))?;
+        assert_eq!(playerinfo.observer_group(1), Some(group));
+
+        playerinfo.process_all();
+        playerinfo.tick();
+        for spectator in 0..2 {
+            assert!(playerinfo.playerinfos[spectator][2].local);
+            assert!(!playerinfo.playerinfos[spectator][1 - spectator].local);
         }
-    } else {
-        let movement_steps = &mut playerinfoentry.movement_steps;
-        let walk_step = movement_steps.get(0).context("failed getting walk step")?;
-        let walk_rotation = get_direction_rotation(walk_step)?;
+        assert!(!playerinfo.has_pending_update(0));
 
-        let mut dx = *direction_diff_x.get(walk_rotation as usize).context("dx")?;
-        let mut dy = *direction_diff_y.get(walk_rotation as usize).context("dy")?;
+        // Leaving the group brings back the view around the own player
+        playerinfo.remove_observer_group(group)?;
+        assert_eq!(playerinfo.observer_group(0), None);
+        playerinfo.process_all();
+        playerinfo.tick();
+        assert!(playerinfo.playerinfos[0][1].local);
+        assert!(!playerinfo.playerinfos[0][2].local);
 
-        let mut running = false;
-        let mut direction = 0;
+        Ok(())
+    }
 
-        if let Some(run_step) = movement_steps.get(1) {
-            let run_rotation = get_direction_rotation(run_step)?;
+    #[test]
+    fn keepalive_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3225, 3218, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        playerinfo.queue_player_step(1, 1, 0)?;
+        playerinfo.add_player_sequence_mask(
+            1,
+            SequenceMask {
+                id: 808,
+                delay: 0,
+                priority: 1,
+            },
+            false,
+        )?;
+        let records = playerinfo.client_records(0)?;
+        let packet = playerinfo.encode_keepalive(0)?;
+        // A skip count over both local players, and one over the other 2045 global players
+        assert_eq!(packet, [0x21, 0x7F, 0xF0]);
+        let decoded = decoder::decode(&packet, &records)?;
+        assert_eq!(decoded.update(1), None);
+        assert_ne!(playerinfo.playerupdates[1].mask_flags & SEQUENCE_MASK, 0);
+        playerinfo.process(1)?;
+        playerinfo.tick();
+
+        // The movement skipped over is caught up on in the next update
+        let records = playerinfo.client_records(0)?;
+        let decoded = decoder::decode(&playerinfo.process(0)?, &records)?;
+        assert_eq!(
+            decoded.update(1),
+            Some(DecodedUpdate::Teleported {
+                dx: 1,
+                dy: 0,
+                dz: 0,
+                masks: false
+            })
+        );
 
-            dx += *direction_diff_x
-                .get(run_rotation as usize)
-                .context("dx 2")?;
-            dy += *direction_diff_y
-                .get(run_rotation as usize)
-                .context("dy 2")?;
+        Ok(())
+    }
 
-            if let Some(run_dir) = run_dir(dx, dy) {
-                direction = run_dir;
-                running = true;
+    #[test]
+    fn small_world_test() -> Result<()> {
+        let mut small = PlayerInfo::with_config(WorldInfoConfig::small_world(2))?;
+        let mut full = PlayerInfo::new();
+        for playerinfo in [&mut small, &mut full] {
+            playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+            assert!(!playerinfo.has_pending_update(0));
+        }
+        assert!(small.add_player_at(Coordinates::new(3222, 3218, 0)).is_ok());
+        assert!(small
+            .add_player_at(Coordinates::new(3222, 3218, 0))
+            .is_err());
+        full.add_player_at(Coordinates::new(3222, 3218, 0))?;
+
+        // The records past the max players are skipped as a whole, with the same result as visiting each of them
+        for tick in 0..4 {
+            for playerinfo in [&mut small, &mut full] {
+                playerinfo.queue_player_step(tick % 2, 1, 0)?;
+                playerinfo.add_player_direction_mask(1, DirectionMask { direction: 512 })?;
             }
+            for player_id in 0..2 {
+                assert_eq!(small.process(player_id)?, full.process(player_id)?);
+                assert_eq!(
+                    small.client_records(player_id)?,
+                    full.client_records(player_id)?
+                );
+            }
+            small.tick();
+            full.tick();
         }
 
-        if !running {
-            if let Some(walk_dir) = walk_dir(dx, dy) {
-                direction = walk_dir;
-            }
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_identical_encodings_test() -> Result<()> {
+        let mut reusing = PlayerInfo::with_config(WorldInfoConfig {
+            reuse_identical_encodings: true,
+            ..Default::default()
+        })?;
+        let mut encoding = PlayerInfo::new();
+        for playerinfo in [&mut reusing, &mut encoding] {
+            playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+            playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+            playerinfo.add_player_at(Coordinates::new(3225, 3218, 0))?;
         }
 
-        if running {
-            bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
-            bit_buf.write(4, direction)?;
-        } else {
-            bit_buf.write(2, LOCAL_MOVEMENT_WALK)?;
-            bit_buf.write(3, direction)?;
+        for tick in 0..4 {
+            for playerinfo in [&mut reusing, &mut encoding] {
+                playerinfo.add_player_direction_mask(2, DirectionMask { direction: tick })?;
+            }
+            let reused: Vec<Vec<u8>> = reusing
+                .process_all()
+                .into_iter()
+                .map(|(_, result)| result)
+                .collect::<Result<_>>()?;
+            let encoded: Vec<Vec<u8>> = encoding
+                .process_all()
+                .into_iter()
+                .map(|(_, result)| result)
+                .collect::<Result<_>>()?;
+            assert_eq!(reused, encoded);
+
+            reusing.tick();
+            encoding.tick();
+            // Both players only see themselves in the first tick, which makes their records differ
+            #[cfg(feature = "alloc-metrics")]
+            assert_eq!(reusing.metrics().reused_packets, usize::from(tick > 0));
         }
 
-        movement_steps.clear();
+        Ok(())
     }
 
-    Ok(())
-}
+    #[test]
+    fn coordinate_multiplier_test() -> Result<()> {
+        let encode = |old_multiplier, new_multiplier| -> Result<Vec<u8>> {
+            let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+            write_coordinate_multiplier(&mut bit_buf, old_multiplier, new_multiplier)?;
+            bit_buf.byte_align()?;
+            Ok(bit_buf.into_writer())
+        };
+
+        // The plane is taken from the top bits of the region hash, and its delta wraps around
+        assert_eq!(encode(0x00000, 0x10000)?, [0b0101_0000]);
+        assert_eq!(encode(0x30000, 0x00000)?, [0b0101_0000]);
+        // A neighbouring region is written as a direction, even when the plane changes along with it
+        assert_eq!(encode(0x00000, 0x10101)?, [0b1001_1110]);
+        // Regions further away are written as deltas of x and y
+        assert_eq!(
+            encode(0x00000, 0x00300)?,
+            [0b1100_0000, 0b0011_0000, 0b0000_0000]
+        );
 
-fn write_mask_update_signal(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-) -> Result<()> {
-    bit_buf.write_bit(true)?;
-    bit_buf.write(2, LOCAL_MOVEMENT_NONE)?;
+        Ok(())
+    }
 
-    Ok(())
-}
+    #[test]
+    fn instanced_plane_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
 
-fn write_direction_mask(
-    direction_mask: &DirectionMask,
-    mask_buf: &mut Cursor<Vec<u8>>,
-) -> Result<()> {
-    mask_buf.write_i16_add(direction_mask.direction)?;
+        playerinfo.queue_player_step(0, 1, 0)?;
+        playerinfo.teleport_player(0, Coordinates::new(3222, 3218, 9))?;
+        let vec = playerinfo.process(0)?;
 
-    Ok(())
-}
+        // Update, no mask update, teleport, small change, a plane delta of 1 and no delta on either axis
+        assert_eq!(vec[0], 0b1011_0010);
+        assert_eq!(vec[1], 0b0000_0000);
+        assert_eq!(
+            playerinfo.player_coordinates(0),
+            Some(Coordinates::new(3222, 3218, 9))
+        );
+        playerinfo.tick();
 
-fn write_appearance_mask(
-    appearance_mask: &AppearanceMask,
-    mask_buf: &mut Cursor<Vec<u8>>,
-) -> Result<()> {
-    let mut temp_buf = Cursor::new(Vec::new());
+        playerinfo.queue_player_step(0, 1, 1)?;
+        playerinfo.queue_player_step(0, 1, 1)?;
+        assert!(playerinfo.queue_player_step(0, 1, 1).is_err());
+        assert!(playerinfo.queue_player_step(0, 2, 0).is_err());
+        assert_eq!(
+            playerinfo
+                .teleport_player(0, Coordinates::new(-1, 3218, 0))
+                .unwrap_err()
+                .downcast::<CoordinateError>()?,
+            CoordinateError::X(-1)
+        );
+        assert_eq!(
+            playerinfo.player_coordinates(0),
+            Some(Coordinates::new(3224, 3220, 9))
+        );
 
-    temp_buf.write_i8(appearance_mask.gender)?;
-    if appearance_mask.skull {
-        temp_buf.write_i8(1)?;
-    } else {
-        temp_buf.write_i8(-1)?;
+        Ok(())
     }
 
-    temp_buf.write_i8(appearance_mask.overhead_prayer)?;
-
-    // Equipment here, skipped for now
-    temp_buf.write_i8(0)?; // Head
-    temp_buf.write_i8(0)?; // Cape
-    temp_buf.write_i8(0)?; // Neck
-    temp_buf.write_i8(0)?; // Weapon
-
-    temp_buf.write_i16(256 + 18)?; // Torso
-    temp_buf.write_i8(0)?; // Shield
-    temp_buf.write_i16(256 + appearance_mask.arms)?; // Arms
-    temp_buf.write_i16(256 + appearance_mask.legs)?; // Legs
-    temp_buf.write_i16(256 + appearance_mask.hair)?; // Hair
-    temp_buf.write_i16(256 + appearance_mask.hands)?; // Hands
-    temp_buf.write_i16(256 + appearance_mask.feet)?; // Feet
-
-    if appearance_mask.gender == 0 {
-        temp_buf.write_i16(256 + appearance_mask.beard)?; // Beard
-    } else {
-        temp_buf.write_i16(0)?;
-    }
+    #[test]
+    fn oversized_packet_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
 
-    temp_buf.write_i8(appearance_mask.colors_hair)?;
-    temp_buf.write_i8(appearance_mask.colors_torso)?;
-    temp_buf.write_i8(appearance_mask.colors_legs)?;
-    temp_buf.write_i8(appearance_mask.colors_feet)?;
-    temp_buf.write_i8(appearance_mask.colors_skin)?;
+        let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook_reports = reports.clone();
+        playerinfo.set_oversized_packet_hook(move |report| {
+            hook_reports.lock().unwrap().push(report.clone());
+        });
 
-    temp_buf.write_i16(appearance_mask.weapon_stance_stand)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_walk)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn180)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn90cw)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn90ccw)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_run)?;
+        playerinfo.add_player_appearance_mask(0, test_appearance())?;
+        playerinfo.process(0)?;
+        assert!(reports.lock().unwrap().is_empty());
 
-    temp_buf.write_string_cp1252(&appearance_mask.username)?;
-    temp_buf.write_i8(appearance_mask.combat_level)?;
-    temp_buf.write_i16(appearance_mask.skill_id_level)?;
-    temp_buf.write_i8(appearance_mask.hidden)?;
+        playerinfo.set_packet_size_threshold(0, Some(16))?;
+        let vec = playerinfo.process(0)?;
 
-    mask_buf.write_i8(temp_buf.position() as i8)?;
+        let reports = reports.lock().unwrap();
+        let report = reports.first().context("missing report")?;
+        assert_eq!(report.size, vec.len());
+        assert_eq!(report.header_size, 1);
+        assert_eq!(report.masks.len(), 1);
+        assert_eq!(report.masks[0].mask, APPEARANCE_MASK);
+        assert_eq!(
+            report.bit_size + report.header_size + report.masks[0].bytes,
+            report.size
+        );
 
-    mask_buf.write_bytes_reversed_add(temp_buf.get_ref())?;
+        Ok(())
+    }
 
-    Ok(())
-}
+    #[test]
+    fn anomaly_sink_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3225, 3218, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        let anomalies = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink_anomalies = anomalies.clone();
+        playerinfo.set_anomaly_sink(move |anomaly: &Anomaly| {
+            sink_anomalies.lock().unwrap().push(anomaly.clone());
+        });
 
-fn get_direction_rotation(some_movement: &(i32, i32)) -> Result<i32> {
-    match some_movement {
-        (-1, -1) => Ok(0),
-        (0, -1) => Ok(1),
-        (1, -1) => Ok(2),
-        (-1, 0) => Ok(3),
-        (1, 0) => Ok(4),
-        (-1, 1) => Ok(5),
-        (0, 1) => Ok(6),
-        (1, 1) => Ok(7),
-        _ => Err(anyhow!("Failed getting direction rotation")),
-    }
-}
+        // Stepping back and forth is written as a teleport in place
+        playerinfo.queue_player_step(1, 1, 0)?;
+        playerinfo.queue_player_step(1, -1, 0)?;
+        let records = playerinfo.client_records(0)?;
+        let decoded = decoder::decode(&playerinfo.process(0)?, &records)?;
+        assert!(matches!(
+            decoded.update(1),
+            Some(DecodedUpdate::Teleported { .. })
+        ));
+        playerinfo.tick();
+
+        // A mask flagged without its data fails the observer
+        playerinfo.playerupdates[1].mask_flags |= SEQUENCE_MASK;
+        assert!(playerinfo.process(0).is_err());
 
-fn run_dir(dx: i32, dy: i32) -> Option<i32> {
-    match (dx, dy) {
-        (-2, -2) => Some(0),
-        (-1, -2) => Some(1),
-        (0, -2) => Some(2),
-        (1, -2) => Some(3),
-        (2, -2) => Some(4),
-        (-2, -1) => Some(5),
-        (2, -1) => Some(6),
-        (-2, 0) => Some(7),
-        (2, 0) => Some(8),
-        (-2, 1) => Some(9),
-        (2, 1) => Some(10),
-        (-2, 2) => Some(11),
-        (-1, 2) => Some(12),
-        (0, 2) => Some(13),
-        (1, 2) => Some(14),
-        (2, 2) => Some(15),
-        _ => None,
-    }
-}
+        assert_eq!(
+            *anomalies.lock().unwrap(),
+            [
+                Anomaly::UnwritableMovement {
+                    observer: 0,
+                    subject: 1,
+                    dx: 0,
+                    dy: 0,
+                },
+                Anomaly::MissingMask {
+                    observer: 0,
+                    subject: 1,
+                    mask: SEQUENCE_MASK,
+                },
+            ]
+        );
 
-fn walk_dir(dx: i32, dy: i32) -> Option<i32> {
-    match (dx, dy) {
-        (-1, -1) => Some(0),
-        (0, -1) => Some(1),
-        (1, -1) => Some(2),
-        (-1, 0) => Some(3),
-        (1, 0) => Some(4),
-        (-1, 1) => Some(5),
-        (0, 1) => Some(6),
-        (1, 1) => Some(7),
-        _ => None,
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn add_player_test() -> Result<()> {
+    fn movement_hook_test() -> Result<()> {
         let mut playerinfo = PlayerInfo::new();
-        playerinfo.add_player(123)?;
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3225, 3218, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook_reports = reports.clone();
+        playerinfo.set_movement_hook(move |movement| {
+            hook_reports.lock().unwrap().push(*movement);
+        });
 
-        assert_eq!(playerinfo.playerinfos.len(), 1);
+        // Movement written to several observers is reported once
+        playerinfo.queue_player_step(1, 1, 0)?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        playerinfo.queue_player_step(1, 1, 1)?;
+        playerinfo.queue_player_step(1, 0, 1)?;
+        playerinfo.process_all();
+        playerinfo.tick();
+        playerinfo.teleport_player(1, Coordinates::new(3220, 3230, 0))?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        let movement = |kind, dx, dy| EncodedMovement {
+            player_id: 1,
+            kind,
+            dx,
+            dy,
+            dz: 0,
+        };
+        assert_eq!(
+            *reports.lock().unwrap(),
+            [
+                movement(MovementKind::Walk, 1, 0),
+                movement(MovementKind::Run, 1, 2),
+                movement(MovementKind::Teleport, -7, 10),
+            ]
+        );
 
         Ok(())
     }
@@ -946,49 +6846,9 @@ mod tests {
     #[test]
     fn playerinfo_test() -> Result<()> {
         let mut playerinfo = PlayerInfo::new();
-        playerinfo.add_player(131313)?;
-
-        let playerinfodata = playerinfo.playerupdates.get_mut(0).context("yes")?;
+        playerinfo.add_player(0x20101)?;
 
-        playerinfo.add_player_appearance_mask(
-            0,
-            AppearanceMask {
-                gender: 0,
-                skull: false,
-                overhead_prayer: -1,
-                head: 0,
-                cape: 0,
-                neck: 0,
-                weapon: 0,
-                body: 0,
-                shield: 0,
-                is_full_body: false,
-                legs: 36,
-                covers_hair: false,
-                hands: 33,
-                feet: 42,
-                covers_face: false,
-                colors_hair: 0,
-                colors_torso: 0,
-                colors_legs: 0,
-                colors_feet: 0,
-                colors_skin: 0,
-                weapon_stance_stand: 808,
-                weapon_stance_turn: 823,
-                weapon_stance_walk: 819,
-                weapon_stance_turn180: 820,
-                weapon_stance_turn90cw: 821,
-                weapon_stance_turn90ccw: 822,
-                weapon_stance_run: 824,
-                username: "Sage".to_string(),
-                combat_level: 126,
-                skill_id_level: 0,
-                hidden: 0,
-                arms: 26,
-                hair: 0,
-                beard: 10,
-            },
-        )?;
+        playerinfo.add_player_appearance_mask(0, test_appearance())?;
 
         playerinfo.add_player_direction_mask(0, DirectionMask { direction: 1536 })?;
 
@@ -1004,7 +6864,7 @@ mod tests {
             ]
         );
 
-        let vec = playerinfo.process(0)?;
+        playerinfo.process(0)?;
 
         Ok(())
     }
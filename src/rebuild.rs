@@ -0,0 +1,332 @@
+//! The map rebuild packets, loading the build area around a player
+use crate::coordinates::{Coordinates, WIRE_PLANES};
+use crate::zoneinfo::{self, ZoneCoordinates, BUILD_AREA_SIZE};
+use anyhow::{anyhow, Result};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+use osrs_buffer::WriteExt;
+use std::collections::BTreeMap;
+
+/// The XTEA keys the map of a region is encrypted with
+pub type XteaKeys = [i32; 4];
+
+// The length in zones of the side of a region
+const REGION_ZONES: i32 = 8;
+// The distance in zones from the zone the build area is centered on to its edges
+const BUILD_AREA_RADIUS: i32 = 6;
+/// The length in zones of the side of the build area
+pub const BUILD_AREA_ZONES: usize = 13;
+// The bits the source zone of an instanced zone is written using, along x and y
+const SOURCE_ZONE_X_BITS: u32 = 10;
+const SOURCE_ZONE_Y_BITS: u32 = 11;
+const MAX_ROTATION: u8 = 3;
+
+/// The id of a region, 8 bits of region x followed by 8 bits of region y
+pub fn region_id(zone: ZoneCoordinates) -> u16 {
+    ((zone.x / REGION_ZONES) << 8 | (zone.y / REGION_ZONES)) as u16
+}
+
+/// Check whether the map has to be rebuilt around the player, as it came within the rebuild boundary of the edge
+/// of the build area centered on the zone of its last rebuild. Moving further than the boundary is sent as
+/// a large teleport, which the client only follows within the build area it loaded
+pub fn needs_rebuild(center: Coordinates, coordinates: Coordinates, rebuild_boundary: i32) -> bool {
+    let build_area = zoneinfo::build_area(center);
+    let outside =
+        |offset: i32| offset < rebuild_boundary || offset >= BUILD_AREA_SIZE - rebuild_boundary;
+
+    outside(coordinates.x - build_area.x) || outside(coordinates.y - build_area.y)
+}
+
+/// The REBUILD_NORMAL packet, loading the map of the regions around the zone the build area is centered on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildNormal {
+    zone: ZoneCoordinates,
+    keys: BTreeMap<u16, XteaKeys>,
+}
+
+impl RebuildNormal {
+    /// Create a rebuild centering the build area on the zone of the player
+    pub fn new(zone: ZoneCoordinates) -> RebuildNormal {
+        RebuildNormal {
+            zone,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Set the keys of a region, regions without keys are sent as unencrypted
+    pub fn with_keys(mut self, region_id: u16, keys: XteaKeys) -> RebuildNormal {
+        self.keys.insert(region_id, keys);
+        self
+    }
+
+    /// Get the regions the build area overlaps, in the order the client reads their keys
+    pub fn regions(&self) -> Vec<u16> {
+        let range = |center: i32| {
+            (center - BUILD_AREA_RADIUS) / REGION_ZONES
+                ..=(center + BUILD_AREA_RADIUS) / REGION_ZONES
+        };
+
+        range(self.zone.x)
+            .flat_map(|region_x| {
+                range(self.zone.y).map(move |region_y| (region_x << 8 | region_y) as u16)
+            })
+            .collect()
+    }
+
+    /// Encode the packet, the zone followed by the keys of every region the build area overlaps
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let regions = self.regions();
+        let mut buf = Vec::with_capacity(6 + regions.len() * 16);
+        buf.write_u16(self.zone.x as u16)?;
+        buf.write_u16(self.zone.y as u16)?;
+        buf.write_u16(regions.len() as u16)?;
+        for region_id in regions {
+            let keys = self.keys.get(&region_id).copied().unwrap_or_default();
+            for key in keys {
+                buf.write_i32(key)?;
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+/// The zone of the static map an instanced zone is copied from, rotated clockwise in quarter turns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceZone {
+    pub zone: ZoneCoordinates,
+    pub rotation: u8,
+}
+
+/// The REBUILD_REGION packet, loading an instanced map where every zone of the build area is copied from a zone
+/// of the static map, e.g. raids and player owned houses. Zones without a source are left empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildRegion {
+    zone: ZoneCoordinates,
+    reload: bool,
+    // The source of each zone, by the plane and the zone coordinates within the build area
+    zones: BTreeMap<(usize, usize, usize), SourceZone>,
+    keys: BTreeMap<u16, XteaKeys>,
+}
+
+impl RebuildRegion {
+    /// Create a rebuild centering the build area on the zone of the player, without any zones
+    pub fn new(zone: ZoneCoordinates) -> RebuildRegion {
+        RebuildRegion {
+            zone,
+            reload: false,
+            zones: BTreeMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Force the client to reload the map, even if the build area is centered on the same zone
+    pub fn with_reload(mut self, reload: bool) -> RebuildRegion {
+        self.reload = reload;
+        self
+    }
+
+    /// Copy the zone at the coordinates within the build area on the plane from the source zone
+    pub fn with_zone(
+        mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        source: SourceZone,
+    ) -> RebuildRegion {
+        self.zones.insert((plane, x, y), source);
+        self
+    }
+
+    /// Set the keys of a region, regions without keys are sent as unencrypted
+    pub fn with_keys(mut self, region_id: u16, keys: XteaKeys) -> RebuildRegion {
+        self.keys.insert(region_id, keys);
+        self
+    }
+
+    /// Get the regions the source zones are copied from, in the order the client reads their keys
+    pub fn regions(&self) -> Vec<u16> {
+        let mut regions = Vec::new();
+        for source in self.zones.values() {
+            let region_id = region_id(source.zone);
+            if !regions.contains(&region_id) {
+                regions.push(region_id);
+            }
+        }
+
+        regions
+    }
+
+    /// Encode the packet, the zone followed by the source of every zone of the build area
+    /// and the keys of every region they are copied from.
+    /// Fails if a zone is outside of the build area, or its source cannot be written to the client
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        for (&(plane, x, y), source) in self.zones.iter() {
+            validate_zone(plane, x, y, source)?;
+        }
+
+        let mut buf = Vec::new();
+        buf.write_u16(self.zone.x as u16)?;
+        buf.write_u16(self.zone.y as u16)?;
+        buf.write_u8(self.reload as u8)?;
+
+        // A bit per zone of the build area, followed by its source if it has one
+        let mut bit_buf = BitWriter::endian(buf, BigEndian);
+        for plane in 0..WIRE_PLANES as usize {
+            for x in 0..BUILD_AREA_ZONES {
+                for y in 0..BUILD_AREA_ZONES {
+                    let Some(source) = self.zones.get(&(plane, x, y)) else {
+                        bit_buf.write_bit(false)?;
+                        continue;
+                    };
+
+                    bit_buf.write_bit(true)?;
+                    bit_buf.write(2, source.zone.plane as u32)?;
+                    bit_buf.write(SOURCE_ZONE_X_BITS, source.zone.x as u32)?;
+                    bit_buf.write(SOURCE_ZONE_Y_BITS, source.zone.y as u32)?;
+                    bit_buf.write(2, u32::from(source.rotation))?;
+                    bit_buf.write(1, 0)?;
+                }
+            }
+        }
+        bit_buf.byte_align()?;
+        let mut buf = bit_buf.into_writer();
+
+        let regions = self.regions();
+        buf.write_u16(regions.len() as u16)?;
+        for region_id in regions {
+            let keys = self.keys.get(&region_id).copied().unwrap_or_default();
+            for key in keys {
+                buf.write_i32(key)?;
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+fn validate_zone(plane: usize, x: usize, y: usize, source: &SourceZone) -> Result<()> {
+    if plane >= WIRE_PLANES as usize || x >= BUILD_AREA_ZONES || y >= BUILD_AREA_ZONES {
+        return Err(anyhow!(
+            "Zone {}, {} on plane {} is outside of the build area",
+            x,
+            y,
+            plane
+        ));
+    }
+    if !(0..WIRE_PLANES).contains(&source.zone.plane)
+        || !(0..1 << SOURCE_ZONE_X_BITS).contains(&source.zone.x)
+        || !(0..1 << SOURCE_ZONE_Y_BITS).contains(&source.zone.y)
+    {
+        return Err(anyhow!("Source zone {:?} cannot be written", source.zone));
+    }
+    if source.rotation > MAX_ROTATION {
+        return Err(anyhow!(
+            "Rotation {} exceeds {}",
+            source.rotation,
+            MAX_ROTATION
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream_io::{BitRead, BitReader};
+    use std::io::Cursor;
+
+    #[test]
+    fn rebuild_normal_test() -> Result<()> {
+        // Lumbridge is centered in its region, so the build area overlaps the regions around it
+        let zone = ZoneCoordinates::of(Coordinates::new(3222, 3218, 0));
+        assert_eq!(region_id(zone), 12850);
+
+        let rebuild = RebuildNormal::new(zone).with_keys(12850, [1, 2, 3, 4]);
+        assert_eq!(
+            rebuild.regions(),
+            [12593, 12594, 12595, 12849, 12850, 12851, 13105, 13106, 13107]
+        );
+
+        let buf = rebuild.encode()?;
+        assert_eq!(buf.len(), 6 + 9 * 16);
+        assert_eq!(buf[..6], [0x01, 0x92, 0x01, 0x92, 0, 9]);
+        assert_eq!(
+            buf[6 + 4 * 16..6 + 5 * 16],
+            [0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4]
+        );
+        assert!(buf[6..6 + 4 * 16].iter().all(|&byte| byte == 0));
+
+        // Near the edge of a region only the regions within 6 zones are loaded
+        let rebuild = RebuildNormal::new(ZoneCoordinates::of(Coordinates::new(3200, 3200, 0)));
+        assert_eq!(rebuild.regions(), [12593, 12594, 12849, 12850]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn needs_rebuild_test() {
+        // The build area spans 3168 to 3271, of which the rebuild boundary is cut off
+        let center = Coordinates::new(3222, 3218, 0);
+        assert!(!needs_rebuild(center, center, 16));
+        assert!(!needs_rebuild(center, Coordinates::new(3184, 3255, 3), 16));
+        assert!(needs_rebuild(center, Coordinates::new(3183, 3218, 0), 16));
+        assert!(needs_rebuild(center, Coordinates::new(3222, 3256, 0), 16));
+        assert!(!needs_rebuild(center, Coordinates::new(3183, 3218, 0), 8));
+    }
+
+    #[test]
+    fn rebuild_region_test() -> Result<()> {
+        let source = SourceZone {
+            zone: ZoneCoordinates {
+                x: 402,
+                y: 402,
+                plane: 0,
+            },
+            rotation: 1,
+        };
+        let rebuild = RebuildRegion::new(ZoneCoordinates {
+            x: 800,
+            y: 800,
+            plane: 0,
+        })
+        .with_reload(true)
+        .with_zone(0, 0, 1, source)
+        .with_zone(1, 12, 12, source)
+        .with_keys(12850, [1, 2, 3, 4]);
+        assert_eq!(rebuild.regions(), [12850]);
+
+        // 4 planes of 13 by 13 bits, with 26 bits for each of the 2 zones
+        let buf = rebuild.encode()?;
+        assert_eq!(
+            buf.len(),
+            5 + (4 * 13 * 13 + 2 * 26_usize).div_ceil(8) + 2 + 16
+        );
+        assert_eq!(buf[..5], [0x03, 0x20, 0x03, 0x20, 1]);
+
+        let mut reader = BitReader::endian(Cursor::new(&buf[5..]), BigEndian);
+        assert!(!reader.read_bit()?);
+        assert!(reader.read_bit()?);
+        assert_eq!(reader.read::<u32>(26)?, 402 << 14 | 402 << 3 | 1 << 1);
+        assert_eq!(buf[buf.len() - 18..buf.len() - 16], [0, 1]);
+
+        assert!(RebuildRegion::new(source.zone)
+            .with_zone(0, 13, 0, source)
+            .encode()
+            .is_err());
+        assert!(RebuildRegion::new(source.zone)
+            .with_zone(
+                0,
+                0,
+                0,
+                SourceZone {
+                    rotation: 4,
+                    ..source
+                }
+            )
+            .encode()
+            .is_err());
+
+        Ok(())
+    }
+}
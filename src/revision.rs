@@ -0,0 +1,240 @@
+//! The client revisions masks are encoded for, which shuffle the order and the bits of the masks between builds
+use crate::playerinfo::{MASKS, MASK_CONTINUATION_MEDIUM, MASK_CONTINUATION_SHORT};
+use anyhow::{anyhow, Result};
+
+/// A client revision, given by the order the client reads the masks in and the bit it reads each mask from.
+/// Masks are identified by their flags in this crate, and mapped to the flags of the revision once written.
+/// Implemented by a unit struct returning constants, the revision is selected at compile time and encoding
+/// does not branch on it
+pub trait Revision: Send + 'static {
+    /// The masks in the order the client reads them
+    fn masks(&self) -> &[u32];
+
+    /// Get the flag the client reads the mask from
+    fn wire_flag(&self, mask: u32) -> u32;
+
+    /// Get the flags the client reads the masks from
+    fn wire_flags(&self, mask_flags: u32) -> u32 {
+        self.masks()
+            .iter()
+            .filter(|&&mask| mask_flags & mask != 0)
+            .fold(0, |flags, &mask| flags | self.wire_flag(mask))
+    }
+}
+
+/// The revision the masks of this crate are laid out for, where every mask is read from its own flag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefaultRevision;
+
+impl Revision for DefaultRevision {
+    #[inline]
+    fn masks(&self) -> &[u32] {
+        MASKS
+    }
+
+    #[inline]
+    fn wire_flag(&self, mask: u32) -> u32 {
+        mask
+    }
+
+    #[inline]
+    fn wire_flags(&self, mask_flags: u32) -> u32 {
+        mask_flags
+    }
+}
+
+/// A revision given at runtime, e.g. loaded from the config of a server targeting several client builds.
+/// Starts out as the default revision, and is checked once a PlayerInfo is created using it.
+/// Encoding looks up the flag of every written mask, which a revision selected at compile time avoids
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionProfile {
+    masks: Vec<u32>,
+    // The flags the client reads masks from where they differ from those of the crate, as the mask and its flag
+    wire_flags: Vec<(u32, u32)>,
+}
+
+impl Default for RevisionProfile {
+    fn default() -> Self {
+        RevisionProfile {
+            masks: MASKS.to_vec(),
+            wire_flags: Vec::new(),
+        }
+    }
+}
+
+impl RevisionProfile {
+    /// Set the order the client reads the masks in
+    pub fn with_order(mut self, masks: &[u32]) -> RevisionProfile {
+        self.masks = masks.to_vec();
+        self
+    }
+
+    /// Set the flag the client reads the mask from
+    pub fn with_flag(mut self, mask: u32, flag: u32) -> RevisionProfile {
+        self.wire_flags.retain(|(other, _)| *other != mask);
+        self.wire_flags.push((mask, flag));
+        self
+    }
+}
+
+impl Revision for RevisionProfile {
+    fn masks(&self) -> &[u32] {
+        &self.masks
+    }
+
+    fn wire_flag(&self, mask: u32) -> u32 {
+        self.wire_flags
+            .iter()
+            .find(|(other, _)| *other == mask)
+            .map_or(mask, |(_, flag)| *flag)
+    }
+}
+
+/// Check the revision orders every mask exactly once, and reads each from a distinct bit of the mask header
+pub(crate) fn validate(revision: &impl Revision) -> Result<()> {
+    let masks = revision.masks();
+    if masks.len() != MASKS.len() || MASKS.iter().any(|mask| !masks.contains(mask)) {
+        return Err(anyhow!("Revision does not order every mask exactly once"));
+    }
+
+    let mut wire_flags = 0;
+    for &mask in masks {
+        let flag = revision.wire_flag(mask);
+        if flag.count_ones() != 1 || flag > 0xFFFFFF {
+            return Err(anyhow!(
+                "Mask {:#x} is read from {:#x}, which is not a single bit of the header",
+                mask,
+                flag
+            ));
+        }
+        if flag & (MASK_CONTINUATION_SHORT | MASK_CONTINUATION_MEDIUM) != 0 {
+            return Err(anyhow!(
+                "Mask {:#x} is read from the continuation marker {:#x}",
+                mask,
+                flag
+            ));
+        }
+        if wire_flags & flag != 0 {
+            return Err(anyhow!(
+                "Mask {:#x} is read from a taken flag {:#x}",
+                mask,
+                flag
+            ));
+        }
+        wire_flags |= flag;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playerinfo::{
+        DirectionMask, FaceCoordinateMask, PlayerInfo, CHAT_MASK, DIRECTION_MASK,
+        FACE_COORDINATE_MASK,
+    };
+
+    // The default order with the direction and face coordinate masks swapped
+    const SWAPPED_MASKS: [u32; MASKS.len()] = {
+        let mut masks = [0; MASKS.len()];
+        let mut i = 0;
+        while i < MASKS.len() {
+            masks[i] = match MASKS[i] {
+                DIRECTION_MASK => FACE_COORDINATE_MASK,
+                FACE_COORDINATE_MASK => DIRECTION_MASK,
+                mask => mask,
+            };
+            i += 1;
+        }
+        masks
+    };
+
+    // A revision reading the direction and face coordinate masks from each other's flag and in each other's place
+    struct SwappedRevision;
+
+    impl Revision for SwappedRevision {
+        fn masks(&self) -> &[u32] {
+            &SWAPPED_MASKS
+        }
+
+        fn wire_flag(&self, mask: u32) -> u32 {
+            match mask {
+                DIRECTION_MASK => FACE_COORDINATE_MASK,
+                FACE_COORDINATE_MASK => DIRECTION_MASK,
+                _ => mask,
+            }
+        }
+    }
+
+    // A revision reading every mask from the same flag
+    struct CollidingRevision;
+
+    impl Revision for CollidingRevision {
+        fn masks(&self) -> &[u32] {
+            MASKS
+        }
+
+        fn wire_flag(&self, _mask: u32) -> u32 {
+            DIRECTION_MASK
+        }
+    }
+
+    // Encode the direction and face coordinate masks of a player for the revision
+    fn encode_masks<R: Revision>(revision: R) -> Result<Vec<u8>> {
+        let mut playerinfo = PlayerInfo::with_revision(Default::default(), revision)?;
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.add_player_face_coordinate_mask(0, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        let vec = playerinfo.process(0)?;
+
+        Ok(vec[vec.len() - 8..].to_vec())
+    }
+
+    #[test]
+    fn revision_test() -> Result<()> {
+        assert!(validate(&DefaultRevision).is_ok());
+        assert!(validate(&CollidingRevision).is_err());
+        assert!(PlayerInfo::with_revision(Default::default(), CollidingRevision).is_err());
+
+        let mut playerinfo = PlayerInfo::with_revision(Default::default(), SwappedRevision)?;
+        playerinfo.add_player(0x101)?;
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec[vec.len() - 4..], [0x40, 0x20, 0x02, 0x80]);
+        playerinfo.tick();
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 512 })?;
+        playerinfo.add_player_face_coordinate_mask(0, FaceCoordinateMask { x: 3222, y: 3218 })?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(
+            vec[vec.len() - 8..],
+            [0x48, 0x20, 0x19, 0x2D, 0x19, 0x25, 0x02, 0x80]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn revision_profile_test() -> Result<()> {
+        let profile = RevisionProfile::default()
+            .with_order(&SWAPPED_MASKS)
+            .with_flag(DIRECTION_MASK, FACE_COORDINATE_MASK)
+            .with_flag(FACE_COORDINATE_MASK, DIRECTION_MASK);
+        assert_eq!(encode_masks(profile)?, encode_masks(SwappedRevision)?);
+        assert_eq!(
+            encode_masks(RevisionProfile::default())?,
+            encode_masks(DefaultRevision)?
+        );
+
+        // Every mask has to be ordered, and read from its own flag
+        assert!(validate(&RevisionProfile::default().with_order(&MASKS[1..])).is_err());
+        assert!(
+            validate(&RevisionProfile::default().with_flag(DIRECTION_MASK, CHAT_MASK)).is_err()
+        );
+        assert!(validate(&RevisionProfile::default().with_flag(DIRECTION_MASK, 0x40)).is_err());
+
+        Ok(())
+    }
+}
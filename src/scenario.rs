@@ -0,0 +1,346 @@
+//! A builder for scripted scenarios driving the real API, asserting on the packets as the client decodes them.
+//!
+//! ```ignore
+//! scenario()
+//!     .player("a").at(3200, 3200)
+//!     .player("b").at(3205, 3200)
+//!     .tick()
+//!     .player("a").walks_east(2).chats("hi")
+//!     .tick()
+//!     .expect("b", "a", DecodedUpdate::Walked { direction: EAST, masks: true })
+//!     .expect_shout("b", "a", "hi");
+//! ```
+use crate::config::WorldInfoConfig;
+use crate::coordinates::Coordinates;
+use crate::decoder::{self, DecodedMasks, DecodedPacket, DecodedUpdate};
+use crate::playerinfo::{ForcedChatMask, PlayerInfo, PlayerKey, SHOUT_MASK};
+
+/// The walk directions as decoded by the client
+pub(crate) const SOUTH: u32 = 1;
+pub(crate) const WEST: u32 = 3;
+pub(crate) const EAST: u32 = 4;
+pub(crate) const NORTH: u32 = 6;
+
+/// Start an empty scenario
+pub(crate) fn scenario() -> Scenario {
+    scenario_with_config(WorldInfoConfig::default())
+}
+
+/// Start an empty scenario using the given config
+pub(crate) fn scenario_with_config(config: WorldInfoConfig) -> Scenario {
+    Scenario {
+        playerinfo: PlayerInfo::with_config(config).expect("invalid config"),
+        players: Vec::new(),
+        selected: None,
+        walks: Vec::new(),
+        packets: Vec::new(),
+    }
+}
+
+pub(crate) struct Scenario {
+    playerinfo: PlayerInfo,
+    // The names of the players along with their keys, in the order they were added
+    players: Vec<(String, Option<PlayerKey>)>,
+    selected: Option<usize>,
+    // The walks in progress as the step taken each tick and the amount of steps left
+    walks: Vec<(PlayerKey, (i32, i32), u32)>,
+    // The packets of the last tick as decoded by the client of each observer
+    packets: Vec<(PlayerKey, DecodedPacket)>,
+}
+
+impl Scenario {
+    /// Select the player with the given name for the following actions, it joins once placed using `at`
+    pub(crate) fn player(mut self, name: &str) -> Scenario {
+        let index = match self.players.iter().position(|(other, _)| other == name) {
+            Some(index) => index,
+            None => {
+                self.players.push((name.to_string(), None));
+                self.players.len() - 1
+            }
+        };
+        self.selected = Some(index);
+
+        self
+    }
+
+    /// Place the selected player, adding it if it has not joined yet and teleporting it otherwise
+    pub(crate) fn at(mut self, x: i32, y: i32) -> Scenario {
+        let index = self.selected.expect("no player selected");
+        let coordinates = Coordinates::new(x, y, 0);
+
+        match self.players[index].1 {
+            Some(player_id) => self
+                .playerinfo
+                .teleport_player(player_id, coordinates)
+                .expect("failed teleporting player"),
+            None => {
+                // Players are never removed, so keys are handed out in the order players join
+                let player_id = self.players.iter().filter(|(_, key)| key.is_some()).count();
+                self.playerinfo
+                    .add_player_at(coordinates)
+                    .expect("failed adding player");
+                self.players[index].1 = Some(player_id);
+            }
+        }
+
+        self
+    }
+
+    pub(crate) fn walks_north(self, tiles: u32) -> Scenario {
+        self.walks((0, 1), tiles)
+    }
+
+    pub(crate) fn walks_east(self, tiles: u32) -> Scenario {
+        self.walks((1, 0), tiles)
+    }
+
+    pub(crate) fn walks_south(self, tiles: u32) -> Scenario {
+        self.walks((0, -1), tiles)
+    }
+
+    pub(crate) fn walks_west(self, tiles: u32) -> Scenario {
+        self.walks((-1, 0), tiles)
+    }
+
+    /// Walk the selected player a tile each tick, starting with the next one
+    fn walks(mut self, step: (i32, i32), tiles: u32) -> Scenario {
+        let player_id = self.selected_player();
+        self.walks.retain(|(other, _, _)| *other != player_id);
+        self.walks.push((player_id, step, tiles));
+
+        self
+    }
+
+    /// Let the selected player shout the text overhead in the next tick
+    pub(crate) fn chats(mut self, text: &str) -> Scenario {
+        let player_id = self.selected_player();
+        self.playerinfo
+            .add_player_forced_chat_mask(
+                player_id,
+                ForcedChatMask {
+                    text: text.to_string(),
+                },
+            )
+            .expect("failed setting forced chat");
+
+        self
+    }
+
+    /// Process and decode the packets of every player, and advance to the next tick
+    pub(crate) fn tick(mut self) -> Scenario {
+        for (player_id, step, tiles) in self.walks.iter_mut() {
+            self.playerinfo
+                .queue_player_step(*player_id, step.0, step.1)
+                .expect("failed queueing step");
+            *tiles -= 1;
+        }
+        self.walks.retain(|(_, _, tiles)| *tiles > 0);
+
+        let observers: Vec<PlayerKey> = self.players.iter().filter_map(|(_, key)| *key).collect();
+        let records: Vec<_> = observers
+            .iter()
+            .map(|player_id| {
+                self.playerinfo
+                    .client_records(*player_id)
+                    .expect("failed getting client records")
+            })
+            .collect();
+
+        self.packets.clear();
+        for (player_id, records) in observers.into_iter().zip(records) {
+            let packet = self
+                .playerinfo
+                .process(player_id)
+                .expect("failed processing player");
+            let decoded = decoder::decode(&packet, &records).expect("failed decoding packet");
+            self.packets.push((player_id, decoded));
+        }
+        self.playerinfo.tick();
+
+        self
+    }
+
+    /// Run the given amount of ticks
+    pub(crate) fn ticks(mut self, ticks: u32) -> Scenario {
+        for _ in 0..ticks {
+            self = self.tick();
+        }
+
+        self
+    }
+
+    /// Assert what the observer decoded for the subject during the last tick
+    pub(crate) fn expect(self, observer: &str, subject: &str, update: DecodedUpdate) -> Scenario {
+        assert_eq!(
+            self.update(observer, subject),
+            Some(update),
+            "update of {} seen by {}",
+            subject,
+            observer
+        );
+
+        self
+    }
+
+    /// Assert the observer decoded nothing for the subject during the last tick
+    pub(crate) fn expect_nothing(self, observer: &str, subject: &str) -> Scenario {
+        assert_eq!(
+            self.update(observer, subject),
+            None,
+            "update of {} seen by {}",
+            subject,
+            observer
+        );
+
+        self
+    }
+
+    /// Assert the observer decoded the subject shouting the text during the last tick
+    pub(crate) fn expect_shout(self, observer: &str, subject: &str, text: &str) -> Scenario {
+        let shout = self
+            .masks(observer, subject)
+            .and_then(|masks| masks.block(SHOUT_MASK))
+            .map(|block| String::from_utf8_lossy(&block[..block.len() - 1]).into_owned());
+        assert_eq!(
+            shout.as_deref(),
+            Some(text),
+            "shout of {} seen by {}",
+            subject,
+            observer
+        );
+
+        self
+    }
+
+    /// Get what the observer decoded for the subject during the last tick
+    pub(crate) fn update(&self, observer: &str, subject: &str) -> Option<DecodedUpdate> {
+        self.packet(observer).update(self.key(subject))
+    }
+
+    /// Get the masks the observer decoded for the subject during the last tick
+    pub(crate) fn masks(&self, observer: &str, subject: &str) -> Option<&DecodedMasks> {
+        self.packet(observer).masks(self.key(subject))
+    }
+
+    fn packet(&self, observer: &str) -> &DecodedPacket {
+        let observer = self.key(observer);
+        self.packets
+            .iter()
+            .find(|(player_id, _)| *player_id == observer)
+            .map(|(_, packet)| packet)
+            .expect("observer was not processed in the last tick")
+    }
+
+    fn key(&self, name: &str) -> PlayerKey {
+        self.players
+            .iter()
+            .find(|(other, _)| other == name)
+            .and_then(|(_, key)| *key)
+            .unwrap_or_else(|| panic!("player {} has not joined", name))
+    }
+
+    fn selected_player(&self) -> PlayerKey {
+        let index = self.selected.expect("no player selected");
+        self.players[index]
+            .1
+            .expect("selected player has not joined")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_and_chat_scenario_test() {
+        scenario()
+            .player("a")
+            .at(3200, 3200)
+            .player("b")
+            .at(3205, 3200)
+            .player("c")
+            .at(3230, 3200)
+            .tick()
+            .expect(
+                "b",
+                "a",
+                DecodedUpdate::Added {
+                    x: 3200 & 0x1FFF,
+                    y: 3200 & 0x1FFF,
+                    masks: false,
+                },
+            )
+            .expect_nothing("b", "c")
+            .player("a")
+            .walks_east(2)
+            .chats("hi")
+            .tick()
+            .expect(
+                "b",
+                "a",
+                DecodedUpdate::Walked {
+                    direction: EAST,
+                    masks: true,
+                },
+            )
+            .expect_shout("b", "a", "hi")
+            .tick()
+            .expect(
+                "b",
+                "a",
+                DecodedUpdate::Walked {
+                    direction: EAST,
+                    masks: false,
+                },
+            )
+            .tick()
+            .expect_nothing("b", "a")
+            // Walking out of view removes the player
+            .player("b")
+            .walks_north(16)
+            .tick()
+            .expect(
+                "a",
+                "b",
+                DecodedUpdate::Walked {
+                    direction: NORTH,
+                    masks: false,
+                },
+            )
+            .ticks(15)
+            .expect("a", "b", DecodedUpdate::Removed)
+            .player("b")
+            .walks_south(2)
+            .tick()
+            .expect(
+                "a",
+                "b",
+                DecodedUpdate::Added {
+                    x: 3205 & 0x1FFF,
+                    y: 3215 & 0x1FFF,
+                    masks: false,
+                },
+            )
+            .tick()
+            .expect(
+                "a",
+                "b",
+                DecodedUpdate::Walked {
+                    direction: SOUTH,
+                    masks: false,
+                },
+            )
+            .player("a")
+            .walks_west(1)
+            .tick()
+            .expect(
+                "b",
+                "a",
+                DecodedUpdate::Walked {
+                    direction: WEST,
+                    masks: false,
+                },
+            )
+            .expect_nothing("a", "c");
+    }
+}
@@ -0,0 +1,945 @@
+//! Snapshots of the PlayerInfo state, and tooling for comparing them when debugging desyncs
+use crate::coordinates::Coordinates;
+use crate::playerinfo::{
+    AppearanceMask, AppearanceMorph, DirectionMask, FaceCoordinateMask, FaceEntity, FaceEntityMask,
+    MoveSpeed, NameModifiersMask, APPEARANCE_MASK, DIRECTION_MASK, FACE_COORDINATE_MASK,
+    LOCK_TURNTO_MASK, MOVEMENT_CACHED_MASK, NAME_MODIFIERS_MASK,
+};
+use anyhow::{anyhow, Context, Result};
+use osrs_buffer::{ReadExt, WriteExt};
+use std::io::{Cursor, Write};
+
+/// The version of the encoding written by `PlayerInfoSnapshot::to_bytes`
+pub const SNAPSHOT_VERSION: u8 = 2;
+
+// Prefixes every versioned encoding. Encodings without it predate the header and are read as version 0
+const SNAPSHOT_MAGIC: [u8; 3] = *b"GPI";
+
+/// A point in time copy of the state of a PlayerInfo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerInfoSnapshot {
+    pub cycle: u32,
+    /// Every observer along with its records of the other players, ordered by player id
+    pub observers: Vec<ObserverSnapshot>,
+    /// Every player along with its pending updates, ordered by player id
+    pub subjects: Vec<SubjectSnapshot>,
+}
+
+/// The records an observer keeps of all players
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObserverSnapshot {
+    pub player_id: usize,
+    pub suspended: bool,
+    /// The record of every player, indexed by player id
+    pub records: Vec<RecordSnapshot>,
+}
+
+/// The record an observer keeps of a single player
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordSnapshot {
+    pub flags: i32,
+    pub local: bool,
+    pub coordinates: i32,
+    pub reset: bool,
+    pub local_to_global: bool,
+    pub global_to_local: bool,
+    /// Movement missed while the observer was suspended
+    pub pending_movement: Option<(i32, i32, i32)>,
+    /// State masks missed while the observer was suspended
+    pub pending_mask_flags: u32,
+}
+
+/// The pending updates of a single player, along with where it is and its lasting state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubjectSnapshot {
+    pub player_id: usize,
+    pub mask_flags: u32,
+    /// The absolute coordinates of the player, unknown for players added by their packed coordinates
+    pub coordinates: Option<Coordinates>,
+    pub move_speed: MoveSpeed,
+    /// The data of the persistent masks, replayed to observers the player is added to
+    pub appearance: Option<AppearanceMask>,
+    pub direction: Option<DirectionMask>,
+    pub face_coordinate: Option<FaceCoordinateMask>,
+    pub face_entity: Option<FaceEntityMask>,
+    pub name_modifiers: Option<NameModifiersMask>,
+}
+
+impl SubjectSnapshot {
+    /// Create the snapshot of a player without any lasting state
+    pub fn new(player_id: usize, mask_flags: u32) -> SubjectSnapshot {
+        SubjectSnapshot {
+            player_id,
+            mask_flags,
+            coordinates: None,
+            move_speed: MoveSpeed::default(),
+            appearance: None,
+            direction: None,
+            face_coordinate: None,
+            face_entity: None,
+            name_modifiers: None,
+        }
+    }
+
+    // The flags of the persistent masks whose data differs from the other snapshot
+    fn persistent_divergence(&self, other: &SubjectSnapshot) -> u32 {
+        [
+            (APPEARANCE_MASK, self.appearance != other.appearance),
+            (DIRECTION_MASK, self.direction != other.direction),
+            (
+                FACE_COORDINATE_MASK,
+                self.face_coordinate != other.face_coordinate,
+            ),
+            (LOCK_TURNTO_MASK, self.face_entity != other.face_entity),
+            (
+                NAME_MODIFIERS_MASK,
+                self.name_modifiers != other.name_modifiers,
+            ),
+            (MOVEMENT_CACHED_MASK, self.move_speed != other.move_speed),
+        ]
+        .iter()
+        .filter(|(_, differs)| *differs)
+        .fold(0, |flags, (mask, _)| flags | mask)
+    }
+}
+
+/// A difference between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub observer: usize,
+    /// The player the record is of, equal to the observer for divergences of the player itself
+    pub subject: usize,
+    pub kind: DivergenceKind,
+}
+
+/// The kind of difference between two snapshots, holding the value of the left and right snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The player only exists in one of the snapshots
+    Missing { left: bool, right: bool },
+    /// The subject is local to the observer in one snapshot but not in the other
+    Locality { left: bool, right: bool },
+    /// The coordinates the observer last saw the subject at differ
+    Coordinates { left: i32, right: i32 },
+    /// The subject is in a different update group
+    UpdateGroup { left: i32, right: i32 },
+    /// A reset of the record is still pending in one of the snapshots
+    Reset { left: bool, right: bool },
+    /// A transition from local to global is still pending in one of the snapshots
+    LocalToGlobal { left: bool, right: bool },
+    /// A transition from global to local is still pending in one of the snapshots
+    GlobalToLocal { left: bool, right: bool },
+    /// The observer has a different number of records, e.g. from a different player limit
+    RecordCount { left: usize, right: usize },
+    /// The player has different masks pending
+    PendingMasks { left: u32, right: u32 },
+    /// The absolute coordinates of the player differ
+    SubjectCoordinates {
+        left: Option<Coordinates>,
+        right: Option<Coordinates>,
+    },
+    /// The data of the persistent masks with the given flags differs, as replayed to observers adding the player
+    PersistentMasks { flags: u32 },
+    /// The observer is suspended in one snapshot but not in the other
+    Suspended { left: bool, right: bool },
+    /// The movement of the subject the suspended observer missed differs
+    MissedMovement {
+        left: Option<(i32, i32, i32)>,
+        right: Option<(i32, i32, i32)>,
+    },
+    /// The state masks of the subject the suspended observer missed differ
+    MissedMasks { left: u32, right: u32 },
+}
+
+impl PlayerInfoSnapshot {
+    /// Compare against another snapshot, reporting every divergence per observer and subject pair.
+    /// Records are only compared for observers present in both snapshots
+    pub fn diff(&self, other: &PlayerInfoSnapshot) -> Vec<Divergence> {
+        let mut divergences = Vec::new();
+
+        for observer in &self.observers {
+            let other_observer = match other.observer(observer.player_id) {
+                Some(other_observer) => other_observer,
+                None => {
+                    divergences.push(Divergence {
+                        observer: observer.player_id,
+                        subject: observer.player_id,
+                        kind: DivergenceKind::Missing {
+                            left: true,
+                            right: false,
+                        },
+                    });
+                    continue;
+                }
+            };
+
+            if observer.suspended != other_observer.suspended {
+                divergences.push(Divergence {
+                    observer: observer.player_id,
+                    subject: observer.player_id,
+                    kind: DivergenceKind::Suspended {
+                        left: observer.suspended,
+                        right: other_observer.suspended,
+                    },
+                });
+            }
+            for (subject, (left, right)) in observer
+                .records
+                .iter()
+                .zip(&other_observer.records)
+                .enumerate()
+            {
+                diff_record(&mut divergences, observer.player_id, subject, left, right);
+            }
+            // Records past the end of the shorter list are reported as a whole
+            if observer.records.len() != other_observer.records.len() {
+                divergences.push(Divergence {
+                    observer: observer.player_id,
+                    subject: observer.player_id,
+                    kind: DivergenceKind::RecordCount {
+                        left: observer.records.len(),
+                        right: other_observer.records.len(),
+                    },
+                });
+            }
+        }
+
+        for other_observer in &other.observers {
+            if self.observer(other_observer.player_id).is_none() {
+                divergences.push(Divergence {
+                    observer: other_observer.player_id,
+                    subject: other_observer.player_id,
+                    kind: DivergenceKind::Missing {
+                        left: false,
+                        right: true,
+                    },
+                });
+            }
+        }
+
+        for subject in &self.subjects {
+            if let Some(other_subject) = other.subject(subject.player_id) {
+                if subject.mask_flags != other_subject.mask_flags {
+                    divergences.push(Divergence {
+                        observer: subject.player_id,
+                        subject: subject.player_id,
+                        kind: DivergenceKind::PendingMasks {
+                            left: subject.mask_flags,
+                            right: other_subject.mask_flags,
+                        },
+                    });
+                }
+                if subject.coordinates != other_subject.coordinates {
+                    divergences.push(Divergence {
+                        observer: subject.player_id,
+                        subject: subject.player_id,
+                        kind: DivergenceKind::SubjectCoordinates {
+                            left: subject.coordinates,
+                            right: other_subject.coordinates,
+                        },
+                    });
+                }
+                let flags = subject.persistent_divergence(other_subject);
+                if flags != 0 {
+                    divergences.push(Divergence {
+                        observer: subject.player_id,
+                        subject: subject.player_id,
+                        kind: DivergenceKind::PersistentMasks { flags },
+                    });
+                }
+            }
+        }
+
+        divergences
+    }
+
+    /// Get the snapshot of an observer
+    pub fn observer(&self, player_id: usize) -> Option<&ObserverSnapshot> {
+        self.observers
+            .iter()
+            .find(|observer| observer.player_id == player_id)
+    }
+
+    /// Get the snapshot of a subject
+    pub fn subject(&self, player_id: usize) -> Option<&SubjectSnapshot> {
+        self.subjects
+            .iter()
+            .find(|subject| subject.player_id == player_id)
+    }
+
+    /// Encode the snapshot with the current version header, so it can be stored or compared in another process.
+    /// Only records that differ from a fresh record are written
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        buf.write_all(&SNAPSHOT_MAGIC)?;
+        buf.write_u8(SNAPSHOT_VERSION)?;
+        buf.write_u32(self.cycle)?;
+        buf.write_u16(self.observers.len() as u16)?;
+        for observer in &self.observers {
+            let records: Vec<_> = observer
+                .records
+                .iter()
+                .enumerate()
+                .filter(|(_, record)| **record != RecordSnapshot::default())
+                .collect();
+
+            buf.write_u16(observer.player_id as u16)?;
+            buf.write_u8(observer.suspended as u8)?;
+            buf.write_u16(observer.records.len() as u16)?;
+            buf.write_u16(records.len() as u16)?;
+            for (index, record) in records {
+                buf.write_u16(index as u16)?;
+                buf.write_i32(record.flags)?;
+                buf.write_i32(record.coordinates)?;
+                buf.write_u8(
+                    record.local as u8
+                        | (record.reset as u8) << 1
+                        | (record.local_to_global as u8) << 2
+                        | (record.global_to_local as u8) << 3
+                        | (record.pending_movement.is_some() as u8) << 4,
+                )?;
+                buf.write_u32(record.pending_mask_flags)?;
+                if let Some((dx, dy, dz)) = record.pending_movement {
+                    buf.write_i32(dx)?;
+                    buf.write_i32(dy)?;
+                    buf.write_i32(dz)?;
+                }
+            }
+        }
+
+        buf.write_u16(self.subjects.len() as u16)?;
+        for subject in &self.subjects {
+            buf.write_u16(subject.player_id as u16)?;
+            buf.write_u32(subject.mask_flags)?;
+            write_subject_state(&mut buf, subject)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Decode a snapshot encoded with `to_bytes` by this or any earlier version of the crate.
+    /// State that older versions did not store is migrated to its default, i.e. nothing missed while suspended,
+    /// and players without known coordinates or lasting state
+    pub fn from_bytes(bytes: &[u8]) -> Result<PlayerInfoSnapshot> {
+        let (version, body) = match bytes.strip_prefix(&SNAPSHOT_MAGIC) {
+            Some(rest) => (
+                *rest.first().context("Snapshot is missing its version")?,
+                &rest[1..],
+            ),
+            None => (0, bytes),
+        };
+        if version > SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "Snapshot version {} is newer than the supported version {}",
+                version,
+                SNAPSHOT_VERSION
+            ));
+        }
+
+        let mut buf = Cursor::new(body);
+
+        let cycle = buf.read_u32()?;
+
+        let observer_count = buf.read_u16()?;
+        let mut observers = Vec::with_capacity(observer_count as usize);
+        for _ in 0..observer_count {
+            let player_id = buf.read_u16()? as usize;
+            let suspended = version >= 1 && buf.read_u8()? != 0;
+            let mut records = vec![RecordSnapshot::default(); buf.read_u16()? as usize];
+
+            for _ in 0..buf.read_u16()? {
+                let index = buf.read_u16()? as usize;
+                let flags = buf.read_i32()?;
+                let coordinates = buf.read_i32()?;
+                let bits = buf.read_u8()?;
+                let pending_mask_flags = if version >= 1 { buf.read_u32()? } else { 0 };
+                let pending_movement = if bits & 0x10 != 0 {
+                    Some((buf.read_i32()?, buf.read_i32()?, buf.read_i32()?))
+                } else {
+                    None
+                };
+
+                *records
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow!("Snapshot record {} out of range", index))? =
+                    RecordSnapshot {
+                        flags,
+                        local: bits & 0x1 != 0,
+                        coordinates,
+                        reset: bits & 0x2 != 0,
+                        local_to_global: bits & 0x4 != 0,
+                        global_to_local: bits & 0x8 != 0,
+                        pending_movement,
+                        pending_mask_flags,
+                    };
+            }
+
+            observers.push(ObserverSnapshot {
+                player_id,
+                suspended,
+                records,
+            });
+        }
+
+        let subject_count = buf.read_u16()?;
+        let mut subjects = Vec::with_capacity(subject_count as usize);
+        for _ in 0..subject_count {
+            let mut subject = SubjectSnapshot::new(buf.read_u16()? as usize, buf.read_u32()?);
+            if version >= 2 {
+                read_subject_state(&mut buf, &mut subject)?;
+            }
+            subjects.push(subject);
+        }
+
+        Ok(PlayerInfoSnapshot {
+            cycle,
+            observers,
+            subjects,
+        })
+    }
+}
+
+// Write where the player is and its lasting state, each optional part preceded by whether it is present
+fn write_subject_state(buf: &mut Vec<u8>, subject: &SubjectSnapshot) -> Result<()> {
+    buf.write_u8(
+        subject.coordinates.is_some() as u8
+            | (subject.appearance.is_some() as u8) << 1
+            | (subject.direction.is_some() as u8) << 2
+            | (subject.face_coordinate.is_some() as u8) << 3
+            | (subject.face_entity.is_some() as u8) << 4
+            | (subject.name_modifiers.is_some() as u8) << 5,
+    )?;
+    buf.write_u8(subject.move_speed as u8)?;
+    if let Some(coordinates) = subject.coordinates {
+        buf.write_i32(coordinates.x)?;
+        buf.write_i32(coordinates.y)?;
+        buf.write_i32(coordinates.plane)?;
+    }
+    if let Some(appearance) = &subject.appearance {
+        write_appearance(buf, appearance)?;
+    }
+    if let Some(direction) = &subject.direction {
+        buf.write_i16(direction.direction)?;
+    }
+    if let Some(face_coordinate) = &subject.face_coordinate {
+        buf.write_i32(face_coordinate.x)?;
+        buf.write_i32(face_coordinate.y)?;
+    }
+    if let Some(face_entity) = &subject.face_entity {
+        match face_entity.entity {
+            FaceEntity::Player(player_id) => {
+                buf.write_u8(0)?;
+                buf.write_u16(player_id as u16)?;
+            }
+            FaceEntity::Npc(npc_id) => {
+                buf.write_u8(1)?;
+                buf.write_u16(npc_id)?;
+            }
+            FaceEntity::None => buf.write_u8(2)?,
+        }
+    }
+    if let Some(name_modifiers) = &subject.name_modifiers {
+        buf.write_string_cp1252(&name_modifiers.prefix)?;
+        buf.write_string_cp1252(&name_modifiers.infix)?;
+        buf.write_string_cp1252(&name_modifiers.suffix)?;
+    }
+
+    Ok(())
+}
+
+fn read_subject_state(buf: &mut Cursor<&[u8]>, subject: &mut SubjectSnapshot) -> Result<()> {
+    let bits = buf.read_u8()?;
+    subject.move_speed = match buf.read_u8()? {
+        0 => MoveSpeed::Crawl,
+        1 => MoveSpeed::Walk,
+        2 => MoveSpeed::Run,
+        move_speed => return Err(anyhow!("Snapshot move speed {} is unknown", move_speed)),
+    };
+    if bits & 0x1 != 0 {
+        subject.coordinates = Some(Coordinates::new(
+            buf.read_i32()?,
+            buf.read_i32()?,
+            buf.read_i32()?,
+        ));
+    }
+    if bits & 0x2 != 0 {
+        subject.appearance = Some(read_appearance(buf)?);
+    }
+    if bits & 0x4 != 0 {
+        subject.direction = Some(DirectionMask {
+            direction: buf.read_i16()?,
+        });
+    }
+    if bits & 0x8 != 0 {
+        subject.face_coordinate = Some(FaceCoordinateMask {
+            x: buf.read_i32()?,
+            y: buf.read_i32()?,
+        });
+    }
+    if bits & 0x10 != 0 {
+        let entity = match buf.read_u8()? {
+            0 => FaceEntity::Player(buf.read_u16()? as usize),
+            1 => FaceEntity::Npc(buf.read_u16()?),
+            2 => FaceEntity::None,
+            kind => return Err(anyhow!("Snapshot face entity kind {} is unknown", kind)),
+        };
+        subject.face_entity = Some(FaceEntityMask { entity });
+    }
+    if bits & 0x20 != 0 {
+        subject.name_modifiers = Some(NameModifiersMask {
+            prefix: buf.read_string_cp1252()?,
+            infix: buf.read_string_cp1252()?,
+            suffix: buf.read_string_cp1252()?,
+        });
+    }
+
+    Ok(())
+}
+
+// Write every field of the appearance mask in declaration order
+fn write_appearance(buf: &mut Vec<u8>, appearance: &AppearanceMask) -> Result<()> {
+    buf.write_i8(appearance.gender)?;
+    buf.write_u8(
+        appearance.skull as u8
+            | (appearance.is_full_body as u8) << 1
+            | (appearance.covers_hair as u8) << 2
+            | (appearance.covers_face as u8) << 3,
+    )?;
+    buf.write_i8(appearance.overhead_prayer)?;
+    match appearance.morph {
+        AppearanceMorph::Player => buf.write_u8(0)?,
+        AppearanceMorph::Npc(npc) => {
+            buf.write_u8(1)?;
+            buf.write_u16(npc)?;
+        }
+        AppearanceMorph::Loc(loc) => {
+            buf.write_u8(2)?;
+            buf.write_u16(loc)?;
+        }
+    }
+    for part in [
+        appearance.head,
+        appearance.cape,
+        appearance.neck,
+        appearance.weapon,
+        appearance.body,
+        appearance.shield,
+        appearance.torso,
+        appearance.arms,
+        appearance.legs,
+        appearance.hair,
+        appearance.hands,
+        appearance.feet,
+        appearance.beard,
+    ] {
+        buf.write_i16(part)?;
+    }
+    for color in [
+        appearance.colors_hair,
+        appearance.colors_torso,
+        appearance.colors_legs,
+        appearance.colors_feet,
+        appearance.colors_skin,
+    ] {
+        buf.write_i8(color)?;
+    }
+    for stance in [
+        appearance.weapon_stance_stand,
+        appearance.weapon_stance_turn,
+        appearance.weapon_stance_walk,
+        appearance.weapon_stance_turn180,
+        appearance.weapon_stance_turn90cw,
+        appearance.weapon_stance_turn90ccw,
+        appearance.weapon_stance_run,
+    ] {
+        buf.write_i16(stance)?;
+    }
+    buf.write_string_cp1252(&appearance.username)?;
+    buf.write_i8(appearance.combat_level)?;
+    buf.write_i16(appearance.skill_id_level)?;
+    buf.write_i8(appearance.hidden)?;
+
+    Ok(())
+}
+
+fn read_appearance(buf: &mut Cursor<&[u8]>) -> Result<AppearanceMask> {
+    let gender = buf.read_i8()?;
+    let flags = buf.read_u8()?;
+    let overhead_prayer = buf.read_i8()?;
+    let morph = match buf.read_u8()? {
+        0 => AppearanceMorph::Player,
+        1 => AppearanceMorph::Npc(buf.read_u16()?),
+        2 => AppearanceMorph::Loc(buf.read_u16()?),
+        kind => return Err(anyhow!("Snapshot appearance morph {} is unknown", kind)),
+    };
+    let mut parts = [0; 13];
+    for part in parts.iter_mut() {
+        *part = buf.read_i16()?;
+    }
+    let mut colors = [0; 5];
+    for color in colors.iter_mut() {
+        *color = buf.read_i8()?;
+    }
+    let mut stances = [0; 7];
+    for stance in stances.iter_mut() {
+        *stance = buf.read_i16()?;
+    }
+    let [head, cape, neck, weapon, body, shield, torso, arms, legs, hair, hands, feet, beard] =
+        parts;
+    let [colors_hair, colors_torso, colors_legs, colors_feet, colors_skin] = colors;
+    let [weapon_stance_stand, weapon_stance_turn, weapon_stance_walk, weapon_stance_turn180, weapon_stance_turn90cw, weapon_stance_turn90ccw, weapon_stance_run] =
+        stances;
+
+    Ok(AppearanceMask {
+        gender,
+        skull: flags & 0x1 != 0,
+        overhead_prayer,
+        morph,
+        head,
+        cape,
+        neck,
+        weapon,
+        body,
+        shield,
+        torso,
+        arms,
+        is_full_body: flags & 0x2 != 0,
+        legs,
+        hair,
+        covers_hair: flags & 0x4 != 0,
+        hands,
+        feet,
+        covers_face: flags & 0x8 != 0,
+        beard,
+        colors_hair,
+        colors_torso,
+        colors_legs,
+        colors_feet,
+        colors_skin,
+        weapon_stance_stand,
+        weapon_stance_turn,
+        weapon_stance_walk,
+        weapon_stance_turn180,
+        weapon_stance_turn90cw,
+        weapon_stance_turn90ccw,
+        weapon_stance_run,
+        username: buf.read_string_cp1252()?,
+        combat_level: buf.read_i8()?,
+        skill_id_level: buf.read_i16()?,
+        hidden: buf.read_i8()?,
+    })
+}
+
+fn diff_record(
+    divergences: &mut Vec<Divergence>,
+    observer: usize,
+    subject: usize,
+    left: &RecordSnapshot,
+    right: &RecordSnapshot,
+) {
+    let mut push = |kind| {
+        divergences.push(Divergence {
+            observer,
+            subject,
+            kind,
+        })
+    };
+
+    if left.local != right.local {
+        push(DivergenceKind::Locality {
+            left: left.local,
+            right: right.local,
+        });
+    }
+    if left.coordinates != right.coordinates {
+        push(DivergenceKind::Coordinates {
+            left: left.coordinates,
+            right: right.coordinates,
+        });
+    }
+    if left.flags != right.flags {
+        push(DivergenceKind::UpdateGroup {
+            left: left.flags,
+            right: right.flags,
+        });
+    }
+    if left.reset != right.reset {
+        push(DivergenceKind::Reset {
+            left: left.reset,
+            right: right.reset,
+        });
+    }
+    if left.local_to_global != right.local_to_global {
+        push(DivergenceKind::LocalToGlobal {
+            left: left.local_to_global,
+            right: right.local_to_global,
+        });
+    }
+    if left.global_to_local != right.global_to_local {
+        push(DivergenceKind::GlobalToLocal {
+            left: left.global_to_local,
+            right: right.global_to_local,
+        });
+    }
+    if left.pending_movement != right.pending_movement {
+        push(DivergenceKind::MissedMovement {
+            left: left.pending_movement,
+            right: right.pending_movement,
+        });
+    }
+    if left.pending_mask_flags != right.pending_mask_flags {
+        push(DivergenceKind::MissedMasks {
+            left: left.pending_mask_flags,
+            right: right.pending_mask_flags,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playerinfo::PlayerInfo;
+
+    #[test]
+    fn snapshot_diff_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+
+        let before = playerinfo.snapshot();
+        assert!(before.diff(&before).is_empty());
+
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 0 })?;
+        playerinfo.process(0)?;
+        let after = playerinfo.snapshot();
+
+        let divergences = before.diff(&after);
+        assert!(divergences.contains(&Divergence {
+            observer: 1,
+            subject: 1,
+            kind: DivergenceKind::PendingMasks {
+                left: 0,
+                right: 0x8
+            },
+        }));
+        // Every record of the processed observer moved to the inactive group
+        assert!(divergences.contains(&Divergence {
+            observer: 0,
+            subject: 5,
+            kind: DivergenceKind::UpdateGroup { left: 0, right: 1 },
+        }));
+        // The direction is kept after it is written, to be replayed to observers adding the player
+        assert!(divergences.contains(&Divergence {
+            observer: 1,
+            subject: 1,
+            kind: DivergenceKind::PersistentMasks {
+                flags: DIRECTION_MASK
+            },
+        }));
+        assert!(divergences.iter().all(|divergence| divergence.observer != 1
+            || matches!(
+                divergence.kind,
+                DivergenceKind::PendingMasks { .. } | DivergenceKind::PersistentMasks { .. }
+            )));
+
+        let decoded = PlayerInfoSnapshot::from_bytes(&after.to_bytes()?)?;
+        assert_eq!(decoded, after);
+
+        let mut truncated = after.clone();
+        let count = after.observers[0].records.len();
+        truncated.observers[0].records.truncate(count - 1);
+        assert_eq!(
+            after.diff(&truncated),
+            [Divergence {
+                observer: 0,
+                subject: 0,
+                kind: DivergenceKind::RecordCount {
+                    left: count,
+                    right: count - 1
+                },
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_suspension_diff_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+        let before = playerinfo.snapshot();
+
+        let mut after = before.clone();
+        after.observers[0].suspended = true;
+        after.observers[0].records[1].pending_movement = Some((1, 0, 0));
+        after.observers[0].records[1].pending_mask_flags = 0x8;
+        assert_eq!(
+            before.diff(&after),
+            [
+                Divergence {
+                    observer: 0,
+                    subject: 0,
+                    kind: DivergenceKind::Suspended {
+                        left: false,
+                        right: true
+                    },
+                },
+                Divergence {
+                    observer: 0,
+                    subject: 1,
+                    kind: DivergenceKind::MissedMovement {
+                        left: None,
+                        right: Some((1, 0, 0))
+                    },
+                },
+                Divergence {
+                    observer: 0,
+                    subject: 1,
+                    kind: DivergenceKind::MissedMasks {
+                        left: 0,
+                        right: 0x8
+                    },
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_migration_test() -> Result<()> {
+        // A headerless encoding written before snapshots were versioned
+        let mut legacy = Vec::new();
+        legacy.write_u32(7)?;
+        legacy.write_u16(1)?;
+        legacy.write_u16(0)?;
+        legacy.write_u16(3)?;
+        legacy.write_u16(1)?;
+        legacy.write_u16(1)?;
+        legacy.write_i32(1)?;
+        legacy.write_i32(99)?;
+        legacy.write_u8(0x1)?;
+        legacy.write_u16(1)?;
+        legacy.write_u16(0)?;
+        legacy.write_u32(0x2)?;
+
+        let snapshot = PlayerInfoSnapshot::from_bytes(&legacy)?;
+        assert_eq!(snapshot.cycle, 7);
+        assert!(!snapshot.observers[0].suspended);
+        assert_eq!(
+            snapshot.observers[0].records[1],
+            RecordSnapshot {
+                flags: 1,
+                local: true,
+                coordinates: 99,
+                ..Default::default()
+            }
+        );
+        assert_eq!(snapshot.subjects[0].mask_flags, 0x2);
+
+        let bytes = snapshot.to_bytes()?;
+        assert_eq!(bytes[..4], [b'G', b'P', b'I', SNAPSHOT_VERSION]);
+        assert_eq!(PlayerInfoSnapshot::from_bytes(&bytes)?, snapshot);
+
+        let mut future = bytes;
+        future[3] = SNAPSHOT_VERSION + 1;
+        assert!(PlayerInfoSnapshot::from_bytes(&future).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn restore_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(0x101)?;
+        playerinfo.add_player(0x100)?;
+        playerinfo.add_player(0x10001)?;
+        playerinfo.remove_player(1)?;
+        playerinfo.process(0)?;
+        playerinfo.suspend_player(2)?;
+        playerinfo.tick();
+
+        let snapshot = playerinfo.snapshot();
+        let bytes = snapshot.to_bytes()?;
+        let mut restored = PlayerInfo::restore(&PlayerInfoSnapshot::from_bytes(&bytes)?)?;
+        assert!(snapshot.diff(&restored.snapshot()).is_empty());
+        assert!(restored.is_suspended(2));
+
+        // Player ids are kept, so new players fill the same vacant keys
+        restored.add_player(0x100)?;
+        playerinfo.add_player(0x100)?;
+        assert_eq!(restored.snapshot(), playerinfo.snapshot());
+
+        Ok(())
+    }
+
+    #[test]
+    fn restore_state_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player_at(Coordinates::new(3222, 3218, 0))?;
+        playerinfo.add_player_at(Coordinates::new(3260, 3218, 0))?;
+        playerinfo.add_player_appearance_mask(
+            1,
+            AppearanceMask::builder()
+                .with_username("Zezima")
+                .with_morph(AppearanceMorph::Npc(7))
+                .build(),
+        )?;
+        playerinfo.add_player_face_entity_mask(
+            1,
+            FaceEntityMask {
+                entity: FaceEntity::Npc(3),
+            },
+        )?;
+        playerinfo.add_player_name_modifiers_mask(
+            1,
+            NameModifiersMask {
+                prefix: "Sir ".to_string(),
+                ..Default::default()
+            },
+        )?;
+        playerinfo.set_player_move_speed(1, MoveSpeed::Run)?;
+        playerinfo.process_all();
+        playerinfo.tick();
+
+        let snapshot = playerinfo.snapshot();
+        let decoded = PlayerInfoSnapshot::from_bytes(&snapshot.to_bytes()?)?;
+        assert_eq!(decoded, snapshot);
+        let mut restored = PlayerInfo::restore(&decoded)?;
+        assert!(snapshot.diff(&restored.snapshot()).is_empty());
+        assert_eq!(
+            restored.player_coordinates(1),
+            Some(Coordinates::new(3260, 3218, 0))
+        );
+
+        // Players keep moving into view, and are added with their lasting state like before the restore
+        playerinfo.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        restored.teleport_player(1, Coordinates::new(3230, 3218, 0))?;
+        assert_eq!(restored.process(0)?, playerinfo.process(0)?);
+        assert_eq!(restored.process(1)?, playerinfo.process(1)?);
+
+        let mut diverged = snapshot.clone();
+        diverged.subjects[1].coordinates = None;
+        diverged.subjects[1].name_modifiers = None;
+        assert_eq!(
+            snapshot
+                .diff(&diverged)
+                .into_iter()
+                .map(|divergence| divergence.kind)
+                .collect::<Vec<_>>(),
+            [
+                DivergenceKind::SubjectCoordinates {
+                    left: Some(Coordinates::new(3260, 3218, 0)),
+                    right: None
+                },
+                DivergenceKind::PersistentMasks {
+                    flags: NAME_MODIFIERS_MASK
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,567 @@
+//! WorldEntityInfo stuff, the movable world entities such as ships that revisions 220 and later update
+//! in their own info packet
+use crate::config::WorldInfoConfig;
+use crate::coordinates::Coordinates;
+use crate::playerinfo::{self, PlayerKey};
+use crate::zoneinfo::BUILD_AREA_SIZE;
+use anyhow::{anyhow, Context, Result};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+use slab::Slab;
+
+/// The key of a world entity, the index the client knows it by
+pub type WorldEntityKey = usize;
+
+// The bits of the index of an added world entity, where the highest index terminates the additions
+const INDEX_BITS: u32 = 11;
+const INDEX_TERMINATOR: u32 = (1 << INDEX_BITS) - 1;
+const MAX_WORLD_ENTITIES: usize = INDEX_TERMINATOR as usize;
+// The amount of local world entities is written using 8 bits
+const MAX_LOCAL_WORLD_ENTITIES: usize = 255;
+// The bits of the deltas of added and moved world entities along each axis
+const DELTA_BITS: u32 = 8;
+const MAX_DELTA: i32 = (1 << (DELTA_BITS - 1)) - 1;
+const SIZE_BITS: u32 = 8;
+const ANGLE_BITS: u32 = 11;
+/// The angle of a world entity, in 2048ths of a full turn
+pub const MAX_ANGLE: u16 = (1 << ANGLE_BITS) - 1;
+/// The largest size of a world entity along either axis, so the south-west corner of an entity in view
+/// stays within reach of the deltas of its addition
+pub const MAX_WORLD_ENTITY_SIZE: u8 = 64;
+/// The distance in tiles at which world entities are seen, as far as the build area reaches from its center
+pub const WORLD_ENTITY_VIEW_DISTANCE: i32 = BUILD_AREA_SIZE / 2;
+
+const WORLD_ENTITY_UPDATE_MOVE: u32 = 1;
+const WORLD_ENTITY_UPDATE_REMOVE: u32 = 3;
+
+struct WorldEntity {
+    coordinates: Coordinates,
+    // The coordinates at the start of the tick, which moves are written relative to
+    tick_coordinates: Coordinates,
+    size_x: u8,
+    size_z: u8,
+    angle: u16,
+    turned: bool,
+}
+
+impl WorldEntity {
+    fn moved(&self) -> bool {
+        self.coordinates != self.tick_coordinates || self.turned
+    }
+
+    // Whether the entity moved further than the deltas of a move reach, so observers add it anew
+    fn jumped(&self) -> bool {
+        let (dx, dy, _) = self.tick_coordinates.delta(self.coordinates);
+        self.coordinates.plane != self.tick_coordinates.plane
+            || dx.abs() > MAX_DELTA
+            || dy.abs() > MAX_DELTA
+    }
+}
+
+// A world entity known to the client of an observer
+struct LocalWorldEntity {
+    entity_id: WorldEntityKey,
+    // Whether the entity was removed from the WorldEntityInfo, so its key may already belong to another entity
+    removed: bool,
+}
+
+struct WorldEntityObserver {
+    coordinates: Coordinates,
+    // The local world entities in the order the client knows them
+    local_entities: Vec<LocalWorldEntity>,
+}
+
+// The update of a local world entity
+enum LocalUpdate {
+    Idle,
+    Move(i32, i32, u16),
+    Remove,
+}
+
+/// The WorldEntityInfo containing the world entities and the world entities seen by each player
+pub struct WorldEntityInfo {
+    entities: Slab<WorldEntity>,
+    // Keyed by the same keys as the players of the PlayerInfo
+    observers: Slab<WorldEntityObserver>,
+    config: WorldInfoConfig,
+}
+
+impl Default for WorldEntityInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorldEntityInfo {
+    /// Create a WorldEntityInfo using the default config
+    pub fn new() -> WorldEntityInfo {
+        WorldEntityInfo::with_config(WorldInfoConfig::default()).expect("default config is valid")
+    }
+
+    /// Create a WorldEntityInfo using the player limit of the config.
+    /// Fails if the config is invalid
+    pub fn with_config(config: WorldInfoConfig) -> Result<WorldEntityInfo> {
+        config.validate()?;
+
+        Ok(WorldEntityInfo {
+            entities: Slab::new(),
+            observers: Slab::new(),
+            config,
+        })
+    }
+
+    /// Add a player seeing the world entities around the coordinates, keyed like the players of the PlayerInfo
+    pub fn add_player(&mut self, coordinates: Coordinates) -> Result<PlayerKey> {
+        coordinates.validate()?;
+
+        let player_id = self.observers.vacant_key();
+        if player_id >= self.config.max_players {
+            return Err(anyhow!(
+                "Maximum amount of players processable by WorldEntityInfo reached"
+            ));
+        }
+
+        self.observers.insert(WorldEntityObserver {
+            coordinates,
+            local_entities: Vec::new(),
+        });
+
+        Ok(player_id)
+    }
+
+    /// Remove a player from the WorldEntityInfo
+    pub fn remove_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        self.observers
+            .try_remove(player_id)
+            .context("failed getting player")?;
+
+        Ok(())
+    }
+
+    /// Move the player the world entities are seen from, which should be kept in line with its coordinates in the PlayerInfo
+    pub fn set_player_coordinates(
+        &mut self,
+        player_id: PlayerKey,
+        coordinates: Coordinates,
+    ) -> Result<()> {
+        coordinates.validate()?;
+
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Get the local world entities of the player, in the order its client knows them
+    pub fn local_world_entities(&self, player_id: PlayerKey) -> Vec<WorldEntityKey> {
+        self.observers
+            .get(player_id)
+            .map(|observer| {
+                observer
+                    .local_entities
+                    .iter()
+                    .filter(|local| !local.removed)
+                    .map(|local| local.entity_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Add a world entity spanning the given amount of tiles along each axis from its south-west corner at the coordinates,
+    /// returning the index the client knows it by
+    pub fn add_world_entity(
+        &mut self,
+        coordinates: Coordinates,
+        size_x: u8,
+        size_z: u8,
+    ) -> Result<WorldEntityKey> {
+        coordinates.validate()?;
+        for size in [size_x, size_z] {
+            if !(1..=MAX_WORLD_ENTITY_SIZE).contains(&size) {
+                return Err(anyhow!("World entity size out of range: {}", size));
+            }
+        }
+
+        let entity_id = self.entities.vacant_key();
+        if entity_id >= MAX_WORLD_ENTITIES {
+            return Err(anyhow!(
+                "Maximum amount of world entities processable by WorldEntityInfo reached"
+            ));
+        }
+
+        self.entities.insert(WorldEntity {
+            coordinates,
+            tick_coordinates: coordinates,
+            size_x,
+            size_z,
+            angle: 0,
+            turned: false,
+        });
+
+        Ok(entity_id)
+    }
+
+    /// Remove a world entity, which observers that can see it remove in their next update
+    pub fn remove_world_entity(&mut self, entity_id: WorldEntityKey) -> Result<()> {
+        self.entities
+            .try_remove(entity_id)
+            .context("failed getting world entity")?;
+
+        // The key is free to be reused by another entity, which the client has to add anew
+        for (_, observer) in self.observers.iter_mut() {
+            for local in observer.local_entities.iter_mut() {
+                if local.entity_id == entity_id {
+                    local.removed = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the coordinates of the world entity
+    pub fn world_entity_coordinates(&self, entity_id: WorldEntityKey) -> Option<Coordinates> {
+        self.entities
+            .get(entity_id)
+            .map(|entity| entity.coordinates)
+    }
+
+    /// Move the world entity to the coordinates this tick. Observers that can see it move it by the delta,
+    /// or remove it and add it anew when it changed plane or moved further than a move reaches
+    pub fn set_world_entity_coordinates(
+        &mut self,
+        entity_id: WorldEntityKey,
+        coordinates: Coordinates,
+    ) -> Result<()> {
+        coordinates.validate()?;
+
+        self.entities
+            .get_mut(entity_id)
+            .context("failed getting world entity")?
+            .coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Turn the world entity to the angle this tick, in 2048ths of a full turn
+    pub fn set_world_entity_angle(&mut self, entity_id: WorldEntityKey, angle: u16) -> Result<()> {
+        if angle > MAX_ANGLE {
+            return Err(anyhow!("World entity angle out of range: {}", angle));
+        }
+
+        let entity = self
+            .entities
+            .get_mut(entity_id)
+            .context("failed getting world entity")?;
+        entity.turned |= entity.angle != angle;
+        entity.angle = angle;
+
+        Ok(())
+    }
+
+    /// Advance to the next tick, settling the movement of every world entity.
+    /// Should be called once at the end of every tick, after all players have been processed
+    pub fn tick(&mut self) {
+        for (_, entity) in self.entities.iter_mut() {
+            entity.tick_coordinates = entity.coordinates;
+            entity.turned = false;
+        }
+    }
+
+    /// Process a player contained in the WorldEntityInfo, returning a buffer with the updates of the world entities around the player.
+    /// Local entities are updated in the order the client knows them, with runs of idle entities skipped like the players
+    /// of the PlayerInfo, after which the entities that came into view are added
+    pub fn process(&mut self, player_id: PlayerKey) -> Result<Vec<u8>> {
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+        bit_buf.write(8, observer.local_entities.len() as u32)?;
+
+        let updates: Vec<LocalUpdate> = observer
+            .local_entities
+            .iter()
+            .map(|local| match self.entities.get(local.entity_id) {
+                Some(entity)
+                    if !local.removed
+                        && !entity.jumped()
+                        && in_view(observer.coordinates, entity) =>
+                {
+                    if entity.moved() {
+                        let (dx, dy, _) = entity.tick_coordinates.delta(entity.coordinates);
+                        LocalUpdate::Move(dx, dy, entity.angle)
+                    } else {
+                        LocalUpdate::Idle
+                    }
+                }
+                _ => LocalUpdate::Remove,
+            })
+            .collect();
+
+        let mut index = 0;
+        while index < updates.len() {
+            match updates[index] {
+                LocalUpdate::Idle => {
+                    let skip_count = updates[index + 1..]
+                        .iter()
+                        .take_while(|update| matches!(update, LocalUpdate::Idle))
+                        .count();
+                    bit_buf.write_bit(false)?;
+                    playerinfo::write_skip_count(&mut bit_buf, skip_count as i32, false)?;
+                    index += skip_count;
+                }
+                LocalUpdate::Move(dx, dy, angle) => {
+                    bit_buf.write_bit(true)?;
+                    bit_buf.write(2, WORLD_ENTITY_UPDATE_MOVE)?;
+                    write_delta(&mut bit_buf, dx, dy)?;
+                    bit_buf.write(ANGLE_BITS, angle as u32)?;
+                }
+                LocalUpdate::Remove => {
+                    bit_buf.write_bit(true)?;
+                    bit_buf.write(2, WORLD_ENTITY_UPDATE_REMOVE)?;
+                }
+            }
+            index += 1;
+        }
+
+        let mut local_entities: Vec<LocalWorldEntity> = observer
+            .local_entities
+            .drain(..)
+            .zip(updates)
+            .filter(|(_, update)| !matches!(update, LocalUpdate::Remove))
+            .map(|(local, _)| local)
+            .collect();
+
+        // Like NPCs, the nearest entities are added first. Entities past the local limit are added in later ticks
+        let mut candidates: Vec<(i32, WorldEntityKey)> = self
+            .entities
+            .iter()
+            .filter(|&(entity_id, entity)| {
+                in_view(observer.coordinates, entity)
+                    && !local_entities
+                        .iter()
+                        .any(|local| local.entity_id == entity_id)
+            })
+            .map(|(entity_id, entity)| (entity_distance(observer.coordinates, entity), entity_id))
+            .collect();
+        candidates.sort_unstable();
+        let additions = MAX_LOCAL_WORLD_ENTITIES.saturating_sub(local_entities.len());
+
+        for &(_, entity_id) in candidates.iter().take(additions) {
+            let entity = &self.entities[entity_id];
+            let (dx, dy, _) = observer.coordinates.delta(entity.coordinates);
+            bit_buf.write(INDEX_BITS, entity_id as u32)?;
+            bit_buf.write(SIZE_BITS, entity.size_x as u32)?;
+            bit_buf.write(SIZE_BITS, entity.size_z as u32)?;
+            write_delta(&mut bit_buf, dx, dy)?;
+            bit_buf.write(ANGLE_BITS, entity.angle as u32)?;
+            local_entities.push(LocalWorldEntity {
+                entity_id,
+                removed: false,
+            });
+        }
+        bit_buf.write(INDEX_BITS, INDEX_TERMINATOR)?;
+        bit_buf.byte_align()?;
+        observer.local_entities = local_entities;
+
+        Ok(bit_buf.into_writer())
+    }
+}
+
+/// Whether the world entity is within view of an observer at the coordinates
+fn in_view(coordinates: Coordinates, entity: &WorldEntity) -> bool {
+    coordinates.plane == entity.coordinates.plane
+        && entity_distance(coordinates, entity) <= WORLD_ENTITY_VIEW_DISTANCE
+}
+
+// The distance to the nearest tile the world entity spans
+fn entity_distance(coordinates: Coordinates, entity: &WorldEntity) -> i32 {
+    let nearest = Coordinates {
+        x: coordinates.x.clamp(
+            entity.coordinates.x,
+            entity.coordinates.x + i32::from(entity.size_x) - 1,
+        ),
+        y: coordinates.y.clamp(
+            entity.coordinates.y,
+            entity.coordinates.y + i32::from(entity.size_z) - 1,
+        ),
+        ..entity.coordinates
+    };
+    coordinates.distance(nearest)
+}
+
+fn write_delta(bit_buf: &mut BitWriter<Vec<u8>, BigEndian>, dx: i32, dy: i32) -> Result<()> {
+    let delta_mask = (1 << DELTA_BITS) - 1;
+    bit_buf.write(DELTA_BITS, dx as u32 & delta_mask)?;
+    bit_buf.write(DELTA_BITS, dy as u32 & delta_mask)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream_io::{BitRead, BitReader};
+    use std::io::Cursor;
+
+    // A world entity update as decoded by the client
+    #[derive(Debug, PartialEq, Eq)]
+    enum Decoded {
+        Skipped(u32),
+        Moved(i32, i32, u32),
+        Removed,
+        Added {
+            index: u32,
+            size_x: u32,
+            size_z: u32,
+            dx: i32,
+            dy: i32,
+            angle: u32,
+        },
+    }
+
+    fn read_delta(reader: &mut BitReader<Cursor<&[u8]>, BigEndian>) -> Result<i32> {
+        Ok(reader.read::<u8>(DELTA_BITS)? as i8 as i32)
+    }
+
+    // Decode a packet like the client would
+    fn decode(packet: &[u8]) -> Result<Vec<Decoded>> {
+        let mut reader = BitReader::endian(Cursor::new(packet), BigEndian);
+        let mut updates = Vec::new();
+
+        let local_count: u32 = reader.read(8)?;
+        let mut index = 0;
+        while index < local_count {
+            if !reader.read_bit()? {
+                let skip_count = match reader.read::<u32>(2)? {
+                    0 => 0,
+                    1 => reader.read(5)?,
+                    2 => reader.read(8)?,
+                    _ => reader.read(11)?,
+                };
+                updates.push(Decoded::Skipped(skip_count));
+                index += skip_count;
+            } else {
+                match reader.read::<u32>(2)? {
+                    WORLD_ENTITY_UPDATE_MOVE => {
+                        let dx = read_delta(&mut reader)?;
+                        let dy = read_delta(&mut reader)?;
+                        updates.push(Decoded::Moved(dx, dy, reader.read(ANGLE_BITS)?));
+                    }
+                    WORLD_ENTITY_UPDATE_REMOVE => updates.push(Decoded::Removed),
+                    update => return Err(anyhow!("Unexpected update type {}", update)),
+                }
+            }
+            index += 1;
+        }
+
+        loop {
+            let index: u32 = reader.read(INDEX_BITS)?;
+            if index == INDEX_TERMINATOR {
+                break;
+            }
+            updates.push(Decoded::Added {
+                index,
+                size_x: reader.read(SIZE_BITS)?,
+                size_z: reader.read(SIZE_BITS)?,
+                dx: read_delta(&mut reader)?,
+                dy: read_delta(&mut reader)?,
+                angle: reader.read(ANGLE_BITS)?,
+            });
+        }
+
+        Ok(updates)
+    }
+
+    #[test]
+    fn world_entity_test() -> Result<()> {
+        let mut world_entity_info = WorldEntityInfo::new();
+        let origin = Coordinates::new(3200, 3200, 0);
+        let player_id = world_entity_info.add_player(origin)?;
+
+        assert!(world_entity_info.add_world_entity(origin, 0, 4).is_err());
+        assert!(world_entity_info
+            .add_world_entity(origin, 4, MAX_WORLD_ENTITY_SIZE + 1)
+            .is_err());
+
+        // Large entities are seen from their nearest tile, even with their corner beyond the view distance
+        let ship = world_entity_info.add_world_entity(origin.translate(-60, 2), 12, 5)?;
+        world_entity_info.set_world_entity_angle(ship, 512)?;
+        let raft = world_entity_info.add_world_entity(origin.translate(3, 0), 2, 2)?;
+        let barge = world_entity_info.add_world_entity(origin.translate(0, -5), 4, 8)?;
+        let far = world_entity_info.add_world_entity(origin.translate(-70, 0), 4, 4)?;
+        assert!(world_entity_info
+            .set_world_entity_angle(raft, MAX_ANGLE + 1)
+            .is_err());
+
+        assert_eq!(
+            decode(&world_entity_info.process(player_id)?)?,
+            vec![
+                Decoded::Added {
+                    index: barge as u32,
+                    size_x: 4,
+                    size_z: 8,
+                    dx: 0,
+                    dy: -5,
+                    angle: 0
+                },
+                Decoded::Added {
+                    index: raft as u32,
+                    size_x: 2,
+                    size_z: 2,
+                    dx: 3,
+                    dy: 0,
+                    angle: 0
+                },
+                Decoded::Added {
+                    index: ship as u32,
+                    size_x: 12,
+                    size_z: 5,
+                    dx: -60,
+                    dy: 2,
+                    angle: 512
+                },
+            ]
+        );
+        assert!(!world_entity_info
+            .local_world_entities(player_id)
+            .contains(&far));
+        world_entity_info.tick();
+
+        // Idle entities are skipped in runs, the last one moving and turning
+        world_entity_info.set_world_entity_coordinates(ship, origin.translate(-58, 3))?;
+        world_entity_info.set_world_entity_angle(ship, 640)?;
+        assert_eq!(
+            decode(&world_entity_info.process(player_id)?)?,
+            vec![Decoded::Skipped(1), Decoded::Moved(2, 1, 640)]
+        );
+        world_entity_info.tick();
+
+        // Moves reach as far as the deltas, while removed entities are removed by every observer
+        world_entity_info.set_world_entity_coordinates(raft, origin.translate(-40, 0))?;
+        world_entity_info.remove_world_entity(barge)?;
+        assert_eq!(
+            decode(&world_entity_info.process(player_id)?)?,
+            vec![
+                Decoded::Removed,
+                Decoded::Moved(-43, 0, 0),
+                Decoded::Skipped(0)
+            ]
+        );
+        world_entity_info.tick();
+        assert_eq!(
+            world_entity_info.local_world_entities(player_id),
+            vec![raft, ship]
+        );
+
+        assert_eq!(
+            decode(&world_entity_info.process(player_id)?)?,
+            vec![Decoded::Skipped(1)]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! The world as seen by each player, combining PlayerInfo, NpcInfo and ZoneInfo
+use crate::config::WorldInfoConfig;
+use crate::coordinates::Coordinates;
+use crate::npcinfo::NpcInfo;
+use crate::playerinfo::{PlayerInfo, PlayerKey};
+use crate::rebuild;
+use crate::zoneinfo::{ZoneInfo, ZonePacket};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// A packet encoded for a player, returned in the order it has to be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorldPacket {
+    PlayerInfo(Vec<u8>),
+    NpcInfo(Vec<u8>),
+    Zone(ZonePacket),
+}
+
+// The keys of a player in the NpcInfo and the ZoneInfo
+struct WorldPlayer {
+    npc_observer: PlayerKey,
+    zone_observer: PlayerKey,
+    // Whether the client missed NPC and zone updates while suspended, resent once it is resumed
+    missed_updates: bool,
+}
+
+/// The WorldInfo owning the PlayerInfo, NpcInfo and ZoneInfo, keeping the players of each in sync
+pub struct WorldInfo {
+    players: PlayerInfo,
+    npcs: NpcInfo,
+    zones: ZoneInfo,
+    // The keys of each player in the other subsystems, by its key in the PlayerInfo
+    world_players: HashMap<PlayerKey, WorldPlayer>,
+}
+
+impl Default for WorldInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorldInfo {
+    /// Create a new WorldInfo using the default config
+    pub fn new() -> WorldInfo {
+        Self::with_config(WorldInfoConfig::default()).expect("default config is valid")
+    }
+
+    /// Create a new WorldInfo using the given config, failing if the config is invalid
+    pub fn with_config(config: WorldInfoConfig) -> Result<WorldInfo> {
+        Ok(WorldInfo {
+            players: PlayerInfo::with_config(config.clone())?,
+            npcs: NpcInfo::with_config(config)?,
+            zones: ZoneInfo::new(),
+            world_players: HashMap::new(),
+        })
+    }
+
+    /// Get the PlayerInfo, to update players
+    pub fn player_info(&self) -> &PlayerInfo {
+        &self.players
+    }
+
+    /// Get the PlayerInfo mutably, to update players. Players are added and removed through the WorldInfo
+    pub fn player_info_mut(&mut self) -> &mut PlayerInfo {
+        &mut self.players
+    }
+
+    /// Get the NpcInfo, to update NPCs
+    pub fn npc_info(&self) -> &NpcInfo {
+        &self.npcs
+    }
+
+    /// Get the NpcInfo mutably, to update NPCs. Players are added and removed through the WorldInfo
+    pub fn npc_info_mut(&mut self) -> &mut NpcInfo {
+        &mut self.npcs
+    }
+
+    /// Get the ZoneInfo, to queue zone events
+    pub fn zone_info(&self) -> &ZoneInfo {
+        &self.zones
+    }
+
+    /// Get the ZoneInfo mutably, to queue zone events. Players are added and removed through the WorldInfo
+    pub fn zone_info_mut(&mut self) -> &mut ZoneInfo {
+        &mut self.zones
+    }
+
+    /// Add a new player at absolute coordinates, returning its key in the PlayerInfo.
+    /// The keys of the player in the NpcInfo and ZoneInfo are given by `npc_observer` and `zone_observer`
+    pub fn add_player(&mut self, coordinates: Coordinates) -> Result<PlayerKey> {
+        // Coordinates are validated by the PlayerInfo, so the other subsystems can only fail on their own limits
+        let player_id = self.players.next_player_key();
+        self.players.add_player_at(coordinates)?;
+        let npc_observer = match self.npcs.add_player(coordinates) {
+            Ok(npc_observer) => npc_observer,
+            Err(e) => {
+                self.players.remove_player(player_id)?;
+                return Err(e);
+            }
+        };
+        let zone_observer = match self.zones.add_player(coordinates) {
+            Ok(zone_observer) => zone_observer,
+            Err(e) => {
+                self.players.remove_player(player_id)?;
+                self.npcs.remove_player(npc_observer)?;
+                return Err(e);
+            }
+        };
+        self.world_players.insert(
+            player_id,
+            WorldPlayer {
+                npc_observer,
+                zone_observer,
+                missed_updates: false,
+            },
+        );
+
+        Ok(player_id)
+    }
+
+    /// Get the key of the player in the NpcInfo
+    pub fn npc_observer(&self, player_id: PlayerKey) -> Option<PlayerKey> {
+        self.world_players
+            .get(&player_id)
+            .map(|world_player| world_player.npc_observer)
+    }
+
+    /// Get the key of the player in the ZoneInfo
+    pub fn zone_observer(&self, player_id: PlayerKey) -> Option<PlayerKey> {
+        self.world_players
+            .get(&player_id)
+            .map(|world_player| world_player.zone_observer)
+    }
+
+    /// Remove a player from the WorldInfo
+    pub fn remove_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        let world_player = self
+            .world_players
+            .remove(&player_id)
+            .context("failed getting player")?;
+        self.players.remove_player(player_id)?;
+        self.npcs.remove_player(world_player.npc_observer)?;
+        self.zones.remove_player(world_player.zone_observer)?;
+
+        Ok(())
+    }
+
+    /// Check whether the map has to be rebuilt around the player, as it came within the rebuild boundary of the edge
+    /// of its build area. Once the rebuild is sent, the build area is centered on the player using `set_build_area`
+    pub fn needs_rebuild(&self, player_id: PlayerKey) -> Result<bool> {
+        let zone_observer = self
+            .zone_observer(player_id)
+            .context("failed getting player")?;
+        let center = self
+            .zones
+            .build_center(zone_observer)
+            .context("failed getting build area")?;
+        let coordinates = self
+            .players
+            .player_coordinates(player_id)
+            .context("Coordinates of the player are unknown")?;
+
+        Ok(rebuild::needs_rebuild(
+            center,
+            coordinates,
+            self.players.config().rebuild_boundary,
+        ))
+    }
+
+    /// Center the build area of the player on the coordinates, as sent to its client in a map rebuild
+    pub fn set_build_area(&mut self, player_id: PlayerKey, center: Coordinates) -> Result<()> {
+        let zone_observer = self
+            .zone_observer(player_id)
+            .context("failed getting player")?;
+
+        self.zones.set_build_area(zone_observer, center)
+    }
+
+    /// Advance to the next tick, should be called once at the end of every tick after all players have been processed
+    pub fn tick(&mut self) {
+        self.players.tick();
+        self.npcs.tick();
+        self.zones.tick();
+    }
+
+    /// Process a player, returning the packets to send to it this tick in the order they have to be sent:
+    /// the PlayerInfo, the NpcInfo, then the zone packets.
+    /// NPCs and zones are viewed from the coordinates the player is moved to in the PlayerInfo, as the client places
+    /// them relative to the player after reading its movement.
+    /// Suspended players are sent nothing. Once resumed, the NPCs around them are removed and added anew, and the
+    /// zones they observe are followed anew, as the NPC steps and zone events of the suspension were never sent
+    pub fn process(&mut self, player_id: PlayerKey) -> Result<Vec<WorldPacket>> {
+        let world_player = self
+            .world_players
+            .get_mut(&player_id)
+            .context("failed getting player")?;
+        if self.players.is_suspended(player_id) {
+            world_player.missed_updates = true;
+            return Ok(Vec::new());
+        }
+        if world_player.missed_updates {
+            self.npcs.resync_player(world_player.npc_observer)?;
+            self.zones.resync_player(world_player.zone_observer)?;
+            world_player.missed_updates = false;
+        }
+
+        let player_info = self.players.process(player_id)?;
+        let coordinates = self
+            .players
+            .player_coordinates(player_id)
+            .context("Coordinates of the player are unknown")?;
+        self.npcs
+            .set_player_coordinates(world_player.npc_observer, coordinates)?;
+        let npc_info = self.npcs.process(world_player.npc_observer)?;
+        self.zones
+            .set_player_coordinates(world_player.zone_observer, coordinates)?;
+        let zone_packets = self.zones.process(world_player.zone_observer)?;
+
+        let mut packets = vec![
+            WorldPacket::PlayerInfo(player_info),
+            WorldPacket::NpcInfo(npc_info),
+        ];
+        packets.extend(zone_packets.into_iter().map(WorldPacket::Zone));
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zoneinfo::ZoneProt;
+    use bitstream_io::{BigEndian, BitRead, BitReader};
+    use std::io::Cursor;
+
+    #[test]
+    fn worldinfo_test() -> Result<()> {
+        let mut worldinfo = WorldInfo::new();
+        let player_id = worldinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = worldinfo
+            .npc_info_mut()
+            .add_npc(100, Coordinates::new(3210, 3200, 0))?;
+
+        let packets = worldinfo.process(player_id)?;
+        assert!(matches!(
+            packets[..2],
+            [WorldPacket::PlayerInfo(_), WorldPacket::NpcInfo(_)]
+        ));
+        assert!(packets[2..]
+            .iter()
+            .all(|packet| matches!(packet, WorldPacket::Zone(_))));
+        let npc_observer = worldinfo.npc_observer(player_id).unwrap();
+        assert_eq!(worldinfo.npc_info().local_npcs(npc_observer), [npc_id]);
+        worldinfo.tick();
+
+        // NPCs follow the player moved in the PlayerInfo
+        worldinfo
+            .player_info_mut()
+            .teleport_player(player_id, Coordinates::new(3180, 3200, 0))?;
+        worldinfo.process(player_id)?;
+        assert!(worldinfo.npc_info().local_npcs(npc_observer).is_empty());
+        worldinfo.tick();
+
+        // The player is centered in the build area of its first rebuild until it nears its edge
+        assert!(!worldinfo.needs_rebuild(player_id)?);
+        worldinfo
+            .player_info_mut()
+            .teleport_player(player_id, Coordinates::new(3160, 3200, 0))?;
+        assert!(worldinfo.needs_rebuild(player_id)?);
+        worldinfo.set_build_area(player_id, Coordinates::new(3160, 3200, 0))?;
+        assert!(!worldinfo.needs_rebuild(player_id)?);
+
+        worldinfo.player_info_mut().suspend_player(player_id)?;
+        assert!(worldinfo.process(player_id)?.is_empty());
+        worldinfo.tick();
+
+        worldinfo.remove_player(player_id)?;
+        assert!(worldinfo.process(player_id).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn suspended_npc_test() -> Result<()> {
+        let mut worldinfo = WorldInfo::new();
+        let player_id = worldinfo.add_player(Coordinates::new(3200, 3200, 0))?;
+        let npc_id = worldinfo
+            .npc_info_mut()
+            .add_npc(100, Coordinates::new(3205, 3200, 0))?;
+        worldinfo.process(player_id)?;
+        worldinfo.tick();
+
+        // The NPC walks while the player is suspended, which its client never learns of
+        worldinfo.player_info_mut().suspend_player(player_id)?;
+        for _ in 0..2 {
+            worldinfo.npc_info_mut().queue_npc_step(npc_id, 0, 1)?;
+            assert!(worldinfo.process(player_id)?.is_empty());
+            worldinfo.tick();
+        }
+
+        // Once resumed, the NPC is removed and added anew at its current coordinates
+        worldinfo.player_info_mut().resume_player(player_id)?;
+        let packets = worldinfo.process(player_id)?;
+        let npc_info = match &packets[1] {
+            WorldPacket::NpcInfo(npc_info) => npc_info,
+            _ => panic!("expected the NpcInfo"),
+        };
+        let mut reader = BitReader::endian(Cursor::new(npc_info), BigEndian);
+        assert_eq!(reader.read::<u32>(8)?, 1);
+        assert!(reader.read_bit()?);
+        assert_eq!(reader.read::<u32>(2)?, 3);
+        assert_eq!(reader.read::<u32>(13)?, npc_id as u32);
+        assert_eq!(reader.read_signed::<i32>(5)?, 5);
+        assert_eq!(reader.read_signed::<i32>(5)?, 2);
+        assert_eq!(
+            worldinfo
+                .npc_info()
+                .local_npcs(worldinfo.npc_observer(player_id).unwrap()),
+            [npc_id]
+        );
+
+        // Every observed zone is followed anew
+        assert!(packets[2..].iter().any(|packet| matches!(
+            packet,
+            WorldPacket::Zone(ZonePacket {
+                prot: ZoneProt::UpdateZoneFullFollows,
+                ..
+            })
+        )));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1852 @@
+//! ZoneInfo stuff, the events of the 8x8 tile zones of the world sent to the players observing them
+use crate::coordinates::Coordinates;
+use crate::playerinfo::{self, FaceEntity, FaceEntityMask, PlayerKey};
+use anyhow::{anyhow, Context, Result};
+use osrs_buffer::WriteExt;
+use slab::Slab;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The length in tiles of the side of a zone
+pub const ZONE_SIZE: i32 = 8;
+/// The length in tiles of the side of the build area, the square of the map loaded by the client
+pub const BUILD_AREA_SIZE: i32 = 104;
+/// The distance in zones from the zone of a player to the zones it observes, unless configured otherwise
+pub const DEFAULT_ZONE_VIEW_RADIUS: i32 = 3;
+/// The id of an instance, such as a raid or a player-owned house, whose zones are isolated from the zones of the world
+/// and of other instances at the same coordinates
+pub type InstanceId = u32;
+/// The instance of the shared world, where every player starts
+pub const WORLD_INSTANCE: InstanceId = 0;
+/// The furthest a player observes zones, as far as the build area reaches from its center zone
+pub const MAX_ZONE_VIEW_RADIUS: i32 = 6;
+// The highest shape of a loc, such as walls, wall decorations, roofs and ground decorations
+const MAX_LOC_SHAPE: u8 = 22;
+const MAX_LOC_ROTATION: u8 = 3;
+
+/// The zone packets, identified by the crate and mapped to the opcodes of the revision by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneProt {
+    /// Clear the zone of the locs and objs sent before, and select it for the events following it
+    UpdateZoneFullFollows,
+    /// Select the zone for the events following it
+    UpdateZonePartialFollows,
+    /// The events of a single zone, each prefixed with the index of its prot
+    UpdateZonePartialEnclosed,
+    LocAddChange,
+    LocDel,
+    LocAnim,
+    ObjAdd,
+    ObjDel,
+    ObjCount,
+    MapProjAnim,
+    MapAnim,
+    SoundArea,
+}
+
+impl ZoneProt {
+    /// Get the index the client reads the prot of an event from within an enclosed batch
+    pub fn enclosed_index(self) -> Option<u8> {
+        match self {
+            ZoneProt::UpdateZoneFullFollows
+            | ZoneProt::UpdateZonePartialFollows
+            | ZoneProt::UpdateZonePartialEnclosed => None,
+            ZoneProt::LocAddChange => Some(0),
+            ZoneProt::LocDel => Some(1),
+            ZoneProt::LocAnim => Some(2),
+            ZoneProt::ObjAdd => Some(3),
+            ZoneProt::ObjDel => Some(4),
+            ZoneProt::ObjCount => Some(5),
+            ZoneProt::MapProjAnim => Some(6),
+            ZoneProt::MapAnim => Some(7),
+            ZoneProt::SoundArea => Some(8),
+        }
+    }
+}
+
+/// A packet encoded for a player by the ZoneInfo, to be sent as the opcode of its prot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZonePacket {
+    pub prot: ZoneProt,
+    pub payload: Vec<u8>,
+}
+
+/// The coordinates of a zone, the absolute coordinates divided by the size of a zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ZoneCoordinates {
+    pub x: i32,
+    pub y: i32,
+    pub plane: i32,
+}
+
+impl ZoneCoordinates {
+    /// Get the zone containing the coordinates
+    pub fn of(coordinates: Coordinates) -> ZoneCoordinates {
+        ZoneCoordinates {
+            x: coordinates.x.div_euclid(ZONE_SIZE),
+            y: coordinates.y.div_euclid(ZONE_SIZE),
+            plane: coordinates.plane,
+        }
+    }
+
+    /// Get the coordinates of the south-west tile of the zone
+    pub fn south_west(self) -> Coordinates {
+        Coordinates::new(self.x * ZONE_SIZE, self.y * ZONE_SIZE, self.plane)
+    }
+}
+
+/// An event on a tile within a zone, sent to every player observing the zone in the tick it was queued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZoneEvent {
+    LocAddChange(LocAddChange),
+    LocDel(LocDel),
+    LocAnim(LocAnim),
+    ObjAdd(ObjAdd),
+    ObjDel(ObjDel),
+    ObjCount(ObjCount),
+    MapProjAnim(MapProjAnim),
+    MapAnim(MapAnim),
+    SoundArea(SoundArea),
+}
+
+/// Add a loc to the tile, or change the loc of the same layer already on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocAddChange {
+    pub id: u16,
+    pub shape: u8,
+    pub rotation: u8,
+}
+
+/// Remove the loc of the layer given by the shape from the tile, e.g. a temporary loc or an opened door
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocDel {
+    pub shape: u8,
+    pub rotation: u8,
+}
+
+/// Play an animation on the loc of the layer given by the shape on the tile, e.g. an agility obstacle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocAnim {
+    pub id: u16,
+    pub shape: u8,
+    pub rotation: u8,
+}
+
+/// Add a stack of an item to the tile, e.g. a dropped item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjAdd {
+    pub id: u16,
+    pub quantity: i32,
+}
+
+/// Remove a stack of an item from the tile, e.g. a picked up item.
+/// The client removes the first stack on the tile of both the id and the quantity, so the quantity has to be
+/// that of the stack as it was last sent, and other stacks of the same item are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjDel {
+    pub id: u16,
+    pub quantity: i32,
+}
+
+/// Change the quantity of a stack of an item on the tile in place, e.g. when another item is dropped onto it.
+/// The stack is found like `ObjDel` does, by the id and its quantity as it was last sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjCount {
+    pub id: u16,
+    pub old_quantity: i32,
+    pub new_quantity: i32,
+}
+
+/// Launch a projectile from the tile towards the tile at the offset, following the target if there is one,
+/// e.g. arrows and spells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapProjAnim {
+    /// The spot animation of the projectile
+    pub id: u16,
+    pub dx: i8,
+    pub dy: i8,
+    pub target: FaceEntity,
+    pub start_height: u8,
+    pub end_height: u8,
+    /// The client cycles before the projectile is launched
+    pub delay: u16,
+    /// The client cycles the projectile takes to arrive after it is launched
+    pub duration: u16,
+    /// The angle of the arc the projectile follows
+    pub arc: u8,
+    /// The distance from the centre of the tile the projectile is launched at
+    pub source_offset: u8,
+}
+
+/// Play a spot animation on the tile, e.g. the splash of a barrage or an environmental effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapAnim {
+    pub id: u16,
+    pub height: u8,
+    /// The client cycles before the spot animation is played
+    pub delay: u16,
+}
+
+/// Play a sound heard by players within the radius of the tile, fading out with the distance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoundArea {
+    pub id: u16,
+    /// The distance in tiles the sound is heard from
+    pub radius: u8,
+    pub repetitions: u8,
+    /// The client cycles before the sound is played
+    pub delay: u8,
+}
+
+impl ZoneEvent {
+    /// Check whether the event does not change the state of the zone, and is not replayed to players
+    /// following the zone later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ZoneEvent::LocAnim(_)
+                | ZoneEvent::MapProjAnim(_)
+                | ZoneEvent::MapAnim(_)
+                | ZoneEvent::SoundArea(_)
+        )
+    }
+
+    /// Get the prot the event is sent as on its own
+    pub fn prot(&self) -> ZoneProt {
+        match self {
+            ZoneEvent::LocAddChange(_) => ZoneProt::LocAddChange,
+            ZoneEvent::LocDel(_) => ZoneProt::LocDel,
+            ZoneEvent::LocAnim(_) => ZoneProt::LocAnim,
+            ZoneEvent::ObjAdd(_) => ZoneProt::ObjAdd,
+            ZoneEvent::ObjDel(_) => ZoneProt::ObjDel,
+            ZoneEvent::ObjCount(_) => ZoneProt::ObjCount,
+            ZoneEvent::MapProjAnim(_) => ZoneProt::MapProjAnim,
+            ZoneEvent::MapAnim(_) => ZoneProt::MapAnim,
+            ZoneEvent::SoundArea(_) => ZoneProt::SoundArea,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            ZoneEvent::LocAddChange(loc) => validate_loc(loc.shape, loc.rotation),
+            ZoneEvent::LocDel(loc) => validate_loc(loc.shape, loc.rotation),
+            ZoneEvent::LocAnim(loc) => validate_loc(loc.shape, loc.rotation),
+            ZoneEvent::ObjAdd(obj) => validate_quantity(obj.quantity),
+            ZoneEvent::ObjDel(obj) => validate_quantity(obj.quantity),
+            ZoneEvent::ObjCount(obj) => {
+                validate_quantity(obj.old_quantity)?;
+                validate_quantity(obj.new_quantity)
+            }
+            ZoneEvent::MapProjAnim(projectile) => {
+                playerinfo::validate_face_entity_mask(&FaceEntityMask {
+                    entity: projectile.target,
+                })?;
+                projectile_target(projectile.target)?;
+                if projectile.delay.checked_add(projectile.duration).is_none() {
+                    return Err(anyhow!(
+                        "Projectile arrives after {} client cycles, which exceeds {}",
+                        u32::from(projectile.delay) + u32::from(projectile.duration),
+                        u16::MAX
+                    ));
+                }
+
+                Ok(())
+            }
+            ZoneEvent::MapAnim(_) | ZoneEvent::SoundArea(_) => Ok(()),
+        }
+    }
+
+    // Write the event on the tile, given as its packed coordinates within the zone
+    fn encode(&self, buf: &mut Vec<u8>, tile: u8) -> Result<()> {
+        match self {
+            ZoneEvent::LocAddChange(loc) => {
+                buf.write_u8(tile)?;
+                buf.write_u8(loc.shape << 2 | loc.rotation)?;
+                buf.write_u16(loc.id)?;
+            }
+            ZoneEvent::LocDel(loc) => {
+                buf.write_u8(loc.shape << 2 | loc.rotation)?;
+                buf.write_u8(tile)?;
+            }
+            ZoneEvent::LocAnim(loc) => {
+                buf.write_u16(loc.id)?;
+                buf.write_u8(tile)?;
+                buf.write_u8(loc.shape << 2 | loc.rotation)?;
+            }
+            ZoneEvent::ObjAdd(obj) => {
+                buf.write_u16(obj.id)?;
+                buf.write_u8(tile)?;
+                buf.write_i32(obj.quantity)?;
+            }
+            ZoneEvent::ObjDel(obj) => {
+                buf.write_u8(tile)?;
+                buf.write_u16(obj.id)?;
+                buf.write_i32(obj.quantity)?;
+            }
+            ZoneEvent::ObjCount(obj) => {
+                buf.write_u8(tile)?;
+                buf.write_u16(obj.id)?;
+                buf.write_i32(obj.old_quantity)?;
+                buf.write_i32(obj.new_quantity)?;
+            }
+            // The client reads the cycle the projectile arrives at rather than its duration
+            ZoneEvent::MapProjAnim(projectile) => {
+                buf.write_u8(tile)?;
+                buf.write_i8(projectile.dx)?;
+                buf.write_i8(projectile.dy)?;
+                buf.write_i16(projectile_target(projectile.target)?)?;
+                buf.write_u16(projectile.id)?;
+                buf.write_u8(projectile.start_height)?;
+                buf.write_u8(projectile.end_height)?;
+                buf.write_u16(projectile.delay)?;
+                buf.write_u16(projectile.delay + projectile.duration)?;
+                buf.write_u8(projectile.arc)?;
+                buf.write_u8(projectile.source_offset)?;
+            }
+            ZoneEvent::MapAnim(spot_animation) => {
+                buf.write_u16(spot_animation.id)?;
+                buf.write_u8(tile)?;
+                buf.write_u8(spot_animation.height)?;
+                buf.write_u16(spot_animation.delay)?;
+            }
+            ZoneEvent::SoundArea(sound) => {
+                buf.write_u16(sound.id)?;
+                buf.write_u8(sound.radius)?;
+                buf.write_u8(sound.repetitions)?;
+                buf.write_u8(sound.delay)?;
+                buf.write_u8(tile)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Projectiles follow NPCs by their index plus one, players by their negated index minus one, and nothing by zero
+fn projectile_target(target: FaceEntity) -> Result<i16> {
+    let target = match target {
+        FaceEntity::Player(player_id) => -(player_id as i32) - 1,
+        FaceEntity::Npc(npc_id) => i32::from(npc_id) + 1,
+        FaceEntity::None => 0,
+    };
+
+    i16::try_from(target).map_err(|_| anyhow!("Projectile target {} is out of range", target))
+}
+
+fn validate_quantity(quantity: i32) -> Result<()> {
+    if quantity < 1 {
+        return Err(anyhow!("Item quantity {} is not positive", quantity));
+    }
+
+    Ok(())
+}
+
+fn validate_loc(shape: u8, rotation: u8) -> Result<()> {
+    if shape > MAX_LOC_SHAPE {
+        return Err(anyhow!("Loc shape {} exceeds {}", shape, MAX_LOC_SHAPE));
+    }
+    if rotation > MAX_LOC_ROTATION {
+        return Err(anyhow!(
+            "Loc rotation {} exceeds {}",
+            rotation,
+            MAX_LOC_ROTATION
+        ));
+    }
+
+    Ok(())
+}
+
+// The players an event is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Visibility {
+    Public,
+    Owner(PlayerKey),
+    // Every player but the owner, who was sent the event before it became public
+    Others(PlayerKey),
+}
+
+impl Visibility {
+    fn visible_to(self, player_id: PlayerKey) -> bool {
+        match self {
+            Visibility::Public => true,
+            Visibility::Owner(owner) => owner == player_id,
+            Visibility::Others(owner) => owner != player_id,
+        }
+    }
+}
+
+struct QueuedEvent {
+    // The packed coordinates of the tile within the zone
+    tile: u8,
+    visibility: Visibility,
+    event: ZoneEvent,
+}
+
+// A stack of an item on a tile of the zone
+struct ZoneObj {
+    tile: u8,
+    obj: ObjAdd,
+    // The player the stack is only visible to until it becomes public
+    owner: Option<PlayerKey>,
+    // The ticks left until the stack becomes public, or none if it stays private
+    public_after: Option<u32>,
+}
+
+// The locs and objs changed in a zone, replayed to players following the zone after the change
+#[derive(Default)]
+struct ZoneState {
+    // The last change of each loc, by the packed tile and the layer of the loc
+    locs: BTreeMap<(u8, u8), ZoneEvent>,
+    objs: Vec<ZoneObj>,
+}
+
+impl ZoneState {
+    fn is_empty(&self) -> bool {
+        self.locs.is_empty() && self.objs.is_empty()
+    }
+
+    // Find a stack like the client does, by its tile, id and quantity
+    fn find_obj(&self, tile: u8, id: u16, quantity: i32) -> Option<usize> {
+        self.objs.iter().position(|zone_obj| {
+            zone_obj.tile == tile && zone_obj.obj.id == id && zone_obj.obj.quantity == quantity
+        })
+    }
+
+    // Apply the event to the state, returning the players it is sent to
+    fn apply(&mut self, tile: u8, event: &ZoneEvent) -> Visibility {
+        let owner = match event {
+            ZoneEvent::LocAddChange(LocAddChange { shape, .. })
+            | ZoneEvent::LocDel(LocDel { shape, .. }) => {
+                self.locs.insert((tile, loc_layer(*shape)), event.clone());
+                None
+            }
+            ZoneEvent::ObjAdd(obj) => {
+                self.objs.push(ZoneObj {
+                    tile,
+                    obj: *obj,
+                    owner: None,
+                    public_after: None,
+                });
+                None
+            }
+            ZoneEvent::ObjDel(obj) => self
+                .find_obj(tile, obj.id, obj.quantity)
+                .and_then(|index| self.objs.remove(index).owner),
+            ZoneEvent::ObjCount(obj) => {
+                self.find_obj(tile, obj.id, obj.old_quantity)
+                    .and_then(|index| {
+                        let zone_obj = &mut self.objs[index];
+                        zone_obj.obj.quantity = obj.new_quantity;
+                        zone_obj.owner
+                    })
+            }
+            _ => None,
+        };
+
+        owner.map_or(Visibility::Public, Visibility::Owner)
+    }
+
+    // The events recreating the state for the player
+    fn replay(&self, player_id: PlayerKey) -> impl Iterator<Item = (u8, ZoneEvent)> + '_ {
+        let locs = self
+            .locs
+            .iter()
+            .map(|(&(tile, _), event)| (tile, event.clone()));
+        let objs = self
+            .objs
+            .iter()
+            .filter(move |zone_obj| zone_obj.owner.is_none_or(|owner| owner == player_id))
+            .map(|zone_obj| (zone_obj.tile, ZoneEvent::ObjAdd(zone_obj.obj)));
+
+        locs.chain(objs)
+    }
+}
+
+// Locs of different layers are kept on the same tile: walls, wall decorations, centrepieces and ground decorations
+fn loc_layer(shape: u8) -> u8 {
+    match shape {
+        0..=3 => 0,
+        4..=8 => 1,
+        9..=21 => 2,
+        _ => 3,
+    }
+}
+
+struct ZoneObserver {
+    coordinates: Coordinates,
+    // The coordinates the build area was last centered on
+    build_center: Coordinates,
+    // The south-west tile of the build area, which zones are written relative to
+    build_area: Coordinates,
+    // The distance in zones from the zone of the player to the zones it observes
+    view_radius: i32,
+    // The instance the player observes the zones of
+    instance: InstanceId,
+    // The zones the client was sent the full state of, which it keeps until it leaves them
+    followed_zones: BTreeSet<ZoneCoordinates>,
+}
+
+impl ZoneObserver {
+    fn observed_zones(&self) -> impl Iterator<Item = ZoneCoordinates> + '_ {
+        let center = ZoneCoordinates::of(self.coordinates);
+        let radius = self.view_radius;
+        (-radius..=radius)
+            .flat_map(move |dx| {
+                (-radius..=radius).map(move |dy| ZoneCoordinates {
+                    x: center.x + dx,
+                    y: center.y + dy,
+                    plane: center.plane,
+                })
+            })
+            .filter(|&zone| self.observes(zone))
+    }
+
+    fn observes(&self, zone: ZoneCoordinates) -> bool {
+        let center = ZoneCoordinates::of(self.coordinates);
+        let (dx, dy) = (
+            zone.south_west().x - self.build_area.x,
+            zone.south_west().y - self.build_area.y,
+        );
+
+        zone.plane == center.plane
+            && (zone.x - center.x).abs() <= self.view_radius
+            && (zone.y - center.y).abs() <= self.view_radius
+            && (0..BUILD_AREA_SIZE).contains(&dx)
+            && (0..BUILD_AREA_SIZE).contains(&dy)
+    }
+}
+
+/// The ZoneInfo collecting the events of every zone during a tick, and the players observing them
+#[derive(Default)]
+pub struct ZoneInfo {
+    // The events queued this tick, ordered by instance and zone so every player is sent them in the same order
+    zones: BTreeMap<(InstanceId, ZoneCoordinates), Vec<QueuedEvent>>,
+    states: BTreeMap<(InstanceId, ZoneCoordinates), ZoneState>,
+    observers: Slab<ZoneObserver>,
+}
+
+impl ZoneInfo {
+    /// Create a new ZoneInfo
+    pub fn new() -> ZoneInfo {
+        ZoneInfo::default()
+    }
+
+    /// Add a player observing the zones around the coordinates, with the build area centered on its zone.
+    /// Fails with a `CoordinateError` if the coordinates cannot be written to the client
+    pub fn add_player(&mut self, coordinates: Coordinates) -> Result<PlayerKey> {
+        coordinates.validate()?;
+
+        Ok(self.observers.insert(ZoneObserver {
+            coordinates,
+            build_center: coordinates,
+            build_area: build_area(coordinates),
+            view_radius: DEFAULT_ZONE_VIEW_RADIUS,
+            instance: WORLD_INSTANCE,
+            followed_zones: BTreeSet::new(),
+        }))
+    }
+
+    /// Remove a player, which stops observing zones.
+    /// Its private stacks that were to become public are added for every player now, and the others are deleted,
+    /// as its key goes to the next player to be added
+    pub fn remove_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        self.observers
+            .try_remove(player_id)
+            .context("failed getting player")?;
+        self.release_private_objs(player_id);
+
+        Ok(())
+    }
+
+    fn release_private_objs(&mut self, owner: PlayerKey) {
+        let mut public_objs = Vec::new();
+        for (&zone, state) in self.states.iter_mut() {
+            state.objs.retain_mut(|zone_obj| {
+                if zone_obj.owner != Some(owner) {
+                    return true;
+                }
+                if zone_obj.public_after.is_none() {
+                    return false;
+                }
+                zone_obj.owner = None;
+                zone_obj.public_after = None;
+                public_objs.push((zone, zone_obj.tile, zone_obj.obj));
+                true
+            });
+        }
+
+        // Events of this tick only sent to the owner would otherwise reach the next player with its key
+        for events in self.zones.values_mut() {
+            events.retain(|queued| queued.visibility != Visibility::Owner(owner));
+            for queued in events.iter_mut() {
+                if queued.visibility == Visibility::Others(owner) {
+                    queued.visibility = Visibility::Public;
+                }
+            }
+        }
+        for (zone, tile, obj) in public_objs {
+            self.push_event(zone, tile, Visibility::Public, ZoneEvent::ObjAdd(obj));
+        }
+    }
+
+    /// Move the player, which observes the zones around its new coordinates within its build area
+    pub fn set_player_coordinates(
+        &mut self,
+        player_id: PlayerKey,
+        coordinates: Coordinates,
+    ) -> Result<()> {
+        coordinates.validate()?;
+
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Center the build area of the player on the zone of the coordinates, as sent to its client in a map rebuild.
+    /// The client reloads the map, so every zone it observes is followed anew
+    pub fn set_build_area(&mut self, player_id: PlayerKey, center: Coordinates) -> Result<()> {
+        center.validate()?;
+
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        observer.build_center = center;
+        observer.build_area = build_area(center);
+        observer.followed_zones.clear();
+
+        Ok(())
+    }
+
+    /// Follow every zone the player observes anew, e.g. after its client missed the events of a suspension
+    pub fn resync_player(&mut self, player_id: PlayerKey) -> Result<()> {
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .followed_zones
+            .clear();
+
+        Ok(())
+    }
+
+    /// Set the distance in zones from the zone of the player to the zones it observes, e.g. further for zoomed out clients.
+    /// Zones within the new radius are followed from the next process, and zones beyond it are forgotten
+    pub fn set_view_radius(&mut self, player_id: PlayerKey, view_radius: i32) -> Result<()> {
+        if !(0..=MAX_ZONE_VIEW_RADIUS).contains(&view_radius) {
+            return Err(anyhow!("Zone view radius out of range: {}", view_radius));
+        }
+
+        self.observers
+            .get_mut(player_id)
+            .context("failed getting player")?
+            .view_radius = view_radius;
+
+        Ok(())
+    }
+
+    /// Get the distance in zones from the zone of the player to the zones it observes
+    pub fn view_radius(&self, player_id: PlayerKey) -> Option<i32> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.view_radius)
+    }
+
+    /// Move the player into the instance, after which it only observes the zones of that instance.
+    /// The client is expected to rebuild its map when entering an instance, so every zone it observes is followed anew
+    pub fn set_player_instance(
+        &mut self,
+        player_id: PlayerKey,
+        instance: InstanceId,
+    ) -> Result<()> {
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+        if observer.instance != instance {
+            observer.instance = instance;
+            observer.followed_zones.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Get the instance the player observes the zones of
+    pub fn player_instance(&self, player_id: PlayerKey) -> Option<InstanceId> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.instance)
+    }
+
+    /// Get the zones the client of the player was sent the full state of, and is sent the events of
+    pub fn followed_zones(&self, player_id: PlayerKey) -> Vec<ZoneCoordinates> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.followed_zones.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Get the coordinates the build area of the player was last centered on
+    pub fn build_center(&self, player_id: PlayerKey) -> Option<Coordinates> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.build_center)
+    }
+
+    /// Get the south-west tile of the build area of the player
+    pub fn build_area(&self, player_id: PlayerKey) -> Option<Coordinates> {
+        self.observers
+            .get(player_id)
+            .map(|observer| observer.build_area)
+    }
+
+    /// Check whether the player observes the zone, and is sent its events
+    pub fn observes(&self, player_id: PlayerKey, zone: ZoneCoordinates) -> bool {
+        self.observers
+            .get(player_id)
+            .is_some_and(|observer| observer.observes(zone))
+    }
+
+    /// Queue an event on the tile of the shared world, sent to every player observing its zone when processed this tick.
+    /// Events changing locs and objs are kept, and replayed to players following the zone later.
+    /// Fails with a `CoordinateError` if the coordinates cannot be written to the client
+    pub fn queue_event(&mut self, coordinates: Coordinates, event: ZoneEvent) -> Result<()> {
+        self.queue_instance_event(WORLD_INSTANCE, coordinates, event)
+    }
+
+    /// Queue an event on the tile of an instance, only sent to the players in that instance
+    pub fn queue_instance_event(
+        &mut self,
+        instance: InstanceId,
+        coordinates: Coordinates,
+        event: ZoneEvent,
+    ) -> Result<()> {
+        coordinates.validate()?;
+        event.validate()?;
+
+        let zone = (instance, ZoneCoordinates::of(coordinates));
+        let tile = zone_tile(coordinates);
+        let visibility = self.states.entry(zone).or_default().apply(tile, &event);
+        self.push_event(zone, tile, visibility, event);
+
+        Ok(())
+    }
+
+    fn push_event(
+        &mut self,
+        zone: (InstanceId, ZoneCoordinates),
+        tile: u8,
+        visibility: Visibility,
+        event: ZoneEvent,
+    ) {
+        self.zones.entry(zone).or_default().push(QueuedEvent {
+            tile,
+            visibility,
+            event,
+        });
+    }
+
+    /// Queue a loc to be added to the tile, or to replace the loc of the same layer on it
+    pub fn queue_loc_add_change(
+        &mut self,
+        coordinates: Coordinates,
+        loc: LocAddChange,
+    ) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::LocAddChange(loc))
+    }
+
+    /// Queue the loc of the layer given by the shape to be removed from the tile
+    pub fn queue_loc_del(&mut self, coordinates: Coordinates, loc: LocDel) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::LocDel(loc))
+    }
+
+    /// Queue an animation to be played on the loc of the layer given by the shape on the tile
+    pub fn queue_loc_anim(&mut self, coordinates: Coordinates, loc: LocAnim) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::LocAnim(loc))
+    }
+
+    /// Queue a stack of an item to be added to the tile
+    pub fn queue_obj_add(&mut self, coordinates: Coordinates, obj: ObjAdd) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::ObjAdd(obj))
+    }
+
+    /// Queue a stack of an item to be added to the tile for its owner only, e.g. a drop, in the instance of the owner.
+    /// The stack is added for every other player once the given amount of ticks passed, or never if none.
+    /// Deleting or counting the stack while it is private is only sent to the owner. Fails if the owner is unknown
+    pub fn queue_private_obj_add(
+        &mut self,
+        coordinates: Coordinates,
+        obj: ObjAdd,
+        owner: PlayerKey,
+        public_after: Option<u32>,
+    ) -> Result<()> {
+        let instance = self
+            .player_instance(owner)
+            .context("failed getting player")?;
+        if public_after == Some(0) {
+            return self.queue_instance_event(instance, coordinates, ZoneEvent::ObjAdd(obj));
+        }
+
+        coordinates.validate()?;
+        let event = ZoneEvent::ObjAdd(obj);
+        event.validate()?;
+
+        let zone = (instance, ZoneCoordinates::of(coordinates));
+        let tile = zone_tile(coordinates);
+        self.states.entry(zone).or_default().objs.push(ZoneObj {
+            tile,
+            obj,
+            owner: Some(owner),
+            public_after,
+        });
+        self.push_event(zone, tile, Visibility::Owner(owner), event);
+
+        Ok(())
+    }
+
+    /// Queue the stack of an item of the same quantity to be removed from the tile
+    pub fn queue_obj_del(&mut self, coordinates: Coordinates, obj: ObjDel) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::ObjDel(obj))
+    }
+
+    /// Queue the quantity of a stack of an item on the tile to be changed
+    pub fn queue_obj_count(&mut self, coordinates: Coordinates, obj: ObjCount) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::ObjCount(obj))
+    }
+
+    /// Queue a projectile to be launched from the tile, sent to the players observing the zone of the tile
+    pub fn queue_map_proj_anim(
+        &mut self,
+        coordinates: Coordinates,
+        projectile: MapProjAnim,
+    ) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::MapProjAnim(projectile))
+    }
+
+    /// Queue a spot animation to be played on the tile
+    pub fn queue_map_anim(
+        &mut self,
+        coordinates: Coordinates,
+        spot_animation: MapAnim,
+    ) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::MapAnim(spot_animation))
+    }
+
+    /// Queue a sound to be played around the tile, sent to the players observing the zone of the tile
+    /// rather than those within its radius
+    pub fn queue_sound_area(&mut self, coordinates: Coordinates, sound: SoundArea) -> Result<()> {
+        self.queue_event(coordinates, ZoneEvent::SoundArea(sound))
+    }
+
+    /// Advance to the next tick, dropping the events of the current one.
+    /// Private stacks of items that become public are added for every player but their owner in the next tick.
+    /// Should be called once at the end of every tick, after all players have been processed
+    pub fn tick(&mut self) {
+        self.zones.clear();
+        self.states.retain(|_, state| !state.is_empty());
+
+        let mut public_objs = Vec::new();
+        for (&zone, state) in self.states.iter_mut() {
+            for zone_obj in state.objs.iter_mut() {
+                match (zone_obj.owner, &mut zone_obj.public_after) {
+                    (Some(owner), Some(1)) => {
+                        public_objs.push((zone, zone_obj.tile, zone_obj.obj, owner));
+                        zone_obj.owner = None;
+                        zone_obj.public_after = None;
+                    }
+                    (Some(_), Some(ticks)) => *ticks -= 1,
+                    _ => {}
+                }
+            }
+        }
+        for (zone, tile, obj, owner) in public_objs {
+            self.push_event(
+                zone,
+                tile,
+                Visibility::Others(owner),
+                ZoneEvent::ObjAdd(obj),
+            );
+        }
+    }
+
+    /// Forget the locs and objs changed in the zone of the shared world, e.g. when it is reset.
+    /// Players following the zone keep them until they follow it anew
+    pub fn clear_zone(&mut self, zone: ZoneCoordinates) {
+        self.states.remove(&(WORLD_INSTANCE, zone));
+    }
+
+    /// Forget the locs and objs changed in every zone of the instance, and the events queued in it this tick,
+    /// e.g. when the instance is destroyed
+    pub fn clear_instance(&mut self, instance: InstanceId) {
+        self.states
+            .retain(|&(zone_instance, _), _| zone_instance != instance);
+        self.zones
+            .retain(|&(zone_instance, _), _| zone_instance != instance);
+    }
+
+    /// Process a player, returning the packets with the events of the zones it observes this tick.
+    /// Zones the player starts observing are cleared and followed by the locs and objs changed in them,
+    /// and the transient events of this tick.
+    /// The events of each zone are enclosed in a single batch, or sent on their own if there is only one
+    pub fn process(&mut self, player_id: PlayerKey) -> Result<Vec<ZonePacket>> {
+        let observer = self
+            .observers
+            .get_mut(player_id)
+            .context("failed getting player")?;
+
+        // Zones the player left are forgotten, as the client may have dropped them
+        let observed_zones: BTreeSet<ZoneCoordinates> = observer.observed_zones().collect();
+        observer
+            .followed_zones
+            .retain(|zone| observed_zones.contains(zone));
+
+        let mut packets = Vec::new();
+        for zone in observed_zones {
+            let queued_events = self
+                .zones
+                .get(&(observer.instance, zone))
+                .into_iter()
+                .flatten()
+                .filter(|queued| queued.visibility.visible_to(player_id));
+            if !observer.followed_zones.insert(zone) {
+                let events: Vec<(u8, &ZoneEvent)> = queued_events
+                    .map(|queued| (queued.tile, &queued.event))
+                    .collect();
+                write_zone_events(&mut packets, observer, zone, &events, false)?;
+                continue;
+            }
+
+            let mut payload = Vec::new();
+            write_zone_header(&mut payload, observer, zone)?;
+            packets.push(ZonePacket {
+                prot: ZoneProt::UpdateZoneFullFollows,
+                payload,
+            });
+
+            let replay: Vec<(u8, ZoneEvent)> = self
+                .states
+                .get(&(observer.instance, zone))
+                .into_iter()
+                .flat_map(|state| state.replay(player_id))
+                .collect();
+            let events: Vec<(u8, &ZoneEvent)> = replay
+                .iter()
+                .map(|(tile, event)| (*tile, event))
+                .chain(
+                    queued_events
+                        .filter(|queued| queued.event.is_transient())
+                        .map(|queued| (queued.tile, &queued.event)),
+                )
+                .collect();
+            write_zone_events(&mut packets, observer, zone, &events, true)?;
+        }
+
+        Ok(packets)
+    }
+}
+
+// Write the events of a zone, enclosed in a single batch if there are several of them.
+// A single event is sent on its own, after selecting the zone unless it already is
+fn write_zone_events(
+    packets: &mut Vec<ZonePacket>,
+    observer: &ZoneObserver,
+    zone: ZoneCoordinates,
+    events: &[(u8, &ZoneEvent)],
+    selected: bool,
+) -> Result<()> {
+    match events {
+        [] => {}
+        [(tile, event)] => {
+            if !selected {
+                let mut payload = Vec::new();
+                write_zone_header(&mut payload, observer, zone)?;
+                packets.push(ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload,
+                });
+            }
+
+            let mut payload = Vec::new();
+            event.encode(&mut payload, *tile)?;
+            packets.push(ZonePacket {
+                prot: event.prot(),
+                payload,
+            });
+        }
+        _ => {
+            let mut payload = Vec::new();
+            write_zone_header(&mut payload, observer, zone)?;
+            for (tile, event) in events {
+                let index = event
+                    .prot()
+                    .enclosed_index()
+                    .context("event cannot be enclosed")?;
+                payload.write_u8(index)?;
+                event.encode(&mut payload, *tile)?;
+            }
+            packets.push(ZonePacket {
+                prot: ZoneProt::UpdateZonePartialEnclosed,
+                payload,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// The coordinates of the tile within its zone, packed into 3 bits each
+fn zone_tile(coordinates: Coordinates) -> u8 {
+    (coordinates.x.rem_euclid(ZONE_SIZE) << 4 | coordinates.y.rem_euclid(ZONE_SIZE)) as u8
+}
+
+// The build area extends 6 zones to the south and west of the zone it is centered on
+pub(crate) fn build_area(center: Coordinates) -> Coordinates {
+    let zone = ZoneCoordinates::of(center);
+    ZoneCoordinates {
+        x: (zone.x - 6).max(0),
+        y: (zone.y - 6).max(0),
+        plane: zone.plane,
+    }
+    .south_west()
+}
+
+// The zone is written as its south-west tile relative to the build area, and its wire plane
+fn write_zone_header(
+    buf: &mut Vec<u8>,
+    observer: &ZoneObserver,
+    zone: ZoneCoordinates,
+) -> Result<()> {
+    let south_west = zone.south_west();
+    let x = u8::try_from(south_west.x - observer.build_area.x)
+        .map_err(|_| anyhow!("Zone {:?} is outside of the build area", zone))?;
+    let y = u8::try_from(south_west.y - observer.build_area.y)
+        .map_err(|_| anyhow!("Zone {:?} is outside of the build area", zone))?;
+
+    buf.write_u8(x)?;
+    buf.write_u8(y)?;
+    buf.write_u8(south_west.wire_plane() as u8)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Add a player that was already sent the full state of the zones it observes
+    fn add_following_player(
+        zoneinfo: &mut ZoneInfo,
+        coordinates: Coordinates,
+    ) -> Result<PlayerKey> {
+        let player_id = zoneinfo.add_player(coordinates)?;
+        zoneinfo.process(player_id)?;
+
+        Ok(player_id)
+    }
+
+    #[test]
+    fn zone_view_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = zoneinfo.add_player(Coordinates::new(3222, 3218, 0))?;
+        assert_eq!(
+            zoneinfo.build_area(player_id),
+            Some(Coordinates::new(3168, 3168, 0))
+        );
+
+        let zone = ZoneCoordinates::of(Coordinates::new(3222, 3218, 0));
+        assert_eq!(
+            zone,
+            ZoneCoordinates {
+                x: 402,
+                y: 402,
+                plane: 0
+            }
+        );
+        assert!(zoneinfo.observes(player_id, zone));
+        assert!(zoneinfo.observes(
+            player_id,
+            ZoneCoordinates {
+                x: 405,
+                y: 399,
+                plane: 0
+            }
+        ));
+        assert!(!zoneinfo.observes(
+            player_id,
+            ZoneCoordinates {
+                x: 406,
+                y: 402,
+                plane: 0
+            }
+        ));
+        assert!(!zoneinfo.observes(player_id, ZoneCoordinates { plane: 1, ..zone }));
+
+        // Zones around the player are only observed within its build area
+        zoneinfo.set_player_coordinates(player_id, Coordinates::new(3168, 3218, 0))?;
+        assert!(zoneinfo.observes(
+            player_id,
+            ZoneCoordinates {
+                x: 396,
+                y: 402,
+                plane: 0
+            }
+        ));
+        assert!(!zoneinfo.observes(
+            player_id,
+            ZoneCoordinates {
+                x: 395,
+                y: 402,
+                plane: 0
+            }
+        ));
+
+        // Players further zoomed out observe further, still within their build area
+        zoneinfo.set_player_coordinates(player_id, Coordinates::new(3222, 3218, 0))?;
+        assert_eq!(
+            zoneinfo.view_radius(player_id),
+            Some(DEFAULT_ZONE_VIEW_RADIUS)
+        );
+        assert!(zoneinfo
+            .set_view_radius(player_id, MAX_ZONE_VIEW_RADIUS + 1)
+            .is_err());
+        assert!(zoneinfo.set_view_radius(player_id, -1).is_err());
+        zoneinfo.set_view_radius(player_id, 5)?;
+        assert!(zoneinfo.observes(player_id, ZoneCoordinates { x: 407, ..zone }));
+        assert!(!zoneinfo.observes(player_id, ZoneCoordinates { x: 408, ..zone }));
+        assert_eq!(zoneinfo.process(player_id)?.len(), 11 * 11);
+
+        zoneinfo.set_view_radius(player_id, 1)?;
+        assert!(!zoneinfo.observes(player_id, ZoneCoordinates { x: 404, ..zone }));
+        zoneinfo.process(player_id)?;
+        assert_eq!(zoneinfo.followed_zones(player_id).len(), 3 * 3);
+
+        zoneinfo.remove_player(player_id)?;
+        assert!(zoneinfo.process(player_id).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn loc_add_change_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+        let far_id = add_following_player(&mut zoneinfo, Coordinates::new(3300, 3218, 0))?;
+
+        let door = LocAddChange {
+            id: 1530,
+            shape: 0,
+            rotation: 2,
+        };
+        zoneinfo.queue_loc_add_change(Coordinates::new(3219, 3219, 0), door)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::LocAddChange,
+                    payload: vec![0x33, 0x02, 0x05, 0xFA],
+                }
+            ]
+        );
+        assert!(zoneinfo.process(far_id)?.is_empty());
+
+        zoneinfo.tick();
+        assert!(zoneinfo.process(player_id)?.is_empty());
+        assert!(zoneinfo
+            .queue_loc_add_change(
+                Coordinates::new(3219, 3219, 0),
+                LocAddChange { shape: 23, ..door }
+            )
+            .is_err());
+        assert!(zoneinfo
+            .queue_loc_add_change(
+                Coordinates::new(3219, 3219, 0),
+                LocAddChange {
+                    rotation: 4,
+                    ..door
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn loc_del_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        // Events within the same zone are sent in the order they were queued
+        let door = LocDel {
+            shape: 0,
+            rotation: 2,
+        };
+        zoneinfo.queue_loc_del(Coordinates::new(3219, 3219, 0), door)?;
+        zoneinfo.queue_loc_add_change(
+            Coordinates::new(3219, 3218, 0),
+            LocAddChange {
+                id: 1531,
+                shape: 0,
+                rotation: 3,
+            },
+        )?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [ZonePacket {
+                prot: ZoneProt::UpdateZonePartialEnclosed,
+                payload: vec![48, 48, 0, 1, 0x02, 0x33, 0, 0x32, 0x03, 0x05, 0xFB],
+            }]
+        );
+        assert!(zoneinfo
+            .queue_loc_del(
+                Coordinates::new(3219, 3219, 0),
+                LocDel { shape: 23, ..door }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn loc_anim_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let obstacle = LocAnim {
+            id: 497,
+            shape: 10,
+            rotation: 1,
+        };
+        zoneinfo.queue_loc_anim(Coordinates::new(3225, 3210, 0), obstacle)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![56, 40, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::LocAnim,
+                    payload: vec![0x01, 0xF1, 0x12, 0x29],
+                }
+            ]
+        );
+        assert!(zoneinfo
+            .queue_loc_anim(
+                Coordinates::new(3225, 3210, 0),
+                LocAnim {
+                    rotation: 4,
+                    ..obstacle
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn obj_add_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let coins = ObjAdd {
+            id: 995,
+            quantity: 100_000,
+        };
+        zoneinfo.queue_obj_add(Coordinates::new(3222, 3218, 0), coins)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::ObjAdd,
+                    payload: vec![0x03, 0xE3, 0x62, 0x00, 0x01, 0x86, 0xA0],
+                }
+            ]
+        );
+        assert!(zoneinfo
+            .queue_obj_add(
+                Coordinates::new(3222, 3218, 0),
+                ObjAdd {
+                    quantity: 0,
+                    ..coins
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn obj_del_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let coins = ObjDel {
+            id: 995,
+            quantity: 100_000,
+        };
+        zoneinfo.queue_obj_del(Coordinates::new(3222, 3218, 0), coins)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::ObjDel,
+                    payload: vec![0x62, 0x03, 0xE3, 0x00, 0x01, 0x86, 0xA0],
+                }
+            ]
+        );
+        assert!(zoneinfo
+            .queue_obj_del(
+                Coordinates::new(3222, 3218, 0),
+                ObjDel {
+                    quantity: -1,
+                    ..coins
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn obj_count_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let coins = ObjCount {
+            id: 995,
+            old_quantity: 100,
+            new_quantity: 250,
+        };
+        zoneinfo.queue_obj_count(Coordinates::new(3222, 3218, 0), coins)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::ObjCount,
+                    payload: vec![0x62, 0x03, 0xE3, 0, 0, 0, 100, 0, 0, 0, 250],
+                }
+            ]
+        );
+        assert!(zoneinfo
+            .queue_obj_count(
+                Coordinates::new(3222, 3218, 0),
+                ObjCount {
+                    new_quantity: 0,
+                    ..coins
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_proj_anim_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let arrow = MapProjAnim {
+            id: 10,
+            dx: 3,
+            dy: -2,
+            target: FaceEntity::Npc(4),
+            start_height: 43,
+            end_height: 31,
+            delay: 41,
+            duration: 15,
+            arc: 15,
+            source_offset: 11,
+        };
+        zoneinfo.queue_map_proj_anim(Coordinates::new(3222, 3218, 0), arrow)?;
+        zoneinfo.queue_map_proj_anim(
+            Coordinates::new(3222, 3218, 0),
+            MapProjAnim {
+                target: FaceEntity::Player(1),
+                ..arrow
+            },
+        )?;
+        let packets = zoneinfo.process(player_id)?;
+        assert_eq!(
+            packets[0].payload[3..20],
+            [6, 0x62, 3, 0xFE, 0, 5, 0, 10, 43, 31, 0, 41, 0, 56, 15, 11, 6]
+        );
+        assert_eq!(packets[0].payload[23..25], [0xFF, 0xFE]);
+
+        assert!(zoneinfo
+            .queue_map_proj_anim(
+                Coordinates::new(3222, 3218, 0),
+                MapProjAnim {
+                    delay: u16::MAX,
+                    ..arrow
+                }
+            )
+            .is_err());
+        assert!(zoneinfo
+            .queue_map_proj_anim(
+                Coordinates::new(3222, 3218, 0),
+                MapProjAnim {
+                    target: FaceEntity::Npc(32768),
+                    ..arrow
+                }
+            )
+            .is_err());
+        // The index of the NPC plus one no longer fits the target
+        assert!(zoneinfo
+            .queue_map_proj_anim(
+                Coordinates::new(3222, 3218, 0),
+                MapProjAnim {
+                    target: FaceEntity::Npc(32767),
+                    ..arrow
+                }
+            )
+            .is_err());
+        zoneinfo.tick();
+        assert!(zoneinfo.process(player_id)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_anim_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let splash = MapAnim {
+            id: 369,
+            height: 0,
+            delay: 60,
+        };
+        zoneinfo.queue_map_anim(Coordinates::new(3223, 3219, 0), splash)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::MapAnim,
+                    payload: vec![0x01, 0x71, 0x73, 0, 0, 60],
+                }
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sound_area_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let sound = SoundArea {
+            id: 2739,
+            radius: 5,
+            repetitions: 1,
+            delay: 0,
+        };
+        zoneinfo.queue_sound_area(Coordinates::new(3217, 3223, 0), sound)?;
+        assert_eq!(
+            zoneinfo.process(player_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::SoundArea,
+                    payload: vec![0x0A, 0xB3, 5, 1, 0, 0x17],
+                }
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn zone_follow_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let player_id = zoneinfo.add_player(Coordinates::new(3222, 3218, 0))?;
+
+        // Every zone observed is cleared once, starting with the south-west zone
+        let packets = zoneinfo.process(player_id)?;
+        assert_eq!(packets.len(), 49);
+        assert!(packets
+            .iter()
+            .all(|packet| packet.prot == ZoneProt::UpdateZoneFullFollows));
+        assert_eq!(packets[0].payload, [24, 24, 0]);
+        assert_eq!(zoneinfo.followed_zones(player_id).len(), 49);
+        assert!(zoneinfo.process(player_id)?.is_empty());
+
+        // Walking a zone east follows the column of zones entered, with their events on their own
+        zoneinfo.queue_loc_del(
+            Coordinates::new(3250, 3218, 0),
+            LocDel {
+                shape: 10,
+                rotation: 0,
+            },
+        )?;
+        zoneinfo.set_player_coordinates(player_id, Coordinates::new(3230, 3218, 0))?;
+        let packets = zoneinfo.process(player_id)?;
+        assert_eq!(packets.len(), 8);
+        assert_eq!(
+            packets[3..5],
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZoneFullFollows,
+                    payload: vec![80, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::LocDel,
+                    payload: vec![40, 0x22],
+                }
+            ]
+        );
+        assert!(!zoneinfo
+            .followed_zones(player_id)
+            .contains(&ZoneCoordinates {
+                x: 399,
+                y: 402,
+                plane: 0
+            }));
+
+        // A rebuild reloads every zone, replaying the deleted loc
+        zoneinfo.tick();
+        zoneinfo.set_build_area(player_id, Coordinates::new(3230, 3218, 0))?;
+        assert!(zoneinfo.followed_zones(player_id).is_empty());
+        assert_eq!(zoneinfo.process(player_id)?.len(), 50);
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_obj_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let owner_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+        let other_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let coordinates = Coordinates::new(3222, 3218, 0);
+        let bones = ObjAdd {
+            id: 526,
+            quantity: 1,
+        };
+        let coins = ObjAdd {
+            id: 995,
+            quantity: 10,
+        };
+        zoneinfo.queue_private_obj_add(coordinates, bones, owner_id, Some(3))?;
+        zoneinfo.queue_private_obj_add(coordinates, coins, owner_id, Some(3))?;
+        // Both stacks are enclosed in a single batch
+        assert_eq!(zoneinfo.process(owner_id)?.len(), 1);
+        assert!(zoneinfo.process(other_id)?.is_empty());
+        zoneinfo.tick();
+
+        // Counting a private stack is only sent to its owner, and the stack becomes public at its new quantity
+        zoneinfo.queue_obj_count(
+            coordinates,
+            ObjCount {
+                id: 995,
+                old_quantity: 10,
+                new_quantity: 20,
+            },
+        )?;
+        assert_eq!(zoneinfo.process(owner_id)?.len(), 2);
+        assert!(zoneinfo.process(other_id)?.is_empty());
+        zoneinfo.tick();
+
+        // Deleting a private stack is only sent to its owner, and the stack never becomes public
+        zoneinfo.queue_obj_del(
+            coordinates,
+            ObjDel {
+                id: 526,
+                quantity: 1,
+            },
+        )?;
+        assert_eq!(zoneinfo.process(owner_id)?.len(), 2);
+        assert!(zoneinfo.process(other_id)?.is_empty());
+        zoneinfo.tick();
+
+        assert!(zoneinfo.process(owner_id)?.is_empty());
+        assert_eq!(
+            zoneinfo.process(other_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                ZonePacket {
+                    prot: ZoneProt::ObjAdd,
+                    payload: vec![0x03, 0xE3, 0x62, 0, 0, 0, 20],
+                }
+            ]
+        );
+        zoneinfo.tick();
+
+        // Deleting a public stack is sent to every player
+        zoneinfo.queue_obj_del(
+            coordinates,
+            ObjDel {
+                id: 995,
+                quantity: 20,
+            },
+        )?;
+        assert_eq!(zoneinfo.process(owner_id)?.len(), 2);
+        assert_eq!(zoneinfo.process(other_id)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_obj_owner_removed_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let owner_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+        let other_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+
+        let coordinates = Coordinates::new(3222, 3218, 0);
+        zoneinfo.queue_private_obj_add(
+            coordinates,
+            ObjAdd {
+                id: 526,
+                quantity: 1,
+            },
+            owner_id,
+            None,
+        )?;
+        zoneinfo.queue_private_obj_add(
+            coordinates,
+            ObjAdd {
+                id: 995,
+                quantity: 10,
+            },
+            owner_id,
+            Some(50),
+        )?;
+        zoneinfo.process(owner_id)?;
+        zoneinfo.process(other_id)?;
+        zoneinfo.tick();
+
+        // The stack that was to become public is added right away, the one that was to stay private is gone
+        zoneinfo.remove_player(owner_id)?;
+        let coins = ZonePacket {
+            prot: ZoneProt::ObjAdd,
+            payload: vec![0x03, 0xE3, 0x62, 0, 0, 0, 10],
+        };
+        assert_eq!(
+            zoneinfo.process(other_id)?,
+            [
+                ZonePacket {
+                    prot: ZoneProt::UpdateZonePartialFollows,
+                    payload: vec![48, 48, 0],
+                },
+                coins.clone()
+            ]
+        );
+        zoneinfo.tick();
+
+        // Stacks of a player that is gone are refused rather than added to the shared world
+        assert!(zoneinfo
+            .queue_private_obj_add(
+                coordinates,
+                ObjAdd {
+                    id: 526,
+                    quantity: 1,
+                },
+                owner_id,
+                Some(50),
+            )
+            .is_err());
+
+        // The next player given the key of the owner does not inherit its stacks
+        let player_id = zoneinfo.add_player(coordinates)?;
+        assert_eq!(player_id, owner_id);
+        let packets = zoneinfo.process(player_id)?;
+        assert_eq!(
+            packets
+                .iter()
+                .filter(|packet| packet.prot == ZoneProt::ObjAdd)
+                .collect::<Vec<_>>(),
+            [&coins]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn zone_replay_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let owner_id = add_following_player(&mut zoneinfo, Coordinates::new(3222, 3218, 0))?;
+        let player_id = zoneinfo.add_player(Coordinates::new(3300, 3218, 0))?;
+        zoneinfo.process(player_id)?;
+
+        // Only the last change of a loc is replayed, along with the objs visible to the player
+        let coordinates = Coordinates::new(3222, 3218, 0);
+        let door = LocAddChange {
+            id: 1530,
+            shape: 0,
+            rotation: 2,
+        };
+        zoneinfo.queue_loc_add_change(coordinates, door)?;
+        zoneinfo.queue_loc_add_change(coordinates, LocAddChange { id: 1531, ..door })?;
+        zoneinfo.queue_loc_del(
+            coordinates,
+            LocDel {
+                shape: 22,
+                rotation: 0,
+            },
+        )?;
+        zoneinfo.queue_loc_anim(
+            coordinates,
+            LocAnim {
+                id: 497,
+                shape: 0,
+                rotation: 2,
+            },
+        )?;
+        zoneinfo.queue_obj_add(
+            coordinates,
+            ObjAdd {
+                id: 995,
+                quantity: 10,
+            },
+        )?;
+        zoneinfo.queue_obj_add(
+            coordinates,
+            ObjAdd {
+                id: 526,
+                quantity: 1,
+            },
+        )?;
+        zoneinfo.queue_obj_del(
+            coordinates,
+            ObjDel {
+                id: 526,
+                quantity: 1,
+            },
+        )?;
+        zoneinfo.queue_private_obj_add(
+            coordinates,
+            ObjAdd {
+                id: 526,
+                quantity: 1,
+            },
+            owner_id,
+            None,
+        )?;
+        zoneinfo.process(owner_id)?;
+        zoneinfo.tick();
+
+        // Transient events are only sent in the tick they were queued
+        zoneinfo.queue_map_anim(
+            coordinates,
+            MapAnim {
+                id: 369,
+                height: 0,
+                delay: 0,
+            },
+        )?;
+        zoneinfo.set_player_coordinates(player_id, coordinates)?;
+        zoneinfo.set_build_area(player_id, coordinates)?;
+        let packets = zoneinfo.process(player_id)?;
+        let zone = packets
+            .iter()
+            .position(|packet| packet.payload == [48, 48, 0])
+            .unwrap();
+        assert_eq!(packets[zone].prot, ZoneProt::UpdateZoneFullFollows);
+        assert_eq!(
+            packets[zone + 1],
+            ZonePacket {
+                prot: ZoneProt::UpdateZonePartialEnclosed,
+                payload: vec![
+                    48, 48, 0, 0, 0x62, 0x02, 0x05, 0xFB, 1, 0x58, 0x62, 3, 0x03, 0xE3, 0x62, 0, 0,
+                    0, 10, 7, 0x01, 0x71, 0x62, 0, 0, 0
+                ],
+            }
+        );
+        assert_eq!(packets[zone + 2].prot, ZoneProt::UpdateZoneFullFollows);
+
+        Ok(())
+    }
+
+    #[test]
+    fn instance_test() -> Result<()> {
+        let mut zoneinfo = ZoneInfo::new();
+        let coordinates = Coordinates::new(3222, 3218, 0);
+        let world_id = add_following_player(&mut zoneinfo, coordinates)?;
+        let raider_id = zoneinfo.add_player(coordinates)?;
+        zoneinfo.set_player_instance(raider_id, 7)?;
+        assert_eq!(zoneinfo.player_instance(raider_id), Some(7));
+        assert_eq!(zoneinfo.player_instance(world_id), Some(WORLD_INSTANCE));
+        zoneinfo.process(raider_id)?;
+
+        // Events at the same coordinates stay within their instance
+        let door = LocAddChange {
+            id: 1530,
+            shape: 0,
+            rotation: 2,
+        };
+        zoneinfo.queue_loc_add_change(coordinates, door)?;
+        assert_eq!(zoneinfo.process(world_id)?.len(), 2);
+        assert!(zoneinfo.process(raider_id)?.is_empty());
+        zoneinfo.tick();
+
+        let chest = LocAddChange {
+            id: 378,
+            shape: 10,
+            rotation: 0,
+        };
+        zoneinfo.queue_instance_event(7, coordinates, ZoneEvent::LocAddChange(chest))?;
+        let bones = ObjAdd {
+            id: 526,
+            quantity: 1,
+        };
+        zoneinfo.queue_private_obj_add(coordinates, bones, raider_id, Some(0))?;
+        let packets = zoneinfo.process(raider_id)?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].prot, ZoneProt::UpdateZonePartialEnclosed);
+        assert!(zoneinfo.process(world_id)?.is_empty());
+        zoneinfo.tick();
+
+        // Entering an instance follows its zones anew, replaying its locs and objs
+        let visitor_id = add_following_player(&mut zoneinfo, coordinates)?;
+        zoneinfo.set_player_instance(visitor_id, 7)?;
+        let packets = zoneinfo.process(visitor_id)?;
+        let zone = packets
+            .iter()
+            .position(|packet| {
+                packet.prot == ZoneProt::UpdateZoneFullFollows && packet.payload == [48, 48, 0]
+            })
+            .context("zone followed anew")?;
+        assert_eq!(packets[zone + 1].prot, ZoneProt::UpdateZonePartialEnclosed);
+
+        // Destroying the instance forgets its state, leaving the world untouched
+        zoneinfo.clear_instance(7);
+        zoneinfo.set_player_instance(visitor_id, WORLD_INSTANCE)?;
+        let packets = zoneinfo.process(visitor_id)?;
+        assert_eq!(packets[zone + 1].prot, ZoneProt::LocAddChange);
+        zoneinfo.set_player_instance(visitor_id, 7)?;
+        let packets = zoneinfo.process(visitor_id)?;
+        assert_ne!(
+            packets.get(zone + 1).map(|packet| packet.prot),
+            Some(ZoneProt::UpdateZonePartialEnclosed)
+        );
+
+        Ok(())
+    }
+}